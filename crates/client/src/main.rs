@@ -2,16 +2,19 @@ mod app;
 mod assets;
 mod debug;
 mod game;
-pub mod net;
 mod render;
 mod tui;
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use clap::Parser;
 use winit::event_loop::EventLoop;
 
-use net::{ClientConfig, NetworkClient};
+use dual::PacketLossSimulation;
+use dual::net::key_from_hex;
+use dual_client::net;
+use net::{ClientConfig, NetworkClient, TokenSource};
 
 #[derive(Parser)]
 #[command(name = "dual")]
@@ -20,12 +23,68 @@ struct Args {
     #[arg(
         short,
         long,
-        help = "Server address to connect to (e.g., 127.0.0.1:27015)"
+        help = "Server address to connect to (e.g., 127.0.0.1:27015 or [::1]:27015)"
     )]
     server: Option<String>,
 
     #[arg(long, help = "Skip TUI menu and launch game directly")]
     skip_menu: bool,
+
+    #[arg(long, help = "Connect token file written by dual-token")]
+    token: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with = "token",
+        help = "Hex key to mint local connect tokens with (defaults to the development key)"
+    )]
+    private_key: Option<String>,
+
+    #[arg(long, help = "Simulate a bad link in both directions")]
+    simulate_packet_loss: bool,
+
+    #[arg(long, default_value_t = 0.0, help = "Packet loss percentage (0-100)")]
+    loss_percent: f32,
+
+    #[arg(long, default_value_t = 0, help = "Minimum latency in ms")]
+    min_latency: u32,
+
+    #[arg(long, default_value_t = 0, help = "Maximum latency in ms")]
+    max_latency: u32,
+
+    #[arg(long, default_value_t = 0, help = "Jitter in ms")]
+    jitter: u32,
+
+    #[arg(long, default_value_t = 0.0, help = "Packet duplication percentage")]
+    duplicate_percent: f32,
+
+    #[arg(long, default_value_t = 0.0, help = "Packet reordering percentage")]
+    reorder_percent: f32,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Link bandwidth cap in KB/s (0 = none)"
+    )]
+    bandwidth_cap: u32,
+
+    #[arg(long, help = "Seed for the link simulation, to replay a run")]
+    sim_seed: Option<u64>,
+
+    #[arg(long, help = "Record every datagram to this file for dual-dissect")]
+    capture: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Append session keys to this file so dual-dissect can decrypt a capture"
+    )]
+    key_log: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Master server for the server browser (e.g., 203.0.113.5:27010)"
+    )]
+    master: Option<SocketAddr>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -33,8 +92,33 @@ fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
 
+    let tokens = match (&args.token, &args.private_key) {
+        (Some(path), _) => TokenSource::from_file(path)?,
+        (None, Some(hex)) => TokenSource::from_key(key_from_hex(hex)?),
+        (None, None) => TokenSource::default(),
+    };
+
+    let config = ClientConfig {
+        link_conditions: args.simulate_packet_loss.then(|| PacketLossSimulation {
+            enabled: true,
+            loss_percent: args.loss_percent,
+            min_latency_ms: args.min_latency,
+            max_latency_ms: args.max_latency,
+            jitter_ms: args.jitter,
+            duplicate_percent: args.duplicate_percent,
+            reorder_percent: args.reorder_percent,
+            bandwidth_bytes_per_sec: args.bandwidth_cap * 1024,
+            ..Default::default()
+        }),
+        link_seed: args.sim_seed,
+        capture_path: args.capture,
+        key_log_path: args.key_log,
+        master_addr: args.master,
+        ..Default::default()
+    };
+
     if let Some(server_addr) = args.server {
-        let client = connect_to_server(&server_addr)?;
+        let client = connect_to_server(&server_addr, &tokens, config)?;
         run_game(Some(client))?;
         return Ok(());
     }
@@ -44,7 +128,7 @@ fn main() -> anyhow::Result<()> {
         return Ok(());
     }
 
-    match tui::run_menu() {
+    match tui::run_menu(tokens, config) {
         Ok(Some(client)) => {
             run_game(Some(client))?;
         }
@@ -60,11 +144,14 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn connect_to_server(addr: &str) -> anyhow::Result<NetworkClient> {
+fn connect_to_server(
+    addr: &str,
+    tokens: &TokenSource,
+    config: ClientConfig,
+) -> anyhow::Result<NetworkClient> {
     let socket_addr: SocketAddr = addr.parse()?;
-    let config = ClientConfig::default();
-    let mut client = NetworkClient::new(config)?;
-    client.connect(socket_addr)?;
+    let mut client = NetworkClient::new(socket_addr, config)?;
+    client.connect(tokens.token_for(socket_addr)?)?;
     Ok(client)
 }
 
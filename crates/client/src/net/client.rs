@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -6,51 +7,93 @@ use std::time::{Duration, Instant};
 
 use glam::Vec3;
 
+use dual::net::{
+    BlockDirection, BlockKind, BlockProgress, CaptureWriter, ClientCommandBatch,
+    CommandBufferStatus, CompletedBlock, ConnectToken, ConnectionStats, DenyReason, KeyLogWriter,
+    LinkConditioner, SnapshotCodec, Transport, UdpTransport, generate_client_id, is_too_large,
+    sequence_greater_than, unix_timestamp,
+};
 use dual::{
-    ClientConnection, ConnectionState, NetworkEndpoint, NetworkStats, PacketType, Reliability,
-    WorldSnapshot,
+    ClientCommand, ClientConnection, ConnectionState, JitterBufferConfig, NetworkEndpoint,
+    NetworkStats, Packet, PacketHeader, PacketType, Reliability, TestingGround, WorldSnapshot,
 };
 
+use super::clock::{ClockSync, CommandClock};
 use super::config::ClientConfig;
 use super::input::InputState;
 use super::interpolation::{InterpolatedEntity, InterpolationConfig, InterpolationEngine};
 use super::prediction::ClientPrediction;
 
-pub struct NetworkClient {
-    endpoint: NetworkEndpoint,
+/// Largest server config block we take; it's a short description.
+const MAX_SERVER_CONFIG_SIZE: usize = 64 * 1024;
+
+pub struct NetworkClient<T: Transport = UdpTransport> {
+    endpoint: NetworkEndpoint<LinkConditioner<T>>,
     connection: ClientConnection,
     config: ClientConfig,
     state: ConnectionState,
     client_id: Option<u32>,
     entity_id: Option<u32>,
-    client_salt: u64,
-    server_salt: Option<u64>,
+    connect_token: Option<ConnectToken>,
+    server_index: usize,
+    // (ChallengeSequence, ChallengeData) echoed back until we're accepted
+    challenge: Option<(u64, Vec<u8>)>,
+    last_handshake_send: Instant,
+    handshake_resend_interval: Duration,
+    map_checksum: u64,
+    deny_reason: Option<DenyReason>,
+    // Handed back by the server to reattach to this session from a new address
+    session_token: Option<Vec<u8>>,
+    server_info: Option<String>,
     interpolation: InterpolationEngine,
     prediction: ClientPrediction,
     command_sequence: u32,
+    unacked_commands: VecDeque<ClientCommand>,
     command_interval: Duration,
     last_ping_time: Instant,
     ping_interval: Duration,
     connection_start_time: Option<Instant>,
     running: Arc<AtomicBool>,
     last_server_ack: u32,
-    estimated_server_tick: u32,
-    clock_offset_ms: i64,
+    clock: ClockSync,
+    command_clock: CommandClock,
     input_accumulator: f32,
+    command_buffer_status: Option<CommandBufferStatus>,
+    snapshot_codec: SnapshotCodec,
 }
 
 impl NetworkClient {
-    pub fn new(config: ClientConfig) -> io::Result<Self> {
-        let mut endpoint = NetworkEndpoint::bind("0.0.0.0:0")?;
+    /// Binds a local socket of the family `server_addr` resolved to.
+    pub fn new(server_addr: SocketAddr, config: ClientConfig) -> io::Result<Self> {
+        Self::with_transport(UdpTransport::bind_for(server_addr)?, config)
+    }
+}
+
+impl<T: Transport> NetworkClient<T> {
+    pub fn with_transport(transport: T, config: ClientConfig) -> io::Result<Self> {
+        let seed = match config.link_seed {
+            Some(seed) => seed,
+            None => generate_client_id().map_err(io::Error::other)?,
+        };
+        let mut conditioner = LinkConditioner::new(transport, seed);
+        if let Some(sim) = &config.link_conditions {
+            conditioner.set_conditions(sim.clone(), sim.clone());
+        }
+        let mut endpoint = NetworkEndpoint::with_transport(conditioner)?;
         endpoint.set_timeout(Duration::from_secs(config.connection_timeout_secs));
+        if let Some(path) = &config.capture_path {
+            endpoint.start_capture(CaptureWriter::create(path)?);
+        }
+        if let Some(path) = &config.key_log_path {
+            endpoint.start_key_log(KeyLogWriter::create(path)?);
+        }
 
         let interpolation_config = InterpolationConfig::default();
 
         let tick_rate = config.server_tick_rate;
-        let client_salt = Self::generate_salt();
 
         // Dummy connection initially
-        let connection = ClientConnection::new("127.0.0.1:80".parse().unwrap(), 0, client_salt);
+        let connection = ClientConnection::new("127.0.0.1:80".parse().unwrap(), 0);
 
         Ok(Self {
             endpoint,
@@ -60,57 +103,88 @@ impl NetworkClient {
             state: ConnectionState::Disconnected,
             client_id: None,
             entity_id: None,
-            client_salt,
-            server_salt: None,
-            command_sequence: 0,
+            connect_token: None,
+            server_index: 0,
+            challenge: None,
+            last_handshake_send: Instant::now(),
+            handshake_resend_interval: Duration::from_secs_f32(
+                config.handshake_resend_interval_secs,
+            ),
+            map_checksum: TestingGround::new().checksum(),
+            deny_reason: None,
+            session_token: None,
+            server_info: None,
+            command_sequence: 1,
+            unacked_commands: VecDeque::new(),
             command_interval: Duration::from_secs_f64(1.0 / config.command_rate as f64),
             last_ping_time: Instant::now(),
             ping_interval: Duration::from_secs_f32(config.ping_interval_secs),
             connection_start_time: None,
             running: Arc::new(AtomicBool::new(true)),
             last_server_ack: 0,
-            estimated_server_tick: 0,
-            clock_offset_ms: 0,
+            clock: ClockSync::new(tick_rate),
+            command_clock: CommandClock::default(),
             input_accumulator: 0.0,
+            command_buffer_status: None,
+            snapshot_codec: SnapshotCodec::default(),
             config,
         })
     }
 
-    fn generate_salt() -> u64 {
-        use std::collections::hash_map::RandomState;
-        use std::hash::{BuildHasher, Hasher};
+    /// Starts the handshake with the first server listed in `token`, moving
+    /// down the list if one doesn't answer.
+    pub fn connect(&mut self, token: ConnectToken) -> io::Result<()> {
+        if token.server_addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "connect token lists no servers",
+            ));
+        }
+        if token.is_expired(unix_timestamp()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "connect token expired",
+            ));
+        }
 
-        let state = RandomState::new();
-        let mut hasher = state.build_hasher();
-        hasher.write_u64(
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64,
-        );
-        hasher.finish()
+        self.connect_token = Some(token);
+        self.deny_reason = None;
+        self.connect_to_server(0)
     }
 
-    pub fn connect(&mut self, server_addr: SocketAddr) -> io::Result<()> {
+    fn connect_to_server(&mut self, server_index: usize) -> io::Result<()> {
+        let Some(token) = &self.connect_token else {
+            return Ok(());
+        };
+        let Some(&server_addr) = token.server_addresses.get(server_index) else {
+            return Ok(());
+        };
+        let (send_key, receive_key) = (token.client_to_server_key, token.server_to_client_key);
+
         log::info!("Connecting to {}", server_addr);
 
         self.endpoint.set_remote(server_addr);
         self.state = ConnectionState::Connecting;
         self.connection_start_time = Some(Instant::now());
+        self.server_index = server_index;
+        self.challenge = None;
 
-        self.connection = ClientConnection::new(server_addr, 0, self.client_salt);
-
-        self.send_connection_request()?;
+        self.connection = ClientConnection::new(server_addr, 0);
+        self.connection.set_session_keys(&send_key, &receive_key);
+        self.endpoint
+            .log_session_keys(server_addr, &send_key, &receive_key);
+        self.connection
+            .accept_blocks(BlockKind::ServerConfig, MAX_SERVER_CONFIG_SIZE);
 
-        Ok(())
+        self.send_connection_request()
     }
 
     pub fn disconnect(&mut self) -> io::Result<()> {
         if self.state == ConnectionState::Connected {
-            let packet = self
+            let _ = self
                 .connection
-                .send_packet(PacketType::Disconnect, Reliability::Reliable);
-            let _ = self.endpoint.send(&packet);
+                .queue_message(PacketType::Disconnect, Reliability::Reliable);
+            let _ = self.flush();
         }
 
         self.reset();
@@ -121,25 +195,46 @@ impl NetworkClient {
         self.state = ConnectionState::Disconnected;
         self.client_id = None;
         self.entity_id = None;
-        self.server_salt = None;
-        self.client_salt = Self::generate_salt();
+        self.connect_token = None;
+        self.server_index = 0;
+        self.challenge = None;
+        self.session_token = None;
         self.interpolation.reset();
         self.prediction.reset();
-        self.command_sequence = 0;
+        self.command_sequence = 1;
+        self.unacked_commands.clear();
         self.connection_start_time = None;
         self.last_server_ack = 0;
-        self.estimated_server_tick = 0;
+        self.clock.reset();
+        self.command_clock.reset();
+        self.command_buffer_status = None;
+        self.snapshot_codec = SnapshotCodec::default();
     }
 
     fn send_connection_request(&mut self) -> io::Result<()> {
-        let packet = self.connection.send_packet(
-            PacketType::ConnectionRequest {
-                client_salt: self.client_salt,
+        let Some(token) = &self.connect_token else {
+            return Ok(());
+        };
+
+        let payload = match &self.challenge {
+            Some((challenge_sequence, challenge_data)) => PacketType::ChallengeResponse {
+                challenge_sequence: *challenge_sequence,
+                challenge_data: challenge_data.clone(),
             },
-            Reliability::Unreliable,
-        );
-        self.endpoint.send(&packet)?;
-        Ok(())
+            None => PacketType::connection_request(
+                self.map_checksum,
+                token.expire_timestamp,
+                token.nonce,
+                token.private_data.clone(),
+            ),
+        };
+
+        // The server keeps no state for us yet, so resend until it answers
+        let _ = self
+            .connection
+            .queue_message(payload, Reliability::Unreliable);
+        self.last_handshake_send = Instant::now();
+        self.flush()
     }
 
     pub fn update(&mut self, delta_time: f32, input: Option<&InputState>) -> io::Result<bool> {
@@ -150,31 +245,48 @@ impl NetworkClient {
 
         match self.state {
             ConnectionState::Connecting | ConnectionState::ChallengeResponse => {
-                if let Some(start) = self.connection_start_time {
-                    if start.elapsed() > Duration::from_secs(self.config.connection_timeout_secs) {
-                        log::warn!("Connection timeout");
-                        self.reset();
-                    }
+                let timed_out = self.connection_start_time.is_some_and(|start| {
+                    start.elapsed() > Duration::from_secs(self.config.handshake_timeout_secs)
+                });
+                let server_count = self
+                    .connect_token
+                    .as_ref()
+                    .map_or(0, |token| token.server_addresses.len());
+
+                // Once a server has our challenge response it may have spent
+                // the token, and its keys mustn't open a second session
+                if timed_out && self.challenge.is_none() && self.server_index + 1 < server_count {
+                    log::warn!("Connection timeout, trying next server");
+                    self.connect_to_server(self.server_index + 1)?;
+                } else if timed_out {
+                    log::warn!("Connection timeout");
+                    self.reset();
+                } else if self.last_handshake_send.elapsed() >= self.handshake_resend_interval {
+                    self.send_connection_request()?;
                 }
             }
             ConnectionState::Connected => {
                 self.interpolation.update(delta_time);
                 self.prediction.update(delta_time);
 
+                self.sync_command_clock();
+
                 self.input_accumulator += delta_time;
-                let step = self.command_interval.as_secs_f32();
+                // Commands still simulate a whole tick; only their pacing stretches
+                let tick_step = self.command_interval.as_secs_f32();
+                let step = self.command_clock.step(tick_step);
 
                 while self.input_accumulator >= step {
                     self.input_accumulator -= step;
                     ticks_processed = true;
 
+                    let tick = self.command_clock.next_tick();
                     if let Some(input) = input {
-                        let command =
-                            input.to_command(self.estimated_server_tick, self.command_sequence);
+                        let command = input.to_command(tick, self.command_sequence);
 
                         self.prediction.prepare_tick();
-                        self.prediction.apply_input(&command, step);
-                        self.send_command(input)?;
+                        self.prediction.apply_input(&command, tick_step);
+                        self.send_command(command)?;
                     }
                 }
 
@@ -197,81 +309,168 @@ impl NetworkClient {
             _ => {}
         }
 
+        self.flush()?;
+
         Ok(ticks_processed)
     }
 
     fn process_resends(&mut self) -> io::Result<()> {
-        let packets = self.connection.collect_resends();
-        for packet in packets {
-            let _ = self.endpoint.send(&packet);
+        self.connection.queue_resends();
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut resume = self.session_token.clone().filter(|_| {
+            self.is_resuming()
+                && self.last_handshake_send.elapsed() >= self.handshake_resend_interval
+        });
+
+        for packet in self.connection.flush() {
+            // In case our address changed, one packet per resend interval asks
+            // the server to move the session to wherever it came from
+            let packet = match resume.take() {
+                Some(session_token) => {
+                    self.last_handshake_send = Instant::now();
+                    let sealed = packet.serialize().map_err(io::Error::other)?;
+                    Packet::new(
+                        PacketHeader::new(0, 0, 0),
+                        PacketType::SessionResume {
+                            session_token,
+                            packet: sealed,
+                        },
+                    )
+                }
+                None => packet,
+            };
+
+            let mtu = self.connection.mtu_for(&packet);
+            match self.endpoint.send_mtu(&packet, mtu) {
+                Ok(bytes) => self.connection.record_sent(&packet, bytes),
+                Err(e) if is_too_large(&e) => {
+                    log::debug!("Packet exceeded the path MTU of {}", mtu);
+                    self.connection.on_packet_too_large(&packet);
+                }
+                Err(e) => return Err(e),
+            }
         }
         Ok(())
     }
 
-    fn send_command(&mut self, input: &InputState) -> io::Result<()> {
-        let command = input.to_command(self.estimated_server_tick, self.command_sequence);
+    /// Whether the server has been quiet long enough that we're asking to
+    /// resume the session.
+    pub fn is_resuming(&self) -> bool {
+        self.state == ConnectionState::Connected
+            && self.session_token.is_some()
+            && self.connection.last_receive_time.elapsed()
+                >= Duration::from_secs_f32(self.config.resume_after_secs)
+    }
+
+    /// Steers the command clock toward half a round trip plus the server's
+    /// buffer depth ahead of where we reckon the server is now.
+    fn sync_command_clock(&mut self) {
+        let Some(server_tick) = self.clock.server_tick() else {
+            return;
+        };
+        let buffer_depth = self
+            .command_buffer_status
+            .map_or(JitterBufferConfig::default().initial_depth, |status| {
+                u32::from(status.target_depth)
+            });
+        let target_lead =
+            CommandClock::target_lead(self.clock.rtt_ms(), self.clock.tick_ms(), buffer_depth);
+        self.command_clock.update(server_tick, target_lead);
+    }
+
+    fn send_command(&mut self, command: ClientCommand) -> io::Result<()> {
         let sequence = self.command_sequence;
         self.command_sequence = self.command_sequence.wrapping_add(1);
 
         self.prediction.store_command(&command, sequence);
 
-        let packet = self
-            .connection
-            .send_packet(PacketType::ClientCommand(command), Reliability::Unreliable);
-        self.endpoint.send(&packet)?;
+        self.unacked_commands.push_back(command);
+        while self.unacked_commands.len() > self.config.command_redundancy.max(1) {
+            self.unacked_commands.pop_front();
+        }
+
+        let commands = self.unacked_commands.make_contiguous();
+        let Some(batch) = ClientCommandBatch::new(commands) else {
+            return Ok(());
+        };
+
+        let _ = self.connection.queue_message(
+            PacketType::ClientCommandBatch(batch),
+            Reliability::Unreliable,
+        );
 
         Ok(())
     }
 
     fn send_ping(&mut self) -> io::Result<()> {
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
+        let timestamp = self.clock.local_ms();
 
-        let packet = self
+        let _ = self
             .connection
-            .send_packet(PacketType::Ping { timestamp }, Reliability::Unreliable);
-        self.endpoint.send(&packet)?;
+            .queue_message(PacketType::Ping { timestamp }, Reliability::Unreliable);
 
         Ok(())
     }
 
     fn process_network(&mut self) -> io::Result<()> {
-        let packets = self.endpoint.receive()?;
+        let packets = self.endpoint.receive_sized()?;
 
-        for (packet, _addr) in packets {
+        for (packet, _addr, size) in packets {
+            self.connection.record_received(size);
+            let auth_failures = self.connection.auth_failures;
             let payloads = self.connection.process_packet(packet);
+            self.endpoint
+                .record_auth_failures(self.connection.auth_failures - auth_failures);
             for payload in payloads {
                 self.handle_payload(payload)?;
             }
         }
 
+        for block in self.connection.take_completed_blocks() {
+            self.handle_block(block);
+        }
+
         Ok(())
     }
 
     fn handle_payload(&mut self, payload: PacketType) -> io::Result<()> {
         match payload {
             PacketType::ConnectionChallenge {
-                server_salt,
-                challenge,
+                challenge_sequence,
+                challenge_data,
             } => {
-                self.handle_challenge(server_salt, challenge)?;
+                self.handle_challenge(challenge_sequence, challenge_data)?;
             }
             PacketType::ConnectionAccepted {
                 client_id,
                 entity_id,
+                snapshot_codec,
+                session_token,
             } => {
-                self.handle_connection_accepted(client_id, entity_id)?;
+                self.session_token = Some(session_token);
+                self.handle_connection_accepted(client_id, entity_id, snapshot_codec)?;
             }
             PacketType::ConnectionDenied { reason } => {
-                self.handle_connection_denied(&reason)?;
+                self.handle_connection_denied(reason)?;
             }
             PacketType::WorldSnapshot(snapshot) => {
                 self.handle_snapshot(snapshot)?;
             }
-            PacketType::Pong { timestamp } => {
-                self.handle_pong(timestamp)?;
+            PacketType::PackedSnapshot(data) => match self.snapshot_codec.decode(&data) {
+                Ok(snapshot) => self.handle_snapshot(snapshot)?,
+                Err(e) => log::warn!("Failed to decode snapshot: {}", e),
+            },
+            PacketType::Pong {
+                timestamp,
+                server_time_ms,
+            } => {
+                self.handle_pong(timestamp, server_time_ms)?;
+            }
+            PacketType::CommandBufferStatus(status) => {
+                self.handle_command_buffer_status(status);
             }
             PacketType::Disconnect => {
                 log::info!("Disconnected by server");
@@ -283,31 +482,44 @@ impl NetworkClient {
         Ok(())
     }
 
-    fn handle_challenge(&mut self, server_salt: u64, challenge: u64) -> io::Result<()> {
+    fn handle_challenge(
+        &mut self,
+        challenge_sequence: u64,
+        challenge_data: Vec<u8>,
+    ) -> io::Result<()> {
+        if !matches!(
+            self.state,
+            ConnectionState::Connecting | ConnectionState::ChallengeResponse
+        ) {
+            return Ok(());
+        }
+
         log::debug!("Received challenge from server");
 
-        self.server_salt = Some(server_salt);
         self.state = ConnectionState::ChallengeResponse;
         self.connection.state = ConnectionState::ChallengeResponse;
+        self.challenge = Some((challenge_sequence, challenge_data));
 
-        let expected_challenge = self.client_salt ^ server_salt;
-        if challenge != expected_challenge {
-            log::warn!("Challenge mismatch");
-            return Ok(());
-        }
-
-        let packet = self.connection.send_packet(
-            PacketType::ChallengeResponse {
-                combined_salt: expected_challenge,
-            },
-            Reliability::Reliable,
-        );
-        self.endpoint.send(&packet)?;
+        self.send_connection_request()
+    }
 
-        Ok(())
+    fn handle_block(&mut self, block: CompletedBlock) {
+        match block.kind {
+            BlockKind::ServerConfig => {
+                let info = String::from_utf8_lossy(&block.data).into_owned();
+                log::info!("Server config: {}", info);
+                self.server_info = Some(info);
+            }
+            kind => log::debug!("Ignoring {} block of {} bytes", kind, block.data.len()),
+        }
     }
 
-    fn handle_connection_accepted(&mut self, client_id: u32, entity_id: u32) -> io::Result<()> {
+    fn handle_connection_accepted(
+        &mut self,
+        client_id: u32,
+        entity_id: u32,
+        snapshot_codec: SnapshotCodec,
+    ) -> io::Result<()> {
         log::info!(
             "Connected to server with client ID {}, entity ID {}",
             client_id,
@@ -316,83 +528,126 @@ impl NetworkClient {
 
         self.client_id = Some(client_id);
         self.entity_id = Some(entity_id);
+        self.challenge = None;
+        self.snapshot_codec = snapshot_codec;
         self.state = ConnectionState::Connected;
         self.connection.state = ConnectionState::Connected;
         self.connection.client_id = client_id;
         self.connection.entity_id = Some(entity_id);
+        self.connection.start_mtu_discovery();
         self.endpoint.set_state(ConnectionState::Connected);
 
         Ok(())
     }
 
-    fn handle_connection_denied(&mut self, reason: &str) -> io::Result<()> {
+    fn handle_connection_denied(&mut self, reason: DenyReason) -> io::Result<()> {
+        // Denials are unauthenticated, so an established session only heeds
+        // one while it's trying to resume
+        let handshaking = matches!(
+            self.state,
+            ConnectionState::Connecting | ConnectionState::ChallengeResponse
+        );
+        if !handshaking && !self.is_resuming() {
+            return Ok(());
+        }
+
         log::warn!("Connection denied: {}", reason);
         self.reset();
+        self.deny_reason = Some(reason);
         Ok(())
     }
 
     fn handle_snapshot(&mut self, snapshot: WorldSnapshot) -> io::Result<()> {
         let received_tick = snapshot.tick;
 
-        self.estimated_server_tick = snapshot
-            .tick
-            .saturating_add(self.config.interpolation_delay);
+        self.clock
+            .on_snapshot(snapshot.tick, snapshot.server_time_ms);
 
         self.last_server_ack = snapshot.last_command_ack;
-
-        let local_time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64;
-        self.clock_offset_ms = snapshot.server_time_ms as i64 - local_time;
-
-        if let Some(entity_id) = self.entity_id {
-            if let Some(local_state) = snapshot.entities.iter().find(|e| e.entity_id == entity_id) {
-                let position = Vec3::from(local_state.position);
-                let orientation_arr = local_state.decode_orientation();
-                let orientation = glam::Quat::from_xyzw(
-                    orientation_arr[0],
-                    orientation_arr[1],
-                    orientation_arr[2],
-                    orientation_arr[3],
-                );
-                self.prediction
-                    .reconcile(position, orientation, snapshot.last_command_ack);
-            }
+        while self.unacked_commands.front().is_some_and(|cmd| {
+            !sequence_greater_than(cmd.command_sequence, snapshot.last_command_ack)
+        }) {
+            self.unacked_commands.pop_front();
         }
 
+        let last_command_ack = snapshot.last_command_ack;
+        let local_changed = self.entity_id.filter(|&entity_id| {
+            snapshot.entities.iter().any(|e| e.entity_id == entity_id)
+                || snapshot
+                    .entity_deltas
+                    .iter()
+                    .any(|d| d.entity_id == entity_id)
+        });
+
         self.interpolation.push_snapshot(snapshot);
 
+        // Deltas may only carry some fields, so reconcile against the merged state
+        if let Some(entity_id) = local_changed
+            && let Some(local_state) = self.interpolation.known_state(entity_id, received_tick)
+        {
+            let position = Vec3::from(local_state.position);
+            let orientation_arr = local_state.decode_orientation();
+            let orientation = glam::Quat::from_xyzw(
+                orientation_arr[0],
+                orientation_arr[1],
+                orientation_arr[2],
+                orientation_arr[3],
+            );
+            self.prediction
+                .reconcile(position, orientation, last_command_ack);
+        }
+
         self.send_snapshot_ack(received_tick)?;
 
         Ok(())
     }
 
     fn send_snapshot_ack(&mut self, received_tick: u32) -> io::Result<()> {
-        let packet = self.connection.send_packet(
+        let _ = self.connection.queue_message(
             PacketType::SnapshotAck { received_tick },
             Reliability::Unreliable,
         );
-        self.endpoint.send(&packet)?;
         Ok(())
     }
 
-    fn handle_pong(&mut self, timestamp: u64) -> io::Result<()> {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
-
-        let rtt = now.saturating_sub(timestamp);
-        log::debug!("Ping RTT: {} ms", rtt);
+    fn handle_pong(&mut self, timestamp: u64, server_time_ms: u64) -> io::Result<()> {
+        let now = self.clock.local_ms();
+        self.clock.on_pong(timestamp, server_time_ms, now);
+        log::debug!(
+            "Ping RTT: {} ms, clock offset {:.0} ms",
+            now.saturating_sub(timestamp),
+            self.clock.offset_ms().unwrap_or_default()
+        );
 
         Ok(())
     }
 
+    fn handle_command_buffer_status(&mut self, status: CommandBufferStatus) {
+        if let Some(previous) = self.command_buffer_status {
+            let underruns = status.underruns.saturating_sub(previous.underruns);
+            let overruns = status.overruns.saturating_sub(previous.overruns);
+            if underruns > 0 || overruns > 0 {
+                log::debug!(
+                    "Server command buffer: {} underruns, {} overruns (depth {}/{})",
+                    underruns,
+                    overruns,
+                    status.depth,
+                    status.target_depth
+                );
+            }
+        }
+        self.command_buffer_status = Some(status);
+    }
+
     pub fn state(&self) -> ConnectionState {
         self.state
     }
 
+    /// Why the last connection attempt was refused, if the server said.
+    pub fn deny_reason(&self) -> Option<&DenyReason> {
+        self.deny_reason.as_ref()
+    }
+
     pub fn is_connected(&self) -> bool {
         self.state == ConnectionState::Connected
     }
@@ -430,10 +685,37 @@ impl NetworkClient {
         self.interpolation.is_ready()
     }
 
+    pub fn server_info(&self) -> Option<&str> {
+        self.server_info.as_deref()
+    }
+
+    pub fn block_progress(&self) -> Vec<BlockProgress> {
+        self.connection.block_progress()
+    }
+
+    /// Abandons every block still being received from the server.
+    pub fn cancel_downloads(&mut self) {
+        for progress in self.connection.block_progress() {
+            if progress.direction == BlockDirection::Incoming {
+                self.connection
+                    .cancel_block(BlockDirection::Incoming, progress.transfer_id);
+            }
+        }
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.connection.mtu()
+    }
+
     pub fn stats(&self) -> &NetworkStats {
         self.endpoint.stats()
     }
 
+    /// Link quality to the server, as opposed to the socket-wide totals.
+    pub fn connection_stats(&self) -> &ConnectionStats {
+        self.connection.stats()
+    }
+
     pub fn running(&self) -> Arc<AtomicBool> {
         Arc::clone(&self.running)
     }
@@ -443,12 +725,27 @@ impl NetworkClient {
         let _ = self.disconnect();
     }
 
+    /// The tick the server is on now, by our synced clock.
     pub fn estimated_server_tick(&self) -> u32 {
-        self.estimated_server_tick
+        self.clock
+            .server_tick()
+            .map_or(0, |tick| tick.max(0.0) as u32)
     }
 
     pub fn clock_offset_ms(&self) -> i64 {
-        self.clock_offset_ms
+        self.clock
+            .offset_ms()
+            .map_or(0, |offset| offset.round() as i64)
+    }
+
+    /// How far the command clock is stretched to hold its lead; positive
+    /// while it's running slow.
+    pub fn time_dilation(&self) -> f32 {
+        self.command_clock.dilation()
+    }
+
+    pub fn command_buffer_status(&self) -> Option<CommandBufferStatus> {
+        self.command_buffer_status
     }
 
     pub fn interpolation_stats(&self) -> super::interpolation::InterpolationStats {
@@ -463,10 +760,63 @@ mod tests {
     #[test]
     fn test_client_creation() {
         let config = ClientConfig::default();
-        let client = NetworkClient::new(config);
+        let client = NetworkClient::new("127.0.0.1:27015".parse().unwrap(), config);
         assert!(client.is_ok());
 
         let client = client.unwrap();
         assert_eq!(client.state(), ConnectionState::Disconnected);
     }
+
+    #[test]
+    fn test_connect_over_loopback() {
+        use dual::net::{DEV_PRIVATE_KEY, LoopbackHub, TokenIssuer};
+
+        let hub = LoopbackHub::new();
+        let server_addr = "127.0.0.1:27015".parse().unwrap();
+        let mut server = NetworkEndpoint::with_transport(hub.bind(server_addr).unwrap()).unwrap();
+
+        let transport = hub.bind("0.0.0.0:0").unwrap();
+        let mut client = NetworkClient::with_transport(transport, ClientConfig::default()).unwrap();
+        let token = TokenIssuer::new(DEV_PRIVATE_KEY)
+            .issue(1, &[server_addr])
+            .unwrap();
+        client.connect(token).unwrap();
+
+        let received = server.receive().unwrap();
+        assert_eq!(received.len(), 1);
+        assert!(matches!(
+            received[0].0.messages[0].payload,
+            PacketType::ConnectionRequest { .. }
+        ));
+    }
+
+    #[test]
+    fn test_connect_over_ipv6_loopback() {
+        use dual::net::{DEV_PRIVATE_KEY, TokenIssuer};
+
+        let mut server = NetworkEndpoint::bind("[::1]:0").unwrap();
+        let server_addr = server.local_addr();
+
+        let mut client = NetworkClient::new(server_addr, ClientConfig::default()).unwrap();
+        assert!(client.endpoint.local_addr().is_ipv6());
+        let token = TokenIssuer::new(DEV_PRIVATE_KEY)
+            .issue(1, &[server_addr])
+            .unwrap();
+        client.connect(token).unwrap();
+
+        let start = Instant::now();
+        let received = loop {
+            let received = server.receive().unwrap();
+            if !received.is_empty() || start.elapsed() > Duration::from_secs(2) {
+                break received;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(received.len(), 1);
+        assert!(received[0].1.is_ipv6());
+        assert!(matches!(
+            received[0].0.messages[0].payload,
+            PacketType::ConnectionRequest { .. }
+        ));
+    }
 }
@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use dual::net::sequence_greater_than;
+
+// Round trips remembered for filtering
+const CLOCK_SAMPLES: usize = 16;
+
+/// Most the command clock runs fast or slow while it closes in on its lead.
+pub const MAX_TIME_DILATION: f32 = 0.05;
+// Dilation per tick of lead error, so small errors get small corrections
+const DILATION_PER_TICK: f32 = 0.01;
+// Past this, drifting back would take seconds, so jump instead
+const RESYNC_TICKS: f64 = 15.0;
+
+#[derive(Debug, Clone, Copy)]
+struct ClockSample {
+    offset_ms: f64,
+    rtt_ms: f64,
+}
+
+/// NTP-style estimate of the server's clock, built from ping round trips.
+///
+/// Each pong gives an offset assuming the trip was symmetric. Queuing makes
+/// slow trips lopsided, so only the quickest half of recent samples count,
+/// and the median of those is taken.
+#[derive(Debug)]
+pub struct ClockSync {
+    epoch: Instant,
+    tick_ms: f64,
+    samples: VecDeque<ClockSample>,
+    offset_ms: Option<f64>,
+    rtt_ms: f64,
+    // Tick and server time of the newest snapshot, to count ticks on from
+    anchor: Option<(u32, u64)>,
+}
+
+impl ClockSync {
+    pub fn new(tick_rate: u32) -> Self {
+        Self {
+            epoch: Instant::now(),
+            tick_ms: 1000.0 / tick_rate.max(1) as f64,
+            samples: VecDeque::with_capacity(CLOCK_SAMPLES),
+            offset_ms: None,
+            rtt_ms: 0.0,
+            anchor: None,
+        }
+    }
+
+    /// Our own clock, which pings are stamped with.
+    pub fn local_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    /// A pong for the ping sent at `sent_ms`, which the server answered at
+    /// `server_time_ms` and we read at `received_ms`.
+    pub fn on_pong(&mut self, sent_ms: u64, server_time_ms: u64, received_ms: u64) {
+        if received_ms < sent_ms {
+            return;
+        }
+        let rtt_ms = (received_ms - sent_ms) as f64;
+        let midpoint = (sent_ms + received_ms) as f64 / 2.0;
+
+        if self.samples.len() >= CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ClockSample {
+            offset_ms: server_time_ms as f64 - midpoint,
+            rtt_ms,
+        });
+
+        let mut trusted: Vec<ClockSample> = self.samples.iter().copied().collect();
+        trusted.sort_by(|a, b| a.rtt_ms.total_cmp(&b.rtt_ms));
+        trusted.truncate(trusted.len().div_ceil(2));
+        self.rtt_ms = trusted[trusted.len() / 2].rtt_ms;
+
+        trusted.sort_by(|a, b| a.offset_ms.total_cmp(&b.offset_ms));
+        self.offset_ms = Some(trusted[trusted.len() / 2].offset_ms);
+    }
+
+    pub fn on_snapshot(&mut self, tick: u32, server_time_ms: u64) {
+        let newer = self
+            .anchor
+            .is_none_or(|(anchor_tick, _)| sequence_greater_than(tick, anchor_tick));
+        if newer {
+            self.anchor = Some((tick, server_time_ms));
+        }
+    }
+
+    /// Server time minus ours, once a pong has come back.
+    pub fn offset_ms(&self) -> Option<f64> {
+        self.offset_ms
+    }
+
+    /// Round trip of the trusted samples.
+    pub fn rtt_ms(&self) -> f64 {
+        self.rtt_ms
+    }
+
+    pub fn tick_ms(&self) -> f64 {
+        self.tick_ms
+    }
+
+    /// The tick the server is on at `local_ms`, fractional part included.
+    pub fn server_tick_at(&self, local_ms: f64) -> Option<f64> {
+        let offset_ms = self.offset_ms?;
+        let (tick, server_time_ms) = self.anchor?;
+        let since_anchor = local_ms + offset_ms - server_time_ms as f64;
+        Some(tick as f64 + since_anchor / self.tick_ms)
+    }
+
+    pub fn server_tick(&self) -> Option<f64> {
+        self.server_tick_at(self.local_ms() as f64)
+    }
+
+    pub fn reset(&mut self) {
+        self.samples.clear();
+        self.offset_ms = None;
+        self.rtt_ms = 0.0;
+        self.anchor = None;
+    }
+}
+
+/// Stamps commands far enough ahead of the server that they arrive just
+/// before its jitter buffer plays them.
+///
+/// The lead is held by running the command clock slightly fast or slow
+/// rather than skipping ticks, so the server sees a steady stream.
+#[derive(Debug, Default)]
+pub struct CommandClock {
+    tick: Option<u32>,
+    dilation: f32,
+}
+
+impl CommandClock {
+    /// Half a round trip to get there, plus the ticks the server buffers.
+    pub fn target_lead(rtt_ms: f64, tick_ms: f64, buffer_depth: u32) -> f64 {
+        rtt_ms / 2.0 / tick_ms + buffer_depth as f64
+    }
+
+    pub fn update(&mut self, server_tick: f64, target_lead: f64) {
+        let error = self
+            .tick
+            .map(|tick| tick as f64 - server_tick - target_lead);
+
+        match error {
+            Some(error) if error.abs() <= RESYNC_TICKS => {
+                self.dilation =
+                    (error as f32 * DILATION_PER_TICK).clamp(-MAX_TIME_DILATION, MAX_TIME_DILATION);
+            }
+            _ => {
+                self.tick = Some((server_tick + target_lead).ceil() as u32);
+                self.dilation = 0.0;
+            }
+        }
+    }
+
+    /// The tick for the next command, or 0 until the clock has synced.
+    pub fn next_tick(&mut self) -> u32 {
+        let tick = self.tick.unwrap_or(0);
+        self.tick = self.tick.map(|tick| tick.wrapping_add(1));
+        tick
+    }
+
+    /// How long to wait between commands; longer while we're too far ahead.
+    pub fn step(&self, base_step: f32) -> f32 {
+        base_step * (1.0 + self.dilation)
+    }
+
+    pub fn dilation(&self) -> f32 {
+        self.dilation
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offset_ignores_slow_round_trips() {
+        let mut clock = ClockSync::new(60);
+
+        // Server is 5000ms ahead; quick trips are 20ms and symmetric
+        let mut sent = 0;
+        for i in 0..CLOCK_SAMPLES as u64 {
+            let (rtt, out) = if i % 3 == 0 { (200, 180) } else { (20, 10) };
+            clock.on_pong(sent, sent + out + 5000, sent + rtt);
+            sent += 250;
+        }
+
+        let offset = clock.offset_ms().unwrap();
+        assert!((offset - 5000.0).abs() < 1.0, "offset {}", offset);
+        assert_eq!(clock.rtt_ms(), 20.0);
+    }
+
+    #[test]
+    fn test_server_tick_counts_on_from_snapshot() {
+        let mut clock = ClockSync::new(50);
+        assert!(clock.server_tick_at(0.0).is_none());
+
+        clock.on_pong(0, 1010, 20);
+        clock.on_snapshot(100, 1000);
+        // A stale snapshot doesn't move the anchor back
+        clock.on_snapshot(90, 800);
+
+        // Our 10ms is the server's 1010ms, half a 20ms tick past the anchor
+        let tick = clock.server_tick_at(10.0).unwrap();
+        assert!((tick - 100.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_lead_dilates_instead_of_jumping() {
+        let mut clock = CommandClock::default();
+        clock.update(100.0, 4.0);
+        assert_eq!(clock.next_tick(), 104);
+
+        // The server hasn't moved on since, so we're a tick ahead and slow down
+        clock.update(100.0, 4.0);
+        assert!(clock.dilation() > 0.0);
+        assert!(clock.step(1.0) > 1.0);
+        assert_eq!(clock.next_tick(), 105);
+
+        // Behind, so speed up, but never by more than the cap
+        clock.update(110.0, 4.0);
+        assert_eq!(clock.dilation(), -MAX_TIME_DILATION);
+
+        // Hopelessly far off, so resync
+        clock.update(200.0, 4.0);
+        assert_eq!(clock.dilation(), 0.0);
+        assert_eq!(clock.next_tick(), 204);
+    }
+}
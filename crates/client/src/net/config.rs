@@ -1,20 +1,46 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use dual::PacketLossSimulation;
+
 #[derive(Debug, Clone)]
 pub struct ClientConfig {
     pub server_tick_rate: u32,
-    pub interpolation_delay: u32,
     pub connection_timeout_secs: u64,
+    pub handshake_timeout_secs: u64,
+    pub handshake_resend_interval_secs: f32,
     pub command_rate: u32,
+    pub command_redundancy: usize,
     pub ping_interval_secs: f32,
+    // Silence from the server after which we try to resume from wherever we are now
+    pub resume_after_secs: f32,
+    // Simulated link conditions in both directions, for testing bad networks
+    pub link_conditions: Option<PacketLossSimulation>,
+    pub link_seed: Option<u64>,
+    // Every datagram in and out is written here for dual-dissect
+    pub capture_path: Option<PathBuf>,
+    // Session keys are appended here so a capture can be decrypted
+    pub key_log_path: Option<PathBuf>,
+    // Where the server browser gets its list
+    pub master_addr: Option<SocketAddr>,
 }
 
 impl Default for ClientConfig {
     fn default() -> Self {
         Self {
             server_tick_rate: 60,
-            interpolation_delay: 2,
             connection_timeout_secs: 120,
+            handshake_timeout_secs: 5,
+            handshake_resend_interval_secs: 0.1,
             command_rate: 60,
+            command_redundancy: 5,
             ping_interval_secs: 0.25,
+            resume_after_secs: 1.0,
+            link_conditions: None,
+            link_seed: None,
+            capture_path: None,
+            key_log_path: None,
+            master_addr: None,
         }
     }
 }
@@ -187,8 +187,17 @@ impl InterpolationEngine {
             }
         }
 
-        for entity in &snapshot.entities {
-            self.known_entities.insert(entity.entity_id, entity.clone());
+        let mut merged = Vec::with_capacity(snapshot.entity_deltas.len());
+        for delta in &snapshot.entity_deltas {
+            let Some(baseline) = self.known_entities.get(&delta.entity_id) else {
+                // Delta against an entity we never received, cannot reconstruct
+                return None;
+            };
+            merged.push(delta.apply(baseline).ok()?);
+        }
+
+        for entity in snapshot.entities.iter().chain(&merged) {
+            self.known_entities.insert(entity.entity_id, *entity);
         }
 
         for removed_id in &snapshot.removed_entity_ids {
@@ -333,6 +342,13 @@ impl InterpolationEngine {
         self.interpolated_entities.get(&entity_id)
     }
 
+    pub fn known_state(&self, entity_id: u32, tick: u32) -> Option<&EntityState> {
+        if self.knowledge_tick != tick {
+            return None;
+        }
+        self.known_entities.get(&entity_id)
+    }
+
     pub fn entities(&self) -> impl Iterator<Item = &InterpolatedEntity> {
         self.interpolated_entities.values()
     }
@@ -443,6 +459,8 @@ pub struct InterpolationStats {
 
 #[cfg(test)]
 mod tests {
+    use dual::net::EntityDelta;
+
     use super::*;
 
     fn create_test_snapshot(tick: u32, time_ms: u64, entity_count: usize) -> WorldSnapshot {
@@ -509,6 +527,39 @@ mod tests {
         assert!((mid.x - 0.5).abs() < 0.1);
     }
 
+    #[test]
+    fn test_partial_delta_merges_onto_baseline() {
+        let mut engine = InterpolationEngine::with_defaults();
+
+        let s10 = create_test_snapshot(10, 1000, 2);
+        let baseline = s10.entities[1];
+        engine.push_snapshot(s10);
+
+        let mut turned = baseline;
+        turned.encode_orientation([0.0, 1.0, 0.0, 0.0]);
+
+        let mut s11 = WorldSnapshot::new_delta(11, 1050, 10);
+        s11.entity_deltas
+            .push(EntityDelta::encode(&baseline, &turned).unwrap());
+        engine.push_snapshot(s11);
+
+        let merged = engine.get_snapshot_by_tick(11).unwrap();
+        assert_eq!(merged.entities.len(), 2);
+        let state = engine.known_state(1, 11).unwrap();
+        assert_eq!(state.position, baseline.position);
+        assert_eq!(state.velocity, baseline.velocity);
+        assert_eq!(state.orientation, turned.orientation);
+
+        // A delta for an entity the client never saw can't be expanded
+        let mut s12 = WorldSnapshot::new_delta(12, 1100, 11);
+        s12.entity_deltas.push(EntityDelta::from_fields(
+            &EntityState::new(99, 0),
+            EntityDelta::FIELD_FLAGS,
+        ));
+        engine.push_snapshot(s12);
+        assert!(engine.get_snapshot_by_tick(12).is_none());
+    }
+
     #[test]
     fn test_baseline_loss_deadlock() {
         let mut config = InterpolationConfig::default();
@@ -1,8 +1,10 @@
 pub mod client;
+pub mod clock;
 pub mod config;
 pub mod input;
 pub mod interpolation;
 pub mod prediction;
+pub mod token;
 
 pub use dual::{
     ClientCommand, ConnectionState, DEFAULT_PORT, DEFAULT_TICK_RATE, Entity, EntityState,
@@ -15,3 +17,4 @@ pub use config::ClientConfig;
 pub use input::InputState;
 pub use interpolation::{InterpolatedEntity, InterpolationEngine, InterpolationStats};
 pub use prediction::ClientPrediction;
+pub use token::TokenSource;
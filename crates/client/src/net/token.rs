@@ -0,0 +1,50 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use dual::net::{ConnectToken, DEV_PRIVATE_KEY, Key, TokenIssuer, generate_client_id};
+
+/// Where the client gets connect tokens from.
+#[derive(Debug, Clone)]
+pub enum TokenSource {
+    /// Token minted ahead of time by `dual-token`. Servers accept it for
+    /// one connection only
+    Provided(ConnectToken),
+    /// Mint a fresh token for every connection, from a shared key, for
+    /// development servers
+    Issuer(TokenIssuer),
+}
+
+// Servers only run on the development key when bound to loopback
+impl Default for TokenSource {
+    fn default() -> Self {
+        Self::from_key(DEV_PRIVATE_KEY)
+    }
+}
+
+impl TokenSource {
+    pub fn from_key(key: Key) -> Self {
+        Self::Issuer(TokenIssuer::new(key))
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let data = std::fs::read(path)?;
+        let token = ConnectToken::from_bytes(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self::Provided(token))
+    }
+
+    pub fn token_for(&self, addr: SocketAddr) -> io::Result<ConnectToken> {
+        match self {
+            Self::Provided(token) if token.server_addresses.contains(&addr) => Ok(token.clone()),
+            Self::Provided(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("connect token is not valid for {}", addr),
+            )),
+            Self::Issuer(issuer) => {
+                let client_id = generate_client_id().map_err(io::Error::other)?;
+                issuer.issue(client_id, &[addr]).map_err(io::Error::other)
+            }
+        }
+    }
+}
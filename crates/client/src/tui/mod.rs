@@ -10,9 +10,11 @@ use crossterm::{cursor, execute};
 use ratatui::Terminal;
 use ratatui::backend::CrosstermBackend;
 
-use crate::net::{ClientConfig, NetworkClient};
+use dual::net::{LanBrowser, MasterBrowser, ServerFilter};
 
-pub use screens::Screen;
+use crate::net::{ClientConfig, ConnectionState, NetworkClient, TokenSource};
+
+pub use screens::{Screen, ServerList, ServerRow};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Action {
@@ -21,6 +23,7 @@ pub enum Action {
     LaunchGame,
     Connect(SocketAddr),
     Disconnect,
+    SkipDownloads,
     ChangeScreen(Screen),
 }
 
@@ -28,15 +31,22 @@ pub struct Tui {
     terminal: Terminal<CrosstermBackend<io::Stdout>>,
     screen: Screen,
     client: Option<NetworkClient>,
+    tokens: TokenSource,
+    config: ClientConfig,
     connect_input: String,
     connect_error: Option<String>,
+    // Only probing while the LAN games screen is open
+    lan: Option<LanBrowser>,
+    // Likewise only fetching while the server browser is open
+    master: Option<MasterBrowser>,
+    server_filter: ServerFilter,
     selected_index: usize,
     should_quit: bool,
     should_launch: bool,
 }
 
 impl Tui {
-    pub fn new() -> io::Result<Self> {
+    pub fn new(tokens: TokenSource, config: ClientConfig) -> io::Result<Self> {
         terminal::enable_raw_mode()?;
         let mut stdout = io::stdout();
         execute!(stdout, EnterAlternateScreen, cursor::Hide)?;
@@ -48,8 +58,13 @@ impl Tui {
             terminal,
             screen: Screen::MainMenu,
             client: None,
+            tokens,
+            config,
             connect_input: String::from("127.0.0.1:27015"),
             connect_error: None,
+            lan: None,
+            master: None,
+            server_filter: ServerFilter::default(),
             selected_index: 0,
             should_quit: false,
             should_launch: false,
@@ -60,15 +75,49 @@ impl Tui {
         while !self.should_quit && !self.should_launch {
             self.draw()?;
 
+            if let Some(lan) = &mut self.lan {
+                // Usually no route to broadcast on, which may come back by itself
+                self.connect_error = lan
+                    .poll()
+                    .err()
+                    .map(|e| format!("LAN discovery failed: {}", e));
+            }
+            if let Some(master) = &mut self.master {
+                self.connect_error = match master.poll() {
+                    Err(e) => Some(format!("Server list failed: {}", e)),
+                    Ok(()) if master.timed_out() => {
+                        Some("No reply from the master server".to_string())
+                    }
+                    Ok(()) => None,
+                };
+            }
+
             if let Some(client) = &mut self.client {
                 let _ = client.update(0.016, None);
 
-                if client.is_connected() && self.screen == Screen::Connecting {
+                // Let anything the server sends on connect finish first
+                if client.is_connected()
+                    && self.screen == Screen::Connecting
+                    && client.block_progress().is_empty()
+                {
                     self.should_launch = true;
                     continue;
                 }
             }
 
+            if let Some(client) = &self.client
+                && self.screen == Screen::Connecting
+                && client.state() == ConnectionState::Disconnected
+            {
+                self.connect_error = Some(match client.deny_reason() {
+                    Some(reason) => screens::describe_deny_reason(reason),
+                    None => "No response from server".to_string(),
+                });
+                self.client = None;
+                self.screen = Screen::Connect;
+                continue;
+            }
+
             if event::poll(Duration::from_millis(50))? {
                 if let Event::Key(key) = event::read()? {
                     if key.kind == KeyEventKind::Press {
@@ -91,6 +140,10 @@ impl Tui {
         let selected = self.selected_index;
         let connect_input = self.connect_input.clone();
         let connect_error = self.connect_error.clone();
+        let servers = ServerList {
+            rows: self.server_rows(),
+            filter: self.server_filter.clone(),
+        };
         let client = &self.client;
 
         self.terminal.draw(|frame| {
@@ -100,6 +153,7 @@ impl Tui {
                 selected,
                 &connect_input,
                 connect_error.as_deref(),
+                &servers,
                 client,
             );
         })?;
@@ -107,6 +161,32 @@ impl Tui {
         Ok(())
     }
 
+    /// Whatever the open browser screen has found, in display order.
+    fn server_rows(&self) -> Vec<ServerRow> {
+        if let Some(lan) = &self.lan {
+            lan.servers()
+                .into_iter()
+                .map(|server| ServerRow {
+                    addr: server.addr,
+                    info: server.info.clone(),
+                    ping_ms: Some(server.ping_ms),
+                })
+                .collect()
+        } else if let Some(master) = &self.master {
+            master
+                .servers()
+                .iter()
+                .map(|entry| ServerRow {
+                    addr: entry.addr,
+                    info: entry.info.clone(),
+                    ping_ms: None,
+                })
+                .collect()
+        } else {
+            Vec::new()
+        }
+    }
+
     fn handle_key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> Action {
         if modifiers.contains(KeyModifiers::CONTROL) && code == KeyCode::Char('c') {
             return Action::Quit;
@@ -115,6 +195,7 @@ impl Tui {
         match self.screen {
             Screen::MainMenu => self.handle_main_menu_key(code),
             Screen::Connect => self.handle_connect_key(code),
+            Screen::LanGames | Screen::ServerBrowser => self.handle_server_list_key(code),
             Screen::Connecting => self.handle_connecting_key(code),
             Screen::Connected => self.handle_connected_key(code),
             Screen::InGame => self.handle_in_game_key(code),
@@ -128,13 +209,14 @@ impl Tui {
                 Action::None
             }
             KeyCode::Down | KeyCode::Char('j') => {
-                self.selected_index = (self.selected_index + 1).min(2);
+                self.selected_index = (self.selected_index + 1).min(3);
                 Action::None
             }
             KeyCode::Enter => match self.selected_index {
                 0 => Action::ChangeScreen(Screen::Connect),
-                1 => Action::ChangeScreen(Screen::Connect),
-                2 => Action::Quit,
+                1 => Action::ChangeScreen(Screen::ServerBrowser),
+                2 => Action::ChangeScreen(Screen::LanGames),
+                3 => Action::Quit,
                 _ => Action::None,
             },
             KeyCode::Char('q') | KeyCode::Esc => Action::Quit,
@@ -171,6 +253,62 @@ impl Tui {
         }
     }
 
+    fn handle_server_list_key(&mut self, code: KeyCode) -> Action {
+        let servers = self.server_rows();
+
+        match code {
+            KeyCode::Esc => {
+                self.connect_error = None;
+                Action::ChangeScreen(Screen::MainMenu)
+            }
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.selected_index = self.selected_index.saturating_sub(1);
+                Action::None
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.selected_index =
+                    (self.selected_index + 1).min(servers.len().saturating_sub(1));
+                Action::None
+            }
+            KeyCode::Enter => match servers.get(self.selected_index) {
+                Some(server) => {
+                    let addr = server.addr;
+                    // So a failed attempt lands on the connect screen ready to retry
+                    self.connect_input = addr.to_string();
+                    Action::Connect(addr)
+                }
+                None => Action::None,
+            },
+            KeyCode::Char('r') => {
+                if let Some(lan) = &mut self.lan {
+                    lan.refresh();
+                }
+                if let Some(master) = &mut self.master {
+                    master.refresh();
+                }
+                Action::None
+            }
+            KeyCode::Char('f') if self.master.is_some() => {
+                self.server_filter.not_full = !self.server_filter.not_full;
+                self.apply_server_filter();
+                Action::None
+            }
+            KeyCode::Char('p') if self.master.is_some() => {
+                self.server_filter.no_password = !self.server_filter.no_password;
+                self.apply_server_filter();
+                Action::None
+            }
+            _ => Action::None,
+        }
+    }
+
+    fn apply_server_filter(&mut self) {
+        if let Some(master) = &mut self.master {
+            master.set_filter(self.server_filter.clone());
+        }
+        self.selected_index = 0;
+    }
+
     fn handle_connecting_key(&mut self, code: KeyCode) -> Action {
         match code {
             KeyCode::Esc => {
@@ -180,6 +318,7 @@ impl Tui {
                 self.client = None;
                 Action::ChangeScreen(Screen::MainMenu)
             }
+            KeyCode::Char('s') => Action::SkipDownloads,
             _ => Action::None,
         }
     }
@@ -222,26 +361,57 @@ impl Tui {
                 self.screen = Screen::MainMenu;
                 self.selected_index = 0;
             }
+            Action::SkipDownloads => {
+                if let Some(client) = &mut self.client {
+                    client.cancel_downloads();
+                }
+            }
             Action::ChangeScreen(screen) => {
                 self.screen = screen;
                 self.selected_index = 0;
+                self.lan = None;
+                self.master = None;
+                match screen {
+                    Screen::LanGames => match LanBrowser::bind() {
+                        Ok(lan) => self.lan = Some(lan),
+                        Err(e) => self.connect_error = Some(format!("LAN discovery failed: {}", e)),
+                    },
+                    Screen::ServerBrowser => self.open_server_browser(),
+                    _ => {}
+                }
             }
         }
 
         Ok(())
     }
 
+    fn open_server_browser(&mut self) {
+        let Some(master_addr) = self.config.master_addr else {
+            self.connect_error = Some("No master server set, start with --master".to_string());
+            return;
+        };
+        match MasterBrowser::bind(master_addr, self.server_filter.clone()) {
+            Ok(master) => self.master = Some(master),
+            Err(e) => self.connect_error = Some(format!("Server list failed: {}", e)),
+        }
+    }
+
     fn connect_to_server(&mut self, addr: SocketAddr) -> io::Result<()> {
-        let config = ClientConfig::default();
-        let mut client = NetworkClient::new(config)?;
+        let mut client = NetworkClient::new(addr, self.config.clone())?;
 
-        if let Err(e) = client.connect(addr) {
+        let connected = self
+            .tokens
+            .token_for(addr)
+            .and_then(|token| client.connect(token));
+        if let Err(e) = connected {
             self.connect_error = Some(format!("Connection failed: {}", e));
             return Ok(());
         }
 
         self.client = Some(client);
         self.screen = Screen::Connecting;
+        self.lan = None;
+        self.master = None;
 
         Ok(())
     }
@@ -263,8 +433,8 @@ impl Drop for Tui {
     }
 }
 
-pub fn run_menu() -> io::Result<Option<NetworkClient>> {
-    let mut tui = Tui::new()?;
+pub fn run_menu(tokens: TokenSource, config: ClientConfig) -> io::Result<Option<NetworkClient>> {
+    let mut tui = Tui::new(tokens, config)?;
     let result = tui.run();
     tui.restore_terminal()?;
     result
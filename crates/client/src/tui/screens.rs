@@ -2,7 +2,11 @@ use ratatui::Frame;
 use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
 use ratatui::style::{Color, Modifier, Style};
 use ratatui::text::{Line, Span};
-use ratatui::widgets::{Block, Borders, Clear, List, ListItem, Paragraph};
+use ratatui::widgets::{Block, Borders, Clear, List, ListItem, Paragraph, Sparkline, Wrap};
+
+use std::net::SocketAddr;
+
+use dual::net::{DenyReason, PROTOCOL_VERSION, QueryInfo, ServerFilter};
 
 use crate::net::NetworkClient;
 
@@ -10,6 +14,8 @@ use crate::net::NetworkClient;
 pub enum Screen {
     MainMenu,
     Connect,
+    ServerBrowser,
+    LanGames,
     Connecting,
     #[allow(dead_code)]
     Connected,
@@ -17,12 +23,26 @@ pub enum Screen {
     InGame,
 }
 
+/// A server as listed by either browser screen.
+pub struct ServerRow {
+    pub addr: SocketAddr,
+    pub info: QueryInfo,
+    /// Only known for servers we probed ourselves.
+    pub ping_ms: Option<f32>,
+}
+
+pub struct ServerList {
+    pub rows: Vec<ServerRow>,
+    pub filter: ServerFilter,
+}
+
 pub fn render(
     frame: &mut Frame,
     screen: Screen,
     selected: usize,
     connect_input: &str,
     connect_error: Option<&str>,
+    servers: &ServerList,
     client: &Option<NetworkClient>,
 ) {
     let area = frame.area();
@@ -42,6 +62,31 @@ pub fn render(
     match screen {
         Screen::MainMenu => render_main_menu(frame, inner, selected),
         Screen::Connect => render_connect(frame, inner, connect_input, connect_error),
+        Screen::ServerBrowser => {
+            let help = format!(
+                "↑↓ Navigate  Enter Connect  R Refresh  F Not full [{}]  P No password [{}]  Esc Back",
+                check(servers.filter.not_full),
+                check(servers.filter.no_password)
+            );
+            render_server_list(
+                frame,
+                inner,
+                " Server Browser ",
+                &help,
+                selected,
+                &servers.rows,
+                connect_error,
+            )
+        }
+        Screen::LanGames => render_server_list(
+            frame,
+            inner,
+            " LAN Games ",
+            "↑↓ Navigate  Enter Connect  R Refresh  Esc Back",
+            selected,
+            &servers.rows,
+            connect_error,
+        ),
         Screen::Connecting => render_connecting(frame, inner, client),
         Screen::Connected => render_connected(frame, inner, client),
         Screen::InGame => render_in_game_menu(frame, inner),
@@ -75,6 +120,7 @@ fn render_main_menu(frame: &mut Frame, area: Rect, selected: usize) {
     let menu_items = vec![
         ListItem::new("  Connect to Server"),
         ListItem::new("  Server Browser"),
+        ListItem::new("  LAN Games"),
         ListItem::new("  Quit"),
     ];
 
@@ -101,7 +147,7 @@ fn render_main_menu(frame: &mut Frame, area: Rect, selected: usize) {
             .border_style(Style::default().fg(Color::DarkGray)),
     );
 
-    let menu_area = centered_rect(40, 8, chunks[2]);
+    let menu_area = centered_rect(40, 9, chunks[2]);
     frame.render_widget(menu, menu_area);
 
     let help = Paragraph::new("↑↓ Navigate  Enter Select  Q Quit")
@@ -122,7 +168,7 @@ fn render_connect(frame: &mut Frame, area: Rect, input: &str, error: Option<&str
         ])
         .split(area);
 
-    let dialog_area = centered_rect(50, 10, area);
+    let dialog_area = centered_rect(50, 12, area);
     frame.render_widget(Clear, dialog_area);
 
     let dialog = Block::default()
@@ -137,7 +183,7 @@ fn render_connect(frame: &mut Frame, area: Rect, input: &str, error: Option<&str
         .constraints([
             Constraint::Length(1),
             Constraint::Length(3),
-            Constraint::Length(1),
+            Constraint::Length(3),
             Constraint::Min(0),
         ])
         .split(dialog_area);
@@ -157,7 +203,8 @@ fn render_connect(frame: &mut Frame, area: Rect, input: &str, error: Option<&str
     if let Some(err) = error {
         let error_text = Paragraph::new(err)
             .style(Style::default().fg(Color::Red))
-            .alignment(Alignment::Center);
+            .alignment(Alignment::Center)
+            .wrap(Wrap { trim: true });
         frame.render_widget(error_text, inner[2]);
     }
 
@@ -167,8 +214,131 @@ fn render_connect(frame: &mut Frame, area: Rect, input: &str, error: Option<&str
     frame.render_widget(help, inner[3]);
 }
 
+fn check(on: bool) -> &'static str {
+    if on { "x" } else { " " }
+}
+
+fn render_server_list(
+    frame: &mut Frame,
+    area: Rect,
+    title: &str,
+    help: &str,
+    selected: usize,
+    servers: &[ServerRow],
+    error: Option<&str>,
+) {
+    let dialog_area = centered_rect(76, 18, area);
+    frame.render_widget(Clear, dialog_area);
+
+    let dialog = Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(Color::Cyan));
+    frame.render_widget(dialog, dialog_area);
+
+    let inner = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(1)
+        .constraints([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(2),
+            Constraint::Length(1),
+        ])
+        .split(dialog_area);
+
+    let header = Paragraph::new(format!(
+        "  {:<24} {:<16} {:>7} {:>6}  {}",
+        "Name", "Map", "Players", "Ping", "Address"
+    ))
+    .style(Style::default().fg(Color::DarkGray));
+    frame.render_widget(header, inner[0]);
+
+    if servers.is_empty() {
+        let searching = Paragraph::new("Searching for servers...")
+            .style(Style::default().fg(Color::White))
+            .alignment(Alignment::Center);
+        frame.render_widget(searching, inner[1]);
+    } else {
+        let items: Vec<ListItem> = servers
+            .iter()
+            .enumerate()
+            .map(|(i, server)| {
+                let full = server.info.players >= server.info.max_players;
+                let ping = server
+                    .ping_ms
+                    .map_or("-".to_string(), |ping| format!("{:.0}ms", ping));
+                let line = format!(
+                    "  {:<24.24} {:<16.16} {:>7} {:>6}  {}",
+                    server.info.name,
+                    server.info.map,
+                    format!("{}/{}", server.info.players, server.info.max_players),
+                    ping,
+                    server.addr
+                );
+                let style = if i == selected {
+                    Style::default()
+                        .fg(Color::Yellow)
+                        .add_modifier(Modifier::BOLD)
+                } else if full {
+                    Style::default().fg(Color::DarkGray)
+                } else {
+                    Style::default().fg(Color::White)
+                };
+                ListItem::new(line).style(style)
+            })
+            .collect();
+        frame.render_widget(List::new(items), inner[1]);
+    }
+
+    if let Some(err) = error {
+        let error_text = Paragraph::new(err)
+            .style(Style::default().fg(Color::Red))
+            .alignment(Alignment::Center)
+            .wrap(Wrap { trim: true });
+        frame.render_widget(error_text, inner[2]);
+    }
+
+    let help = Paragraph::new(help)
+        .style(Style::default().fg(Color::DarkGray))
+        .alignment(Alignment::Center);
+    frame.render_widget(help, inner[3]);
+}
+
+pub fn describe_deny_reason(reason: &DenyReason) -> String {
+    match reason {
+        DenyReason::ServerFull => "Server is full, try again later".to_string(),
+        DenyReason::Banned => "You are banned from this server".to_string(),
+        DenyReason::InvalidToken => {
+            "Server rejected the connect token (expired or issued for another server)".to_string()
+        }
+        DenyReason::AlreadyConnected => "This client is already connected".to_string(),
+        DenyReason::SessionExpired => "Lost the session with the server, reconnect".to_string(),
+        DenyReason::VersionMismatch {
+            server_version,
+            min_version,
+            max_version,
+        } => {
+            if PROTOCOL_VERSION < *min_version {
+                format!(
+                    "Client is out of date: protocol v{}, server accepts v{}-v{}",
+                    PROTOCOL_VERSION, min_version, max_version
+                )
+            } else {
+                format!(
+                    "Server is out of date: protocol v{}, client is v{}",
+                    server_version, PROTOCOL_VERSION
+                )
+            }
+        }
+        DenyReason::MapMismatch { .. } => {
+            "Server is running a different map, update your client".to_string()
+        }
+    }
+}
+
 fn render_connecting(frame: &mut Frame, area: Rect, client: &Option<NetworkClient>) {
-    let dialog_area = centered_rect(40, 8, area);
+    let dialog_area = centered_rect(50, 8, area);
     frame.render_widget(Clear, dialog_area);
 
     let dialog = Block::default()
@@ -183,19 +353,44 @@ fn render_connecting(frame: &mut Frame, area: Rect, client: &Option<NetworkClien
         .constraints([Constraint::Min(0), Constraint::Length(1)])
         .split(dialog_area);
 
-    let status = if let Some(client) = client {
-        let state = format!("{:?}", client.state());
-        format!("Status: {}\n\nPlease wait...", state)
-    } else {
-        "Initializing connection...".to_string()
+    let downloads = client
+        .as_ref()
+        .map(|client| client.block_progress())
+        .unwrap_or_default();
+
+    let status = match client {
+        Some(_) if !downloads.is_empty() => downloads
+            .iter()
+            .map(|progress| {
+                format!(
+                    "Receiving {}: {:.0}% ({:.1}/{:.1} KB)",
+                    progress.kind,
+                    progress.fraction() * 100.0,
+                    progress.bytes_done as f32 / 1024.0,
+                    progress.total_bytes as f32 / 1024.0
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Some(client) => {
+            let state = format!("{:?}", client.state());
+            format!("Status: {}\n\nPlease wait...", state)
+        }
+        None => "Initializing connection...".to_string(),
     };
 
     let status_text = Paragraph::new(status)
         .style(Style::default().fg(Color::White))
-        .alignment(Alignment::Center);
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: true });
     frame.render_widget(status_text, inner[0]);
 
-    let help = Paragraph::new("Esc Cancel")
+    let help_text = if downloads.is_empty() {
+        "Esc Cancel"
+    } else {
+        "S Skip downloads  Esc Cancel"
+    };
+    let help = Paragraph::new(help_text)
         .style(Style::default().fg(Color::DarkGray))
         .alignment(Alignment::Center);
     frame.render_widget(help, inner[1]);
@@ -203,7 +398,7 @@ fn render_connecting(frame: &mut Frame, area: Rect, client: &Option<NetworkClien
 
 #[allow(dead_code)]
 fn render_connected(frame: &mut Frame, area: Rect, client: &Option<NetworkClient>) {
-    let dialog_area = centered_rect(50, 12, area);
+    let dialog_area = centered_rect(60, 17, area);
     frame.render_widget(Clear, dialog_area);
 
     let dialog = Block::default()
@@ -216,9 +411,9 @@ fn render_connected(frame: &mut Frame, area: Rect, client: &Option<NetworkClient
         .direction(Direction::Vertical)
         .margin(1)
         .constraints([
+            Constraint::Length(5),
             Constraint::Length(1),
-            Constraint::Length(1),
-            Constraint::Length(1),
+            Constraint::Length(4),
             Constraint::Length(1),
             Constraint::Min(0),
             Constraint::Length(1),
@@ -231,11 +426,22 @@ fn render_connected(frame: &mut Frame, area: Rect, client: &Option<NetworkClient
             .map(|id| format!("Client ID: {}", id))
             .unwrap_or_else(|| "Client ID: -".to_string());
 
-        let stats = client.stats();
-        let rtt = format!("RTT: {:.1}ms", stats.rtt_ms);
+        let stats = client.connection_stats();
+        let rtt = format!(
+            "RTT: {:.1}ms  Jitter: {:.1}ms  MTU: {}",
+            stats.rtt_ms,
+            stats.jitter_ms,
+            client.mtu()
+        );
+        let loss = format!(
+            "Loss: {:.1}%  Out: {:.1} KB/s  In: {:.1} KB/s",
+            stats.packet_loss_percent,
+            stats.send_bytes_per_sec as f32 / 1024.0,
+            stats.receive_bytes_per_sec as f32 / 1024.0
+        );
         let packets = format!(
-            "Packets: {} sent / {} recv",
-            stats.packets_sent, stats.packets_received
+            "Packets: {} sent / {} recv / {} lost",
+            stats.packets_sent, stats.packets_received, stats.packets_lost
         );
 
         let lines = vec![
@@ -247,11 +453,26 @@ fn render_connected(frame: &mut Frame, area: Rect, client: &Option<NetworkClient
             )),
             Line::from(Span::styled(client_id, Style::default().fg(Color::White))),
             Line::from(Span::styled(rtt, Style::default().fg(Color::Cyan))),
+            Line::from(Span::styled(loss, Style::default().fg(Color::Cyan))),
             Line::from(Span::styled(packets, Style::default().fg(Color::DarkGray))),
         ];
 
         let info = Paragraph::new(lines).alignment(Alignment::Center);
         frame.render_widget(info, inner[0]);
+
+        // Newest samples on the right, as many as fit
+        let history = stats.history();
+        let width = inner[2].width as usize;
+        let rtt_graph = Sparkline::default()
+            .block(Block::default().title("RTT history"))
+            .data(
+                history
+                    .iter()
+                    .skip(history.len().saturating_sub(width))
+                    .map(|sample| sample.rtt_ms as u64),
+            )
+            .style(Style::default().fg(Color::Cyan));
+        frame.render_widget(rtt_graph, inner[2]);
     }
 
     let action = Paragraph::new("Press ENTER to launch game")
@@ -0,0 +1,523 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::Result;
+use clap::{Parser, ValueEnum};
+use rkyv::rancor;
+use rkyv::util::AlignedVec;
+
+use dual::net::{
+    ArchivedPacketType, BlockKind, CaptureDirection, CaptureReader, CaptureRecord, DenyReason,
+    FragmentAssembler, KeyLog, Message, PROTOCOL_VERSION, Packet, QueryKind, QueryResponse,
+};
+
+#[derive(Parser)]
+#[command(name = "dual-dissect")]
+#[command(about = "Decode packet captures recorded by Dual servers and clients")]
+struct Args {
+    #[arg(help = "Capture file written with --capture")]
+    capture: PathBuf,
+
+    #[arg(long, help = "Only show traffic with this peer, as IP or IP:port")]
+    peer: Option<PeerFilter>,
+
+    #[arg(
+        long = "type",
+        help = "Only show packets carrying this message type, e.g. ping or world_snapshot; repeatable"
+    )]
+    types: Vec<String>,
+
+    #[arg(long, value_enum, help = "Only show one direction")]
+    direction: Option<Direction>,
+
+    #[arg(
+        long,
+        help = "Session keys written with --key-log, to decrypt sealed packets"
+    )]
+    key_log: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Clone, Copy)]
+enum PeerFilter {
+    Ip(IpAddr),
+    Addr(SocketAddr),
+}
+
+impl PeerFilter {
+    fn matches(self, peer: SocketAddr) -> bool {
+        match self {
+            Self::Ip(ip) => peer.ip() == ip,
+            Self::Addr(addr) => peer == addr,
+        }
+    }
+}
+
+impl FromStr for PeerFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(Self::Addr(addr));
+        }
+        s.parse()
+            .map(Self::Ip)
+            .map_err(|_| format!("'{}' is neither an IP nor an IP:port", s))
+    }
+}
+
+/// Everything decoded from one datagram, including packets it completed by
+/// being the last fragment of a group.
+#[derive(Default)]
+struct Dissection {
+    lines: Vec<String>,
+    types: Vec<&'static str>,
+}
+
+impl Dissection {
+    fn line(&mut self, depth: usize, text: String) {
+        self.lines
+            .push(format!("{:indent$}{}", "", text, indent = 2 + depth * 2));
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let type_filter: Vec<String> = args.types.iter().map(|t| normalize_type(t)).collect();
+
+    let keys = match &args.key_log {
+        Some(path) => KeyLog::open(path)?,
+        None => KeyLog::default(),
+    };
+
+    let mut reader = CaptureReader::open(&args.capture)?;
+    println!(
+        "{}: capture started at unix time {}",
+        args.capture.display(),
+        reader.started_at()
+    );
+
+    // Each side numbers its own fragment groups, so keep them apart
+    let mut sent_fragments = FragmentAssembler::new();
+    let mut received_fragments = FragmentAssembler::new();
+
+    let mut total = 0;
+    let mut shown = 0;
+    loop {
+        let record = match reader.next_record() {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                eprintln!("warning: capture ends in the middle of a record");
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        total += 1;
+
+        let assembler = match record.direction {
+            CaptureDirection::Sent => &mut sent_fragments,
+            CaptureDirection::Received => &mut received_fragments,
+        };
+        let mut dissection = Dissection::default();
+        let mut context = Context {
+            peer: record.peer,
+            direction: record.direction,
+            fragments: assembler,
+            keys: &keys,
+        };
+        dissect(&record.data, &mut context, 0, &mut dissection);
+
+        if !matches_filters(&args, &type_filter, &record, &dissection) {
+            continue;
+        }
+        shown += 1;
+
+        println!(
+            "#{} {:>12.6}s {} {} {} bytes",
+            total,
+            record.timestamp.as_secs_f64(),
+            record.direction,
+            record.peer,
+            record.data.len()
+        );
+        for line in dissection.lines {
+            println!("{}", line);
+        }
+    }
+
+    println!("{} of {} datagrams shown", shown, total);
+    Ok(())
+}
+
+fn matches_filters(
+    args: &Args,
+    type_filter: &[String],
+    record: &CaptureRecord,
+    dissection: &Dissection,
+) -> bool {
+    if args.peer.is_some_and(|peer| !peer.matches(record.peer)) {
+        return false;
+    }
+
+    let direction = match record.direction {
+        CaptureDirection::Sent => Direction::Sent,
+        CaptureDirection::Received => Direction::Received,
+    };
+    if args.direction.is_some_and(|d| d != direction) {
+        return false;
+    }
+
+    type_filter.is_empty()
+        || dissection
+            .types
+            .iter()
+            .any(|name| type_filter.contains(&normalize_type(name)))
+}
+
+// Lets `world-snapshot`, `WorldSnapshot` and `world_snapshot` all match
+fn normalize_type(name: &str) -> String {
+    name.chars()
+        .filter(|c| *c != '_' && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// What a datagram is read against: where it went, and the state shared by
+/// every datagram going the same way.
+struct Context<'a> {
+    peer: SocketAddr,
+    direction: CaptureDirection,
+    fragments: &'a mut FragmentAssembler,
+    keys: &'a KeyLog,
+}
+
+fn dissect(data: &[u8], context: &mut Context, depth: usize, out: &mut Dissection) {
+    // Archived data must be aligned before it can be accessed in place
+    let mut aligned = AlignedVec::<16>::with_capacity(data.len());
+    aligned.extend_from_slice(data);
+
+    if let Some(reason) = DenyReason::decode_version_denial(data) {
+        out.types.push("VersionDenial");
+        out.line(depth, format!("VersionDenial: {}", reason));
+        return;
+    }
+
+    let packet = match Packet::access_archived(&aligned) {
+        Ok(packet) => packet,
+        Err(e) => {
+            out.types.push("malformed");
+            out.line(depth, format!("not a Dual packet: {}", e));
+            return;
+        }
+    };
+
+    let header = &packet.header;
+    out.line(
+        depth,
+        format!(
+            "v{} seq {} ack {} ack_bits {:032b}",
+            PROTOCOL_VERSION,
+            header.sequence,
+            header.ack,
+            header.ack_bitfield.to_native(),
+        ),
+    );
+
+    for message in packet.messages.iter() {
+        let channel = match message.channel {
+            Message::CHANNEL_UNRELIABLE => "unreliable".to_string(),
+            Message::CHANNEL_RELIABLE => format!("reliable #{}", message.channel_seq),
+            Message::CHANNEL_ORDERED => format!("ordered #{}", message.channel_seq),
+            other => format!("channel {} #{}", other, message.channel_seq),
+        };
+        let (name, summary) = summarize(&message.payload);
+        out.types.push(name);
+        out.line(depth + 1, format!("[{}] {}{}", channel, name, summary));
+
+        if let ArchivedPacketType::Fragment {
+            group_id,
+            index,
+            count,
+            data,
+        } = &message.payload
+            && let Some(inner) = context.fragments.insert(
+                context.peer,
+                group_id.to_native(),
+                *index,
+                *count,
+                data.as_slice(),
+            )
+        {
+            out.line(
+                depth + 1,
+                format!("reassembled group {} ({} bytes):", group_id, inner.len()),
+            );
+            dissect(&inner, context, depth + 2, out);
+        }
+    }
+
+    if let [message] = packet.messages.as_slice()
+        && matches!(message.payload, ArchivedPacketType::Encrypted(_))
+        && !context.keys.is_empty()
+        && let Some(opened) = open_sealed(data, context)
+    {
+        out.line(depth + 1, "decrypted:".to_string());
+        dissect(&opened, context, depth + 2, out);
+    }
+}
+
+// The opened packet is serialized again so it reads like any other datagram
+fn open_sealed(data: &[u8], context: &Context) -> Option<Vec<u8>> {
+    let packet = Packet::deserialize(data).ok()?;
+    let opened = context
+        .keys
+        .open_packet(context.direction, context.peer, &packet)?;
+    opened.serialize().ok()
+}
+
+fn summarize(payload: &ArchivedPacketType) -> (&'static str, String) {
+    match payload {
+        ArchivedPacketType::ConnectionRequest {
+            map_checksum,
+            expire_timestamp,
+            token_data,
+            ..
+        } => (
+            "connection_request",
+            format!(
+                " map {:016x}, token expires {}, {} token bytes",
+                map_checksum.to_native(),
+                expire_timestamp,
+                token_data.len()
+            ),
+        ),
+        ArchivedPacketType::ConnectionChallenge {
+            challenge_sequence,
+            challenge_data,
+        } => (
+            "connection_challenge",
+            format!(" #{}, {} bytes", challenge_sequence, challenge_data.len()),
+        ),
+        ArchivedPacketType::ChallengeResponse {
+            challenge_sequence,
+            challenge_data,
+        } => (
+            "challenge_response",
+            format!(" #{}, {} bytes", challenge_sequence, challenge_data.len()),
+        ),
+        ArchivedPacketType::ConnectionAccepted {
+            client_id,
+            entity_id,
+            ..
+        } => (
+            "connection_accepted",
+            format!(" client {}, entity {}", client_id, entity_id),
+        ),
+        ArchivedPacketType::ConnectionDenied { reason } => (
+            "connection_denied",
+            match rkyv::deserialize::<DenyReason, rancor::Error>(reason) {
+                Ok(reason) => format!(": {}", reason),
+                Err(e) => format!(": undecodable reason ({})", e),
+            },
+        ),
+        ArchivedPacketType::SessionResume {
+            session_token,
+            packet,
+        } => (
+            "session_resume",
+            format!(
+                " {} token bytes, {} byte packet",
+                session_token.len(),
+                packet.len()
+            ),
+        ),
+        ArchivedPacketType::ClientCommand(command) => (
+            "client_command",
+            format!(
+                " tick {}, command #{}",
+                command.tick, command.command_sequence
+            ),
+        ),
+        ArchivedPacketType::ClientCommandBatch(batch) => (
+            "client_command_batch",
+            format!(
+                " from tick {}, command #{}, {} more",
+                batch.base.tick,
+                batch.base.command_sequence,
+                batch.deltas.len()
+            ),
+        ),
+        ArchivedPacketType::CommandBufferStatus(status) => (
+            "command_buffer_status",
+            format!(
+                " depth {}/{}, {} underruns, {} overruns",
+                status.depth, status.target_depth, status.underruns, status.overruns
+            ),
+        ),
+        ArchivedPacketType::WorldSnapshot(snapshot) => (
+            "world_snapshot",
+            format!(
+                " tick {}{}, {} entities, {} deltas, {} removed",
+                snapshot.tick,
+                if snapshot.is_delta {
+                    format!(" (delta from {})", snapshot.baseline_tick)
+                } else {
+                    String::new()
+                },
+                snapshot.entities.len(),
+                snapshot.entity_deltas.len(),
+                snapshot.removed_entity_ids.len()
+            ),
+        ),
+        ArchivedPacketType::PackedSnapshot(data) => {
+            ("packed_snapshot", format!(" {} bytes", data.len()))
+        }
+        ArchivedPacketType::Ping { timestamp } => ("ping", format!(" {}", timestamp)),
+        ArchivedPacketType::Pong {
+            timestamp,
+            server_time_ms,
+        } => (
+            "pong",
+            format!(" {} server time {}ms", timestamp, server_time_ms),
+        ),
+        ArchivedPacketType::SnapshotAck { received_tick } => {
+            ("snapshot_ack", format!(" tick {}", received_tick))
+        }
+        ArchivedPacketType::Disconnect => ("disconnect", String::new()),
+        ArchivedPacketType::LobbyList(lobbies) => {
+            ("lobby_list", format!(" {} lobbies", lobbies.len()))
+        }
+        ArchivedPacketType::LobbyJoin { lobby_id } => ("lobby_join", format!(" {}", lobby_id)),
+        ArchivedPacketType::LobbyLeave => ("lobby_leave", String::new()),
+        ArchivedPacketType::QueueJoin => ("queue_join", String::new()),
+        ArchivedPacketType::QueueLeave => ("queue_leave", String::new()),
+        ArchivedPacketType::QueueStatus {
+            position,
+            estimated_wait_secs,
+        } => (
+            "queue_status",
+            format!(" position {}, ~{}s", position, estimated_wait_secs),
+        ),
+        ArchivedPacketType::Fragment {
+            group_id,
+            index,
+            count,
+            data,
+        } => (
+            "fragment",
+            format!(
+                " group {}, {}/{}, {} bytes",
+                group_id,
+                index + 1,
+                count,
+                data.len()
+            ),
+        ),
+        // Opened further down when the capture came with its key log
+        ArchivedPacketType::Encrypted(data) => {
+            ("encrypted", format!(" {} bytes of ciphertext", data.len()))
+        }
+        ArchivedPacketType::MtuProbe { padding } => {
+            ("mtu_probe", format!(" {} bytes of padding", padding.len()))
+        }
+        ArchivedPacketType::BlockChunk {
+            transfer_id,
+            kind,
+            total_size,
+            index,
+            data,
+            ..
+        } => (
+            "block_chunk",
+            format!(
+                " transfer {} ({}, {} bytes), chunk {}, {} bytes",
+                transfer_id,
+                rkyv::deserialize::<BlockKind, rancor::Error>(kind)
+                    .map_or_else(|_| "unknown kind".to_string(), |kind| kind.to_string()),
+                total_size,
+                index,
+                data.len()
+            ),
+        ),
+        ArchivedPacketType::BlockAck { transfer_id, index } => (
+            "block_ack",
+            format!(" transfer {}, chunk {}", transfer_id, index),
+        ),
+        ArchivedPacketType::BlockCancel {
+            transfer_id,
+            from_sender,
+        } => (
+            "block_cancel",
+            format!(
+                " transfer {} by the {}",
+                transfer_id,
+                if *from_sender { "sender" } else { "receiver" }
+            ),
+        ),
+        ArchivedPacketType::QueryRequest {
+            kind, challenge, ..
+        } => (
+            "query_request",
+            format!(
+                " {}, challenge {:016x}",
+                rkyv::deserialize::<QueryKind, rancor::Error>(kind)
+                    .map_or_else(|_| "unknown kind".to_string(), |kind| kind.to_string()),
+                challenge
+            ),
+        ),
+        ArchivedPacketType::QueryChallenge { challenge } => {
+            ("query_challenge", format!(" {:016x}", challenge))
+        }
+        ArchivedPacketType::QueryResponse(response) => (
+            "query_response",
+            match rkyv::deserialize::<QueryResponse, rancor::Error>(response) {
+                Ok(QueryResponse::Info(info)) => format!(
+                    " info: \"{}\" on {}, {}/{} players",
+                    info.name, info.map, info.players, info.max_players
+                ),
+                Ok(QueryResponse::Players(players)) => format!(" {} players", players.len()),
+                Ok(QueryResponse::Rules(rules)) => format!(" {} rules", rules.len()),
+                Err(e) => format!(": undecodable ({})", e),
+            },
+        ),
+        ArchivedPacketType::DiscoveryRequest { timestamp, .. } => {
+            ("discovery_request", format!(" {}", timestamp))
+        }
+        ArchivedPacketType::DiscoveryResponse { timestamp, info } => (
+            "discovery_response",
+            format!(
+                " {}: \"{}\" on {}, {}/{} players",
+                timestamp, info.name, info.map, info.players, info.max_players
+            ),
+        ),
+        ArchivedPacketType::MasterHeartbeat { challenge, info } => (
+            "master_heartbeat",
+            format!(
+                " challenge {:016x}, \"{}\" on {}, {}/{} players",
+                challenge, info.name, info.map, info.players, info.max_players
+            ),
+        ),
+        ArchivedPacketType::MasterListRequest {
+            offset, challenge, ..
+        } => (
+            "master_list_request",
+            format!(" from {}, challenge {:016x}", offset, challenge),
+        ),
+        ArchivedPacketType::MasterList {
+            total,
+            offset,
+            servers,
+        } => (
+            "master_list",
+            format!(" {} from {} of {} servers", servers.len(), offset, total),
+        ),
+    }
+}
@@ -12,12 +12,13 @@ pub use lobby::{Lobby, LobbyId, LobbyManager, LobbySettings, LobbyState, PlayerI
 pub use map::{MapObject, MapObjectKind, TestingGround};
 pub use net::{
     ClientCommand, ClientConnection, ConnectionManager, ConnectionState, DEFAULT_PORT,
-    DEFAULT_TICK_RATE, EntityState, NetworkEndpoint, NetworkStats, Packet, PacketError,
+    DEFAULT_TICK_RATE, EntityState, Message, NetworkEndpoint, NetworkStats, Packet, PacketError,
     PacketHeader, PacketLossSimulation, PacketType, Reliability, WorldSnapshot,
 };
 pub use physics::{PhysicsHandle, PhysicsHistory, PhysicsSnapshot, PhysicsSync, PhysicsWorld};
 pub use player::{PlayerConfig, PlayerController, PlayerState};
 pub use simulation::{
-    CommandBuffer, CommandProcessor, FixedTimestep, SimulationLoop, SimulationState,
+    CommandBuffer, CommandJitterBuffer, CommandProcessor, FixedTimestep, JitterBufferConfig,
+    JitterBufferStats, SimulationLoop, SimulationState,
 };
 pub use snapshot::{Entity, EntityHandle, EntityType, SnapshotBuffer, World};
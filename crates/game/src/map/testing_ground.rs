@@ -16,6 +16,8 @@ impl Default for TestingGround {
 }
 
 impl TestingGround {
+    pub const NAME: &str = "testing_ground";
+
     const GROUND_SIZE: f32 = 100.0;
     const GROUND_Y: f32 = 0.0;
 
@@ -115,6 +117,34 @@ impl TestingGround {
         &self.objects
     }
 
+    /// FNV-1a over the layout, so a client predicting against different
+    /// geometry can be turned away at connect time.
+    pub fn checksum(&self) -> u64 {
+        const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        let mut hash = FNV_OFFSET;
+        let mut write = |bytes: &[u8]| {
+            for &byte in bytes {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        };
+
+        for object in &self.objects {
+            write(&[object.kind as u8]);
+            for value in object.position.to_array() {
+                write(&value.to_bits().to_le_bytes());
+            }
+            for value in object.half_extents.to_array() {
+                write(&value.to_bits().to_le_bytes());
+            }
+            write(&object.mass.unwrap_or(0.0).to_bits().to_le_bytes());
+        }
+
+        hash
+    }
+
     pub fn spawn(&mut self, world: &mut World, physics: &mut PhysicsWorld) {
         for object in &mut self.objects {
             match object.kind {
@@ -188,4 +218,16 @@ mod tests {
         assert!(world.entity_count() > 0);
         assert!(!ground.dynamic_entity_handles().is_empty());
     }
+
+    #[test]
+    fn checksum_tracks_layout_not_spawn_state() {
+        let mut ground = TestingGround::new();
+        let before = ground.checksum();
+
+        ground.spawn(&mut World::new(), &mut PhysicsWorld::new());
+        assert_eq!(ground.checksum(), before);
+
+        ground.objects[1].position.x += 0.5;
+        assert_ne!(ground.checksum(), before);
+    }
 }
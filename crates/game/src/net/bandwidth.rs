@@ -0,0 +1,249 @@
+use std::time::{Duration, Instant};
+
+// Latency this far above the best seen means queues are building somewhere
+const QUEUING_DELAY_MS: f32 = 40.0;
+const LOSS_BACKOFF: f32 = 0.7;
+const DELAY_BACKOFF: f32 = 0.9;
+const SIZE_SMOOTHING: f32 = 0.1;
+
+#[derive(Debug, Clone)]
+pub struct BandwidthConfig {
+    pub min_bytes_per_sec: u32,
+    pub max_bytes_per_sec: u32,
+    pub initial_bytes_per_sec: u32,
+    /// Bytes per second added for every round trip without congestion
+    pub increase_bytes_per_sec: u32,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            min_bytes_per_sec: 8 * 1024,
+            max_bytes_per_sec: 256 * 1024,
+            initial_bytes_per_sec: 64 * 1024,
+            increase_bytes_per_sec: 4 * 1024,
+        }
+    }
+}
+
+/// AIMD send rate estimate for one connection. Grows while acks come back
+/// on time and backs off on loss or rising latency, at most once per RTT.
+#[derive(Debug, Clone)]
+pub struct BandwidthEstimator {
+    config: BandwidthConfig,
+    bytes_per_sec: f32,
+    min_rtt_ms: Option<f32>,
+    last_adjust: Instant,
+    avg_snapshot_bytes: f32,
+    allowance: f32,
+}
+
+impl BandwidthEstimator {
+    pub fn new(config: BandwidthConfig) -> Self {
+        let mut estimator = Self {
+            bytes_per_sec: config.initial_bytes_per_sec as f32,
+            config,
+            min_rtt_ms: None,
+            last_adjust: Instant::now(),
+            avg_snapshot_bytes: 0.0,
+            allowance: 0.0,
+        };
+        estimator.clamp();
+        estimator
+    }
+
+    pub fn config(&self) -> &BandwidthConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: BandwidthConfig) {
+        self.config = config;
+        self.clamp();
+    }
+
+    pub fn bytes_per_sec(&self) -> u32 {
+        self.bytes_per_sec as u32
+    }
+
+    pub fn on_acks(&mut self, acked: usize, srtt_ms: f32, lost: usize) {
+        self.on_acks_at(acked, srtt_ms, lost, Instant::now());
+    }
+
+    pub fn on_acks_at(&mut self, acked: usize, srtt_ms: f32, lost: usize, now: Instant) {
+        if acked == 0 && lost == 0 {
+            return;
+        }
+
+        let min_rtt = self.min_rtt_ms.map_or(srtt_ms, |min| min.min(srtt_ms));
+        self.min_rtt_ms = Some(min_rtt);
+
+        let round_trip = Duration::from_secs_f32(srtt_ms.max(1.0) / 1000.0);
+        if now.duration_since(self.last_adjust) < round_trip {
+            return;
+        }
+        self.last_adjust = now;
+
+        if lost > 0 {
+            self.bytes_per_sec *= LOSS_BACKOFF;
+        } else if srtt_ms > min_rtt + QUEUING_DELAY_MS {
+            self.bytes_per_sec *= DELAY_BACKOFF;
+        } else {
+            self.bytes_per_sec += self.config.increase_bytes_per_sec as f32;
+        }
+        self.clamp();
+    }
+
+    fn clamp(&mut self) {
+        self.bytes_per_sec = self.bytes_per_sec.clamp(
+            self.config.min_bytes_per_sec as f32,
+            self.config
+                .max_bytes_per_sec
+                .max(self.config.min_bytes_per_sec) as f32,
+        );
+    }
+
+    /// Ticks between snapshots so the average snapshot fits the estimate.
+    pub fn snapshot_interval(&self, tick_rate: u32, min_interval: u32, max_interval: u32) -> u32 {
+        let min_interval = min_interval.max(1);
+        let max_interval = max_interval.max(min_interval);
+        if self.avg_snapshot_bytes <= 0.0 {
+            return min_interval;
+        }
+
+        let snapshots_per_sec = self.bytes_per_sec / self.avg_snapshot_bytes;
+        let interval = (tick_rate as f32 / snapshots_per_sec).ceil() as u32;
+        interval.clamp(min_interval, max_interval)
+    }
+
+    /// Bytes one snapshot may use at the given interval.
+    pub fn byte_budget(&self, tick_rate: u32, interval: u32) -> u32 {
+        (self.bytes_per_sec * interval.max(1) as f32 / tick_rate.max(1) as f32) as u32
+    }
+
+    /// Refills the send allowance for one tick. Idle time only banks one
+    /// interval's budget, or one snapshot if those run larger.
+    pub fn refill(&mut self, tick_rate: u32, interval: u32) {
+        let cap = (self.byte_budget(tick_rate, interval) as f32).max(self.avg_snapshot_bytes);
+        self.allowance = (self.allowance + self.bytes_per_sec / tick_rate.max(1) as f32).min(cap);
+    }
+
+    /// Whether a snapshot of the typical size fits the allowance.
+    pub fn can_send_snapshot(&self) -> bool {
+        self.allowance >= self.avg_snapshot_bytes
+    }
+
+    /// Charges bytes sent for anything other than a snapshot.
+    pub fn record_sent(&mut self, bytes: usize) {
+        self.allowance -= bytes as f32;
+    }
+
+    pub fn record_snapshot(&mut self, bytes: usize) {
+        let bytes = bytes as f32;
+        self.avg_snapshot_bytes = if self.avg_snapshot_bytes <= 0.0 {
+            bytes
+        } else {
+            self.avg_snapshot_bytes + (bytes - self.avg_snapshot_bytes) * SIZE_SMOOTHING
+        };
+        self.allowance -= bytes;
+    }
+
+    pub fn avg_snapshot_bytes(&self) -> u32 {
+        self.avg_snapshot_bytes as u32
+    }
+}
+
+impl Default for BandwidthEstimator {
+    fn default() -> Self {
+        Self::new(BandwidthConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> BandwidthConfig {
+        BandwidthConfig {
+            min_bytes_per_sec: 1_000,
+            max_bytes_per_sec: 10_000,
+            initial_bytes_per_sec: 5_000,
+            increase_bytes_per_sec: 1_000,
+        }
+    }
+
+    #[test]
+    fn test_estimate_grows_then_backs_off_within_bounds() {
+        let mut estimator = BandwidthEstimator::new(config());
+        let mut now = Instant::now();
+
+        for _ in 0..20 {
+            now += Duration::from_millis(100);
+            estimator.on_acks_at(4, 50.0, 0, now);
+        }
+        assert_eq!(estimator.bytes_per_sec(), 10_000);
+
+        now += Duration::from_millis(100);
+        estimator.on_acks_at(4, 50.0, 2, now);
+        assert_eq!(estimator.bytes_per_sec(), 7_000);
+
+        // Only one backoff per round trip
+        estimator.on_acks_at(4, 50.0, 2, now + Duration::from_millis(10));
+        assert_eq!(estimator.bytes_per_sec(), 7_000);
+
+        for _ in 0..20 {
+            now += Duration::from_millis(200);
+            estimator.on_acks_at(4, 150.0, 0, now);
+        }
+        assert_eq!(estimator.bytes_per_sec(), 1_000);
+    }
+
+    #[test]
+    fn test_snapshot_interval_follows_estimate() {
+        let mut estimator = BandwidthEstimator::new(config());
+        assert_eq!(estimator.snapshot_interval(60, 1, 6), 1);
+
+        // 5000 B/s over 500 B snapshots is 10 per second
+        estimator.record_snapshot(500);
+        assert_eq!(estimator.snapshot_interval(60, 1, 6), 6);
+        assert_eq!(estimator.snapshot_interval(60, 1, 4), 4);
+
+        estimator.record_snapshot(50);
+        estimator.set_config(BandwidthConfig {
+            min_bytes_per_sec: 50_000,
+            max_bytes_per_sec: 50_000,
+            ..config()
+        });
+        assert_eq!(estimator.snapshot_interval(60, 2, 6), 2);
+        assert_eq!(estimator.byte_budget(60, 2), 1_666);
+    }
+
+    #[test]
+    fn test_allowance_limits_snapshots() {
+        let mut estimator = BandwidthEstimator::new(config());
+        estimator.record_snapshot(400);
+        assert!(!estimator.can_send_snapshot());
+
+        let mut ticks = 0;
+        while !estimator.can_send_snapshot() {
+            estimator.refill(60, 6);
+            ticks += 1;
+        }
+        // 400 B of debt plus a 400 B snapshot at ~83 B per tick
+        assert_eq!(ticks, 10);
+    }
+
+    #[test]
+    fn test_other_traffic_draws_on_allowance() {
+        let mut estimator = BandwidthEstimator::new(config());
+        estimator.record_snapshot(400);
+        estimator.record_sent(400);
+        assert_eq!(estimator.avg_snapshot_bytes(), 400);
+
+        let mut ticks = 0;
+        while !estimator.can_send_snapshot() {
+            estimator.refill(60, 6);
+            ticks += 1;
+        }
+        assert_eq!(ticks, 15);
+    }
+}
@@ -0,0 +1,596 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::protocol::{BlockKind, MAX_MTU, PacketType};
+
+pub const MAX_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+/// Chunks of one transfer that may be unacked at once.
+pub const BLOCK_WINDOW: usize = 32;
+/// Room a chunk leaves in its datagram for packet, message and cipher framing.
+pub const CHUNK_OVERHEAD: usize = 256;
+
+const MAX_INCOMING_BLOCKS: usize = 4;
+const CLOSED_HISTORY_SIZE: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlockError {
+    #[error("block of {0} bytes exceeds the {MAX_BLOCK_SIZE} byte limit")]
+    TooLarge(usize),
+    #[error("chunk size {0} is out of range")]
+    InvalidChunkSize(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockDirection {
+    Outgoing,
+    Incoming,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockProgress {
+    pub transfer_id: u16,
+    pub kind: BlockKind,
+    pub direction: BlockDirection,
+    pub bytes_done: usize,
+    pub total_bytes: usize,
+}
+
+impl BlockProgress {
+    pub fn fraction(&self) -> f32 {
+        if self.total_bytes == 0 {
+            return 1.0;
+        }
+        self.bytes_done as f32 / self.total_bytes as f32
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompletedBlock {
+    pub transfer_id: u16,
+    pub kind: BlockKind,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct OutgoingBlock {
+    id: u16,
+    kind: BlockKind,
+    data: Vec<u8>,
+    chunk_size: usize,
+    acked: Vec<bool>,
+    acked_count: usize,
+    acked_bytes: usize,
+    last_sent: Vec<Option<Instant>>,
+    // First unacked chunk, the window slides from here
+    base: usize,
+}
+
+impl OutgoingBlock {
+    fn chunk_count(&self) -> usize {
+        self.acked.len()
+    }
+
+    fn chunk(&self, index: usize) -> &[u8] {
+        let start = index * self.chunk_size;
+        let end = (start + self.chunk_size).min(self.data.len());
+        &self.data[start..end]
+    }
+}
+
+// Chunks are kept as they arrive and joined once the last one is in, so
+// memory follows what the peer actually sent rather than what it claims
+#[derive(Debug)]
+struct IncomingBlock {
+    kind: BlockKind,
+    chunk_size: usize,
+    total_size: usize,
+    chunk_count: usize,
+    chunks: BTreeMap<usize, Vec<u8>>,
+    received_bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Closed {
+    Completed,
+    Cancelled,
+}
+
+/// Chunked transfer of payloads too large for one packet. Chunks travel
+/// unreliably and are acked one by one; the sender keeps only the source
+/// bytes and resends whatever in its window goes unacked for too long.
+/// Outgoing blocks go one at a time in the order they were queued. Incoming
+/// blocks are refused unless their kind was accepted with [`Self::accept`].
+#[derive(Debug)]
+pub struct BlockTransfers {
+    outgoing: VecDeque<OutgoingBlock>,
+    incoming: HashMap<u16, IncomingBlock>,
+    // Kinds the peer may send us, with the largest size taken for each
+    accepted: Vec<(BlockKind, usize)>,
+    completed: Vec<CompletedBlock>,
+    closed_incoming: VecDeque<(u16, Closed)>,
+    replies: Vec<PacketType>,
+    next_id: u16,
+    window: usize,
+}
+
+impl Default for BlockTransfers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockTransfers {
+    pub fn new() -> Self {
+        Self::with_window(BLOCK_WINDOW)
+    }
+
+    pub fn with_window(window: usize) -> Self {
+        Self {
+            outgoing: VecDeque::new(),
+            incoming: HashMap::new(),
+            accepted: Vec::new(),
+            completed: Vec::new(),
+            closed_incoming: VecDeque::with_capacity(CLOSED_HISTORY_SIZE),
+            replies: Vec::new(),
+            next_id: 0,
+            window: window.max(1),
+        }
+    }
+
+    /// Lets the peer send blocks of `kind` up to `max_size` bytes.
+    pub fn accept(&mut self, kind: BlockKind, max_size: usize) {
+        let max_size = max_size.min(MAX_BLOCK_SIZE);
+        match self.accepted.iter_mut().find(|(k, _)| *k == kind) {
+            Some((_, limit)) => *limit = max_size,
+            None => self.accepted.push((kind, max_size)),
+        }
+    }
+
+    fn accepted_size(&self, kind: BlockKind) -> Option<usize> {
+        self.accepted
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, limit)| *limit)
+    }
+
+    pub fn send(
+        &mut self,
+        kind: BlockKind,
+        data: Vec<u8>,
+        chunk_size: usize,
+    ) -> Result<u16, BlockError> {
+        if data.len() > MAX_BLOCK_SIZE {
+            return Err(BlockError::TooLarge(data.len()));
+        }
+        let chunk_count = data.len().div_ceil(chunk_size.max(1)).max(1);
+        if chunk_size == 0 || chunk_size > MAX_MTU || chunk_count > u16::MAX as usize {
+            return Err(BlockError::InvalidChunkSize(chunk_size));
+        }
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        self.outgoing.push_back(OutgoingBlock {
+            id,
+            kind,
+            data,
+            chunk_size,
+            acked: vec![false; chunk_count],
+            acked_count: 0,
+            acked_bytes: 0,
+            last_sent: vec![None; chunk_count],
+            base: 0,
+        });
+
+        Ok(id)
+    }
+
+    /// Drops a transfer in either direction and tells the peer to do the same.
+    pub fn cancel(&mut self, direction: BlockDirection, transfer_id: u16) -> bool {
+        let found = match direction {
+            BlockDirection::Outgoing => {
+                let before = self.outgoing.len();
+                self.outgoing.retain(|block| block.id != transfer_id);
+                self.outgoing.len() != before
+            }
+            BlockDirection::Incoming => {
+                let found = self.incoming.remove(&transfer_id).is_some();
+                if found {
+                    self.close_incoming(transfer_id, Closed::Cancelled);
+                }
+                found
+            }
+        };
+
+        if found {
+            self.replies.push(PacketType::BlockCancel {
+                transfer_id,
+                from_sender: direction == BlockDirection::Outgoing,
+            });
+        }
+        found
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.outgoing.is_empty() && self.incoming.is_empty() && self.replies.is_empty()
+    }
+
+    /// Acks and cancels owed to the peer, then every chunk in the window that
+    /// hasn't been sent yet or has waited longer than the resend timeout.
+    pub fn poll(&mut self, now: Instant, rtt_ms: f32) -> Vec<PacketType> {
+        let mut payloads = std::mem::take(&mut self.replies);
+
+        let timeout = if rtt_ms > 0.0 {
+            Duration::from_secs_f32(rtt_ms * 1.5 / 1000.0).max(Duration::from_millis(50))
+        } else {
+            Duration::from_millis(200)
+        };
+
+        let Some(block) = self.outgoing.front_mut() else {
+            return payloads;
+        };
+
+        let end = (block.base + self.window).min(block.chunk_count());
+        for index in block.base..end {
+            if block.acked[index]
+                || block.last_sent[index].is_some_and(|sent| now.duration_since(sent) < timeout)
+            {
+                continue;
+            }
+
+            block.last_sent[index] = Some(now);
+            payloads.push(PacketType::BlockChunk {
+                transfer_id: block.id,
+                kind: block.kind,
+                total_size: block.data.len() as u32,
+                chunk_size: block.chunk_size as u16,
+                index: index as u16,
+                data: block.chunk(index).to_vec(),
+            });
+        }
+
+        payloads
+    }
+
+    /// Consumes block traffic and hands anything else back.
+    pub fn receive(&mut self, payload: PacketType) -> Option<PacketType> {
+        match payload {
+            PacketType::BlockChunk {
+                transfer_id,
+                kind,
+                total_size,
+                chunk_size,
+                index,
+                data,
+            } => {
+                self.receive_chunk(
+                    transfer_id,
+                    kind,
+                    total_size as usize,
+                    chunk_size as usize,
+                    index as usize,
+                    data,
+                );
+                None
+            }
+            PacketType::BlockAck { transfer_id, index } => {
+                self.receive_ack(transfer_id, index as usize);
+                None
+            }
+            PacketType::BlockCancel {
+                transfer_id,
+                from_sender,
+            } => {
+                if from_sender {
+                    if self.incoming.remove(&transfer_id).is_some() {
+                        self.close_incoming(transfer_id, Closed::Cancelled);
+                    }
+                } else {
+                    self.outgoing.retain(|block| block.id != transfer_id);
+                }
+                None
+            }
+            payload => Some(payload),
+        }
+    }
+
+    fn receive_chunk(
+        &mut self,
+        transfer_id: u16,
+        kind: BlockKind,
+        total_size: usize,
+        chunk_size: usize,
+        index: usize,
+        data: Vec<u8>,
+    ) {
+        match self.closed_state(transfer_id) {
+            // The ack was lost, the sender is still waiting on it
+            Some(Closed::Completed) => {
+                self.replies.push(PacketType::BlockAck {
+                    transfer_id,
+                    index: index as u16,
+                });
+                return;
+            }
+            Some(Closed::Cancelled) => {
+                self.replies.push(PacketType::BlockCancel {
+                    transfer_id,
+                    from_sender: false,
+                });
+                return;
+            }
+            None => {}
+        }
+
+        // Unexpected kinds and oversized blocks are turned away before
+        // anything is kept for them
+        if self
+            .accepted_size(kind)
+            .is_none_or(|max_size| total_size > max_size)
+        {
+            self.incoming.remove(&transfer_id);
+            self.close_incoming(transfer_id, Closed::Cancelled);
+            self.replies.push(PacketType::BlockCancel {
+                transfer_id,
+                from_sender: false,
+            });
+            return;
+        }
+
+        if chunk_size == 0 || chunk_size > MAX_MTU {
+            return;
+        }
+        let chunk_count = total_size.div_ceil(chunk_size).max(1);
+        let start = index * chunk_size;
+        let expected = chunk_size.min(total_size.saturating_sub(start));
+        if index >= chunk_count || chunk_count > u16::MAX as usize || data.len() != expected {
+            return;
+        }
+
+        // A reused id with a different shape is a new transfer
+        if self.incoming.get(&transfer_id).is_some_and(|block| {
+            block.kind != kind || block.chunk_size != chunk_size || block.total_size != total_size
+        }) {
+            self.incoming.remove(&transfer_id);
+        }
+
+        if !self.incoming.contains_key(&transfer_id) {
+            if self.incoming.len() >= MAX_INCOMING_BLOCKS {
+                return;
+            }
+            self.incoming.insert(
+                transfer_id,
+                IncomingBlock {
+                    kind,
+                    chunk_size,
+                    total_size,
+                    chunk_count,
+                    chunks: BTreeMap::new(),
+                    received_bytes: 0,
+                },
+            );
+        }
+
+        self.replies.push(PacketType::BlockAck {
+            transfer_id,
+            index: index as u16,
+        });
+
+        let block = self.incoming.get_mut(&transfer_id).unwrap();
+        if block.chunks.contains_key(&index) {
+            return;
+        }
+        block.received_bytes += data.len();
+        block.chunks.insert(index, data);
+
+        if block.chunks.len() == block.chunk_count {
+            let block = self.incoming.remove(&transfer_id).unwrap();
+            self.close_incoming(transfer_id, Closed::Completed);
+            self.completed.push(CompletedBlock {
+                transfer_id,
+                kind: block.kind,
+                data: block.chunks.into_values().collect::<Vec<_>>().concat(),
+            });
+        }
+    }
+
+    fn receive_ack(&mut self, transfer_id: u16, index: usize) {
+        let Some(position) = self
+            .outgoing
+            .iter()
+            .position(|block| block.id == transfer_id)
+        else {
+            return;
+        };
+
+        let block = &mut self.outgoing[position];
+        if index >= block.chunk_count() || block.acked[index] {
+            return;
+        }
+        block.acked[index] = true;
+        block.acked_count += 1;
+        block.acked_bytes += block.chunk(index).len();
+
+        while block.base < block.chunk_count() && block.acked[block.base] {
+            block.base += 1;
+        }
+
+        if block.acked_count == block.chunk_count() {
+            self.outgoing.remove(position);
+        }
+    }
+
+    fn closed_state(&self, transfer_id: u16) -> Option<Closed> {
+        self.closed_incoming
+            .iter()
+            .rev()
+            .find(|(id, _)| *id == transfer_id)
+            .map(|(_, state)| *state)
+    }
+
+    fn close_incoming(&mut self, transfer_id: u16, state: Closed) {
+        if self.closed_incoming.len() >= CLOSED_HISTORY_SIZE {
+            self.closed_incoming.pop_front();
+        }
+        self.closed_incoming.push_back((transfer_id, state));
+    }
+
+    pub fn take_completed(&mut self) -> Vec<CompletedBlock> {
+        std::mem::take(&mut self.completed)
+    }
+
+    pub fn progress(&self) -> Vec<BlockProgress> {
+        let outgoing = self.outgoing.iter().map(|block| BlockProgress {
+            transfer_id: block.id,
+            kind: block.kind,
+            direction: BlockDirection::Outgoing,
+            bytes_done: block.acked_bytes,
+            total_bytes: block.data.len(),
+        });
+        let incoming = self.incoming.iter().map(|(&id, block)| BlockProgress {
+            transfer_id: id,
+            kind: block.kind,
+            direction: BlockDirection::Incoming,
+            bytes_done: block.received_bytes,
+            total_bytes: block.total_size,
+        });
+        outgoing.chain(incoming).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Drops every `drop_every`th payload from `from`, never the first
+    fn exchange(
+        from: &mut BlockTransfers,
+        to: &mut BlockTransfers,
+        now: Instant,
+        drop_every: usize,
+    ) {
+        for (i, payload) in from.poll(now, 20.0).into_iter().enumerate() {
+            if drop_every == 0 || i % drop_every != drop_every - 1 {
+                assert!(to.receive(payload).is_none());
+            }
+        }
+        for payload in to.poll(now, 20.0) {
+            assert!(from.receive(payload).is_none());
+        }
+    }
+
+    #[test]
+    fn test_block_survives_loss_and_reorders_into_place() {
+        let mut sender = BlockTransfers::with_window(8);
+        let mut receiver = BlockTransfers::new();
+        receiver.accept(BlockKind::Map, MAX_BLOCK_SIZE);
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7) as u8).collect();
+
+        let id = sender.send(BlockKind::Map, data.clone(), 300).unwrap();
+        assert_eq!(sender.poll(Instant::now(), 20.0).len(), 8);
+
+        let mut now = Instant::now();
+        for _ in 0..200 {
+            now += Duration::from_millis(60);
+            exchange(&mut sender, &mut receiver, now, 3);
+            if sender.is_idle() {
+                break;
+            }
+        }
+
+        assert!(sender.is_idle());
+        let completed = receiver.take_completed();
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].transfer_id, id);
+        assert_eq!(completed[0].kind, BlockKind::Map);
+        assert_eq!(completed[0].data, data);
+    }
+
+    #[test]
+    fn test_progress_and_cancel_from_receiver() {
+        let mut sender = BlockTransfers::with_window(4);
+        let mut receiver = BlockTransfers::new();
+        receiver.accept(BlockKind::WorldState, MAX_BLOCK_SIZE);
+
+        let id = sender
+            .send(BlockKind::WorldState, vec![1; 4000], 500)
+            .unwrap();
+        exchange(&mut sender, &mut receiver, Instant::now(), 0);
+
+        let progress = sender.progress();
+        assert_eq!(progress[0].bytes_done, 2000);
+        assert_eq!(progress[0].total_bytes, 4000);
+        assert_eq!(receiver.progress()[0].direction, BlockDirection::Incoming);
+
+        assert!(receiver.cancel(BlockDirection::Incoming, id));
+        exchange(&mut receiver, &mut sender, Instant::now(), 0);
+        assert!(sender.progress().is_empty());
+        assert!(receiver.take_completed().is_empty());
+    }
+
+    #[test]
+    fn test_malformed_chunks_are_ignored() {
+        let mut receiver = BlockTransfers::new();
+        receiver.accept(BlockKind::ServerConfig, MAX_BLOCK_SIZE);
+
+        let chunk = |total_size: u32, index: u16, len: usize| PacketType::BlockChunk {
+            transfer_id: 0,
+            kind: BlockKind::ServerConfig,
+            total_size,
+            chunk_size: 100,
+            index,
+            data: vec![0; len],
+        };
+
+        receiver.receive(chunk(250, 3, 50));
+        receiver.receive(chunk(250, 2, 100));
+        assert!(receiver.progress().is_empty());
+
+        receiver.receive(chunk(250, 2, 50));
+        assert_eq!(receiver.progress()[0].bytes_done, 50);
+
+        assert!(
+            BlockTransfers::new()
+                .send(BlockKind::Map, vec![0; MAX_BLOCK_SIZE + 1], 1000)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_unexpected_and_oversized_blocks_are_refused() {
+        let mut receiver = BlockTransfers::new();
+        receiver.accept(BlockKind::ServerConfig, 1000);
+
+        let chunk = |transfer_id: u16, kind: BlockKind, total_size: u32| PacketType::BlockChunk {
+            transfer_id,
+            kind,
+            total_size,
+            chunk_size: 100,
+            index: 0,
+            data: vec![0; 100],
+        };
+
+        receiver.receive(chunk(0, BlockKind::Map, 200));
+        receiver.receive(chunk(1, BlockKind::ServerConfig, 1001));
+        receiver.receive(chunk(2, BlockKind::ServerConfig, MAX_BLOCK_SIZE as u32));
+        assert!(receiver.progress().is_empty());
+
+        let cancelled: Vec<_> = receiver
+            .poll(Instant::now(), 20.0)
+            .into_iter()
+            .filter_map(|payload| match payload {
+                PacketType::BlockCancel {
+                    transfer_id,
+                    from_sender: false,
+                } => Some(transfer_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(cancelled, vec![0, 1, 2]);
+
+        // The claimed size alone reserves nothing
+        receiver.receive(chunk(3, BlockKind::ServerConfig, 1000));
+        assert_eq!(receiver.progress()[0].bytes_done, 100);
+        assert_eq!(receiver.incoming[&3].chunks.len(), 1);
+    }
+}
@@ -0,0 +1,445 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::crypto::PacketCipher;
+use super::protocol::Packet;
+use super::token::{Key, key_from_hex, key_to_hex, unix_timestamp};
+
+pub const CAPTURE_MAGIC: [u8; 8] = *b"DUALCAP\0";
+pub const CAPTURE_VERSION: u16 = 1;
+
+// Anything longer can't have come off a UDP socket, so the file is corrupt
+const MAX_RECORD_SIZE: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureDirection {
+    Sent,
+    Received,
+}
+
+impl std::fmt::Display for CaptureDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sent => write!(f, "sent"),
+            Self::Received => write!(f, "recv"),
+        }
+    }
+}
+
+/// One datagram as it crossed the wire, before any parsing or decryption.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// Time since the capture was started.
+    pub timestamp: Duration,
+    pub direction: CaptureDirection,
+    pub peer: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+/// Writes datagrams to a capture file.
+///
+/// The file is a fixed header (magic, format version, unix start time in
+/// seconds) followed by records of timestamp in microseconds, direction,
+/// peer address and length-prefixed payload, all little-endian.
+pub struct CaptureWriter {
+    writer: Box<dyn Write + Send>,
+    start: Instant,
+    records: u64,
+}
+
+impl CaptureWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&CAPTURE_MAGIC)?;
+        writer.write_all(&CAPTURE_VERSION.to_le_bytes())?;
+        writer.write_all(&unix_timestamp().to_le_bytes())?;
+
+        Ok(Self {
+            writer: Box::new(writer),
+            start: Instant::now(),
+            records: 0,
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        direction: CaptureDirection,
+        peer: SocketAddr,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let micros = self.start.elapsed().as_micros() as u64;
+        let mut header = Vec::with_capacity(32);
+        header.extend_from_slice(&micros.to_le_bytes());
+        header.push(match direction {
+            CaptureDirection::Sent => 0,
+            CaptureDirection::Received => 1,
+        });
+        match peer.ip() {
+            IpAddr::V4(ip) => {
+                header.push(4);
+                header.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                header.push(6);
+                header.extend_from_slice(&ip.octets());
+            }
+        }
+        header.extend_from_slice(&peer.port().to_le_bytes());
+        header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+        self.writer.write_all(&header)?;
+        self.writer.write_all(data)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub struct CaptureReader<R: Read> {
+    reader: R,
+    started_at: u64,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != CAPTURE_MAGIC {
+            return Err(invalid_data("Not a capture file"));
+        }
+
+        let version = u16::from_le_bytes(read_array(&mut reader)?);
+        if version != CAPTURE_VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported capture version {}",
+                version
+            )));
+        }
+        let started_at = u64::from_le_bytes(read_array(&mut reader)?);
+
+        Ok(Self { reader, started_at })
+    }
+
+    /// Unix time in seconds when the capture was started.
+    pub fn started_at(&self) -> u64 {
+        self.started_at
+    }
+
+    /// Reads the next record, or `None` at the end of the file. A record cut
+    /// short, as left by a process killed mid-write, is an `UnexpectedEof`.
+    pub fn next_record(&mut self) -> io::Result<Option<CaptureRecord>> {
+        let mut timestamp = [0u8; 8];
+        match self.reader.read(&mut timestamp)? {
+            0 => return Ok(None),
+            8 => {}
+            n => self.reader.read_exact(&mut timestamp[n..])?,
+        }
+        let timestamp = Duration::from_micros(u64::from_le_bytes(timestamp));
+
+        let [direction, family] = read_array(&mut self.reader)?;
+        let direction = match direction {
+            0 => CaptureDirection::Sent,
+            1 => CaptureDirection::Received,
+            other => return Err(invalid_data(format!("Unknown direction {}", other))),
+        };
+        let ip = match family {
+            4 => IpAddr::V4(Ipv4Addr::from(read_array::<4>(&mut self.reader)?)),
+            6 => IpAddr::V6(Ipv6Addr::from(read_array::<16>(&mut self.reader)?)),
+            other => return Err(invalid_data(format!("Unknown address family {}", other))),
+        };
+        let port = u16::from_le_bytes(read_array(&mut self.reader)?);
+
+        let len = u32::from_le_bytes(read_array(&mut self.reader)?) as usize;
+        if len > MAX_RECORD_SIZE {
+            return Err(invalid_data(format!("Record of {} bytes", len)));
+        }
+        let mut data = vec![0u8; len];
+        self.reader.read_exact(&mut data)?;
+
+        Ok(Some(CaptureRecord {
+            timestamp,
+            direction,
+            peer: SocketAddr::new(ip, port),
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Writes the session keys of each connection next to a capture, so
+/// dual-dissect can open the sealed packets in it. Anyone holding the file
+/// can read the traffic, so it's only ever written when asked for.
+///
+/// Each line is the peer address, then the key this side seals with and the
+/// key it opens with, both in hex. Lines are appended, never rewritten.
+pub struct KeyLogWriter {
+    writer: Box<dyn Write + Send>,
+}
+
+impl KeyLogWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        Ok(Self::new(options.open(path)?))
+    }
+
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            writer: Box::new(writer),
+        }
+    }
+
+    pub fn log(&mut self, peer: SocketAddr, send_key: &Key, receive_key: &Key) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "{} {} {}",
+            peer,
+            key_to_hex(send_key),
+            key_to_hex(receive_key)
+        )?;
+        // A crash shouldn't leave the capture without the keys to read it
+        self.writer.flush()
+    }
+}
+
+/// Session keys read back from a file written by [`KeyLogWriter`].
+#[derive(Debug, Default)]
+pub struct KeyLog {
+    entries: Vec<(SocketAddr, Key, Key)>,
+}
+
+impl KeyLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+
+    pub fn new(reader: impl BufRead) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let bad_line = |e: String| invalid_data(format!("Line {}: {}", number + 1, e));
+
+            let mut fields = line.split_whitespace();
+            let (Some(peer), Some(send_key), Some(receive_key), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(bad_line("expected a peer and two keys".to_string()));
+            };
+            entries.push((
+                peer.parse().map_err(|e| bad_line(format!("{}", e)))?,
+                key_from_hex(send_key).map_err(|e| bad_line(e.to_string()))?,
+                key_from_hex(receive_key).map_err(|e| bad_line(e.to_string()))?,
+            ));
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Opens a sealed packet recorded in `direction` on the logging side.
+    /// Keys logged for `peer` are tried first, newest first; a session that
+    /// moved to another address still opens with the keys it started with.
+    pub fn open_packet(
+        &self,
+        direction: CaptureDirection,
+        peer: SocketAddr,
+        packet: &Packet,
+    ) -> Option<Packet> {
+        let (for_peer, others): (Vec<_>, Vec<_>) = self
+            .entries
+            .iter()
+            .rev()
+            .partition(|(addr, _, _)| *addr == peer);
+
+        for_peer
+            .into_iter()
+            .chain(others)
+            .find_map(|(_, send_key, receive_key)| {
+                let cipher = match direction {
+                    CaptureDirection::Sent => PacketCipher::new(&receive_key, &send_key),
+                    CaptureDirection::Received => PacketCipher::new(&send_key, &receive_key),
+                };
+                cipher.open(packet).ok()
+            })
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // Lets the test read back what a writer it no longer owns produced
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_capture_round_trip() {
+        let buffer = SharedBuffer::default();
+        let mut writer = CaptureWriter::new(buffer.clone()).unwrap();
+
+        let v4: SocketAddr = "127.0.0.1:27015".parse().unwrap();
+        let v6: SocketAddr = "[::1]:40000".parse().unwrap();
+        writer.record(CaptureDirection::Sent, v4, b"hello").unwrap();
+        writer.record(CaptureDirection::Received, v6, &[]).unwrap();
+        assert_eq!(writer.records(), 2);
+
+        let data = buffer.0.lock().unwrap().clone();
+        let mut reader = CaptureReader::new(data.as_slice()).unwrap();
+        assert!(reader.started_at() > 0);
+
+        let first = reader.next_record().unwrap().unwrap();
+        assert_eq!(first.direction, CaptureDirection::Sent);
+        assert_eq!(first.peer, v4);
+        assert_eq!(first.data, b"hello");
+
+        let second = reader.next_record().unwrap().unwrap();
+        assert_eq!(second.direction, CaptureDirection::Received);
+        assert_eq!(second.peer, v6);
+        assert!(second.data.is_empty());
+        assert!(second.timestamp >= first.timestamp);
+
+        assert!(reader.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_capture_rejects_bad_and_truncated_files() {
+        assert_eq!(
+            CaptureReader::new(&b"NOTACAPTURE!!!!!!!"[..])
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let buffer = SharedBuffer::default();
+        let mut writer = CaptureWriter::new(buffer.clone()).unwrap();
+        writer
+            .record(
+                CaptureDirection::Sent,
+                "127.0.0.1:1".parse().unwrap(),
+                b"payload",
+            )
+            .unwrap();
+
+        let mut data = buffer.0.lock().unwrap().clone();
+        data.truncate(data.len() - 3);
+        let mut reader = CaptureReader::new(data.as_slice()).unwrap();
+        assert_eq!(
+            reader.next_record().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_key_log_opens_both_directions() {
+        use super::super::protocol::{PacketHeader, PacketType};
+
+        let peer: SocketAddr = "127.0.0.1:27015".parse().unwrap();
+        let (send_key, receive_key) = ([3; 32], [4; 32]);
+
+        let buffer = SharedBuffer::default();
+        let mut writer = KeyLogWriter::new(buffer.clone());
+        writer.log(peer, &[1; 32], &[2; 32]).unwrap();
+        writer.log(peer, &send_key, &receive_key).unwrap();
+
+        let data = buffer.0.lock().unwrap().clone();
+        let log = KeyLog::new(data.as_slice()).unwrap();
+        assert_eq!(log.len(), 2);
+
+        let ping = Packet::new(
+            PacketHeader::new(5, 0, 0),
+            PacketType::Ping { timestamp: 42 },
+        );
+        let ours = PacketCipher::new(&send_key, &receive_key);
+        let theirs = PacketCipher::new(&receive_key, &send_key);
+
+        let sent = log
+            .open_packet(CaptureDirection::Sent, peer, &ours.seal(&ping).unwrap())
+            .unwrap();
+        assert!(matches!(
+            sent.messages[0].payload,
+            PacketType::Ping { timestamp: 42 }
+        ));
+
+        // The session moved, so only the fallback to every key finds it
+        let moved: SocketAddr = "127.0.0.2:27015".parse().unwrap();
+        let received = log
+            .open_packet(
+                CaptureDirection::Received,
+                moved,
+                &theirs.seal(&ping).unwrap(),
+            )
+            .unwrap();
+        assert!(matches!(
+            received.messages[0].payload,
+            PacketType::Ping { timestamp: 42 }
+        ));
+
+        let stranger = PacketCipher::new(&[9; 32], &[9; 32]);
+        assert!(
+            log.open_packet(CaptureDirection::Sent, peer, &stranger.seal(&ping).unwrap())
+                .is_none()
+        );
+
+        assert!(KeyLog::new(&b"127.0.0.1:1 abcd\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::protocol::{Message, PacketType};
+
+/// Messages a reliable channel may have unacked at once. Power of two so
+/// ring slots stay put across the `u16` wrap.
+pub const CHANNEL_WINDOW: usize = 256;
+/// Messages a channel holds back once its window is full.
+pub const MAX_SEND_QUEUE: usize = 1024;
+// Receivers remember further back than any sender can have in flight
+const RECEIVE_HISTORY: usize = CHANNEL_WINDOW * 4;
+
+/// `true` if `s1` is newer than `s2`, treating the space as a circle split in half.
+pub fn sequence_greater_than_u16(s1: u16, s2: u16) -> bool {
+    s1 != s2 && s1.wrapping_sub(s2) < 0x8000
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ChannelError {
+    #[error("send queue is full ({0} messages waiting)")]
+    Backpressure(usize),
+}
+
+#[derive(Debug)]
+struct InFlight {
+    payload: PacketType,
+    last_send: Instant,
+}
+
+/// Sending half of a reliable channel. Sequences are handed out only inside
+/// the window that starts at the oldest unacked message; anything beyond it
+/// waits in a bounded queue, and a full queue is reported to the caller.
+#[derive(Debug)]
+pub struct SendChannel {
+    channel: u8,
+    slots: Vec<Option<InFlight>>,
+    oldest_unacked: u16,
+    next_seq: u16,
+    queued: VecDeque<PacketType>,
+    max_queued: usize,
+}
+
+impl SendChannel {
+    pub fn new(channel: u8) -> Self {
+        Self::with_limits(channel, CHANNEL_WINDOW, MAX_SEND_QUEUE)
+    }
+
+    pub fn with_limits(channel: u8, window: usize, max_queued: usize) -> Self {
+        let window = window.clamp(1, CHANNEL_WINDOW).next_power_of_two();
+        Self {
+            channel,
+            slots: (0..window).map(|_| None).collect(),
+            oldest_unacked: 0,
+            next_seq: 0,
+            queued: VecDeque::new(),
+            max_queued,
+        }
+    }
+
+    fn slot(&self, seq: u16) -> usize {
+        seq as usize % self.slots.len()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.next_seq.wrapping_sub(self.oldest_unacked) as usize
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn is_window_full(&self) -> bool {
+        self.in_flight() >= self.slots.len()
+    }
+
+    pub fn push(&mut self, payload: PacketType) -> Result<(), ChannelError> {
+        if self.queued.len() >= self.max_queued {
+            return Err(ChannelError::Backpressure(self.queued.len()));
+        }
+        self.queued.push_back(payload);
+        Ok(())
+    }
+
+    /// Sequence for a message that is sent once and never resent. Its slot
+    /// counts as acked straight away so it can't hold the window back.
+    pub fn reserve(&mut self) -> Option<u16> {
+        if self.is_window_full() {
+            return None;
+        }
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.advance();
+        Some(seq)
+    }
+
+    pub fn has_sendable(&self) -> bool {
+        !self.queued.is_empty() && !self.is_window_full()
+    }
+
+    /// Moves queued messages into the window as far as it allows and returns
+    /// them for their first send.
+    pub fn take_new(&mut self, now: Instant) -> Vec<Message> {
+        let mut messages = Vec::new();
+        while !self.is_window_full() {
+            let Some(payload) = self.queued.pop_front() else {
+                break;
+            };
+            let seq = self.next_seq;
+            let slot = self.slot(seq);
+            messages.push(Message::new(self.channel, seq, payload.clone()));
+            self.slots[slot] = Some(InFlight {
+                payload,
+                last_send: now,
+            });
+            self.next_seq = self.next_seq.wrapping_add(1);
+        }
+        messages
+    }
+
+    /// Messages unacked for longer than `timeout`, oldest first.
+    pub fn take_resends(&mut self, now: Instant, timeout: Duration) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut seq = self.oldest_unacked;
+        while seq != self.next_seq {
+            let slot = self.slot(seq);
+            if let Some(pending) = &mut self.slots[slot]
+                && now.duration_since(pending.last_send) > timeout
+            {
+                pending.last_send = now;
+                messages.push(Message::new(self.channel, seq, pending.payload.clone()));
+            }
+            seq = seq.wrapping_add(1);
+        }
+        messages
+    }
+
+    pub fn is_pending(&self, seq: u16) -> bool {
+        self.contains(seq) && self.slots[self.slot(seq)].is_some()
+    }
+
+    fn contains(&self, seq: u16) -> bool {
+        (seq.wrapping_sub(self.oldest_unacked) as usize) < self.in_flight()
+    }
+
+    pub fn ack(&mut self, seq: u16) {
+        if !self.contains(seq) {
+            return;
+        }
+        let slot = self.slot(seq);
+        self.slots[slot] = None;
+        self.advance();
+    }
+
+    fn advance(&mut self) {
+        while self.oldest_unacked != self.next_seq
+            && self.slots[self.slot(self.oldest_unacked)].is_none()
+        {
+            self.oldest_unacked = self.oldest_unacked.wrapping_add(1);
+        }
+    }
+}
+
+/// Duplicate filter for the unordered reliable channel: a bitset of the
+/// last `RECEIVE_HISTORY` sequences behind the newest one seen.
+#[derive(Debug)]
+pub struct ReliableReceiveWindow {
+    newest: Option<u16>,
+    received: Vec<u64>,
+}
+
+impl Default for ReliableReceiveWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReliableReceiveWindow {
+    pub fn new() -> Self {
+        Self {
+            newest: None,
+            received: vec![0; RECEIVE_HISTORY / 64],
+        }
+    }
+
+    fn bit(seq: u16) -> (usize, u64) {
+        let index = seq as usize % RECEIVE_HISTORY;
+        (index / 64, 1 << (index % 64))
+    }
+
+    fn set(&mut self, seq: u16, value: bool) {
+        let (word, mask) = Self::bit(seq);
+        if value {
+            self.received[word] |= mask;
+        } else {
+            self.received[word] &= !mask;
+        }
+    }
+
+    fn is_set(&self, seq: u16) -> bool {
+        let (word, mask) = Self::bit(seq);
+        self.received[word] & mask != 0
+    }
+
+    /// Records `seq`, returning whether it's new. Anything further behind
+    /// than the history is taken as a duplicate, since a sender's window
+    /// can't reach that far back.
+    pub fn receive(&mut self, seq: u16) -> bool {
+        let Some(newest) = self.newest else {
+            self.newest = Some(seq);
+            self.set(seq, true);
+            return true;
+        };
+
+        if sequence_greater_than_u16(seq, newest) {
+            let advance = seq.wrapping_sub(newest) as usize;
+            if advance >= RECEIVE_HISTORY {
+                self.received.fill(0);
+            } else {
+                for step in 1..=advance {
+                    self.set(newest.wrapping_add(step as u16), false);
+                }
+            }
+            self.newest = Some(seq);
+            self.set(seq, true);
+            return true;
+        }
+
+        if newest.wrapping_sub(seq) as usize >= RECEIVE_HISTORY || self.is_set(seq) {
+            return false;
+        }
+        self.set(seq, true);
+        true
+    }
+}
+
+/// Reorder buffer for the ordered channel, one slot per sequence the sender
+/// can have in flight ahead of the next expected one.
+#[derive(Debug)]
+pub struct OrderedReceiveWindow {
+    next_expected: u16,
+    buffer: Vec<Option<PacketType>>,
+}
+
+impl Default for OrderedReceiveWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OrderedReceiveWindow {
+    pub fn new() -> Self {
+        Self {
+            next_expected: 0,
+            buffer: (0..CHANNEL_WINDOW).map(|_| None).collect(),
+        }
+    }
+
+    pub fn receive(&mut self, seq: u16, payload: PacketType, delivered: &mut Vec<PacketType>) {
+        let ahead = seq.wrapping_sub(self.next_expected) as usize;
+        // Behind is a duplicate, past the window is something no sender would send
+        if ahead >= self.buffer.len() {
+            return;
+        }
+
+        let slot = seq as usize % self.buffer.len();
+        if ahead > 0 {
+            self.buffer[slot].get_or_insert(payload);
+            return;
+        }
+
+        delivered.push(payload);
+        self.next_expected = self.next_expected.wrapping_add(1);
+        loop {
+            let slot = self.next_expected as usize % self.buffer.len();
+            let Some(buffered) = self.buffer[slot].take() else {
+                break;
+            };
+            delivered.push(buffered);
+            self.next_expected = self.next_expected.wrapping_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic loss so failures reproduce
+    struct Lossy(u64);
+
+    impl Lossy {
+        fn drop(&mut self, percent: u64) -> bool {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % 100 < percent
+        }
+    }
+
+    fn ping(timestamp: u64) -> PacketType {
+        PacketType::Ping { timestamp }
+    }
+
+    fn timestamp(payload: &PacketType) -> u64 {
+        match payload {
+            PacketType::Ping { timestamp } => *timestamp,
+            _ => panic!("unexpected payload"),
+        }
+    }
+
+    #[test]
+    fn test_sequence_comparison_wraps() {
+        assert!(sequence_greater_than_u16(1, 0));
+        assert!(sequence_greater_than_u16(0, u16::MAX));
+        assert!(sequence_greater_than_u16(100, 65_000));
+        assert!(!sequence_greater_than_u16(65_000, 100));
+        assert!(!sequence_greater_than_u16(7, 7));
+    }
+
+    #[test]
+    fn test_window_limits_in_flight_and_queue_reports_backpressure() {
+        let mut channel = SendChannel::with_limits(Message::CHANNEL_RELIABLE, 4, 4);
+        for i in 0..4 {
+            channel.push(ping(i)).unwrap();
+        }
+        assert_eq!(channel.take_new(Instant::now()).len(), 4);
+        for i in 4..8 {
+            channel.push(ping(i)).unwrap();
+        }
+        assert_eq!(channel.push(ping(8)), Err(ChannelError::Backpressure(4)));
+        assert!(channel.take_new(Instant::now()).is_empty());
+
+        // Nothing new fits until the oldest is acked
+        assert_eq!(channel.in_flight(), 4);
+        channel.ack(1);
+        assert_eq!(channel.in_flight(), 4);
+        channel.ack(0);
+        assert_eq!(channel.in_flight(), 2);
+
+        let now = Instant::now() + Duration::from_secs(1);
+        let sent: Vec<u16> = channel
+            .take_resends(now, Duration::from_millis(100))
+            .into_iter()
+            .chain(channel.take_new(now))
+            .map(|message| message.channel_seq)
+            .collect();
+        assert_eq!(sent, vec![2, 3, 4, 5]);
+        assert_eq!(channel.queued(), 2);
+    }
+
+    #[test]
+    fn test_old_duplicates_are_rejected_past_history() {
+        let mut window = ReliableReceiveWindow::new();
+        assert!(window.receive(0));
+        assert!(!window.receive(0));
+
+        for seq in 1..=300u16 {
+            assert!(window.receive(seq));
+        }
+        // The old deque forgot these after 256 entries
+        assert!(!window.receive(0));
+        assert!(!window.receive(40));
+        assert!(window.receive(301));
+    }
+
+    fn run_channel(ordered: bool, count: u64, loss: u64) {
+        let channel_id = if ordered {
+            Message::CHANNEL_ORDERED
+        } else {
+            Message::CHANNEL_RELIABLE
+        };
+        let mut sender = SendChannel::new(channel_id);
+        let mut reliable = ReliableReceiveWindow::new();
+        let mut ordered_window = OrderedReceiveWindow::new();
+        let mut lossy = Lossy(count + loss);
+
+        let mut next = 0;
+        let mut delivered = Vec::new();
+        let mut seen = vec![false; count as usize];
+        let mut now = Instant::now();
+
+        while delivered.len() < count as usize {
+            while next < count && sender.push(ping(next)).is_ok() {
+                next += 1;
+            }
+
+            now += Duration::from_millis(10);
+            let mut messages = sender.take_resends(now, Duration::from_millis(25));
+            messages.extend(sender.take_new(now));
+            for message in messages {
+                if lossy.drop(loss) {
+                    continue;
+                }
+
+                if ordered {
+                    ordered_window.receive(message.channel_seq, message.payload, &mut delivered);
+                } else if reliable.receive(message.channel_seq) {
+                    delivered.push(message.payload);
+                }
+
+                // The ack travels back over the same lossy link
+                if !lossy.drop(loss) {
+                    sender.ack(message.channel_seq);
+                }
+            }
+        }
+
+        for (i, payload) in delivered.iter().enumerate() {
+            let value = timestamp(payload);
+            assert!(!seen[value as usize], "delivered {} twice", value);
+            seen[value as usize] = true;
+            if ordered {
+                assert_eq!(value, i as u64);
+            }
+        }
+        assert!(seen.iter().all(|&seen| seen));
+    }
+
+    #[test]
+    fn test_reliable_channel_wraps_100k_messages_under_loss() {
+        run_channel(false, 100_000, 20);
+    }
+
+    #[test]
+    fn test_ordered_channel_wraps_100k_messages_under_loss() {
+        run_channel(true, 100_000, 20);
+    }
+}
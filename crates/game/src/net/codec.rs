@@ -0,0 +1,683 @@
+use std::f32::consts::{FRAC_PI_2, PI, TAU};
+
+use rkyv::util::AlignedVec;
+use rkyv::{Archive, Deserialize, Serialize, rancor};
+
+use super::protocol::{
+    EntityDelta, EntityState, PacketError, SMALLEST_THREE_MAX_BITS, WorldSnapshot,
+    compress_quat_smallest_three, decompress_quat_smallest_three, quat_to_view_angles,
+    view_angles_to_quat,
+};
+
+const MAX_VARINT_BYTES: u32 = 10;
+const DELTA_MASK_BITS: u32 = 5;
+
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    buffer: Vec<u8>,
+    scratch: u64,
+    scratch_bits: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_bits(&mut self, value: u32, bits: u32) {
+        debug_assert!(bits <= 32);
+        let mask = if bits == 32 {
+            u32::MAX
+        } else {
+            (1 << bits) - 1
+        };
+        self.scratch |= ((value & mask) as u64) << self.scratch_bits;
+        self.scratch_bits += bits;
+
+        while self.scratch_bits >= 8 {
+            self.buffer.push(self.scratch as u8);
+            self.scratch >>= 8;
+            self.scratch_bits -= 8;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u32, 1);
+    }
+
+    pub fn write_varint(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7F) as u32;
+            value >>= 7;
+            if value == 0 {
+                self.write_bits(byte, 8);
+                return;
+            }
+            self.write_bits(byte | 0x80, 8);
+        }
+    }
+
+    pub fn bits_written(&self) -> usize {
+        self.buffer.len() * 8 + self.scratch_bits as usize
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.scratch_bits > 0 {
+            self.buffer.push(self.scratch as u8);
+        }
+        self.buffer
+    }
+}
+
+#[derive(Debug)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn read_bits(&mut self, bits: u32) -> Result<u32, PacketError> {
+        debug_assert!(bits <= 32);
+        if self.position + bits as usize > self.data.len() * 8 {
+            return Err(PacketError::Bitstream("unexpected end of data"));
+        }
+
+        let mut value = 0u64;
+        let mut read = 0;
+        while read < bits {
+            let offset = (self.position % 8) as u32;
+            let take = (8 - offset).min(bits - read);
+            let chunk = (self.data[self.position / 8] >> offset) as u64 & ((1 << take) - 1);
+            value |= chunk << read;
+            read += take;
+            self.position += take as usize;
+        }
+
+        Ok(value as u32)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, PacketError> {
+        Ok(self.read_bits(1)? != 0)
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64, PacketError> {
+        let mut value = 0u64;
+        for i in 0..MAX_VARINT_BYTES {
+            let byte = self.read_bits(8)? as u64;
+            value |= (byte & 0x7F) << (i * 7);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(PacketError::Bitstream("varint too long"))
+    }
+
+    pub fn bits_remaining(&self) -> usize {
+        self.data.len() * 8 - self.position
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub enum OrientationEncoding {
+    Raw,
+    SmallestThree { component_bits: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct QuantizationConfig {
+    pub world_min: [f32; 3],
+    pub world_max: [f32; 3],
+    pub position_precision: f32,
+    pub velocity_bits: u8,
+    pub orientation: OrientationEncoding,
+    /// Bits per angle when an orientation has no roll and can be sent as
+    /// yaw/pitch instead. Zero always sends the full orientation.
+    pub view_angle_bits: u8,
+}
+
+impl Default for QuantizationConfig {
+    fn default() -> Self {
+        Self {
+            world_min: [-1024.0, -256.0, -1024.0],
+            world_max: [1024.0, 768.0, 1024.0],
+            position_precision: 1.0 / 512.0,
+            velocity_bits: 12,
+            orientation: OrientationEncoding::SmallestThree { component_bits: 10 },
+            view_angle_bits: 12,
+        }
+    }
+}
+
+impl QuantizationConfig {
+    fn position_steps(&self, axis: usize) -> u32 {
+        let range = (self.world_max[axis] - self.world_min[axis]).max(0.0);
+        (range / self.position_precision)
+            .ceil()
+            .min(u32::MAX as f32) as u32
+    }
+
+    pub fn position_bits(&self, axis: usize) -> u32 {
+        (u32::BITS - self.position_steps(axis).leading_zeros()).max(1)
+    }
+
+    fn velocity_bits(&self) -> u32 {
+        (self.velocity_bits as u32).clamp(2, 16)
+    }
+
+    fn quantize_position(&self, value: f32, axis: usize) -> u32 {
+        let clamped = value.clamp(self.world_min[axis], self.world_max[axis]);
+        let steps = ((clamped - self.world_min[axis]) / self.position_precision).round();
+        (steps as u32).min(self.position_steps(axis))
+    }
+
+    fn dequantize_position(&self, value: u32, axis: usize) -> f32 {
+        (self.world_min[axis] + value as f32 * self.position_precision).min(self.world_max[axis])
+    }
+
+    // Symmetric around zero so a resting entity decodes to exactly zero
+    fn quantize_velocity(&self, value: i16) -> u32 {
+        let max = ((1 << (self.velocity_bits() - 1)) - 1) as f32;
+        let scaled = (value as f32 / i16::MAX as f32 * max)
+            .round()
+            .clamp(-max, max);
+        (scaled + max) as u32
+    }
+
+    fn dequantize_velocity(&self, value: u32) -> i16 {
+        let max = ((1 << (self.velocity_bits() - 1)) - 1) as f32;
+        let scaled = (value as f32 - max).clamp(-max, max);
+        (scaled / max * i16::MAX as f32).round() as i16
+    }
+
+    fn view_angle_bits(&self) -> u32 {
+        (self.view_angle_bits as u32).min(16)
+    }
+
+    fn quantize_yaw(&self, yaw: f32) -> u32 {
+        let steps = (1u32 << self.view_angle_bits()) as f32;
+        let turns = (yaw + PI).rem_euclid(TAU) / TAU;
+        ((turns * steps).round() as u32) & ((1 << self.view_angle_bits()) - 1)
+    }
+
+    fn dequantize_yaw(&self, value: u32) -> f32 {
+        let steps = (1u32 << self.view_angle_bits()) as f32;
+        value as f32 / steps * TAU - PI
+    }
+
+    fn quantize_pitch(&self, pitch: f32) -> u32 {
+        let max = ((1u32 << self.view_angle_bits()) - 1) as f32;
+        let normalized = (pitch.clamp(-FRAC_PI_2, FRAC_PI_2) + FRAC_PI_2) / PI;
+        (normalized * max).round() as u32
+    }
+
+    fn dequantize_pitch(&self, value: u32) -> f32 {
+        let max = ((1u32 << self.view_angle_bits()) - 1) as f32;
+        value as f32 / max * PI - FRAC_PI_2
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub enum SnapshotCodec {
+    #[default]
+    Rkyv,
+    Bitpacked(QuantizationConfig),
+}
+
+impl SnapshotCodec {
+    pub fn encode(&self, snapshot: &WorldSnapshot) -> Result<Vec<u8>, PacketError> {
+        match self {
+            SnapshotCodec::Rkyv => rkyv::to_bytes::<rancor::Error>(snapshot)
+                .map(|aligned| aligned.into_vec())
+                .map_err(PacketError::Serialize),
+            SnapshotCodec::Bitpacked(config) => encode_bitpacked(snapshot, config),
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<WorldSnapshot, PacketError> {
+        match self {
+            SnapshotCodec::Rkyv => {
+                let mut aligned = AlignedVec::<16>::with_capacity(data.len());
+                aligned.extend_from_slice(data);
+                rkyv::from_bytes::<WorldSnapshot, rancor::Error>(&aligned)
+                    .map_err(PacketError::Deserialize)
+            }
+            SnapshotCodec::Bitpacked(config) => decode_bitpacked(data, config),
+        }
+    }
+}
+
+fn encode_bitpacked(
+    snapshot: &WorldSnapshot,
+    config: &QuantizationConfig,
+) -> Result<Vec<u8>, PacketError> {
+    let mut writer = BitWriter::new();
+
+    writer.write_bits(snapshot.tick, 32);
+    writer.write_varint(snapshot.server_time_ms);
+    writer.write_varint(snapshot.last_command_ack as u64);
+    writer.write_bool(snapshot.is_delta);
+    if snapshot.is_delta {
+        writer.write_varint(snapshot.tick.wrapping_sub(snapshot.baseline_tick) as u64);
+    }
+
+    writer.write_varint(snapshot.entities.len() as u64);
+    for entity in &snapshot.entities {
+        write_entity(&mut writer, entity, config);
+    }
+
+    writer.write_varint(snapshot.entity_deltas.len() as u64);
+    for delta in &snapshot.entity_deltas {
+        // Unpack onto a blank state so the changed fields can be re-quantized
+        let fields = delta.apply(&EntityState::new(delta.entity_id, 0))?;
+        writer.write_varint(delta.entity_id as u64);
+        writer.write_bits(delta.changed_fields as u32, DELTA_MASK_BITS);
+        write_fields(&mut writer, &fields, delta.changed_fields, config);
+    }
+
+    writer.write_varint(snapshot.removed_entity_ids.len() as u64);
+    for &id in &snapshot.removed_entity_ids {
+        writer.write_varint(id as u64);
+    }
+
+    Ok(writer.finish())
+}
+
+fn write_entity(writer: &mut BitWriter, entity: &EntityState, config: &QuantizationConfig) {
+    writer.write_varint(entity.entity_id as u64);
+    writer.write_bits(entity.entity_type as u32, 8);
+    write_fields(writer, entity, EntityDelta::ALL_FIELDS, config);
+}
+
+fn write_fields(
+    writer: &mut BitWriter,
+    entity: &EntityState,
+    fields: u8,
+    config: &QuantizationConfig,
+) {
+    if fields & EntityDelta::FIELD_POSITION != 0 {
+        for axis in 0..3 {
+            let quantized = config.quantize_position(entity.position[axis], axis);
+            writer.write_bits(quantized, config.position_bits(axis));
+        }
+    }
+
+    if fields & EntityDelta::FIELD_VELOCITY != 0 {
+        let at_rest = entity.velocity == [0; 3];
+        writer.write_bool(at_rest);
+        if !at_rest {
+            for &component in &entity.velocity {
+                writer.write_bits(config.quantize_velocity(component), config.velocity_bits());
+            }
+        }
+    }
+
+    if fields & EntityDelta::FIELD_ORIENTATION != 0 {
+        write_orientation(writer, entity, config);
+    }
+
+    if fields & EntityDelta::FIELD_ANIMATION != 0 {
+        writer.write_bits(entity.animation_state as u32, 8);
+        writer.write_bits(entity.animation_frame as u32, 8);
+    }
+
+    if fields & EntityDelta::FIELD_FLAGS != 0 {
+        writer.write_bits(entity.flags as u32, 16);
+    }
+}
+
+fn decode_bitpacked(
+    data: &[u8],
+    config: &QuantizationConfig,
+) -> Result<WorldSnapshot, PacketError> {
+    let mut reader = BitReader::new(data);
+
+    let tick = reader.read_bits(32)?;
+    let server_time_ms = reader.read_varint()?;
+    let mut snapshot = WorldSnapshot::new(tick, server_time_ms);
+    snapshot.last_command_ack = read_varint_u32(&mut reader)?;
+    snapshot.is_delta = reader.read_bool()?;
+    if snapshot.is_delta {
+        snapshot.baseline_tick = tick.wrapping_sub(read_varint_u32(&mut reader)?);
+    }
+
+    let entity_count = reader.read_varint()?;
+    for _ in 0..entity_count {
+        snapshot.entities.push(read_entity(&mut reader, config)?);
+    }
+
+    let delta_count = reader.read_varint()?;
+    for _ in 0..delta_count {
+        let entity_id = read_varint_u32(&mut reader)?;
+        let changed_fields = reader.read_bits(DELTA_MASK_BITS)? as u8;
+        let mut fields = EntityState::new(entity_id, 0);
+        read_fields(&mut reader, &mut fields, changed_fields, config)?;
+        snapshot
+            .entity_deltas
+            .push(EntityDelta::from_fields(&fields, changed_fields));
+    }
+
+    let removed_count = reader.read_varint()?;
+    for _ in 0..removed_count {
+        snapshot
+            .removed_entity_ids
+            .push(read_varint_u32(&mut reader)?);
+    }
+
+    Ok(snapshot)
+}
+
+fn read_entity(
+    reader: &mut BitReader,
+    config: &QuantizationConfig,
+) -> Result<EntityState, PacketError> {
+    let entity_id = read_varint_u32(reader)?;
+    let entity_type = reader.read_bits(8)? as u8;
+    let mut entity = EntityState::new(entity_id, entity_type);
+    read_fields(reader, &mut entity, EntityDelta::ALL_FIELDS, config)?;
+    Ok(entity)
+}
+
+fn read_fields(
+    reader: &mut BitReader,
+    entity: &mut EntityState,
+    fields: u8,
+    config: &QuantizationConfig,
+) -> Result<(), PacketError> {
+    if fields & EntityDelta::FIELD_POSITION != 0 {
+        for axis in 0..3 {
+            let quantized = reader.read_bits(config.position_bits(axis))?;
+            entity.position[axis] = config.dequantize_position(quantized, axis);
+        }
+    }
+
+    if fields & EntityDelta::FIELD_VELOCITY != 0 {
+        entity.velocity = [0; 3];
+        if !reader.read_bool()? {
+            for component in &mut entity.velocity {
+                *component = config.dequantize_velocity(reader.read_bits(config.velocity_bits())?);
+            }
+        }
+    }
+
+    if fields & EntityDelta::FIELD_ORIENTATION != 0 {
+        read_orientation(reader, entity, config)?;
+    }
+
+    if fields & EntityDelta::FIELD_ANIMATION != 0 {
+        entity.animation_state = reader.read_bits(8)? as u8;
+        entity.animation_frame = reader.read_bits(8)? as u8;
+    }
+
+    if fields & EntityDelta::FIELD_FLAGS != 0 {
+        entity.flags = reader.read_bits(16)? as u16;
+    }
+
+    Ok(())
+}
+
+fn write_orientation(writer: &mut BitWriter, entity: &EntityState, config: &QuantizationConfig) {
+    let quat = entity.decode_orientation();
+
+    if config.view_angle_bits() > 0 {
+        let angles = quat_to_view_angles(quat);
+        writer.write_bool(angles.is_some());
+        if let Some((yaw, pitch)) = angles {
+            writer.write_bits(config.quantize_yaw(yaw), config.view_angle_bits());
+            writer.write_bits(config.quantize_pitch(pitch), config.view_angle_bits());
+            return;
+        }
+    }
+
+    match config.orientation {
+        OrientationEncoding::Raw => {
+            for &component in &entity.orientation {
+                writer.write_bits(component as u16 as u32, 16);
+            }
+        }
+        OrientationEncoding::SmallestThree { component_bits } => {
+            let bits = smallest_three_bits(component_bits);
+            writer.write_bits(compress_quat_smallest_three(quat, bits), 2 + 3 * bits);
+        }
+    }
+}
+
+fn read_orientation(
+    reader: &mut BitReader,
+    entity: &mut EntityState,
+    config: &QuantizationConfig,
+) -> Result<(), PacketError> {
+    if config.view_angle_bits() > 0 && reader.read_bool()? {
+        let yaw = config.dequantize_yaw(reader.read_bits(config.view_angle_bits())?);
+        let pitch = config.dequantize_pitch(reader.read_bits(config.view_angle_bits())?);
+        entity.encode_orientation(view_angles_to_quat(yaw, pitch));
+        return Ok(());
+    }
+
+    match config.orientation {
+        OrientationEncoding::Raw => {
+            for component in &mut entity.orientation {
+                *component = reader.read_bits(16)? as u16 as i16;
+            }
+        }
+        OrientationEncoding::SmallestThree { component_bits } => {
+            let bits = smallest_three_bits(component_bits);
+            let packed = reader.read_bits(2 + 3 * bits)?;
+            entity.encode_orientation(decompress_quat_smallest_three(packed, bits));
+        }
+    }
+    Ok(())
+}
+
+fn smallest_three_bits(component_bits: u8) -> u32 {
+    (component_bits as u32).clamp(1, SMALLEST_THREE_MAX_BITS)
+}
+
+fn read_varint_u32(reader: &mut BitReader) -> Result<u32, PacketError> {
+    u32::try_from(reader.read_varint()?).map_err(|_| PacketError::Bitstream("varint overflow"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    use super::*;
+
+    fn test_snapshot(entity_count: u32) -> WorldSnapshot {
+        let mut snapshot = WorldSnapshot::new(4242, 70_700);
+        snapshot.last_command_ack = 812;
+        for id in 0..entity_count {
+            let mut entity = EntityState::new(id * 3, 1);
+            entity.position = [id as f32 * 1.37 - 20.0, 1.5, -(id as f32) * 0.73];
+            if id % 2 == 0 {
+                entity.encode_velocity([id as f32 * 0.5, -2.25, 7.0]);
+            }
+            entity.encode_orientation([0.0, FRAC_1_SQRT_2, 0.0, FRAC_1_SQRT_2]);
+            entity.animation_state = 2;
+            entity.flags = 0x0101;
+            snapshot.entities.push(entity);
+        }
+        snapshot.removed_entity_ids = vec![5, 300, 70_000];
+        snapshot
+    }
+
+    fn orientation_error(a: &EntityState, b: &EntityState) -> f32 {
+        let a = glam::Quat::from_array(a.decode_orientation()).normalize();
+        let b = glam::Quat::from_array(b.decode_orientation()).normalize();
+        a.angle_between(b)
+    }
+
+    #[test]
+    fn bit_writer_roundtrip() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0b101, 3);
+        writer.write_bool(true);
+        writer.write_bits(u32::MAX, 32);
+        writer.write_varint(300);
+        writer.write_bits(0x1234, 13);
+        assert_eq!(writer.bits_written(), 3 + 1 + 32 + 16 + 13);
+
+        let data = writer.finish();
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b101);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_bits(32).unwrap(), u32::MAX);
+        assert_eq!(reader.read_varint().unwrap(), 300);
+        assert_eq!(reader.read_bits(13).unwrap(), 0x1234 & 0x1FFF);
+        assert!(reader.read_bits(8).is_err());
+    }
+
+    #[test]
+    fn bitpacked_roundtrip_within_precision() {
+        let config = QuantizationConfig::default();
+        let codec = SnapshotCodec::Bitpacked(config);
+        let snapshot = test_snapshot(16);
+
+        let decoded = codec.decode(&codec.encode(&snapshot).unwrap()).unwrap();
+        assert_eq!(decoded.tick, snapshot.tick);
+        assert_eq!(decoded.server_time_ms, snapshot.server_time_ms);
+        assert_eq!(decoded.last_command_ack, snapshot.last_command_ack);
+        assert_eq!(decoded.removed_entity_ids, snapshot.removed_entity_ids);
+        assert_eq!(decoded.entities.len(), snapshot.entities.len());
+
+        let velocity_step = 2.0 * EntityState::MAX_VELOCITY / (1 << config.velocity_bits) as f32;
+        for (a, b) in decoded.entities.iter().zip(&snapshot.entities) {
+            assert_eq!(a.entity_id, b.entity_id);
+            assert!(orientation_error(a, b) < 0.005);
+            assert_eq!(a.flags, b.flags);
+            for axis in 0..3 {
+                assert!((a.position[axis] - b.position[axis]).abs() <= config.position_precision);
+                let (va, vb) = (a.decode_velocity()[axis], b.decode_velocity()[axis]);
+                assert!((va - vb).abs() <= velocity_step);
+            }
+            if b.velocity == [0; 3] {
+                assert_eq!(a.velocity, [0; 3]);
+            }
+        }
+    }
+
+    #[test]
+    fn bitpacked_delta_and_clamping() {
+        let config = QuantizationConfig::default();
+        let codec = SnapshotCodec::Bitpacked(config);
+        let mut snapshot = WorldSnapshot::new_delta(10, 0, u32::MAX - 5);
+        let mut entity = EntityState::new(u32::MAX, 0);
+        entity.position = [5000.0, -5000.0, 0.0];
+        snapshot.entities.push(entity);
+
+        let decoded = codec.decode(&codec.encode(&snapshot).unwrap()).unwrap();
+        assert!(decoded.is_delta);
+        assert_eq!(decoded.baseline_tick, u32::MAX - 5);
+        assert_eq!(decoded.entities[0].entity_id, u32::MAX);
+        assert_eq!(decoded.entities[0].position[0], config.world_max[0]);
+        assert_eq!(decoded.entities[0].position[1], config.world_min[1]);
+    }
+
+    #[test]
+    fn bitpacked_is_smaller_than_rkyv() {
+        let bitpacked = SnapshotCodec::Bitpacked(QuantizationConfig::default());
+
+        for entity_count in [1, 16, 64] {
+            let snapshot = test_snapshot(entity_count);
+            let rkyv_bytes = SnapshotCodec::Rkyv.encode(&snapshot).unwrap();
+            let packed_bytes = bitpacked.encode(&snapshot).unwrap();
+
+            let decoded = SnapshotCodec::Rkyv.decode(&rkyv_bytes).unwrap();
+            assert_eq!(decoded.entities.len(), snapshot.entities.len());
+
+            assert!(
+                packed_bytes.len() * 4 < rkyv_bytes.len() * 3,
+                "{} entities: bitpacked {} bytes, rkyv {} bytes",
+                entity_count,
+                packed_bytes.len(),
+                rkyv_bytes.len()
+            );
+        }
+    }
+
+    #[test]
+    fn bitpacked_entity_deltas() {
+        let config = QuantizationConfig::default();
+        let codec = SnapshotCodec::Bitpacked(config);
+
+        let baseline = EntityState::new(9, 0);
+        let mut current = baseline;
+        current.position = [3.25, 1.0, -8.5];
+        current.flags = 4;
+
+        let mut snapshot = WorldSnapshot::new_delta(20, 0, 18);
+        snapshot
+            .entity_deltas
+            .push(EntityDelta::encode(&baseline, &current).unwrap());
+
+        let decoded = codec.decode(&codec.encode(&snapshot).unwrap()).unwrap();
+        assert_eq!(decoded.entity_deltas.len(), 1);
+
+        let delta = &decoded.entity_deltas[0];
+        assert_eq!(
+            delta.changed_fields,
+            EntityDelta::FIELD_POSITION | EntityDelta::FIELD_FLAGS
+        );
+        let merged = delta.apply(&baseline).unwrap();
+        assert_eq!(merged.flags, 4);
+        assert_eq!(merged.orientation, baseline.orientation);
+        for axis in 0..3 {
+            assert!(
+                (merged.position[axis] - current.position[axis]).abs() <= config.position_precision
+            );
+        }
+    }
+
+    #[test]
+    fn orientation_encodings() {
+        let mut rolled = EntityState::new(1, 5);
+        rolled.encode_orientation(
+            glam::Quat::from_euler(glam::EulerRot::XYZ, 0.3, 1.1, -0.7).to_array(),
+        );
+        let mut snapshot = WorldSnapshot::new(1, 0);
+        snapshot.entities.push(rolled);
+        for id in 2..10 {
+            let mut player = EntityState::new(id, 0);
+            player.encode_orientation(view_angles_to_quat(id as f32 * 0.7, -0.35));
+            snapshot.entities.push(player);
+        }
+
+        let raw = QuantizationConfig {
+            orientation: OrientationEncoding::Raw,
+            view_angle_bits: 0,
+            ..Default::default()
+        };
+        let smallest_three = QuantizationConfig {
+            view_angle_bits: 0,
+            ..Default::default()
+        };
+        let view_angles = QuantizationConfig::default();
+
+        let mut sizes = Vec::new();
+        for config in [raw, smallest_three, view_angles] {
+            let codec = SnapshotCodec::Bitpacked(config);
+            let data = codec.encode(&snapshot).unwrap();
+            let decoded = codec.decode(&data).unwrap();
+            for (a, b) in decoded.entities.iter().zip(&snapshot.entities) {
+                assert!(orientation_error(a, b) < 0.005);
+            }
+            sizes.push(data.len());
+        }
+
+        assert!(sizes[1] < sizes[0]);
+        assert!(sizes[2] < sizes[1]);
+    }
+
+    #[test]
+    fn truncated_data_is_rejected() {
+        let codec = SnapshotCodec::Bitpacked(QuantizationConfig::default());
+        let data = codec.encode(&test_snapshot(4)).unwrap();
+        assert!(codec.decode(&data[..data.len() / 2]).is_err());
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::stats::PacketLossSimulation;
+use super::transport::Transport;
+
+// Extra hold for a datagram picked to arrive out of order
+const REORDER_DELAY: Duration = Duration::from_millis(20);
+
+/// SplitMix64. Small and seedable, so a run can be replayed exactly.
+#[derive(Debug, Clone)]
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn chance(&mut self, percent: f32) -> bool {
+        percent > 0.0 && self.next_f32() * 100.0 < percent
+    }
+
+    pub fn below(&mut self, bound: u32) -> u32 {
+        if bound == 0 {
+            return 0;
+        }
+        (self.next_u64() % bound as u64) as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConditionerStats {
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub queue_dropped: u64,
+}
+
+#[derive(Debug, Default)]
+struct LinkState {
+    in_burst: bool,
+    // When the capped link finishes sending what's already queued
+    busy_until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct Link {
+    outgoing: LinkState,
+    incoming: LinkState,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Scheduled {
+    release: Instant,
+    order: u64,
+    data: Vec<u8>,
+    addr: SocketAddr,
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Earliest first, ties in the order they were scheduled
+        (other.release, other.order).cmp(&(self.release, self.order))
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Outgoing,
+    Incoming,
+}
+
+/// Transport middleware that makes a link misbehave on purpose: loss (flat
+/// or in Gilbert–Elliott bursts), latency and jitter, duplication,
+/// reordering, and a bandwidth cap that queues and eventually drops. Every
+/// decision comes from a seeded RNG, so a run replays exactly given the same
+/// traffic and clock.
+///
+/// Conditions apply to every peer unless one has its own. Delayed outgoing
+/// datagrams go out the next time the conditioner is used or polled.
+pub struct LinkConditioner<T: Transport> {
+    inner: T,
+    rng: SimRng,
+    outgoing: PacketLossSimulation,
+    incoming: PacketLossSimulation,
+    overrides: HashMap<SocketAddr, (PacketLossSimulation, PacketLossSimulation)>,
+    // Peers with their own conditions keep their own state; the rest share one
+    links: HashMap<SocketAddr, Link>,
+    shared: Link,
+    send_queue: BinaryHeap<Scheduled>,
+    receive_queue: BinaryHeap<Scheduled>,
+    ready: VecDeque<(Vec<u8>, SocketAddr)>,
+    next_order: u64,
+    clock: Option<Instant>,
+    stats: ConditionerStats,
+}
+
+impl<T: Transport> LinkConditioner<T> {
+    pub fn new(inner: T, seed: u64) -> Self {
+        Self {
+            inner,
+            rng: SimRng::new(seed),
+            outgoing: PacketLossSimulation::default(),
+            incoming: PacketLossSimulation::default(),
+            overrides: HashMap::new(),
+            links: HashMap::new(),
+            shared: Link::default(),
+            send_queue: BinaryHeap::new(),
+            receive_queue: BinaryHeap::new(),
+            ready: VecDeque::new(),
+            next_order: 0,
+            clock: None,
+            stats: ConditionerStats::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn stats(&self) -> ConditionerStats {
+        self.stats
+    }
+
+    pub fn set_conditions(
+        &mut self,
+        outgoing: PacketLossSimulation,
+        incoming: PacketLossSimulation,
+    ) {
+        self.outgoing = outgoing;
+        self.incoming = incoming;
+    }
+
+    pub fn conditions(&self) -> (&PacketLossSimulation, &PacketLossSimulation) {
+        (&self.outgoing, &self.incoming)
+    }
+
+    pub fn set_link_conditions(
+        &mut self,
+        addr: SocketAddr,
+        outgoing: PacketLossSimulation,
+        incoming: PacketLossSimulation,
+    ) {
+        self.overrides.insert(addr, (outgoing, incoming));
+        self.links.entry(addr).or_default();
+    }
+
+    /// Conditions `addr` sees, its own if it has any.
+    pub fn link_conditions(
+        &self,
+        addr: SocketAddr,
+    ) -> (&PacketLossSimulation, &PacketLossSimulation) {
+        match self.overrides.get(&addr) {
+            Some((outgoing, incoming)) => (outgoing, incoming),
+            None => self.conditions(),
+        }
+    }
+
+    pub fn clear_link_conditions(&mut self, addr: SocketAddr) {
+        self.overrides.remove(&addr);
+        self.links.remove(&addr);
+    }
+
+    /// Runs the conditioner on a clock the caller moves, for tests that
+    /// mustn't depend on how fast they run.
+    pub fn set_time(&mut self, now: Instant) {
+        self.clock = Some(now);
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.unwrap_or_else(Instant::now)
+    }
+
+    /// Sends whatever outgoing datagrams are due.
+    pub fn poll(&mut self) -> io::Result<()> {
+        let now = self.now();
+        while self
+            .send_queue
+            .peek()
+            .is_some_and(|next| next.release <= now)
+        {
+            let Scheduled { data, addr, .. } = self.send_queue.pop().unwrap();
+            self.inner.send_to(&data, addr)?;
+        }
+        Ok(())
+    }
+
+    /// Datagrams held back in either direction.
+    pub fn queued(&self) -> usize {
+        self.send_queue.len() + self.receive_queue.len()
+    }
+
+    fn schedule(&mut self, direction: Direction, data: &[u8], addr: SocketAddr) {
+        let now = self.now();
+        let (outgoing, incoming) = self.link_conditions(addr);
+        let conditions = match direction {
+            Direction::Outgoing => outgoing.clone(),
+            Direction::Incoming => incoming.clone(),
+        };
+        let link = self.links.get_mut(&addr).unwrap_or(&mut self.shared);
+        let state = match direction {
+            Direction::Outgoing => &mut link.outgoing,
+            Direction::Incoming => &mut link.incoming,
+        };
+
+        let loss_percent = match conditions.burst {
+            Some(burst) => {
+                let switch = if state.in_burst {
+                    burst.exit_percent
+                } else {
+                    burst.enter_percent
+                };
+                if self.rng.chance(switch) {
+                    state.in_burst = !state.in_burst;
+                }
+                if state.in_burst {
+                    burst.loss_percent
+                } else {
+                    conditions.loss_percent
+                }
+            }
+            None => conditions.loss_percent,
+        };
+        if self.rng.chance(loss_percent) {
+            self.stats.dropped += 1;
+            return;
+        }
+
+        // A capped link sends one datagram at a time; the wait behind the
+        // ones before it is the queueing delay
+        let mut departure = now;
+        if conditions.bandwidth_bytes_per_sec > 0 {
+            let start = state.busy_until.filter(|&busy| busy > now).unwrap_or(now);
+            let queued = start - now;
+            if conditions.queue_limit_ms > 0
+                && queued > Duration::from_millis(conditions.queue_limit_ms as u64)
+            {
+                self.stats.queue_dropped += 1;
+                return;
+            }
+            departure = start
+                + Duration::from_secs_f64(
+                    data.len() as f64 / conditions.bandwidth_bytes_per_sec as f64,
+                );
+            state.busy_until = Some(departure);
+        }
+
+        let copies = if self.rng.chance(conditions.duplicate_percent) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut release = departure + self.sample_latency(&conditions);
+            if self.rng.chance(conditions.reorder_percent) {
+                self.stats.reordered += 1;
+                release += REORDER_DELAY;
+            }
+
+            let scheduled = Scheduled {
+                release,
+                order: self.next_order,
+                data: data.to_vec(),
+                addr,
+            };
+            self.next_order += 1;
+            match direction {
+                Direction::Outgoing => self.send_queue.push(scheduled),
+                Direction::Incoming => self.receive_queue.push(scheduled),
+            }
+        }
+    }
+
+    fn sample_latency(&mut self, conditions: &PacketLossSimulation) -> Duration {
+        let range = conditions
+            .max_latency_ms
+            .saturating_sub(conditions.min_latency_ms);
+        let ms = conditions.min_latency_ms
+            + self.rng.below(range + 1)
+            + self.rng.below(conditions.jitter_ms + 1);
+        Duration::from_millis(ms as u64)
+    }
+
+    fn is_conditioned(&self, direction: Direction, addr: SocketAddr) -> bool {
+        let (outgoing, incoming) = self.link_conditions(addr);
+        match direction {
+            Direction::Outgoing => outgoing.enabled,
+            Direction::Incoming => incoming.enabled,
+        }
+    }
+}
+
+impl<T: Transport> Transport for LinkConditioner<T> {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.poll()?;
+        if self.is_conditioned(Direction::Outgoing, addr) {
+            self.schedule(Direction::Outgoing, data, addr);
+            self.poll()?;
+            Ok(data.len())
+        } else {
+            self.inner.send_to(data, addr)
+        }
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.poll()?;
+
+        let mut datagram = vec![0u8; buf.len()];
+        loop {
+            match self.inner.recv_from(&mut datagram) {
+                Ok((size, addr)) => {
+                    if self.is_conditioned(Direction::Incoming, addr) {
+                        self.schedule(Direction::Incoming, &datagram[..size], addr);
+                    } else {
+                        self.ready.push_back((datagram[..size].to_vec(), addr));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let now = self.now();
+        while self
+            .receive_queue
+            .peek()
+            .is_some_and(|next| next.release <= now)
+        {
+            let Scheduled { data, addr, .. } = self.receive_queue.pop().unwrap();
+            self.ready.push_back((data, addr));
+        }
+
+        let Some((data, addr)) = self.ready.pop_front() else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+        let size = data.len().min(buf.len());
+        buf[..size].copy_from_slice(&data[..size]);
+        Ok((size, addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::stats::BurstLoss;
+    use crate::net::transport::{LoopbackHub, LoopbackTransport};
+
+    fn pair(seed: u64) -> (LinkConditioner<LoopbackTransport>, LoopbackTransport) {
+        let hub = LoopbackHub::new();
+        let sender = hub.bind("127.0.0.1:1000").unwrap();
+        let receiver = hub.bind("127.0.0.1:2000").unwrap();
+        (LinkConditioner::new(sender, seed), receiver)
+    }
+
+    fn drain(receiver: &mut LoopbackTransport) -> Vec<u32> {
+        let mut buf = [0u8; 64];
+        let mut received = Vec::new();
+        while let Ok((size, _)) = receiver.recv_from(&mut buf) {
+            received.push(u32::from_le_bytes(buf[..size].try_into().unwrap()));
+        }
+        received
+    }
+
+    fn run(seed: u64, conditions: PacketLossSimulation) -> (Vec<u32>, ConditionerStats) {
+        let (mut conditioner, mut receiver) = pair(seed);
+        conditioner.set_conditions(conditions, PacketLossSimulation::default());
+        let to = receiver.local_addr().unwrap();
+
+        let start = Instant::now();
+        let mut received = Vec::new();
+        for i in 0..1000u32 {
+            conditioner.set_time(start + Duration::from_millis(i as u64));
+            conditioner.send_to(&i.to_le_bytes(), to).unwrap();
+            received.extend(drain(&mut receiver));
+        }
+        conditioner.set_time(start + Duration::from_secs(60));
+        conditioner.poll().unwrap();
+        received.extend(drain(&mut receiver));
+        (received, conditioner.stats())
+    }
+
+    #[test]
+    fn test_same_seed_replays_the_same_link() {
+        let conditions = PacketLossSimulation {
+            enabled: true,
+            loss_percent: 10.0,
+            min_latency_ms: 5,
+            max_latency_ms: 40,
+            jitter_ms: 10,
+            duplicate_percent: 5.0,
+            reorder_percent: 5.0,
+            ..Default::default()
+        };
+
+        let (first, stats) = run(7, conditions.clone());
+        assert_eq!(run(7, conditions.clone()), (first.clone(), stats));
+        assert_ne!(run(8, conditions).0, first);
+
+        assert!(stats.dropped > 50 && stats.duplicated > 10 && stats.reordered > 10);
+        assert_eq!(first.len() as u64, 1000 - stats.dropped + stats.duplicated);
+        assert!(first.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn test_burst_loss_clusters_drops() {
+        let conditions = PacketLossSimulation {
+            enabled: true,
+            burst: Some(BurstLoss {
+                enter_percent: 2.0,
+                exit_percent: 20.0,
+                loss_percent: 100.0,
+            }),
+            ..Default::default()
+        };
+        let (received, stats) = run(3, conditions);
+        assert_eq!(received.len() as u64, 1000 - stats.dropped);
+
+        // Long runs of consecutive losses rather than scattered ones
+        let longest_gap = received
+            .windows(2)
+            .map(|pair| pair[1] - pair[0] - 1)
+            .max()
+            .unwrap();
+        assert!(stats.dropped > 20);
+        assert!(longest_gap >= 5);
+    }
+
+    #[test]
+    fn test_bandwidth_cap_queues_then_drops() {
+        let (mut conditioner, mut receiver) = pair(1);
+        conditioner.set_conditions(
+            PacketLossSimulation {
+                enabled: true,
+                bandwidth_bytes_per_sec: 1000,
+                queue_limit_ms: 150,
+                ..Default::default()
+            },
+            PacketLossSimulation::default(),
+        );
+        let to = receiver.local_addr().unwrap();
+        let start = Instant::now();
+        conditioner.set_time(start);
+
+        // 100 bytes is 100 ms on this link, so the second waits and the rest overflow
+        for _ in 0..4 {
+            conditioner.send_to(&[0; 100], to).unwrap();
+        }
+        assert_eq!(conditioner.stats().queue_dropped, 2);
+
+        conditioner.set_time(start + Duration::from_millis(150));
+        conditioner.poll().unwrap();
+        assert_eq!(drain_count(&mut receiver), 1);
+        conditioner.set_time(start + Duration::from_millis(200));
+        conditioner.poll().unwrap();
+        assert_eq!(drain_count(&mut receiver), 1);
+    }
+
+    fn drain_count(receiver: &mut LoopbackTransport) -> usize {
+        let mut buf = [0u8; 128];
+        std::iter::from_fn(|| receiver.recv_from(&mut buf).ok()).count()
+    }
+}
@@ -2,12 +2,31 @@ use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
-use super::protocol::{Packet, PacketHeader, PacketType};
-use super::stats::{PacketLossSimulation, rand_u64};
+use rkyv::rancor;
+use rkyv::util::AlignedVec;
+
+use super::bandwidth::BandwidthEstimator;
+use super::block::{
+    BlockDirection, BlockError, BlockProgress, BlockTransfers, CHUNK_OVERHEAD, CompletedBlock,
+};
+use super::channel::{ChannelError, OrderedReceiveWindow, ReliableReceiveWindow, SendChannel};
+use super::crypto::{PacketCipher, TAG_BYTES};
+use super::mtu::MtuDiscovery;
+use super::protocol::{
+    BlockKind, DenyReason, Message, Packet, PacketHeader, PacketType, sequence_greater_than,
+};
+use super::stats::ConnectionStats;
+use super::token::Key;
 use super::tracking::{AckTracker, ReceiveTracker};
 
 const DEFAULT_TIMEOUT_SECS: u64 = 120;
-const RELIABLE_HISTORY_SIZE: usize = 256;
+// Wire sequences the ack tracker remembers; older packets can't be acked
+const ACK_WINDOW: usize = 1024;
+// Archived header, message vector and alignment, rounded up
+const PACKET_OVERHEAD: usize = 64;
+const MESSAGE_PADDING: usize = 8;
+// Outer header, message and tag a sealed packet adds around the inner messages
+const SEAL_OVERHEAD: usize = 96;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConnectionState {
@@ -30,73 +49,168 @@ pub struct ClientConnection {
     pub addr: SocketAddr,
     pub client_id: u32,
     pub state: ConnectionState,
-    pub client_salt: u64,
-    pub server_salt: u64,
+    // Client id from the connect token the connection was opened with
+    pub token_client_id: u64,
+    cipher: Option<PacketCipher>,
+    pub auth_failures: u64,
 
     // Game state tracking
     pub last_command_ack: u32,
     pub last_acked_tick: u32,
+    pub last_snapshot_tick: u32,
     pub entity_id: Option<u32>,
     pub lobby_id: Option<u64>,
 
-    // Network stats/simulation
+    // Network stats
+    pub connected_at: Instant,
     pub last_receive_time: Instant,
-    pub packet_loss_sim: PacketLossSimulation,
-    pub incoming_packet_loss_sim: PacketLossSimulation,
+    pub bandwidth: BandwidthEstimator,
+    stats: ConnectionStats,
+    mtu: MtuDiscovery,
+    blocks: BlockTransfers,
 
     // Reliability - Send
     pub send_sequence: u32,
     pub ack_tracker: AckTracker,
-    next_reliable_seq: u16,
-    next_ordered_seq: u16,
+    reliable: SendChannel,
+    ordered: SendChannel,
 
-    // WireSeq -> (Channel, ChannelSeq)
-    inflight_packets: HashMap<u32, (u8, u16)>,
+    // Messages waiting for the next flush
+    outgoing: VecDeque<Message>,
 
-    // ChannelSeq -> (Payload, LastSendTime)
-    pending_reliable: HashMap<u16, (PacketType, Instant)>,
-    pending_ordered: HashMap<u16, (PacketType, Instant)>,
+    // WireSeq -> [(Channel, ChannelSeq)] of the reliable messages it carried
+    inflight_packets: HashMap<u32, Vec<(u8, u16)>>,
+    // WireSeq of the last packet carrying a snapshot, charged as one once sent
+    snapshot_sequence: Option<u32>,
 
     // Reliability - Receive
     pub receive_tracker: ReceiveTracker,
-    received_reliable_history: VecDeque<u16>,
-    next_expected_ordered: u16,
-    ordered_buffer: HashMap<u16, PacketType>,
+    received_reliable: ReliableReceiveWindow,
+    received_ordered: OrderedReceiveWindow,
 }
 
 impl ClientConnection {
-    pub fn new(addr: SocketAddr, client_id: u32, client_salt: u64) -> Self {
+    pub fn new(addr: SocketAddr, client_id: u32) -> Self {
         Self {
             addr,
             client_id,
             state: ConnectionState::Connecting,
-            client_salt,
-            server_salt: rand_u64(),
+            token_client_id: 0,
+            cipher: None,
+            auth_failures: 0,
             last_command_ack: 0,
             last_acked_tick: 0,
+            last_snapshot_tick: 0,
+            connected_at: Instant::now(),
             last_receive_time: Instant::now(),
             entity_id: None,
             lobby_id: None,
-            packet_loss_sim: PacketLossSimulation::default(),
-            incoming_packet_loss_sim: PacketLossSimulation::default(),
+            bandwidth: BandwidthEstimator::default(),
+            stats: ConnectionStats::default(),
+            mtu: MtuDiscovery::new(),
+            blocks: BlockTransfers::new(),
 
             send_sequence: 0,
-            ack_tracker: AckTracker::new(1024),
-            next_reliable_seq: 0,
-            next_ordered_seq: 0,
+            ack_tracker: AckTracker::new(ACK_WINDOW),
+            reliable: SendChannel::new(Message::CHANNEL_RELIABLE),
+            ordered: SendChannel::new(Message::CHANNEL_ORDERED),
+            outgoing: VecDeque::new(),
             inflight_packets: HashMap::new(),
-            pending_reliable: HashMap::new(),
-            pending_ordered: HashMap::new(),
+            snapshot_sequence: None,
 
             receive_tracker: ReceiveTracker::new(),
-            received_reliable_history: VecDeque::with_capacity(RELIABLE_HISTORY_SIZE),
-            next_expected_ordered: 0,
-            ordered_buffer: HashMap::new(),
+            received_reliable: ReliableReceiveWindow::new(),
+            received_ordered: OrderedReceiveWindow::new(),
         }
     }
 
-    pub fn combined_salt(&self) -> u64 {
-        self.client_salt ^ self.server_salt
+    /// Once set, packets are sealed as soon as the connection is established
+    /// and unauthenticated packets are dropped.
+    pub fn set_session_keys(&mut self, send_key: &Key, receive_key: &Key) {
+        self.cipher = Some(PacketCipher::new(send_key, receive_key));
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Starts probing the path MTU, normally right after the handshake.
+    pub fn start_mtu_discovery(&mut self) {
+        self.mtu.start();
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu.mtu()
+    }
+
+    pub fn is_probing_mtu(&self) -> bool {
+        self.mtu.is_probing()
+    }
+
+    /// Size limit to send a flushed packet with: the probe size for an MTU
+    /// probe, the negotiated MTU for everything else.
+    pub fn mtu_for(&self, packet: &Packet) -> usize {
+        match (self.mtu.probe_sequence(), self.mtu.probe_size()) {
+            (Some(sequence), Some(size)) if sequence == packet.header.sequence => size,
+            _ => self.mtu.mtu(),
+        }
+    }
+
+    /// Feeds back a send the local stack refused as bigger than the path. A
+    /// probe just marks its size as out of reach; anything else means the
+    /// path shrank, so the MTU steps down. Reliable messages in the packet
+    /// go out again with the usual resends.
+    pub fn on_packet_too_large(&mut self, packet: &Packet) {
+        match self.mtu.probe_sequence() {
+            Some(sequence) if sequence == packet.header.sequence => {
+                self.mtu.on_probe_too_large(sequence)
+            }
+            _ => self.mtu.on_too_large(),
+        }
+    }
+
+    /// Queues a payload too large for one packet for chunked transfer,
+    /// sized to the MTU at the time it starts.
+    pub fn send_block(&mut self, kind: BlockKind, data: Vec<u8>) -> Result<u16, BlockError> {
+        self.blocks
+            .send(kind, data, self.mtu.mtu() - CHUNK_OVERHEAD)
+    }
+
+    /// Lets the peer send blocks of `kind`; anything else is refused.
+    pub fn accept_blocks(&mut self, kind: BlockKind, max_size: usize) {
+        self.blocks.accept(kind, max_size);
+    }
+
+    pub fn cancel_block(&mut self, direction: BlockDirection, transfer_id: u16) -> bool {
+        self.blocks.cancel(direction, transfer_id)
+    }
+
+    pub fn block_progress(&self) -> Vec<BlockProgress> {
+        self.blocks.progress()
+    }
+
+    pub fn take_completed_blocks(&mut self) -> Vec<CompletedBlock> {
+        self.blocks.take_completed()
+    }
+
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// The endpoint knows what a datagram cost on the wire, so whoever sends
+    /// or receives through it reports the size here. Everything sent draws on
+    /// the bandwidth allowance, and snapshot packets also set its pace.
+    pub fn record_sent(&mut self, packet: &Packet, bytes: usize) {
+        self.stats.on_sent(bytes);
+        if self.snapshot_sequence == Some(packet.header.sequence) {
+            self.bandwidth.record_snapshot(bytes);
+        } else {
+            self.bandwidth.record_sent(bytes);
+        }
+    }
+
+    pub fn record_received(&mut self, bytes: usize) {
+        self.stats.on_received(bytes);
     }
 
     pub fn is_timed_out(&self, timeout: Duration) -> bool {
@@ -107,7 +221,169 @@ impl ClientConnection {
         self.last_receive_time = Instant::now();
     }
 
-    pub fn send_packet(&mut self, payload: PacketType, reliability: Reliability) -> Packet {
+    /// Reliable messages wait in their channel until its window has room.
+    /// Fails when that queue is full, which means the peer isn't keeping up.
+    pub fn queue_message(
+        &mut self,
+        payload: PacketType,
+        reliability: Reliability,
+    ) -> Result<(), ChannelError> {
+        match reliability {
+            Reliability::Unreliable => {
+                self.outgoing.push_back(Message::unreliable(payload));
+                Ok(())
+            }
+            Reliability::Reliable => self.reliable.push(payload),
+            Reliability::Ordered => self.ordered.push(payload),
+        }
+    }
+
+    pub fn has_queued_messages(&self) -> bool {
+        !self.outgoing.is_empty() || self.reliable.has_sendable() || self.ordered.has_sendable()
+    }
+
+    /// Messages sent but not yet acked on a reliable channel.
+    pub fn in_flight(&self, reliability: Reliability) -> usize {
+        match reliability {
+            Reliability::Unreliable => 0,
+            Reliability::Reliable => self.reliable.in_flight(),
+            Reliability::Ordered => self.ordered.in_flight(),
+        }
+    }
+
+    /// Messages held back because a channel's window is full.
+    pub fn send_queue_len(&self, reliability: Reliability) -> usize {
+        match reliability {
+            Reliability::Unreliable => 0,
+            Reliability::Reliable => self.reliable.queued(),
+            Reliability::Ordered => self.ordered.queued(),
+        }
+    }
+
+    /// Packs everything queued into as few packets as fit under the MTU. A
+    /// message too large for one packet is sent alone and left to fragmentation.
+    pub fn flush(&mut self) -> Vec<Packet> {
+        let now = Instant::now();
+        self.stats.update(now);
+        self.outgoing.extend(self.reliable.take_new(now));
+        self.outgoing.extend(self.ordered.take_new(now));
+
+        if self.state == ConnectionState::Connected {
+            for payload in self.blocks.poll(now, self.ack_tracker.srtt()) {
+                self.outgoing.push_back(Message::unreliable(payload));
+            }
+        }
+
+        let mut budget = self.mtu.mtu() - PACKET_OVERHEAD;
+        if self.cipher.is_some() {
+            budget -= SEAL_OVERHEAD;
+        }
+        let mut packets = Vec::new();
+        if !self.outgoing.is_empty() {
+            let messages = self.outgoing.drain(..).collect();
+            packets = self.pack(messages, budget);
+        }
+
+        if self.state == ConnectionState::Connected
+            && !self.reliable.is_window_full()
+            && let Some(size) = self.mtu.poll(now, self.ack_tracker.srtt())
+        {
+            let sequence = self.send_sequence;
+            packets.extend(self.build_mtu_probe(size));
+            self.mtu.on_probe_sent(size, sequence, now);
+        }
+
+        packets
+    }
+
+    /// The batch is archived once, and when it fits in one packet, or is a
+    /// single message left to fragmentation, those bytes are what gets sealed.
+    /// Only a batch too large for one packet is measured message by message.
+    fn pack(&mut self, messages: Vec<Message>, budget: usize) -> Vec<Packet> {
+        match rkyv::to_bytes::<rancor::Error>(&messages) {
+            Ok(archived) if archived.len() <= budget || messages.len() == 1 => {
+                return self
+                    .build_packet(messages, Some(archived))
+                    .into_iter()
+                    .collect();
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("Failed to archive {} messages: {}", messages.len(), e);
+                return Vec::new();
+            }
+        }
+
+        let mut packets = Vec::new();
+        let mut batch = Vec::new();
+        let mut size = 0;
+        for message in messages {
+            let message_size = message.wire_size().unwrap_or(budget) + MESSAGE_PADDING;
+            if !batch.is_empty() && size + message_size > budget {
+                packets.extend(self.build_packet(std::mem::take(&mut batch), None));
+                size = 0;
+            }
+            size += message_size;
+            batch.push(message);
+        }
+        if !batch.is_empty() {
+            packets.extend(self.build_packet(batch, None));
+        }
+        packets
+    }
+
+    /// Probes ride the reliable channel so they're acked, but aren't resent:
+    /// a lost probe is the answer, and the search sends the next one itself.
+    fn build_mtu_probe(&mut self, size: usize) -> Option<Packet> {
+        let seq = self.reliable.reserve()?;
+
+        let header = PacketHeader::new(self.send_sequence, 0, 0);
+        let probe = |padding: usize| {
+            vec![Message::new(
+                Message::CHANNEL_RELIABLE,
+                seq,
+                PacketType::MtuProbe {
+                    padding: vec![0; padding],
+                },
+            )]
+        };
+
+        // Archive alignment makes the size step unevenly, so close in on it
+        let mut padding = 0;
+        for _ in 0..4 {
+            let wire = self.probe_wire_size(header, probe(padding))?;
+            if wire == size {
+                break;
+            }
+            padding = (padding + size).saturating_sub(wire);
+        }
+        while padding > 0 && self.probe_wire_size(header, probe(padding))? > size {
+            padding -= 1;
+        }
+
+        self.build_packet(probe(padding), None)
+    }
+
+    fn probe_wire_size(&self, header: PacketHeader, messages: Vec<Message>) -> Option<usize> {
+        let packet = Packet::with_messages(header, messages);
+        let packet = if self.cipher.is_some() {
+            let plaintext = rkyv::to_bytes::<rkyv::rancor::Error>(&packet.messages).ok()?;
+            Packet::new(
+                header,
+                PacketType::Encrypted(vec![0; plaintext.len() + TAG_BYTES]),
+            )
+        } else {
+            packet
+        };
+        packet.serialize().ok().map(|data| data.len())
+    }
+
+    /// `archived` is `messages` already archived, if the caller has it.
+    fn build_packet(
+        &mut self,
+        messages: Vec<Message>,
+        archived: Option<AlignedVec>,
+    ) -> Option<Packet> {
         let (ack, ack_bitfield) = self.receive_tracker.ack_data();
         let sequence = self.send_sequence;
         self.send_sequence = self.send_sequence.wrapping_add(1);
@@ -115,38 +391,70 @@ impl ClientConnection {
         // Track for RTT
         self.ack_tracker.track_packet(sequence);
 
-        let (channel, channel_seq) = match reliability {
-            Reliability::Unreliable => (PacketHeader::CHANNEL_UNRELIABLE, 0),
-            Reliability::Reliable => {
-                let seq = self.next_reliable_seq;
-                self.next_reliable_seq = self.next_reliable_seq.wrapping_add(1);
-
-                self.pending_reliable
-                    .insert(seq, (payload.clone(), Instant::now()));
-                self.inflight_packets
-                    .insert(sequence, (PacketHeader::CHANNEL_RELIABLE, seq));
+        let reliable: Vec<(u8, u16)> = messages
+            .iter()
+            .filter(|message| message.is_reliable())
+            .map(|message| (message.channel, message.channel_seq))
+            .collect();
+        self.inflight_packets
+            .remove(&sequence.wrapping_sub(ACK_WINDOW as u32));
+        if !reliable.is_empty() {
+            self.inflight_packets.insert(sequence, reliable);
+        }
+        if messages.iter().any(|message| {
+            matches!(
+                message.payload,
+                PacketType::WorldSnapshot(_) | PacketType::PackedSnapshot(_)
+            )
+        }) {
+            self.snapshot_sequence = Some(sequence);
+        }
 
-                (PacketHeader::CHANNEL_RELIABLE, seq)
-            }
-            Reliability::Ordered => {
-                let seq = self.next_ordered_seq;
-                self.next_ordered_seq = self.next_ordered_seq.wrapping_add(1);
+        let header = PacketHeader::new(sequence, ack, ack_bitfield);
+        let cipher = match &self.cipher {
+            Some(cipher) if self.state == ConnectionState::Connected => cipher,
+            _ => return Some(Packet::with_messages(header, messages)),
+        };
+        let sealed = match archived {
+            Some(plaintext) => cipher.seal_archived(header, &plaintext),
+            None => cipher.seal(&Packet::with_messages(header, messages)),
+        };
+        sealed
+            .inspect_err(|e| log::error!("Failed to seal packet {}: {}", sequence, e))
+            .ok()
+    }
 
-                self.pending_ordered
-                    .insert(seq, (payload.clone(), Instant::now()));
-                self.inflight_packets
-                    .insert(sequence, (PacketHeader::CHANNEL_ORDERED, seq));
+    fn open_packet(&mut self, packet: Packet) -> Option<Packet> {
+        let Some(cipher) = &self.cipher else {
+            return Some(packet);
+        };
 
-                (PacketHeader::CHANNEL_ORDERED, seq)
+        if packet.is_encrypted() {
+            let opened = cipher.open(&packet).ok();
+            if opened.is_none() {
+                self.auth_failures += 1;
             }
-        };
+            return opened;
+        }
 
-        let header = PacketHeader::new(sequence, ack, ack_bitfield, channel, channel_seq);
-        Packet::new(header, payload)
+        // Plaintext is only expected until the handshake completes; late
+        // handshake resends aren't worth counting
+        if self.state != ConnectionState::Connected {
+            return Some(packet);
+        }
+        if !packet.is_handshake() {
+            self.auth_failures += 1;
+        }
+        None
     }
 
     pub fn process_packet(&mut self, packet: Packet) -> Vec<PacketType> {
+        let Some(packet) = self.open_packet(packet) else {
+            return Vec::new();
+        };
+
         self.touch();
+        self.stats.on_arrival(self.last_receive_time);
 
         let header = &packet.header;
 
@@ -154,18 +462,36 @@ impl ClientConnection {
         let acked_sequences = self
             .ack_tracker
             .process_ack(header.ack, header.ack_bitfield);
-        for seq in acked_sequences {
-            if let Some((channel, c_seq)) = self.inflight_packets.remove(&seq) {
-                match channel {
-                    PacketHeader::CHANNEL_RELIABLE => {
-                        self.pending_reliable.remove(&c_seq);
+        let lost = self.ack_tracker.take_lost();
+        self.bandwidth
+            .on_acks(acked_sequences.len(), self.ack_tracker.srtt(), lost);
+        self.stats.on_acks(
+            acked_sequences.len(),
+            lost,
+            self.ack_tracker.srtt(),
+            self.ack_tracker.rtt_var(),
+        );
+        if !acked_sequences.is_empty() {
+            for seq in acked_sequences {
+                self.mtu.on_acked(seq);
+                for (channel, c_seq) in self.inflight_packets.remove(&seq).unwrap_or_default() {
+                    match channel {
+                        Message::CHANNEL_RELIABLE => self.reliable.ack(c_seq),
+                        Message::CHANNEL_ORDERED => self.ordered.ack(c_seq),
+                        _ => {}
                     }
-                    PacketHeader::CHANNEL_ORDERED => {
-                        self.pending_ordered.remove(&c_seq);
-                    }
-                    _ => {}
                 }
             }
+
+            // Older copies of a message that was acked through a resend can't matter anymore
+            let (reliable, ordered) = (&self.reliable, &self.ordered);
+            self.inflight_packets.retain(|_, messages| {
+                messages.iter().any(|(channel, c_seq)| match *channel {
+                    Message::CHANNEL_RELIABLE => reliable.is_pending(*c_seq),
+                    Message::CHANNEL_ORDERED => ordered.is_pending(*c_seq),
+                    _ => false,
+                })
+            });
         }
 
         // Update receive tracker (wire sequence)
@@ -173,119 +499,74 @@ impl ClientConnection {
             return Vec::new();
         }
 
-        match header.channel {
-            PacketHeader::CHANNEL_UNRELIABLE => vec![packet.payload],
-            PacketHeader::CHANNEL_RELIABLE => {
-                let seq = header.channel_seq;
-                if self.received_reliable_history.contains(&seq) {
-                    Vec::new()
-                } else {
-                    if self.received_reliable_history.len() >= RELIABLE_HISTORY_SIZE {
-                        self.received_reliable_history.pop_front();
-                    }
-                    self.received_reliable_history.push_back(seq);
-                    vec![packet.payload]
-                }
-            }
-            PacketHeader::CHANNEL_ORDERED => {
-                let seq = header.channel_seq;
-
-                if seq == self.next_expected_ordered {
-                    let mut result = Vec::new();
-                    result.push(packet.payload);
-                    self.next_expected_ordered = self.next_expected_ordered.wrapping_add(1);
-
-                    while let Some(buffered) =
-                        self.ordered_buffer.remove(&self.next_expected_ordered)
-                    {
-                        result.push(buffered);
-                        self.next_expected_ordered = self.next_expected_ordered.wrapping_add(1);
-                    }
-                    result
-                } else if self.sequence_greater_than_u16(seq, self.next_expected_ordered) {
-                    self.ordered_buffer.insert(seq, packet.payload);
-                    Vec::new()
-                } else {
-                    Vec::new()
-                }
-            }
-            _ => Vec::new(),
+        let mut payloads = Vec::new();
+        for message in packet.messages {
+            self.receive_message(message, &mut payloads);
         }
+        payloads
     }
 
-    pub fn collect_resends(&mut self) -> Vec<Packet> {
-        let mut packets = Vec::new();
-        let now = Instant::now();
-        let rtt = self.ack_tracker.srtt();
-        let timeout = if rtt > 0.0 {
-            Duration::from_secs_f32(rtt * 1.5 / 1000.0).max(Duration::from_millis(50))
-        } else {
-            Duration::from_millis(200)
-        };
-
-        // Check reliable pending
-        // Iterate and clone to avoid borrow issues
-        let mut reliable_resends = Vec::new();
-        for (seq, (_, last_send)) in &self.pending_reliable {
-            if now.duration_since(*last_send) > timeout {
-                reliable_resends.push(*seq);
-            }
+    /// Processes the sealed packet carried by a session resume. It only
+    /// counts if it authenticates and is newer than anything received so
+    /// far, so a captured resume can't be replayed to steal the session.
+    pub fn resume(&mut self, packet: Packet) -> Option<Vec<PacketType>> {
+        if self.cipher.is_none()
+            || self.state != ConnectionState::Connected
+            || !packet.is_encrypted()
+        {
+            return None;
         }
 
-        for seq in reliable_resends {
-            if let Some((payload, last_send)) = self.pending_reliable.get_mut(&seq) {
-                *last_send = now;
-                let wire_seq = self.send_sequence;
-                self.send_sequence = self.send_sequence.wrapping_add(1);
-                self.inflight_packets
-                    .insert(wire_seq, (PacketHeader::CHANNEL_RELIABLE, seq));
-
-                let (ack, ack_bitfield) = self.receive_tracker.ack_data();
-                let header = PacketHeader::new(
-                    wire_seq,
-                    ack,
-                    ack_bitfield,
-                    PacketHeader::CHANNEL_RELIABLE,
-                    seq,
-                );
-                packets.push(Packet::new(header, payload.clone()));
-            }
+        let (newest, _) = self.receive_tracker.ack_data();
+        if !sequence_greater_than(packet.header.sequence, newest) {
+            return None;
         }
 
-        // Check ordered pending
-        let mut ordered_resends = Vec::new();
-        for (seq, (_, last_send)) in &self.pending_ordered {
-            if now.duration_since(*last_send) > timeout {
-                ordered_resends.push(*seq);
-            }
-        }
+        let auth_failures = self.auth_failures;
+        let payloads = self.process_packet(packet);
+        (self.auth_failures == auth_failures).then_some(payloads)
+    }
 
-        for seq in ordered_resends {
-            if let Some((payload, last_send)) = self.pending_ordered.get_mut(&seq) {
-                *last_send = now;
-                let wire_seq = self.send_sequence;
-                self.send_sequence = self.send_sequence.wrapping_add(1);
-                self.inflight_packets
-                    .insert(wire_seq, (PacketHeader::CHANNEL_ORDERED, seq));
-
-                let (ack, ack_bitfield) = self.receive_tracker.ack_data();
-                let header = PacketHeader::new(
-                    wire_seq,
-                    ack,
-                    ack_bitfield,
-                    PacketHeader::CHANNEL_ORDERED,
-                    seq,
-                );
-                packets.push(Packet::new(header, payload.clone()));
+    fn receive_message(&mut self, message: Message, payloads: &mut Vec<PacketType>) {
+        let seq = message.channel_seq;
+
+        match message.channel {
+            Message::CHANNEL_UNRELIABLE => payloads.extend(self.blocks.receive(message.payload)),
+            // Probes have done their job once the packet is acked
+            Message::CHANNEL_RELIABLE
+                if self.received_reliable.receive(seq)
+                    && !matches!(message.payload, PacketType::MtuProbe { .. }) =>
+            {
+                payloads.push(message.payload);
+            }
+            Message::CHANNEL_ORDERED => {
+                self.received_ordered
+                    .receive(seq, message.payload, payloads)
             }
+            _ => {}
         }
+    }
 
-        packets
+    /// Queues every reliable message that has gone unacknowledged for longer
+    /// than the resend timeout, returning how many were queued.
+    pub fn queue_resends(&mut self) -> usize {
+        self.queue_resends_at(Instant::now())
     }
 
-    fn sequence_greater_than_u16(&self, s1: u16, s2: u16) -> bool {
-        let half = u16::MAX / 2;
-        ((s1 > s2) && (s1 - s2 <= half)) || ((s1 < s2) && (s2 - s1 > half))
+    pub fn queue_resends_at(&mut self, now: Instant) -> usize {
+        let rtt = self.ack_tracker.srtt();
+        let timeout = if rtt > 0.0 {
+            Duration::from_secs_f32(rtt * 1.5 / 1000.0).max(Duration::from_millis(50))
+        } else {
+            Duration::from_millis(200)
+        };
+
+        let mut resends = self.reliable.take_resends(now, timeout);
+        resends.extend(self.ordered.take_resends(now, timeout));
+
+        let count = resends.len();
+        self.outgoing.extend(resends);
+        count
     }
 }
 
@@ -322,20 +603,29 @@ impl ConnectionManager {
     pub fn get_or_create_pending(
         &mut self,
         addr: SocketAddr,
-        client_salt: u64,
-    ) -> Result<&mut ClientConnection, &'static str> {
+        token_client_id: u64,
+    ) -> Result<&mut ClientConnection, DenyReason> {
         if let Some(&client_id) = self.clients_by_addr.get(&addr) {
             return Ok(self.clients.get_mut(&client_id).unwrap());
         }
 
+        if self
+            .clients
+            .values()
+            .any(|c| c.token_client_id == token_client_id)
+        {
+            return Err(DenyReason::AlreadyConnected);
+        }
+
         if self.clients.len() >= self.max_clients {
-            return Err("Server full");
+            return Err(DenyReason::ServerFull);
         }
 
         let client_id = self.next_client_id;
         self.next_client_id += 1;
 
-        let connection = ClientConnection::new(addr, client_id, client_salt);
+        let mut connection = ClientConnection::new(addr, client_id);
+        connection.token_client_id = token_client_id;
         self.clients.insert(client_id, connection);
         self.clients_by_addr.insert(addr, client_id);
 
@@ -381,6 +671,25 @@ impl ConnectionManager {
         }
     }
 
+    /// Moves a client to a new address, failing if another client holds it.
+    pub fn rebind(&mut self, client_id: u32, addr: SocketAddr) -> bool {
+        if self
+            .clients_by_addr
+            .get(&addr)
+            .is_some_and(|&id| id != client_id)
+        {
+            return false;
+        }
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return false;
+        };
+
+        self.clients_by_addr.remove(&client.addr);
+        self.clients_by_addr.insert(addr, client_id);
+        client.addr = addr;
+        true
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &ClientConnection> {
         self.clients.values()
     }
@@ -389,7 +698,9 @@ impl ConnectionManager {
         self.clients.values_mut()
     }
 
-    pub fn cleanup_timed_out(&mut self) -> Vec<u32> {
+    /// Removes clients that have gone quiet, handing them back so whatever
+    /// they owned can be cleaned up.
+    pub fn cleanup_timed_out(&mut self) -> Vec<ClientConnection> {
         let timed_out: Vec<u32> = self
             .clients
             .iter()
@@ -397,11 +708,10 @@ impl ConnectionManager {
             .map(|(&id, _)| id)
             .collect();
 
-        for id in &timed_out {
-            self.remove(*id);
-        }
-
         timed_out
+            .into_iter()
+            .filter_map(|id| self.remove(id))
+            .collect()
     }
 
     pub fn connected_count(&self) -> usize {
@@ -0,0 +1,161 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use rkyv::rancor;
+
+use super::protocol::{
+    Message, PROTOCOL_MAGIC, PROTOCOL_VERSION, Packet, PacketError, PacketHeader, PacketType,
+};
+use super::token::Key;
+
+/// Poly1305 tag appended to every sealed payload.
+pub const TAG_BYTES: usize = 16;
+
+fn packet_nonce(sequence: u32) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..8].copy_from_slice(&sequence.to_le_bytes());
+    nonce
+}
+
+// The header travels in the clear for acks, so it's authenticated instead
+fn header_associated_data(header: &PacketHeader) -> [u8; 20] {
+    let mut data = [0u8; 20];
+    data[0..4].copy_from_slice(&PROTOCOL_MAGIC.to_le_bytes());
+    data[4..8].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    data[8..12].copy_from_slice(&header.sequence.to_le_bytes());
+    data[12..16].copy_from_slice(&header.ack.to_le_bytes());
+    data[16..20].copy_from_slice(&header.ack_bitfield.to_le_bytes());
+    data
+}
+
+/// Session keys for one connection. Each direction has its own key, and the
+/// wire sequence is the nonce, so a sequence must never be sealed twice.
+#[derive(Clone)]
+pub struct PacketCipher {
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+}
+
+impl std::fmt::Debug for PacketCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketCipher").finish_non_exhaustive()
+    }
+}
+
+impl PacketCipher {
+    pub fn new(send_key: &Key, receive_key: &Key) -> Self {
+        Self {
+            send: ChaCha20Poly1305::new(send_key.into()),
+            receive: ChaCha20Poly1305::new(receive_key.into()),
+        }
+    }
+
+    pub fn seal(&self, packet: &Packet) -> Result<Packet, PacketError> {
+        let plaintext =
+            rkyv::to_bytes::<rancor::Error>(&packet.messages).map_err(PacketError::Serialize)?;
+        self.seal_archived(packet.header, &plaintext)
+    }
+
+    /// Seals messages that were already archived, as a `Vec<Message>`.
+    pub fn seal_archived(
+        &self,
+        header: PacketHeader,
+        plaintext: &[u8],
+    ) -> Result<Packet, PacketError> {
+        let ciphertext = self
+            .send
+            .encrypt(
+                Nonce::from_slice(&packet_nonce(header.sequence)),
+                Payload {
+                    msg: plaintext,
+                    aad: &header_associated_data(&header),
+                },
+            )
+            .map_err(|_| PacketError::Authentication)?;
+
+        Ok(Packet::new(header, PacketType::Encrypted(ciphertext)))
+    }
+
+    pub fn open(&self, packet: &Packet) -> Result<Packet, PacketError> {
+        let [
+            Message {
+                payload: PacketType::Encrypted(ciphertext),
+                ..
+            },
+        ] = packet.messages.as_slice()
+        else {
+            return Err(PacketError::Authentication);
+        };
+
+        let plaintext = self
+            .receive
+            .decrypt(
+                Nonce::from_slice(&packet_nonce(packet.header.sequence)),
+                Payload {
+                    msg: ciphertext,
+                    aad: &header_associated_data(&packet.header),
+                },
+            )
+            .map_err(|_| PacketError::Authentication)?;
+        let messages = rkyv::from_bytes::<Vec<Message>, rancor::Error>(&plaintext)
+            .map_err(PacketError::Deserialize)?;
+
+        Ok(Packet::with_messages(packet.header, messages))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping_packet(sequence: u32) -> Packet {
+        Packet::new(
+            PacketHeader::new(sequence, 3, 0b101),
+            PacketType::Ping { timestamp: 99 },
+        )
+    }
+
+    #[test]
+    fn test_sealed_packet_opens_with_peer_keys() {
+        let client = PacketCipher::new(&[1; 32], &[2; 32]);
+        let server = PacketCipher::new(&[2; 32], &[1; 32]);
+
+        let sealed = client.seal(&ping_packet(7)).unwrap();
+        assert!(sealed.is_encrypted());
+
+        let opened = server.open(&sealed).unwrap();
+        assert_eq!(opened.header, sealed.header);
+        assert!(matches!(
+            opened.messages[0].payload,
+            PacketType::Ping { timestamp: 99 }
+        ));
+
+        // Keys are per direction
+        assert!(client.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_tampered_header_or_payload_fails() {
+        let client = PacketCipher::new(&[1; 32], &[2; 32]);
+        let server = PacketCipher::new(&[2; 32], &[1; 32]);
+        let sealed = client.seal(&ping_packet(7)).unwrap();
+
+        let mut replayed = sealed.clone();
+        replayed.header.sequence = 8;
+        assert!(matches!(
+            server.open(&replayed),
+            Err(PacketError::Authentication)
+        ));
+
+        let mut forged_ack = sealed.clone();
+        forged_ack.header.ack_bitfield = u32::MAX;
+        assert!(server.open(&forged_ack).is_err());
+
+        let mut flipped = sealed.clone();
+        if let PacketType::Encrypted(data) = &mut flipped.messages[0].payload {
+            data[0] ^= 1;
+        }
+        assert!(server.open(&flipped).is_err());
+
+        assert!(server.open(&ping_packet(7)).is_err());
+    }
+}
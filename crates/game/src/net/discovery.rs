@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use super::endpoint::NetworkEndpoint;
+use super::protocol::{DEFAULT_PORT, Packet, PacketHeader, PacketType, QueryInfo};
+use super::transport::{Transport, UdpTransport};
+
+/// How often `LanBrowser` probes for servers.
+pub const LAN_PROBE_INTERVAL: Duration = Duration::from_secs(2);
+
+// A few missed probes before a server drops off the list
+const LAN_SERVER_EXPIRY: Duration = Duration::from_secs(7);
+
+/// Whether `ip` is on the local network. Discovery replies are bigger than
+/// the probe, so servers only answer these.
+pub fn is_lan_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unicast_link_local() || ip.is_unique_local(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LanServer {
+    pub addr: SocketAddr,
+    pub info: QueryInfo,
+    pub ping_ms: f32,
+    pub last_seen: Instant,
+}
+
+/// Finds servers on the local network by probing for them periodically.
+pub struct LanBrowser<T: Transport = UdpTransport> {
+    endpoint: NetworkEndpoint<T>,
+    target: SocketAddr,
+    start: Instant,
+    last_probe: Option<Instant>,
+    servers: HashMap<SocketAddr, LanServer>,
+}
+
+impl LanBrowser<UdpTransport> {
+    /// Probes the IPv4 broadcast address on the default port, where every
+    /// server, dual-stack ones included, can hear it.
+    pub fn bind() -> io::Result<Self> {
+        Self::with_transport(
+            UdpTransport::bind_broadcast()?,
+            SocketAddr::from((Ipv4Addr::BROADCAST, DEFAULT_PORT)),
+        )
+    }
+}
+
+impl<T: Transport> LanBrowser<T> {
+    pub fn with_transport(transport: T, target: SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            endpoint: NetworkEndpoint::with_transport(transport)?,
+            target,
+            start: Instant::now(),
+            last_probe: None,
+            servers: HashMap::new(),
+        })
+    }
+
+    /// Sends a probe if one is due and takes in whatever has answered.
+    pub fn poll(&mut self) -> io::Result<()> {
+        if self
+            .last_probe
+            .is_none_or(|probe| probe.elapsed() >= LAN_PROBE_INTERVAL)
+        {
+            let request = Packet::new(
+                PacketHeader::new(0, 0, 0),
+                PacketType::discovery_request(self.start.elapsed().as_micros() as u64),
+            );
+            self.last_probe = Some(Instant::now());
+            self.endpoint.send_to(&request, self.target)?;
+        }
+
+        for (packet, addr) in self.endpoint.receive()? {
+            for message in packet.messages {
+                if let PacketType::DiscoveryResponse { timestamp, info } = message.payload {
+                    let rtt = self
+                        .start
+                        .elapsed()
+                        .saturating_sub(Duration::from_micros(timestamp));
+                    self.servers.insert(
+                        addr,
+                        LanServer {
+                            addr,
+                            info,
+                            ping_ms: rtt.as_secs_f32() * 1000.0,
+                            last_seen: Instant::now(),
+                        },
+                    );
+                }
+            }
+        }
+
+        self.servers
+            .retain(|_, server| server.last_seen.elapsed() < LAN_SERVER_EXPIRY);
+        Ok(())
+    }
+
+    /// Probes on the next poll instead of waiting out the interval.
+    pub fn refresh(&mut self) {
+        self.last_probe = None;
+    }
+
+    /// Servers heard from recently, sorted by name.
+    pub fn servers(&self) -> Vec<&LanServer> {
+        let mut servers: Vec<_> = self.servers.values().collect();
+        servers.sort_by(|a, b| a.info.name.cmp(&b.info.name).then(a.addr.cmp(&b.addr)));
+        servers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lan_addresses() {
+        for ip in [
+            "192.168.1.20",
+            "10.0.0.1",
+            "127.0.0.1",
+            "169.254.3.4",
+            "::1",
+            "fe80::1",
+            "fd00::5",
+        ] {
+            assert!(is_lan_address(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "100.64.0.1", "2001:db8::1", "::ffff:10.0.0.1"] {
+            assert!(!is_lan_address(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -1,47 +1,77 @@
 use std::io;
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
+use super::capture::{CaptureDirection, CaptureWriter, KeyLogWriter};
 use super::connection::ConnectionState;
-use super::protocol::{MAX_PACKET_SIZE, Packet};
+use super::fragment::{FragmentAssembler, fragment_size_for, split_packet};
+use super::protocol::{
+    DenyReason, MAX_MTU, MAX_PACKET_SIZE, MIN_MTU, Message, PACKET_PREFIX_SIZE, Packet,
+    PacketError, PacketHeader, PacketType,
+};
 use super::stats::NetworkStats;
+use super::token::Key;
+use super::transport::{Transport, UdpTransport};
 
 const DEFAULT_TIMEOUT_SECS: u64 = 120;
+// Foreign-version datagrams kept for whoever answers them; the rest are dropped
+const MAX_VERSION_MISMATCHES: usize = 64;
 
-pub struct NetworkEndpoint {
-    socket: UdpSocket,
+pub struct NetworkEndpoint<T: Transport = UdpTransport> {
+    transport: T,
     local_addr: SocketAddr,
     remote_addr: Option<SocketAddr>,
     state: ConnectionState,
     stats: NetworkStats,
-    recv_buffer: [u8; MAX_PACKET_SIZE],
+    recv_buffer: [u8; MAX_MTU],
     timeout: Duration,
     last_receive_time: Instant,
     running: Arc<AtomicBool>,
+    fragments: FragmentAssembler,
+    next_fragment_group: u16,
+    capture: Option<CaptureWriter>,
+    key_log: Option<KeyLogWriter>,
+    version_mismatches: Vec<(SocketAddr, usize)>,
 }
 
 impl NetworkEndpoint {
     pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
-        let socket = UdpSocket::bind(addr)?;
-        socket.set_nonblocking(true)?;
+        Self::with_transport(UdpTransport::bind(addr)?)
+    }
+}
 
-        let local_addr = socket.local_addr()?;
+impl<T: Transport> NetworkEndpoint<T> {
+    pub fn with_transport(transport: T) -> io::Result<Self> {
+        let local_addr = transport.local_addr()?;
 
         Ok(Self {
-            socket,
+            transport,
             local_addr,
             remote_addr: None,
             state: ConnectionState::Disconnected,
             stats: NetworkStats::default(),
-            recv_buffer: [0u8; MAX_PACKET_SIZE],
+            recv_buffer: [0u8; MAX_MTU],
             timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
             last_receive_time: Instant::now(),
             running: Arc::new(AtomicBool::new(true)),
+            fragments: FragmentAssembler::new(),
+            next_fragment_group: 0,
+            capture: None,
+            key_log: None,
+            version_mismatches: Vec::new(),
         })
     }
 
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
@@ -66,22 +96,98 @@ impl NetworkEndpoint {
         &self.stats
     }
 
+    /// Connections authenticate after the endpoint has handed packets off,
+    /// so they report failures back here.
+    pub fn record_auth_failures(&mut self, count: u64) {
+        self.stats.auth_failures += count;
+    }
+
+    /// Records every datagram sent or received from now on, replacing any
+    /// capture already running.
+    pub fn start_capture(&mut self, capture: CaptureWriter) {
+        self.capture = Some(capture);
+    }
+
+    /// Stops capturing and flushes whatever is still buffered.
+    pub fn stop_capture(&mut self) -> io::Result<Option<CaptureWriter>> {
+        let Some(mut capture) = self.capture.take() else {
+            return Ok(None);
+        };
+        capture.flush()?;
+        Ok(Some(capture))
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    /// Logs the session keys of every connection set up from now on, so a
+    /// capture of it can be decrypted.
+    pub fn start_key_log(&mut self, key_log: KeyLogWriter) {
+        self.key_log = Some(key_log);
+    }
+
+    /// Records the keys a connection to `peer` seals and opens with, if a
+    /// key log is running.
+    pub fn log_session_keys(&mut self, peer: SocketAddr, send_key: &Key, receive_key: &Key) {
+        let Some(writer) = &mut self.key_log else {
+            return;
+        };
+        if let Err(e) = writer.log(peer, send_key, receive_key) {
+            log::error!("Key log failed, stopping it: {}", e);
+            self.key_log = None;
+        }
+    }
+
     pub fn send_to(&mut self, packet: &Packet, addr: SocketAddr) -> io::Result<usize> {
-        let data = packet.serialize().map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Serialization error: {}", e),
-            )
-        })?;
-
-        if data.len() > MAX_PACKET_SIZE {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Packet exceeds MTU",
-            ));
+        self.send_to_mtu(packet, addr, MAX_PACKET_SIZE)
+    }
+
+    /// Sends a packet, fragmenting it if it doesn't fit in `mtu` bytes.
+    pub fn send_to_mtu(
+        &mut self,
+        packet: &Packet,
+        addr: SocketAddr,
+        mtu: usize,
+    ) -> io::Result<usize> {
+        let mtu = mtu.clamp(MIN_MTU, MAX_MTU);
+        let data = serialize_packet(packet)?;
+
+        if data.len() <= mtu {
+            return self.send_raw(&data, addr);
+        }
+
+        let group_id = self.next_fragment_group;
+        self.next_fragment_group = self.next_fragment_group.wrapping_add(1);
+
+        let fragment_size = fragment_size_for(mtu);
+        let fragments =
+            split_packet(packet.header, group_id, &data, fragment_size).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Packet exceeds maximum fragmented size",
+                )
+            })?;
+
+        let mut total = 0;
+        for fragment in &fragments {
+            let data = serialize_packet(fragment)?;
+            if data.len() > mtu {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Fragment exceeds MTU",
+                ));
+            }
+            total += self.send_raw(&data, addr)?;
+            self.stats.fragments_sent += 1;
         }
 
-        let bytes = self.socket.send_to(&data, addr)?;
+        Ok(total)
+    }
+
+    fn send_raw(&mut self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let bytes = self.transport.send_to(data, addr)?;
+        record_capture(&mut self.capture, CaptureDirection::Sent, addr, data);
 
         self.stats.packets_sent += 1;
         self.stats.bytes_sent += bytes as u64;
@@ -90,33 +196,80 @@ impl NetworkEndpoint {
     }
 
     pub fn send(&mut self, packet: &Packet) -> io::Result<usize> {
+        self.send_mtu(packet, MAX_PACKET_SIZE)
+    }
+
+    pub fn send_mtu(&mut self, packet: &Packet, mtu: usize) -> io::Result<usize> {
         let addr = self
             .remote_addr
             .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "No remote address set"))?;
-        self.send_to(packet, addr)
+        self.send_to_mtu(packet, addr, mtu)
     }
 
     pub fn receive(&mut self) -> io::Result<Vec<(Packet, SocketAddr)>> {
+        Ok(self
+            .receive_sized()?
+            .into_iter()
+            .map(|(packet, addr, _)| (packet, addr))
+            .collect())
+    }
+
+    /// Like [`receive`](Self::receive), along with the datagram size each
+    /// packet arrived in. A reassembled packet reports the size it was split from.
+    pub fn receive_sized(&mut self) -> io::Result<Vec<(Packet, SocketAddr, usize)>> {
+        self.receive_sized_with(|_| true)
+    }
+
+    /// Like [`receive_sized`](Self::receive_sized), with every datagram first
+    /// put to `admit`. Refused ones are dropped before anything is parsed or
+    /// buffered for fragment reassembly.
+    pub fn receive_sized_with(
+        &mut self,
+        mut admit: impl FnMut(SocketAddr) -> bool,
+    ) -> io::Result<Vec<(Packet, SocketAddr, usize)>> {
         let mut packets = Vec::new();
 
+        self.fragments.expire();
+
         loop {
-            match self.socket.recv_from(&mut self.recv_buffer) {
+            match self.transport.recv_from(&mut self.recv_buffer) {
                 Ok((size, addr)) => {
-                    if size < 8 {
+                    record_capture(
+                        &mut self.capture,
+                        CaptureDirection::Received,
+                        addr,
+                        &self.recv_buffer[..size],
+                    );
+
+                    if size < PACKET_PREFIX_SIZE || !admit(addr) {
                         continue;
                     }
 
-                    match Packet::deserialize(&self.recv_buffer[..size]) {
-                        Ok(packet) => {
-                            if !packet.header.is_valid() {
-                                continue;
-                            }
+                    let data = &self.recv_buffer[..size];
+                    if let Some(reason) = DenyReason::decode_version_denial(data) {
+                        let denied = Packet::new(
+                            PacketHeader::new(0, 0, 0),
+                            PacketType::ConnectionDenied { reason },
+                        );
+                        packets.push((denied, addr, size));
+                        continue;
+                    }
 
+                    match Packet::deserialize(data) {
+                        Err(PacketError::UnsupportedVersion(_)) => {
+                            if self.version_mismatches.len() < MAX_VERSION_MISMATCHES {
+                                self.version_mismatches.push((addr, size));
+                            }
+                        }
+                        Ok(packet) => {
                             self.stats.packets_received += 1;
                             self.stats.bytes_received += size as u64;
 
                             self.last_receive_time = Instant::now();
-                            packets.push((packet, addr));
+
+                            if let Some((packet, size)) = self.reassemble(packet, addr, size) {
+                                packets.push((packet, addr, size));
+                            }
                         }
                         Err(_) => continue,
                     }
@@ -126,9 +279,63 @@ impl NetworkEndpoint {
             }
         }
 
+        self.stats.fragment_groups_dropped = self.fragments.dropped_groups();
+
         Ok(packets)
     }
 
+    fn reassemble(
+        &mut self,
+        packet: Packet,
+        addr: SocketAddr,
+        size: usize,
+    ) -> Option<(Packet, usize)> {
+        let [
+            Message {
+                payload:
+                    PacketType::Fragment {
+                        group_id,
+                        index,
+                        count,
+                        data,
+                    },
+                ..
+            },
+        ] = packet.messages.as_slice()
+        else {
+            return Some((packet, size));
+        };
+
+        self.stats.fragments_received += 1;
+
+        let data = self
+            .fragments
+            .insert(addr, *group_id, *index, *count, data)?;
+        let inner = Packet::deserialize(&data).ok()?;
+
+        // Fragments never nest, and the inner header must match the one they carried
+        let nested = inner
+            .messages
+            .iter()
+            .any(|message| matches!(message.payload, PacketType::Fragment { .. }));
+        if nested || inner.header != packet.header {
+            return None;
+        }
+
+        Some((inner, data.len()))
+    }
+
+    /// Datagrams that arrived since the last call from peers speaking a
+    /// version we can't read, with their sizes. Only the first few are kept.
+    pub fn take_version_mismatches(&mut self) -> Vec<(SocketAddr, usize)> {
+        std::mem::take(&mut self.version_mismatches)
+    }
+
+    /// Tells a peer which versions we speak, in a form any version can read.
+    pub fn send_version_denial(&mut self, addr: SocketAddr) -> io::Result<usize> {
+        self.send_raw(&DenyReason::encode_version_denial(), addr)
+    }
+
     pub fn is_timed_out(&self) -> bool {
         self.last_receive_time.elapsed() > self.timeout
     }
@@ -141,6 +348,7 @@ impl NetworkEndpoint {
         self.state = ConnectionState::Disconnected;
         self.stats = NetworkStats::default();
         self.last_receive_time = Instant::now();
+        self.fragments = FragmentAssembler::new();
     }
 
     pub fn running(&self) -> Arc<AtomicBool> {
@@ -151,3 +359,28 @@ impl NetworkEndpoint {
         self.running.store(false, Ordering::SeqCst);
     }
 }
+
+fn record_capture(
+    capture: &mut Option<CaptureWriter>,
+    direction: CaptureDirection,
+    addr: SocketAddr,
+    data: &[u8],
+) {
+    let Some(writer) = capture else {
+        return;
+    };
+    // A full disk shouldn't take the connection down with it
+    if let Err(e) = writer.record(direction, addr, data) {
+        log::error!("Packet capture failed, stopping it: {}", e);
+        *capture = None;
+    }
+}
+
+fn serialize_packet(packet: &Packet) -> io::Result<Vec<u8>> {
+    packet.serialize().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Serialization error: {}", e),
+        )
+    })
+}
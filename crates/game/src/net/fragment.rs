@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::protocol::{MAX_MTU, MAX_PACKET_SIZE, Packet, PacketHeader, PacketType};
+
+pub const FRAGMENT_SIZE: usize = 1024;
+pub const MAX_FRAGMENTS: usize = 64;
+// Room left in each datagram for the fragment's own header and framing
+const FRAGMENT_OVERHEAD: usize = MAX_PACKET_SIZE - FRAGMENT_SIZE;
+
+const DEFAULT_FRAGMENT_TIMEOUT_MS: u64 = 1000;
+const MAX_PENDING_GROUPS: usize = 256;
+// One source can only crowd out its own groups, never everyone else's
+const MAX_PENDING_GROUPS_PER_SOURCE: usize = 16;
+
+/// Largest fragment payload that still fits in a datagram of `mtu` bytes.
+pub fn fragment_size_for(mtu: usize) -> usize {
+    mtu.saturating_sub(FRAGMENT_OVERHEAD).max(1)
+}
+
+pub fn split_packet(
+    header: PacketHeader,
+    group_id: u16,
+    data: &[u8],
+    fragment_size: usize,
+) -> Option<Vec<Packet>> {
+    let fragment_size = fragment_size.clamp(1, fragment_size_for(MAX_MTU));
+    let count = data.len().div_ceil(fragment_size);
+    if count == 0 || count > MAX_FRAGMENTS {
+        return None;
+    }
+
+    let fragments = data
+        .chunks(fragment_size)
+        .enumerate()
+        .map(|(index, chunk)| {
+            Packet::new(
+                header,
+                PacketType::Fragment {
+                    group_id,
+                    index: index as u8,
+                    count: count as u8,
+                    data: chunk.to_vec(),
+                },
+            )
+        })
+        .collect();
+
+    Some(fragments)
+}
+
+#[derive(Debug)]
+struct FragmentGroup {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    created: Instant,
+}
+
+#[derive(Debug)]
+pub struct FragmentAssembler {
+    groups: HashMap<(SocketAddr, u16), FragmentGroup>,
+    timeout: Duration,
+    dropped_groups: u64,
+}
+
+impl Default for FragmentAssembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FragmentAssembler {
+    pub fn new() -> Self {
+        Self::with_timeout(Duration::from_millis(DEFAULT_FRAGMENT_TIMEOUT_MS))
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            groups: HashMap::new(),
+            timeout,
+            dropped_groups: 0,
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        addr: SocketAddr,
+        group_id: u16,
+        index: u8,
+        count: u8,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        let count = count as usize;
+        let index = index as usize;
+        if count == 0
+            || count > MAX_FRAGMENTS
+            || index >= count
+            || data.len() > fragment_size_for(MAX_MTU)
+        {
+            return None;
+        }
+
+        let key = (addr, group_id);
+        if self
+            .groups
+            .get(&key)
+            .is_some_and(|group| group.fragments.len() != count)
+        {
+            // Group id was reused with a different layout, the old one can't complete
+            self.groups.remove(&key);
+            self.dropped_groups += 1;
+        }
+
+        if !self.groups.contains_key(&key) {
+            let from_source = self.groups.keys().filter(|(a, _)| *a == addr).count();
+            if from_source >= MAX_PENDING_GROUPS_PER_SOURCE {
+                self.evict_oldest(Some(addr));
+            } else if self.groups.len() >= MAX_PENDING_GROUPS {
+                self.evict_oldest(None);
+            }
+        }
+
+        let group = self.groups.entry(key).or_insert_with(|| FragmentGroup {
+            fragments: vec![None; count],
+            received: 0,
+            created: Instant::now(),
+        });
+
+        if group.fragments[index].is_none() {
+            group.fragments[index] = Some(data.to_vec());
+            group.received += 1;
+        }
+
+        if group.received < count {
+            return None;
+        }
+
+        let group = self.groups.remove(&key)?;
+        Some(group.fragments.into_iter().flatten().flatten().collect())
+    }
+
+    pub fn expire(&mut self) -> usize {
+        let timeout = self.timeout;
+        let before = self.groups.len();
+        self.groups
+            .retain(|_, group| group.created.elapsed() <= timeout);
+        let expired = before - self.groups.len();
+        self.dropped_groups += expired as u64;
+        expired
+    }
+
+    pub fn pending_groups(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn dropped_groups(&self) -> u64 {
+        self.dropped_groups
+    }
+
+    pub fn clear(&mut self) {
+        self.groups.clear();
+    }
+
+    fn evict_oldest(&mut self, source: Option<SocketAddr>) {
+        let oldest = self
+            .groups
+            .iter()
+            .filter(|((addr, _), _)| source.is_none_or(|source| *addr == source))
+            .min_by_key(|(_, group)| group.created)
+            .map(|(&key, _)| key);
+        if let Some(key) = oldest {
+            self.groups.remove(&key);
+            self.dropped_groups += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:4000".parse().unwrap()
+    }
+
+    fn fragment_parts(packets: &[Packet]) -> Vec<(u16, u8, u8, Vec<u8>)> {
+        packets
+            .iter()
+            .map(|packet| match &packet.messages[0].payload {
+                PacketType::Fragment {
+                    group_id,
+                    index,
+                    count,
+                    data,
+                } => (*group_id, *index, *count, data.clone()),
+                _ => panic!("Expected Fragment"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_and_reassemble_out_of_order() {
+        let data: Vec<u8> = (0..FRAGMENT_SIZE * 3 + 17).map(|i| i as u8).collect();
+        let header = PacketHeader::new(5, 0, 0);
+        let packets = split_packet(header, 9, &data, FRAGMENT_SIZE).unwrap();
+        assert_eq!(packets.len(), 4);
+        assert!(packets.iter().all(|p| p.header == header));
+
+        let mut parts = fragment_parts(&packets);
+        parts.reverse();
+
+        let mut assembler = FragmentAssembler::new();
+        let mut result = None;
+        for (group_id, index, count, chunk) in &parts {
+            assert!(result.is_none());
+            result = assembler.insert(addr(), *group_id, *index, *count, chunk);
+        }
+
+        assert_eq!(result.unwrap(), data);
+        assert_eq!(assembler.pending_groups(), 0);
+    }
+
+    #[test]
+    fn duplicate_fragments_are_ignored() {
+        let data = vec![7u8; FRAGMENT_SIZE + 1];
+        let header = PacketHeader::new(0, 0, 0);
+        let parts = fragment_parts(&split_packet(header, 1, &data, FRAGMENT_SIZE).unwrap());
+
+        let mut assembler = FragmentAssembler::new();
+        let (group_id, index, count, chunk) = &parts[0];
+        assert!(
+            assembler
+                .insert(addr(), *group_id, *index, *count, chunk)
+                .is_none()
+        );
+        assert!(
+            assembler
+                .insert(addr(), *group_id, *index, *count, chunk)
+                .is_none()
+        );
+
+        let (group_id, index, count, chunk) = &parts[1];
+        assert_eq!(
+            assembler.insert(addr(), *group_id, *index, *count, chunk),
+            Some(data)
+        );
+    }
+
+    #[test]
+    fn incomplete_groups_expire() {
+        let data = vec![1u8; FRAGMENT_SIZE * 2];
+        let header = PacketHeader::new(0, 0, 0);
+        let parts = fragment_parts(&split_packet(header, 3, &data, FRAGMENT_SIZE).unwrap());
+
+        let mut assembler = FragmentAssembler::with_timeout(Duration::from_millis(5));
+        let (group_id, index, count, chunk) = &parts[0];
+        assembler.insert(addr(), *group_id, *index, *count, chunk);
+        assert_eq!(assembler.pending_groups(), 1);
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(assembler.expire(), 1);
+        assert_eq!(assembler.dropped_groups(), 1);
+
+        // The late half alone must not complete the dropped group
+        let (group_id, index, count, chunk) = &parts[1];
+        assert!(
+            assembler
+                .insert(addr(), *group_id, *index, *count, chunk)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_payloads() {
+        let data = vec![0u8; FRAGMENT_SIZE * MAX_FRAGMENTS + 1];
+        let header = PacketHeader::new(0, 0, 0);
+        assert!(split_packet(header, 0, &data, FRAGMENT_SIZE).is_none());
+    }
+
+    #[test]
+    fn one_source_cannot_crowd_out_others() {
+        let mut assembler = FragmentAssembler::new();
+        let other: SocketAddr = "127.0.0.2:4000".parse().unwrap();
+        assembler.insert(other, 0, 0, 2, &[1]);
+
+        for group_id in 0..MAX_PENDING_GROUPS as u16 * 2 {
+            assembler.insert(addr(), group_id, 0, 2, &[1]);
+        }
+        assert_eq!(
+            assembler.pending_groups(),
+            MAX_PENDING_GROUPS_PER_SOURCE + 1
+        );
+
+        // The other source's group is still there to be completed
+        assert_eq!(assembler.insert(other, 0, 1, 2, &[2]), Some(vec![1, 2]));
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Buckets tracked at once; past this, idle ones are dropped to make room
+const MAX_TRACKED_SOURCES: usize = 4096;
+// Dropping idle buckets walks all of them, so it's done at most this often
+const SOURCE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub per_source_per_sec: f32,
+    pub per_source_burst: f32,
+    pub global_per_sec: f32,
+    pub global_burst: f32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_source_per_sec: 4.0,
+            per_source_burst: 8.0,
+            global_per_sec: 256.0,
+            global_burst: 512.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f32,
+    capacity: f32,
+    tokens: f32,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full, so a burst is allowed straight away.
+    pub fn new(rate: f32, capacity: f32, now: Instant) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether it has refilled to where a new bucket would start.
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimited {
+    Source,
+    Global,
+}
+
+/// Token buckets for traffic from addresses that haven't proven they own
+/// them: one per source and one shared by everyone.
+///
+/// An IPv6 source is its /64, since a single host can pick any address in it.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    global: TokenBucket,
+    sources: HashMap<IpAddr, TokenBucket>,
+    last_sweep: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            global: TokenBucket::new(config.global_per_sec, config.global_burst, Instant::now()),
+            sources: HashMap::new(),
+            last_sweep: Instant::now(),
+            config,
+        }
+    }
+
+    pub fn check(&mut self, ip: IpAddr) -> Result<(), RateLimited> {
+        self.check_at(ip, Instant::now())
+    }
+
+    pub fn check_at(&mut self, ip: IpAddr, now: Instant) -> Result<(), RateLimited> {
+        let source = source_of(ip);
+        if !self.sources.contains_key(&source) && self.sources.len() >= MAX_TRACKED_SOURCES {
+            if now.saturating_duration_since(self.last_sweep) >= SOURCE_SWEEP_INTERVAL {
+                self.last_sweep = now;
+                self.sources.retain(|_, bucket| !bucket.is_full(now));
+            }
+            // Every tracked source is busy, which is a flood in itself
+            if self.sources.len() >= MAX_TRACKED_SOURCES {
+                return Err(RateLimited::Global);
+            }
+        }
+
+        let config = &self.config;
+        let bucket = self.sources.entry(source).or_insert_with(|| {
+            TokenBucket::new(config.per_source_per_sec, config.per_source_burst, now)
+        });
+        if !bucket.try_take(now) {
+            return Err(RateLimited::Source);
+        }
+        if !self.global.try_take(now) {
+            return Err(RateLimited::Global);
+        }
+        Ok(())
+    }
+
+    pub fn tracked_sources(&self) -> usize {
+        self.sources.len()
+    }
+}
+
+fn source_of(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(v6) => {
+            let prefix = u128::from(v6) & !((1u128 << 64) - 1);
+            IpAddr::V6(prefix.into())
+        }
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_bucket_refills() {
+        let config = RateLimitConfig {
+            per_source_per_sec: 2.0,
+            per_source_burst: 3.0,
+            ..Default::default()
+        };
+        let mut limiter = RateLimiter::new(config);
+        let now = Instant::now();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(ip, now).is_ok());
+        }
+        assert_eq!(limiter.check_at(ip, now), Err(RateLimited::Source));
+
+        // Someone else is unaffected, and half a second buys one more
+        assert!(
+            limiter
+                .check_at("203.0.113.8".parse().unwrap(), now)
+                .is_ok()
+        );
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check_at(ip, later).is_ok());
+        assert!(limiter.check_at(ip, later).is_err());
+    }
+
+    #[test]
+    fn test_global_bucket_and_ipv6_prefixes() {
+        let config = RateLimitConfig {
+            global_per_sec: 1.0,
+            global_burst: 4.0,
+            ..Default::default()
+        };
+        let mut limiter = RateLimiter::new(config);
+        let now = Instant::now();
+
+        // Spoofing addresses across the network only drains the shared bucket
+        for i in 0..4u8 {
+            let ip = IpAddr::from([198, 51, 100, i]);
+            assert!(limiter.check_at(ip, now).is_ok());
+        }
+        let ip = IpAddr::from([198, 51, 100, 200]);
+        assert_eq!(limiter.check_at(ip, now), Err(RateLimited::Global));
+
+        // Addresses in one /64 share a bucket
+        let mut limiter = RateLimiter::new(RateLimitConfig::default());
+        for i in 0..8u16 {
+            let ip = IpAddr::from([0x2001, 0xdb8, 0, 1, 0, 0, 0, i]);
+            assert!(limiter.check_at(ip, now).is_ok());
+        }
+        let ip = IpAddr::from([0x2001, 0xdb8, 0, 1, 0xffff, 0, 0, 1]);
+        assert_eq!(limiter.check_at(ip, now), Err(RateLimited::Source));
+        assert_eq!(limiter.tracked_sources(), 1);
+    }
+
+    #[test]
+    fn test_full_table_is_swept_at_most_once_per_interval() {
+        let config = RateLimitConfig {
+            global_per_sec: 1e6,
+            global_burst: 1e6,
+            ..Default::default()
+        };
+        let mut limiter = RateLimiter::new(config);
+        let now = Instant::now();
+        let ip = |i: usize| IpAddr::from([10, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+
+        for i in 0..MAX_TRACKED_SOURCES {
+            assert!(limiter.check_at(ip(i), now).is_ok());
+        }
+        let stranger = ip(MAX_TRACKED_SOURCES);
+        assert_eq!(limiter.check_at(stranger, now), Err(RateLimited::Global));
+
+        // Long enough for every bucket to refill, but the last sweep was just now
+        let refilled = now + Duration::from_secs(2);
+        limiter.last_sweep = refilled;
+        assert_eq!(
+            limiter.check_at(stranger, refilled),
+            Err(RateLimited::Global)
+        );
+        assert_eq!(limiter.tracked_sources(), MAX_TRACKED_SOURCES);
+
+        let later = refilled + SOURCE_SWEEP_INTERVAL;
+        assert!(limiter.check_at(stranger, later).is_ok());
+        assert_eq!(limiter.tracked_sources(), 1);
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rkyv::{Archive, Deserialize, Serialize};
+
+use super::endpoint::NetworkEndpoint;
+use super::protocol::{Packet, PacketHeader, PacketType, QueryInfo};
+use super::query::QueryChallenger;
+use super::token::Key;
+use super::transport::{Transport, UdpTransport};
+
+pub const MASTER_PORT: u16 = 27010;
+pub const MASTER_HEARTBEAT_SECS: u64 = 30;
+/// Servers missing this many heartbeats in a row are dropped from the list.
+pub const MASTER_MISSED_HEARTBEATS: u32 = 3;
+/// Most servers in one list reply, which keeps it inside a couple of fragments.
+pub const MASTER_PAGE_SIZE: usize = 16;
+
+const MASTER_RESEND_INTERVAL: Duration = Duration::from_millis(500);
+const MASTER_FETCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What a client wants listed. Empty fields match anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct ServerFilter {
+    pub map: Option<String>,
+    pub game_mode: Option<String>,
+    pub not_full: bool,
+    pub no_password: bool,
+}
+
+impl ServerFilter {
+    pub fn matches(&self, info: &QueryInfo) -> bool {
+        self.map.as_ref().is_none_or(|map| *map == info.map)
+            && self
+                .game_mode
+                .as_ref()
+                .is_none_or(|mode| *mode == info.game_mode)
+            && !(self.not_full && info.players >= info.max_players)
+            && !(self.no_password && info.has_password)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct MasterEntry {
+    pub addr: SocketAddr,
+    pub info: QueryInfo,
+}
+
+/// The master server's list of game servers, fed by their heartbeats.
+///
+/// Heartbeats and list requests both need a `QueryChallenge` first, so
+/// nobody can list a server from an address they don't receive at, or have
+/// a list bounced at someone else.
+pub struct MasterRegistry {
+    servers: HashMap<SocketAddr, (QueryInfo, Instant)>,
+    challenger: QueryChallenger,
+    expiry: Duration,
+}
+
+impl MasterRegistry {
+    pub fn new(expiry: Duration, challenge_key: &Key) -> Self {
+        Self {
+            servers: HashMap::new(),
+            challenger: QueryChallenger::new(challenge_key),
+            expiry,
+        }
+    }
+
+    /// Handles one packet from `addr`, returning the reply to send if any.
+    pub fn handle_packet(&mut self, packet: Packet, addr: SocketAddr) -> Option<Packet> {
+        let message = packet.messages.into_iter().next()?;
+        let reply = match message.payload {
+            PacketType::MasterHeartbeat { challenge, info } => {
+                if !self.challenger.verify(addr, challenge) {
+                    self.challenge(addr)
+                } else {
+                    if self.servers.insert(addr, (info, Instant::now())).is_none() {
+                        log::info!("Registered server {}", addr);
+                    }
+                    return None;
+                }
+            }
+            PacketType::MasterListRequest {
+                filter,
+                offset,
+                challenge,
+            } => {
+                if !self.challenger.verify(addr, challenge) {
+                    self.challenge(addr)
+                } else {
+                    self.list(&filter, offset)
+                }
+            }
+            _ => return None,
+        };
+        Some(Packet::new(PacketHeader::new(0, 0, 0), reply))
+    }
+
+    fn challenge(&self, addr: SocketAddr) -> PacketType {
+        PacketType::QueryChallenge {
+            challenge: self.challenger.challenge_for(addr),
+        }
+    }
+
+    fn list(&self, filter: &ServerFilter, offset: u32) -> PacketType {
+        let matching = self.list_matching(filter);
+        PacketType::MasterList {
+            total: matching.len() as u32,
+            offset,
+            servers: matching
+                .into_iter()
+                .skip(offset as usize)
+                .take(MASTER_PAGE_SIZE)
+                .collect(),
+        }
+    }
+
+    /// Every live server `filter` lets through, in a stable order so pages
+    /// line up between requests.
+    pub fn list_matching(&self, filter: &ServerFilter) -> Vec<MasterEntry> {
+        let mut matching: Vec<MasterEntry> = self
+            .servers
+            .iter()
+            .filter(|(_, (info, _))| filter.matches(info))
+            .map(|(&addr, (info, _))| MasterEntry {
+                addr,
+                info: info.clone(),
+            })
+            .collect();
+        matching.sort_by_key(|entry| entry.addr);
+        matching
+    }
+
+    /// Drops servers that stopped sending heartbeats, returning their addresses.
+    pub fn expire(&mut self) -> Vec<SocketAddr> {
+        let expired: Vec<SocketAddr> = self
+            .servers
+            .iter()
+            .filter(|(_, (_, last_seen))| last_seen.elapsed() >= self.expiry)
+            .map(|(&addr, _)| addr)
+            .collect();
+        for addr in &expired {
+            self.servers.remove(addr);
+        }
+        expired
+    }
+
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+}
+
+/// A game server's side of registering with one master.
+pub struct MasterLink {
+    pub addr: SocketAddr,
+    challenge: u64,
+    interval: Duration,
+    last_sent: Option<Instant>,
+}
+
+impl MasterLink {
+    pub fn new(addr: SocketAddr, interval: Duration) -> Self {
+        Self {
+            addr,
+            challenge: 0,
+            interval,
+            last_sent: None,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.last_sent
+            .is_none_or(|sent| sent.elapsed() >= self.interval)
+    }
+
+    pub fn heartbeat(&mut self, info: QueryInfo) -> Packet {
+        self.last_sent = Some(Instant::now());
+        Packet::new(
+            PacketHeader::new(0, 0, 0),
+            PacketType::MasterHeartbeat {
+                challenge: self.challenge,
+                info,
+            },
+        )
+    }
+
+    /// The master wants the heartbeat again with this challenge, right away.
+    pub fn set_challenge(&mut self, challenge: u64) {
+        self.challenge = challenge;
+        self.last_sent = None;
+    }
+}
+
+/// Fetches a server list from a master a page at a time, without blocking.
+pub struct MasterBrowser<T: Transport = UdpTransport> {
+    endpoint: NetworkEndpoint<T>,
+    master: SocketAddr,
+    filter: ServerFilter,
+    challenge: u64,
+    servers: Vec<MasterEntry>,
+    // Pages of the fetch in progress, swapped in once complete
+    incoming: Vec<MasterEntry>,
+    fetch_started: Option<Instant>,
+    last_request: Option<Instant>,
+    timed_out: bool,
+}
+
+impl MasterBrowser<UdpTransport> {
+    pub fn bind(master: SocketAddr, filter: ServerFilter) -> io::Result<Self> {
+        Self::with_transport(UdpTransport::bind_for(master)?, master, filter)
+    }
+}
+
+impl<T: Transport> MasterBrowser<T> {
+    pub fn with_transport(
+        transport: T,
+        master: SocketAddr,
+        filter: ServerFilter,
+    ) -> io::Result<Self> {
+        let mut browser = Self {
+            endpoint: NetworkEndpoint::with_transport(transport)?,
+            master,
+            filter,
+            challenge: 0,
+            servers: Vec::new(),
+            incoming: Vec::new(),
+            fetch_started: None,
+            last_request: None,
+            timed_out: false,
+        };
+        browser.refresh();
+        Ok(browser)
+    }
+
+    pub fn filter(&self) -> &ServerFilter {
+        &self.filter
+    }
+
+    /// Starts over with a new filter.
+    pub fn set_filter(&mut self, filter: ServerFilter) {
+        self.filter = filter;
+        self.refresh();
+    }
+
+    /// Fetches the list again. The current one stays up until it arrives.
+    pub fn refresh(&mut self) {
+        self.incoming.clear();
+        self.fetch_started = Some(Instant::now());
+        self.last_request = None;
+        self.timed_out = false;
+    }
+
+    pub fn poll(&mut self) -> io::Result<()> {
+        let Some(started) = self.fetch_started else {
+            // Drain anything late so it doesn't pile up
+            self.endpoint.receive()?;
+            return Ok(());
+        };
+        if started.elapsed() >= MASTER_FETCH_TIMEOUT {
+            self.fetch_started = None;
+            self.timed_out = true;
+            return Ok(());
+        }
+
+        if self
+            .last_request
+            .is_none_or(|sent| sent.elapsed() >= MASTER_RESEND_INTERVAL)
+        {
+            let request = Packet::new(
+                PacketHeader::new(0, 0, 0),
+                PacketType::MasterListRequest {
+                    filter: self.filter.clone(),
+                    offset: self.incoming.len() as u32,
+                    challenge: self.challenge,
+                },
+            );
+            self.last_request = Some(Instant::now());
+            self.endpoint.send_to(&request, self.master)?;
+        }
+
+        for (packet, addr) in self.endpoint.receive()? {
+            if addr != self.master {
+                continue;
+            }
+            for message in packet.messages {
+                match message.payload {
+                    PacketType::QueryChallenge { challenge } => {
+                        self.challenge = challenge;
+                        self.last_request = None;
+                    }
+                    // Pages for an offset we've moved past are resends
+                    PacketType::MasterList {
+                        total,
+                        offset,
+                        servers,
+                    } if offset as usize == self.incoming.len() => {
+                        self.incoming.extend(servers);
+                        if self.incoming.len() >= total as usize {
+                            self.servers = std::mem::take(&mut self.incoming);
+                            self.fetch_started = None;
+                        } else {
+                            self.last_request = None;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn servers(&self) -> &[MasterEntry] {
+        &self.servers
+    }
+
+    pub fn is_fetching(&self) -> bool {
+        self.fetch_started.is_some()
+    }
+
+    /// Whether the last fetch gave up without hearing back from the master.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(players: u32, has_password: bool) -> QueryInfo {
+        QueryInfo {
+            name: "test".to_string(),
+            map: "testing_ground".to_string(),
+            map_checksum: 0,
+            game_mode: "deathmatch".to_string(),
+            has_password,
+            protocol_version: 1,
+            tick_rate: 60,
+            players,
+            max_players: 8,
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let open = info(3, false);
+        assert!(ServerFilter::default().matches(&open));
+        assert!(
+            ServerFilter {
+                map: Some("testing_ground".to_string()),
+                game_mode: Some("deathmatch".to_string()),
+                not_full: true,
+                no_password: true,
+            }
+            .matches(&open)
+        );
+
+        let other_map = ServerFilter {
+            map: Some("arena".to_string()),
+            ..Default::default()
+        };
+        assert!(!other_map.matches(&open));
+
+        let not_full = ServerFilter {
+            not_full: true,
+            ..Default::default()
+        };
+        assert!(!not_full.matches(&info(8, false)));
+
+        let no_password = ServerFilter {
+            no_password: true,
+            ..Default::default()
+        };
+        assert!(!no_password.matches(&info(3, true)));
+    }
+
+    #[test]
+    fn test_heartbeat_needs_challenge() {
+        let mut registry = MasterRegistry::new(Duration::from_secs(90), &[7; 32]);
+        let server: SocketAddr = "10.0.0.1:27015".parse().unwrap();
+        let mut link = MasterLink::new("10.0.0.2:27010".parse().unwrap(), Duration::from_secs(30));
+
+        let reply = registry
+            .handle_packet(link.heartbeat(info(0, false)), server)
+            .expect("Expected a challenge");
+        assert!(registry.is_empty());
+        assert!(!link.is_due());
+
+        let PacketType::QueryChallenge { challenge } = reply.messages[0].payload else {
+            panic!("Expected QueryChallenge");
+        };
+        link.set_challenge(challenge);
+        assert!(link.is_due());
+
+        assert!(
+            registry
+                .handle_packet(link.heartbeat(info(0, false)), server)
+                .is_none()
+        );
+        assert_eq!(registry.len(), 1);
+
+        // The same challenge from anywhere else is no good
+        let spoofed: SocketAddr = "10.0.0.3:27015".parse().unwrap();
+        assert!(
+            registry
+                .handle_packet(link.heartbeat(info(0, false)), spoofed)
+                .is_some()
+        );
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn test_silent_servers_expire() {
+        let mut registry = MasterRegistry::new(Duration::ZERO, &[7; 32]);
+        let server: SocketAddr = "10.0.0.1:27015".parse().unwrap();
+        let mut link = MasterLink::new("10.0.0.2:27010".parse().unwrap(), Duration::from_secs(30));
+        link.set_challenge(registry.challenger.challenge_for(server));
+        registry.handle_packet(link.heartbeat(info(0, false)), server);
+
+        assert_eq!(registry.expire(), vec![server]);
+        assert!(registry.is_empty());
+    }
+}
@@ -1,15 +1,75 @@
+mod bandwidth;
+mod block;
+mod capture;
+mod channel;
+mod codec;
+mod conditioner;
 mod connection;
+mod crypto;
+mod discovery;
 mod endpoint;
+mod fragment;
+mod limiter;
+mod master;
+mod mtu;
 mod protocol;
+mod query;
 mod stats;
+mod token;
 mod tracking;
+mod transport;
 
+pub use bandwidth::{BandwidthConfig, BandwidthEstimator};
+pub use block::{
+    BLOCK_WINDOW, BlockDirection, BlockError, BlockProgress, BlockTransfers, CHUNK_OVERHEAD,
+    CompletedBlock, MAX_BLOCK_SIZE,
+};
+pub use capture::{
+    CAPTURE_MAGIC, CAPTURE_VERSION, CaptureDirection, CaptureReader, CaptureRecord, CaptureWriter,
+    KeyLog, KeyLogWriter,
+};
+pub use channel::{
+    CHANNEL_WINDOW, ChannelError, MAX_SEND_QUEUE, OrderedReceiveWindow, ReliableReceiveWindow,
+    SendChannel, sequence_greater_than_u16,
+};
+pub use codec::{BitReader, BitWriter, OrientationEncoding, QuantizationConfig, SnapshotCodec};
+pub use conditioner::{ConditionerStats, LinkConditioner, SimRng};
 pub use connection::{ClientConnection, ConnectionManager, ConnectionState, Reliability};
+pub use crypto::PacketCipher;
+pub use discovery::{LAN_PROBE_INTERVAL, LanBrowser, LanServer, is_lan_address};
 pub use endpoint::NetworkEndpoint;
-pub use protocol::{ArchivedPacket, sequence_greater_than};
+pub use fragment::{
+    FRAGMENT_SIZE, FragmentAssembler, MAX_FRAGMENTS, fragment_size_for, split_packet,
+};
+pub use limiter::{RateLimitConfig, RateLimited, RateLimiter, TokenBucket};
+pub use master::{
+    MASTER_HEARTBEAT_SECS, MASTER_MISSED_HEARTBEATS, MASTER_PAGE_SIZE, MASTER_PORT, MasterBrowser,
+    MasterEntry, MasterLink, MasterRegistry, ServerFilter,
+};
+pub use mtu::{MTU_PROBE_SIZES, MtuDiscovery};
 pub use protocol::{
-    ClientCommand, DEFAULT_PORT, DEFAULT_TICK_RATE, EntityState, LobbyInfo, MAX_PACKET_SIZE,
-    PROTOCOL_MAGIC, PROTOCOL_VERSION, Packet, PacketError, PacketHeader, PacketType, WorldSnapshot,
+    ArchivedPacket, ArchivedPacketType, SMALLEST_THREE_MAX_BITS, compress_quat_smallest_three,
+    decompress_quat_smallest_three, quat_to_view_angles, sequence_greater_than,
+    view_angles_to_quat,
+};
+pub use protocol::{
+    BlockKind, CONNECTION_REQUEST_SIZE, ClientCommand, ClientCommandBatch, CommandBufferStatus,
+    CommandDelta, DEFAULT_PORT, DEFAULT_TICK_RATE, DenyReason, EntityDelta, EntityState, LobbyInfo,
+    MAX_MTU, MAX_PACKET_SIZE, MIN_MTU, MIN_PROTOCOL_VERSION, Message, PACKET_PREFIX_SIZE,
+    PROTOCOL_MAGIC, PROTOCOL_VERSION, Packet, PacketError, PacketHeader, PacketType,
+    QUERY_REQUEST_SIZE, QueryInfo, QueryKind, QueryPlayer, QueryResponse, QueryRule,
+    VERSION_DENIAL_MAGIC, VERSION_DENIAL_SIZE, WorldSnapshot, is_version_supported, read_prefix,
+};
+pub use query::{QUERY_CHALLENGE_SECS, QueryChallenger, QueryClient};
+pub use stats::{
+    BurstLoss, ConnectionStats, NetworkStats, PacketLossSimulation, STATS_HISTORY_LEN,
+    STATS_SAMPLE_INTERVAL, StatsHistory, StatsSample,
+};
+pub use token::{
+    ChallengeToken, ConnectToken, DEFAULT_TOKEN_EXPIRY_SECS, DEV_PRIVATE_KEY, KEY_BYTES, Key,
+    MAX_SERVER_ADDRESSES, PrivateConnectToken, SessionToken, TOKEN_NONCE_BYTES, TokenError,
+    TokenIssuer, TokenReplayCache, generate_client_id, generate_key, key_from_hex, key_to_hex,
+    unix_timestamp,
 };
-pub use stats::{NetworkStats, PacketLossSimulation};
 pub use tracking::{AckTracker, PendingPacket, ReceiveTracker};
+pub use transport::{LoopbackHub, LoopbackTransport, Transport, UdpTransport, is_too_large};
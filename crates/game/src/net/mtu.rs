@@ -0,0 +1,251 @@
+use std::time::{Duration, Instant};
+
+use super::protocol::{MAX_MTU, MAX_PACKET_SIZE, MIN_MTU};
+
+/// Probe ladder, smallest first so a path that can't carry the default
+/// still finds something that fits.
+pub const MTU_PROBE_SIZES: [usize; 7] = [MIN_MTU, 1024, MAX_PACKET_SIZE, 1280, 1400, 1452, MAX_MTU];
+
+const MAX_PROBE_ATTEMPTS: u32 = 3;
+const MIN_PROBE_TIMEOUT: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy)]
+struct Probe {
+    size: usize,
+    sequence: u32,
+    sent_at: Instant,
+}
+
+/// Path MTU search for one connection. Each probe is a packet padded up to
+/// the size being tested; an ack for it confirms the size, and a size that
+/// is never acked after a few attempts ends the search.
+#[derive(Debug, Clone)]
+pub struct MtuDiscovery {
+    mtu: usize,
+    confirmed: Option<usize>,
+    next: usize,
+    attempts: u32,
+    probe: Option<Probe>,
+    active: bool,
+}
+
+impl Default for MtuDiscovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MtuDiscovery {
+    pub fn new() -> Self {
+        Self {
+            mtu: MAX_PACKET_SIZE,
+            confirmed: None,
+            next: 0,
+            attempts: 0,
+            probe: None,
+            active: false,
+        }
+    }
+
+    pub fn start(&mut self) {
+        *self = Self {
+            active: true,
+            ..Self::new()
+        };
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    pub fn is_probing(&self) -> bool {
+        self.active
+    }
+
+    pub fn probe_sequence(&self) -> Option<u32> {
+        self.probe.map(|probe| probe.sequence)
+    }
+
+    pub fn probe_size(&self) -> Option<usize> {
+        self.probe.map(|probe| probe.size)
+    }
+
+    /// Size of the probe to send now, if one is due.
+    pub fn poll(&mut self, now: Instant, rtt_ms: f32) -> Option<usize> {
+        if !self.active {
+            return None;
+        }
+
+        if let Some(probe) = self.probe {
+            let timeout =
+                Duration::from_secs_f32(rtt_ms.max(0.0) * 2.0 / 1000.0).max(MIN_PROBE_TIMEOUT);
+            if now.duration_since(probe.sent_at) < timeout {
+                return None;
+            }
+
+            self.probe = None;
+            self.attempts += 1;
+            if self.attempts >= MAX_PROBE_ATTEMPTS {
+                self.finish(self.confirmed.unwrap_or(MIN_MTU));
+                return None;
+            }
+        }
+
+        Some(MTU_PROBE_SIZES[self.next])
+    }
+
+    pub fn on_probe_sent(&mut self, size: usize, sequence: u32, now: Instant) {
+        self.probe = Some(Probe {
+            size,
+            sequence,
+            sent_at: now,
+        });
+    }
+
+    pub fn on_acked(&mut self, sequence: u32) {
+        let Some(probe) = self.probe.filter(|probe| probe.sequence == sequence) else {
+            return;
+        };
+
+        self.probe = None;
+        self.attempts = 0;
+        self.confirmed = Some(probe.size);
+        self.mtu = self.mtu.max(probe.size);
+        self.next += 1;
+
+        if self.next == MTU_PROBE_SIZES.len() {
+            self.finish(probe.size);
+        }
+    }
+
+    /// The local stack refused the probe outright, so its size is out of
+    /// reach and the search ends on the last size that got through.
+    pub fn on_probe_too_large(&mut self, sequence: u32) {
+        if self.probe_sequence() == Some(sequence) {
+            self.finish(self.confirmed.unwrap_or(MIN_MTU));
+        }
+    }
+
+    /// An ordinary packet no longer fits the path: step down to the next
+    /// size on the ladder and probe upward again from there.
+    pub fn on_too_large(&mut self) {
+        let mtu = MTU_PROBE_SIZES
+            .iter()
+            .rev()
+            .copied()
+            .find(|&size| size < self.mtu)
+            .unwrap_or(MIN_MTU);
+        let next = MTU_PROBE_SIZES
+            .iter()
+            .position(|&size| size > mtu)
+            .unwrap_or(MTU_PROBE_SIZES.len());
+
+        *self = Self {
+            mtu,
+            confirmed: Some(mtu),
+            next,
+            attempts: 0,
+            probe: None,
+            active: next < MTU_PROBE_SIZES.len(),
+        };
+    }
+
+    fn finish(&mut self, mtu: usize) {
+        self.mtu = mtu;
+        self.active = false;
+        self.probe = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(discovery: &mut MtuDiscovery, path_mtu: usize) {
+        let mut now = Instant::now();
+        let mut sequence = 0;
+
+        while discovery.is_probing() {
+            if let Some(size) = discovery.poll(now, 50.0) {
+                discovery.on_probe_sent(size, sequence, now);
+                if size <= path_mtu {
+                    discovery.on_acked(sequence);
+                }
+                sequence += 1;
+            }
+            now += Duration::from_millis(100);
+        }
+    }
+
+    #[test]
+    fn test_discovery_climbs_to_path_mtu() {
+        let mut discovery = MtuDiscovery::new();
+        discovery.start();
+        run(&mut discovery, MAX_MTU);
+        assert_eq!(discovery.mtu(), MAX_MTU);
+
+        discovery.start();
+        run(&mut discovery, 1400);
+        assert_eq!(discovery.mtu(), 1400);
+    }
+
+    #[test]
+    fn test_discovery_drops_below_default() {
+        let mut discovery = MtuDiscovery::new();
+        discovery.start();
+        run(&mut discovery, 1100);
+        assert_eq!(discovery.mtu(), 1024);
+
+        discovery.start();
+        run(&mut discovery, 0);
+        assert_eq!(discovery.mtu(), MIN_MTU);
+    }
+
+    #[test]
+    fn test_probe_is_retried_before_giving_up() {
+        let mut discovery = MtuDiscovery::new();
+        discovery.start();
+        let now = Instant::now();
+
+        assert_eq!(discovery.poll(now, 50.0), Some(MIN_MTU));
+        discovery.on_probe_sent(MIN_MTU, 1, now);
+        assert_eq!(discovery.poll(now + Duration::from_millis(100), 50.0), None);
+
+        // Lost once, the same size goes out again
+        assert_eq!(
+            discovery.poll(now + Duration::from_millis(300), 50.0),
+            Some(MIN_MTU)
+        );
+        discovery.on_probe_sent(MIN_MTU, 2, now);
+        discovery.on_acked(1);
+        assert_eq!(discovery.probe_sequence(), Some(2));
+        discovery.on_acked(2);
+        assert_eq!(discovery.poll(now, 50.0), Some(1024));
+    }
+
+    #[test]
+    fn test_shrunken_path_steps_down_and_probes_again() {
+        let mut discovery = MtuDiscovery::new();
+        discovery.start();
+        run(&mut discovery, 1400);
+        assert_eq!(discovery.mtu(), 1400);
+
+        discovery.on_too_large();
+        assert_eq!(discovery.mtu(), 1280);
+        assert!(discovery.is_probing());
+
+        // The probe back up is refused locally too, so 1280 sticks
+        let now = Instant::now();
+        assert_eq!(discovery.poll(now, 50.0), Some(1400));
+        discovery.on_probe_sent(1400, 9, now);
+        discovery.on_probe_too_large(9);
+        assert!(!discovery.is_probing());
+        assert_eq!(discovery.mtu(), 1280);
+
+        // The bottom of the ladder is as low as it goes
+        for _ in 0..MTU_PROBE_SIZES.len() {
+            discovery.on_too_large();
+        }
+        assert_eq!(discovery.mtu(), MIN_MTU);
+    }
+}
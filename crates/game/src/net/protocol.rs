@@ -1,12 +1,43 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use glam::{EulerRot, Quat};
+use rkyv::util::AlignedVec;
 use rkyv::{Archive, Deserialize, Serialize, rancor};
 
+use super::codec::{BitReader, BitWriter, SnapshotCodec};
+use super::master::{MasterEntry, ServerFilter};
+use super::token::TOKEN_NONCE_BYTES;
+
+/// Default MTU, used until path MTU discovery settles on one.
 pub const MAX_PACKET_SIZE: usize = 1200;
-pub const PROTOCOL_VERSION: u32 = 1;
+/// Bounds for discovered MTUs: the smallest datagram IPv4 guarantees and a
+/// full Ethernet frame, minus IP and UDP headers.
+pub const MIN_MTU: usize = 548;
+pub const MAX_MTU: usize = 1472;
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest client protocol this build still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
 pub const PROTOCOL_MAGIC: u32 = 0x4455414C;
+/// Every datagram opens with the magic and protocol version, outside the
+/// archive, so a peer is understood to speak another version even when the
+/// rest of its packet can't be read.
+pub const PACKET_PREFIX_SIZE: usize = 8;
+/// Opens the fixed-layout reply to a peer speaking a version we don't.
+pub const VERSION_DENIAL_MAGIC: u32 = 0x44554156;
+pub const VERSION_DENIAL_SIZE: usize = 16;
 pub const DEFAULT_PORT: u16 = 27015;
 pub const DEFAULT_TICK_RATE: u32 = 60;
+/// Connection requests are padded to at least this, and smaller ones go
+/// unanswered, so no reply to an unverified address outweighs its request.
+pub const CONNECTION_REQUEST_SIZE: usize = 512;
+/// Queries and LAN discovery are padded the same way, to where any answer
+/// that doesn't need fragmenting fits.
+pub const QUERY_REQUEST_SIZE: usize = MAX_PACKET_SIZE;
+
+pub const SMALLEST_THREE_MAX_BITS: u32 = 10;
 
 const SEQUENCE_WRAP_THRESHOLD: u32 = u32::MAX / 2;
+const VIEW_ANGLE_ROLL_EPSILON: f32 = 1e-3;
 
 fn normalize_angle(angle: f32) -> f32 {
     let two_pi = std::f32::consts::TAU;
@@ -22,35 +53,30 @@ fn normalize_angle(angle: f32) -> f32 {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Archive, Serialize, Deserialize)]
 #[rkyv(compare(PartialEq), derive(Debug))]
 pub struct PacketHeader {
-    pub magic: u32,
-    pub version: u32,
     pub sequence: u32,
     pub ack: u32,
     pub ack_bitfield: u32,
-    pub channel: u8,
-    pub channel_seq: u16,
 }
 
 impl PacketHeader {
-    pub const CHANNEL_UNRELIABLE: u8 = 0;
-    pub const CHANNEL_RELIABLE: u8 = 1;
-    pub const CHANNEL_ORDERED: u8 = 2;
-
-    pub fn new(sequence: u32, ack: u32, ack_bitfield: u32, channel: u8, channel_seq: u16) -> Self {
+    pub fn new(sequence: u32, ack: u32, ack_bitfield: u32) -> Self {
         Self {
-            magic: PROTOCOL_MAGIC,
-            version: PROTOCOL_VERSION,
             sequence,
             ack,
             ack_bitfield,
-            channel,
-            channel_seq,
         }
     }
+}
 
-    pub fn is_valid(&self) -> bool {
-        self.magic == PROTOCOL_MAGIC && self.version == PROTOCOL_VERSION
-    }
+pub fn is_version_supported(version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+/// The magic and protocol version a datagram opens with.
+pub fn read_prefix(data: &[u8]) -> Option<(u32, u32)> {
+    let magic = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    let version = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?);
+    Some((magic, version))
 }
 
 #[inline]
@@ -59,33 +85,224 @@ pub fn sequence_greater_than(s1: u32, s2: u32) -> bool {
         || ((s1 < s2) && (s2 - s1 > SEQUENCE_WRAP_THRESHOLD))
 }
 
+/// Packs a unit quaternion as the index of its largest component plus the
+/// other three quantized to `component_bits` each (`2 + 3 * component_bits` bits).
+pub fn compress_quat_smallest_three(quat: [f32; 4], component_bits: u32) -> u32 {
+    debug_assert!((1..=SMALLEST_THREE_MAX_BITS).contains(&component_bits));
+
+    let length = quat.iter().map(|c| c * c).sum::<f32>().sqrt();
+    let mut quat = if length > f32::EPSILON {
+        quat.map(|c| c / length)
+    } else {
+        [0.0, 0.0, 0.0, 1.0]
+    };
+
+    let largest = (0..4)
+        .max_by(|&a, &b| quat[a].abs().total_cmp(&quat[b].abs()))
+        .unwrap_or(3);
+    // q and -q are the same rotation, so the dropped component can always be positive
+    if quat[largest] < 0.0 {
+        quat = quat.map(|c| -c);
+    }
+
+    let max = ((1u32 << component_bits) - 1) as f32;
+    let mut packed = largest as u32;
+    let mut shift = 2;
+    for (i, &component) in quat.iter().enumerate() {
+        if i == largest {
+            continue;
+        }
+        let normalized = (component / FRAC_1_SQRT_2 * 0.5 + 0.5).clamp(0.0, 1.0);
+        packed |= ((normalized * max).round() as u32) << shift;
+        shift += component_bits;
+    }
+    packed
+}
+
+pub fn decompress_quat_smallest_three(packed: u32, component_bits: u32) -> [f32; 4] {
+    debug_assert!((1..=SMALLEST_THREE_MAX_BITS).contains(&component_bits));
+
+    let largest = (packed & 0b11) as usize;
+    let mask = (1u32 << component_bits) - 1;
+    let max = mask as f32;
+
+    let mut quat = [0.0; 4];
+    let mut shift = 2;
+    let mut sum_squares = 0.0;
+    for (i, component) in quat.iter_mut().enumerate() {
+        if i == largest {
+            continue;
+        }
+        let value = ((packed >> shift) & mask) as f32 / max;
+        *component = (value * 2.0 - 1.0) * FRAC_1_SQRT_2;
+        sum_squares += *component * *component;
+        shift += component_bits;
+    }
+    quat[largest] = (1.0 - sum_squares).max(0.0).sqrt();
+
+    let length = quat.iter().map(|c| c * c).sum::<f32>().sqrt();
+    quat.map(|c| c / length)
+}
+
+/// Splits a player orientation back into the view angles it was built from,
+/// returning `None` if the rotation has roll and can't be expressed that way.
+pub fn quat_to_view_angles(quat: [f32; 4]) -> Option<(f32, f32)> {
+    let quat = Quat::from_array(quat).normalize();
+    let (yaw, x, roll) = quat.to_euler(EulerRot::YXZ);
+    (roll.abs() <= VIEW_ANGLE_ROLL_EPSILON).then_some((yaw, -x))
+}
+
+pub fn view_angles_to_quat(yaw: f32, pitch: f32) -> [f32; 4] {
+    Quat::from_euler(EulerRot::YXZ, yaw, -pitch, 0.0).to_array()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub enum DenyReason {
+    ServerFull,
+    Banned,
+    InvalidToken,
+    AlreadyConnected,
+    VersionMismatch {
+        server_version: u32,
+        min_version: u32,
+        max_version: u32,
+    },
+    MapMismatch {
+        server_checksum: u64,
+    },
+    SessionExpired,
+}
+
+impl DenyReason {
+    pub fn version_mismatch() -> Self {
+        Self::VersionMismatch {
+            server_version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+        }
+    }
+
+    /// What a peer speaking another version is sent instead of a packet.
+    /// Its layout must never change, so any build can read it.
+    pub fn encode_version_denial() -> [u8; VERSION_DENIAL_SIZE] {
+        let mut data = [0u8; VERSION_DENIAL_SIZE];
+        data[0..4].copy_from_slice(&VERSION_DENIAL_MAGIC.to_le_bytes());
+        data[4..8].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        data[8..12].copy_from_slice(&MIN_PROTOCOL_VERSION.to_le_bytes());
+        data[12..16].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        data
+    }
+
+    pub fn decode_version_denial(data: &[u8]) -> Option<Self> {
+        if data.len() != VERSION_DENIAL_SIZE {
+            return None;
+        }
+        let (magic, server_version) = read_prefix(data)?;
+        if magic != VERSION_DENIAL_MAGIC {
+            return None;
+        }
+        let (min_version, max_version) = read_prefix(&data[8..])?;
+        Some(Self::VersionMismatch {
+            server_version,
+            min_version,
+            max_version,
+        })
+    }
+}
+
+impl std::fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ServerFull => write!(f, "server full"),
+            Self::Banned => write!(f, "banned"),
+            Self::InvalidToken => write!(f, "invalid connect token"),
+            Self::AlreadyConnected => write!(f, "client already connected"),
+            Self::VersionMismatch {
+                server_version,
+                min_version,
+                max_version,
+            } => write!(
+                f,
+                "protocol version mismatch (server v{}, accepts v{}-v{})",
+                server_version, min_version, max_version
+            ),
+            Self::MapMismatch { server_checksum } => {
+                write!(f, "map mismatch (server map {:016x})", server_checksum)
+            }
+            Self::SessionExpired => write!(f, "session expired"),
+        }
+    }
+}
+
+/// What a block transfer carries, so the receiver knows what to do with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub enum BlockKind {
+    ServerConfig,
+    Map,
+    WorldState,
+    Custom(u16),
+}
+
+impl std::fmt::Display for BlockKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ServerConfig => write!(f, "server config"),
+            Self::Map => write!(f, "map"),
+            Self::WorldState => write!(f, "world state"),
+            Self::Custom(kind) => write!(f, "custom block {}", kind),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Archive, Serialize, Deserialize)]
 #[rkyv(derive(Debug))]
 pub enum PacketType {
     ConnectionRequest {
-        client_salt: u64,
+        map_checksum: u64,
+        expire_timestamp: u64,
+        token_nonce: [u8; TOKEN_NONCE_BYTES],
+        token_data: Vec<u8>,
+        padding: Vec<u8>,
     },
     ConnectionChallenge {
-        server_salt: u64,
-        challenge: u64,
+        challenge_sequence: u64,
+        challenge_data: Vec<u8>,
     },
     ChallengeResponse {
-        combined_salt: u64,
+        challenge_sequence: u64,
+        challenge_data: Vec<u8>,
     },
     ConnectionAccepted {
         client_id: u32,
         entity_id: u32,
+        snapshot_codec: SnapshotCodec,
+        // Lets the client reattach to this session from a new address
+        session_token: Vec<u8>,
     },
     ConnectionDenied {
-        reason: String,
+        reason: DenyReason,
+    },
+    /// Reattaches a session from a new address. `packet` is a sealed packet
+    /// of the session, which proves the sender holds its keys.
+    SessionResume {
+        session_token: Vec<u8>,
+        packet: Vec<u8>,
     },
     ClientCommand(ClientCommand),
+    ClientCommandBatch(ClientCommandBatch),
+    CommandBufferStatus(CommandBufferStatus),
     WorldSnapshot(WorldSnapshot),
+    PackedSnapshot(Vec<u8>),
     Ping {
         timestamp: u64,
     },
+    /// Echoes the ping's timestamp with the server's clock when it answered,
+    /// so the client can work out the offset between the two.
     Pong {
         timestamp: u64,
+        server_time_ms: u64,
     },
     SnapshotAck {
         received_tick: u32,
@@ -102,6 +319,122 @@ pub enum PacketType {
         position: u32,
         estimated_wait_secs: u32,
     },
+    Fragment {
+        group_id: u16,
+        index: u8,
+        count: u8,
+        data: Vec<u8>,
+    },
+    Encrypted(Vec<u8>),
+    MtuProbe {
+        padding: Vec<u8>,
+    },
+    BlockChunk {
+        transfer_id: u16,
+        kind: BlockKind,
+        total_size: u32,
+        chunk_size: u16,
+        index: u16,
+        data: Vec<u8>,
+    },
+    BlockAck {
+        transfer_id: u16,
+        index: u16,
+    },
+    BlockCancel {
+        transfer_id: u16,
+        // Whether the side that started the transfer cancelled it
+        from_sender: bool,
+    },
+    /// Asks about the server without connecting. A zero `challenge` is
+    /// answered with a `QueryChallenge` to repeat the request with.
+    QueryRequest {
+        kind: QueryKind,
+        challenge: u64,
+        padding: Vec<u8>,
+    },
+    QueryChallenge {
+        challenge: u64,
+    },
+    QueryResponse(QueryResponse),
+    /// Broadcast on the LAN; `timestamp` is echoed back to time the reply.
+    DiscoveryRequest {
+        timestamp: u64,
+        padding: Vec<u8>,
+    },
+    DiscoveryResponse {
+        timestamp: u64,
+        info: QueryInfo,
+    },
+    /// Keeps a server on a master's list. Challenged like a query.
+    MasterHeartbeat {
+        challenge: u64,
+        info: QueryInfo,
+    },
+    MasterListRequest {
+        filter: ServerFilter,
+        offset: u32,
+        challenge: u64,
+    },
+    /// One page of the servers matching a list request, starting at `offset`.
+    MasterList {
+        total: u32,
+        offset: u32,
+        servers: Vec<MasterEntry>,
+    },
+}
+
+impl PacketType {
+    /// A connection request padded out to [`CONNECTION_REQUEST_SIZE`] once
+    /// sent on its own.
+    pub fn connection_request(
+        map_checksum: u64,
+        expire_timestamp: u64,
+        token_nonce: [u8; TOKEN_NONCE_BYTES],
+        token_data: Vec<u8>,
+    ) -> Self {
+        Self::ConnectionRequest {
+            map_checksum,
+            expire_timestamp,
+            token_nonce,
+            token_data,
+            padding: Vec::new(),
+        }
+        .padded_to(CONNECTION_REQUEST_SIZE)
+    }
+
+    /// A query padded out to [`QUERY_REQUEST_SIZE`] once sent on its own.
+    pub fn query_request(kind: QueryKind, challenge: u64) -> Self {
+        Self::QueryRequest {
+            kind,
+            challenge,
+            padding: Vec::new(),
+        }
+        .padded_to(QUERY_REQUEST_SIZE)
+    }
+
+    /// A discovery probe padded out to [`QUERY_REQUEST_SIZE`].
+    pub fn discovery_request(timestamp: u64) -> Self {
+        Self::DiscoveryRequest {
+            timestamp,
+            padding: Vec::new(),
+        }
+        .padded_to(QUERY_REQUEST_SIZE)
+    }
+
+    fn padded_to(mut self, size: usize) -> Self {
+        let unpadded = Packet::new(PacketHeader::new(0, 0, 0), self.clone())
+            .serialize()
+            .map_or(0, |data| data.len());
+        // Padding bytes are never archived smaller than they are, so this is enough
+        if let Self::ConnectionRequest { padding, .. }
+        | Self::QueryRequest { padding, .. }
+        | Self::DiscoveryRequest { padding, .. } = &mut self
+        {
+            padding.resize(size.saturating_sub(unpadded), 0);
+        }
+        self
+    }
 }
 
 #[derive(Debug, Clone, Archive, Serialize, Deserialize)]
@@ -116,6 +449,70 @@ pub struct LobbyInfo {
     pub game_mode: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub enum QueryKind {
+    Info,
+    Players,
+    Rules,
+}
+
+impl std::fmt::Display for QueryKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Info => write!(f, "info"),
+            Self::Players => write!(f, "players"),
+            Self::Rules => write!(f, "rules"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct QueryInfo {
+    pub name: String,
+    pub map: String,
+    pub map_checksum: u64,
+    pub game_mode: String,
+    pub has_password: bool,
+    pub protocol_version: u32,
+    pub tick_rate: u32,
+    pub players: u32,
+    pub max_players: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct QueryPlayer {
+    pub client_id: u32,
+    pub connected_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct QueryRule {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub enum QueryResponse {
+    Info(QueryInfo),
+    Players(Vec<QueryPlayer>),
+    Rules(Vec<QueryRule>),
+}
+
+impl QueryResponse {
+    pub fn kind(&self) -> QueryKind {
+        match self {
+            Self::Info(_) => QueryKind::Info,
+            Self::Players(_) => QueryKind::Players,
+            Self::Rules(_) => QueryKind::Rules,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Archive, Serialize, Deserialize)]
 #[rkyv(derive(Debug))]
 pub struct ClientCommand {
@@ -189,6 +586,102 @@ impl ClientCommand {
     }
 }
 
+#[derive(Debug, Clone, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct CommandDelta {
+    pub sequence_delta: u8,
+    pub tick_delta: u8,
+    pub move_direction: Option<[i8; 3]>,
+    pub view_angles: Option<[i16; 2]>,
+    pub input_flags: Option<u16>,
+}
+
+impl CommandDelta {
+    fn encode(prev: &ClientCommand, current: &ClientCommand) -> Option<Self> {
+        let sequence_delta =
+            u8::try_from(current.command_sequence.wrapping_sub(prev.command_sequence))
+                .ok()
+                .filter(|&d| d > 0)?;
+        let tick_delta = u8::try_from(current.tick.wrapping_sub(prev.tick)).ok()?;
+
+        Some(Self {
+            sequence_delta,
+            tick_delta,
+            move_direction: (current.move_direction != prev.move_direction)
+                .then_some(current.move_direction),
+            view_angles: (current.view_angles != prev.view_angles).then_some(current.view_angles),
+            input_flags: (current.input_flags != prev.input_flags).then_some(current.input_flags),
+        })
+    }
+
+    fn apply(&self, prev: &ClientCommand) -> ClientCommand {
+        ClientCommand {
+            tick: prev.tick.wrapping_add(self.tick_delta as u32),
+            command_sequence: prev
+                .command_sequence
+                .wrapping_add(self.sequence_delta as u32),
+            move_direction: self.move_direction.unwrap_or(prev.move_direction),
+            view_angles: self.view_angles.unwrap_or(prev.view_angles),
+            input_flags: self.input_flags.unwrap_or(prev.input_flags),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct ClientCommandBatch {
+    pub base: ClientCommand,
+    pub deltas: Vec<CommandDelta>,
+}
+
+impl ClientCommandBatch {
+    pub const MAX_COMMANDS: usize = 16;
+
+    pub fn new(commands: &[ClientCommand]) -> Option<Self> {
+        let start = commands.len().saturating_sub(Self::MAX_COMMANDS);
+        let mut iter = commands[start..].iter();
+        let mut batch = Self {
+            base: iter.next()?.clone(),
+            deltas: Vec::new(),
+        };
+        let mut prev = batch.base.clone();
+
+        for command in iter {
+            // Restart from this command if it can't be expressed against the previous one
+            match CommandDelta::encode(&prev, command) {
+                Some(delta) => batch.deltas.push(delta),
+                None => {
+                    batch.base = command.clone();
+                    batch.deltas.clear();
+                }
+            }
+            prev = command.clone();
+        }
+
+        Some(batch)
+    }
+
+    pub fn commands(&self) -> Vec<ClientCommand> {
+        let mut commands = Vec::with_capacity(self.deltas.len() + 1);
+        let mut prev = self.base.clone();
+        for delta in &self.deltas {
+            let next = delta.apply(&prev);
+            commands.push(std::mem::replace(&mut prev, next));
+        }
+        commands.push(prev);
+        commands
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct CommandBufferStatus {
+    pub target_depth: u8,
+    pub depth: u8,
+    pub underruns: u32,
+    pub overruns: u32,
+}
+
 #[derive(Debug, Clone, Copy, Default, Archive, Serialize, Deserialize)]
 #[rkyv(derive(Debug))]
 pub struct EntityState {
@@ -253,6 +746,124 @@ impl EntityState {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct EntityDelta {
+    pub entity_id: u32,
+    pub changed_fields: u8,
+    pub data: Vec<u8>,
+}
+
+impl EntityDelta {
+    pub const FIELD_POSITION: u8 = 1 << 0;
+    pub const FIELD_VELOCITY: u8 = 1 << 1;
+    pub const FIELD_ORIENTATION: u8 = 1 << 2;
+    pub const FIELD_ANIMATION: u8 = 1 << 3;
+    pub const FIELD_FLAGS: u8 = 1 << 4;
+    pub const ALL_FIELDS: u8 = (1 << 5) - 1;
+
+    /// Returns `None` when nothing changed or the entity can't be expressed as a
+    /// delta of `baseline` and has to be sent in full.
+    pub fn encode(baseline: &EntityState, current: &EntityState) -> Option<Self> {
+        if baseline.entity_id != current.entity_id || baseline.entity_type != current.entity_type {
+            return None;
+        }
+
+        let changed_fields = Self::changed_fields(baseline, current);
+        if changed_fields == 0 {
+            return None;
+        }
+
+        Some(Self::from_fields(current, changed_fields))
+    }
+
+    pub fn changed_fields(baseline: &EntityState, current: &EntityState) -> u8 {
+        let mut mask = 0;
+        if baseline.position != current.position {
+            mask |= Self::FIELD_POSITION;
+        }
+        if baseline.velocity != current.velocity {
+            mask |= Self::FIELD_VELOCITY;
+        }
+        if baseline.orientation != current.orientation {
+            mask |= Self::FIELD_ORIENTATION;
+        }
+        if baseline.animation_state != current.animation_state
+            || baseline.animation_frame != current.animation_frame
+        {
+            mask |= Self::FIELD_ANIMATION;
+        }
+        if baseline.flags != current.flags {
+            mask |= Self::FIELD_FLAGS;
+        }
+        mask
+    }
+
+    pub fn from_fields(state: &EntityState, changed_fields: u8) -> Self {
+        let mut writer = BitWriter::new();
+
+        if changed_fields & Self::FIELD_POSITION != 0 {
+            for component in state.position {
+                writer.write_bits(component.to_bits(), 32);
+            }
+        }
+        if changed_fields & Self::FIELD_VELOCITY != 0 {
+            for component in state.velocity {
+                writer.write_bits(component as u16 as u32, 16);
+            }
+        }
+        if changed_fields & Self::FIELD_ORIENTATION != 0 {
+            for component in state.orientation {
+                writer.write_bits(component as u16 as u32, 16);
+            }
+        }
+        if changed_fields & Self::FIELD_ANIMATION != 0 {
+            writer.write_bits(state.animation_state as u32, 8);
+            writer.write_bits(state.animation_frame as u32, 8);
+        }
+        if changed_fields & Self::FIELD_FLAGS != 0 {
+            writer.write_bits(state.flags as u32, 16);
+        }
+
+        Self {
+            entity_id: state.entity_id,
+            changed_fields: changed_fields & Self::ALL_FIELDS,
+            data: writer.finish(),
+        }
+    }
+
+    pub fn apply(&self, baseline: &EntityState) -> Result<EntityState, PacketError> {
+        let mut state = *baseline;
+        state.entity_id = self.entity_id;
+        let mut reader = BitReader::new(&self.data);
+
+        if self.changed_fields & Self::FIELD_POSITION != 0 {
+            for component in &mut state.position {
+                *component = f32::from_bits(reader.read_bits(32)?);
+            }
+        }
+        if self.changed_fields & Self::FIELD_VELOCITY != 0 {
+            for component in &mut state.velocity {
+                *component = reader.read_bits(16)? as u16 as i16;
+            }
+        }
+        if self.changed_fields & Self::FIELD_ORIENTATION != 0 {
+            for component in &mut state.orientation {
+                *component = reader.read_bits(16)? as u16 as i16;
+            }
+        }
+        if self.changed_fields & Self::FIELD_ANIMATION != 0 {
+            state.animation_state = reader.read_bits(8)? as u8;
+            state.animation_frame = reader.read_bits(8)? as u8;
+        }
+        if self.changed_fields & Self::FIELD_FLAGS != 0 {
+            state.flags = reader.read_bits(16)? as u16;
+        }
+
+        Ok(state)
+    }
+}
+
 #[derive(Debug, Clone, Archive, Serialize, Deserialize)]
 #[rkyv(derive(Debug))]
 pub struct WorldSnapshot {
@@ -262,6 +873,7 @@ pub struct WorldSnapshot {
     pub baseline_tick: u32,
     pub is_delta: bool,
     pub entities: Vec<EntityState>,
+    pub entity_deltas: Vec<EntityDelta>,
     pub removed_entity_ids: Vec<u32>,
 }
 
@@ -274,6 +886,7 @@ impl WorldSnapshot {
             baseline_tick: 0,
             is_delta: false,
             entities: Vec::new(),
+            entity_deltas: Vec::new(),
             removed_entity_ids: Vec::new(),
         }
     }
@@ -286,16 +899,55 @@ impl WorldSnapshot {
             baseline_tick,
             is_delta: true,
             entities: Vec::new(),
+            entity_deltas: Vec::new(),
             removed_entity_ids: Vec::new(),
         }
     }
 }
 
+#[derive(Debug, Clone, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct Message {
+    pub channel: u8,
+    pub channel_seq: u16,
+    pub payload: PacketType,
+}
+
+impl Message {
+    pub const CHANNEL_UNRELIABLE: u8 = 0;
+    pub const CHANNEL_RELIABLE: u8 = 1;
+    pub const CHANNEL_ORDERED: u8 = 2;
+
+    pub fn new(channel: u8, channel_seq: u16, payload: PacketType) -> Self {
+        Self {
+            channel,
+            channel_seq,
+            payload,
+        }
+    }
+
+    pub fn unreliable(payload: PacketType) -> Self {
+        Self::new(Self::CHANNEL_UNRELIABLE, 0, payload)
+    }
+
+    pub fn is_reliable(&self) -> bool {
+        self.channel != Self::CHANNEL_UNRELIABLE
+    }
+
+    /// Size of this message when archived on its own, which is close to what it
+    /// adds to a packet carrying several messages.
+    pub fn wire_size(&self) -> Result<usize, PacketError> {
+        rkyv::to_bytes::<rancor::Error>(self)
+            .map(|aligned| aligned.len())
+            .map_err(PacketError::Serialize)
+    }
+}
+
 #[derive(Debug, Clone, Archive, Serialize, Deserialize)]
 #[rkyv(derive(Debug))]
 pub struct Packet {
     pub header: PacketHeader,
-    pub payload: PacketType,
+    pub messages: Vec<Message>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -304,25 +956,101 @@ pub enum PacketError {
     Serialize(rancor::Error),
     #[error("deserialization failed: {0}")]
     Deserialize(rancor::Error),
+    #[error("malformed bitstream: {0}")]
+    Bitstream(&'static str),
+    #[error("packet failed authentication")]
+    Authentication,
+    #[error("not a Dual packet")]
+    BadMagic,
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u32),
 }
 
 impl Packet {
     pub fn new(header: PacketHeader, payload: PacketType) -> Self {
-        Self { header, payload }
+        Self::with_messages(header, vec![Message::unreliable(payload)])
+    }
+
+    pub fn with_messages(header: PacketHeader, messages: Vec<Message>) -> Self {
+        Self { header, messages }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(
+            self.messages.as_slice(),
+            [Message {
+                payload: PacketType::Encrypted(_),
+                ..
+            }]
+        )
+    }
+
+    /// Handshake traffic is sent before session keys exist, so it's always plaintext.
+    pub fn is_handshake(&self) -> bool {
+        !self.messages.is_empty()
+            && self.messages.iter().all(|message| {
+                matches!(
+                    message.payload,
+                    PacketType::ConnectionRequest { .. }
+                        | PacketType::ConnectionChallenge { .. }
+                        | PacketType::ChallengeResponse { .. }
+                        | PacketType::ConnectionDenied { .. }
+                        | PacketType::SessionResume { .. }
+                )
+            })
+    }
+
+    /// Queries come from strangers and are answered without a connection.
+    pub fn is_query(&self) -> bool {
+        matches!(
+            self.messages.as_slice(),
+            [Message {
+                payload: PacketType::QueryRequest { .. }
+                    | PacketType::QueryChallenge { .. }
+                    | PacketType::QueryResponse(_)
+                    | PacketType::DiscoveryRequest { .. }
+                    | PacketType::DiscoveryResponse { .. }
+                    | PacketType::MasterHeartbeat { .. }
+                    | PacketType::MasterListRequest { .. }
+                    | PacketType::MasterList { .. },
+                ..
+            }]
+        )
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>, PacketError> {
-        rkyv::to_bytes::<rancor::Error>(self)
-            .map(|aligned| aligned.into_vec())
-            .map_err(PacketError::Serialize)
+        let archive = rkyv::to_bytes::<rancor::Error>(self).map_err(PacketError::Serialize)?;
+        let mut data = Vec::with_capacity(PACKET_PREFIX_SIZE + archive.len());
+        data.extend_from_slice(&PROTOCOL_MAGIC.to_le_bytes());
+        data.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        data.extend_from_slice(&archive);
+        Ok(data)
     }
 
+    /// Checks the prefix before touching the archive, whose layout is only
+    /// known for versions we speak.
     pub fn deserialize(data: &[u8]) -> Result<Self, PacketError> {
-        rkyv::from_bytes::<Self, rancor::Error>(data).map_err(PacketError::Deserialize)
+        let archive = Self::archive_of(data)?;
+        // Archived data must be aligned, which a slice past the prefix may not be
+        let mut aligned = AlignedVec::<16>::with_capacity(archive.len());
+        aligned.extend_from_slice(archive);
+        rkyv::from_bytes::<Self, rancor::Error>(&aligned).map_err(PacketError::Deserialize)
     }
 
+    /// `data` must be aligned so the archive past the prefix is too.
     pub fn access_archived(data: &[u8]) -> Result<&ArchivedPacket, PacketError> {
-        rkyv::access::<ArchivedPacket, rancor::Error>(data).map_err(PacketError::Deserialize)
+        let archive = Self::archive_of(data)?;
+        rkyv::access::<ArchivedPacket, rancor::Error>(archive).map_err(PacketError::Deserialize)
+    }
+
+    fn archive_of(data: &[u8]) -> Result<&[u8], PacketError> {
+        match read_prefix(data) {
+            Some((PROTOCOL_MAGIC, version)) if is_version_supported(version) => {
+                Ok(&data[PACKET_PREFIX_SIZE..])
+            }
+            Some((PROTOCOL_MAGIC, version)) => Err(PacketError::UnsupportedVersion(version)),
+            _ => Err(PacketError::BadMagic),
+        }
     }
 }
 
@@ -353,9 +1081,97 @@ mod tests {
         assert!((quat[3] - 1.0).abs() < 0.0001);
     }
 
+    #[test]
+    fn test_entity_delta_only_sends_changed_fields() {
+        let mut baseline = EntityState::new(7, 0);
+        baseline.position = [1.0, 2.0, 3.0];
+        baseline.encode_velocity([4.0, 0.0, 0.0]);
+        baseline.flags = 3;
+
+        let mut current = baseline;
+        current.encode_orientation([0.0, 1.0, 0.0, 0.0]);
+
+        let delta = EntityDelta::encode(&baseline, &current).unwrap();
+        assert_eq!(delta.changed_fields, EntityDelta::FIELD_ORIENTATION);
+        assert_eq!(delta.data.len(), 8);
+
+        let merged = delta.apply(&baseline).unwrap();
+        assert_eq!(merged.position, baseline.position);
+        assert_eq!(merged.velocity, baseline.velocity);
+        assert_eq!(merged.orientation, current.orientation);
+        assert_eq!(merged.flags, baseline.flags);
+
+        assert!(EntityDelta::encode(&baseline, &baseline).is_none());
+        current.entity_type = 1;
+        assert!(EntityDelta::encode(&baseline, &current).is_none());
+    }
+
+    #[test]
+    fn test_entity_delta_rejects_truncated_data() {
+        let baseline = EntityState::new(1, 0);
+        let mut current = baseline;
+        current.position = [5.0, 0.0, 0.0];
+        current.flags = 1;
+
+        let mut delta = EntityDelta::encode(&baseline, &current).unwrap();
+        delta.data.pop();
+        assert!(delta.apply(&baseline).is_err());
+    }
+
+    fn test_quats() -> Vec<[f32; 4]> {
+        let mut quats = vec![
+            [0.0, 0.0, 0.0, 1.0],
+            [0.0, 0.0, 0.0, -1.0],
+            [1.0, 0.0, 0.0, 0.0],
+            [0.5, 0.5, 0.5, 0.5],
+            [-0.5, 0.5, -0.5, 0.5],
+        ];
+        for i in 0..64 {
+            let t = i as f32 * 0.37;
+            let axis = glam::Vec3::new(t.sin(), (t * 1.7).cos(), (t * 0.3).sin() + 0.1).normalize();
+            quats.push(Quat::from_axis_angle(axis, t * 2.3).to_array());
+        }
+        quats
+    }
+
+    #[test]
+    fn test_smallest_three_roundtrip_accuracy() {
+        for (bits, max_error) in [(9, 0.01), (10, 0.005)] {
+            for quat in test_quats() {
+                let packed = compress_quat_smallest_three(quat, bits);
+                assert!((packed as u64) < 1u64 << (2 + 3 * bits));
+
+                let decoded = decompress_quat_smallest_three(packed, bits);
+                let original = Quat::from_array(quat);
+                let decoded = Quat::from_array(decoded);
+                assert!((decoded.length() - 1.0).abs() < 1e-5);
+                assert!(
+                    original.angle_between(decoded) < max_error,
+                    "{:?} decoded as {:?} with {} bits",
+                    original,
+                    decoded,
+                    bits
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_view_angles_roundtrip() {
+        for (yaw, pitch) in [(0.0, 0.0), (1.2, -0.4), (-3.0, 1.4), (2.5, 0.9)] {
+            let quat = view_angles_to_quat(yaw, pitch);
+            let (decoded_yaw, decoded_pitch) = quat_to_view_angles(quat).unwrap();
+            assert!((normalize_angle(decoded_yaw - yaw)).abs() < 1e-4);
+            assert!((decoded_pitch - pitch).abs() < 1e-4);
+        }
+
+        let rolled = Quat::from_rotation_z(0.5).to_array();
+        assert!(quat_to_view_angles(rolled).is_none());
+    }
+
     #[test]
     fn test_packet_serialization() {
-        let header = PacketHeader::new(1, 0, 0, PacketHeader::CHANNEL_UNRELIABLE, 0);
+        let header = PacketHeader::new(1, 0, 0);
         let payload = PacketType::Ping { timestamp: 12345 };
         let packet = Packet::new(header, payload);
 
@@ -364,4 +1180,79 @@ mod tests {
 
         assert_eq!(packet.header, deserialized.header);
     }
+
+    #[test]
+    fn test_foreign_version_is_read_from_prefix() {
+        let mut data = Packet::new(PacketHeader::new(1, 0, 0), PacketType::Disconnect)
+            .serialize()
+            .unwrap();
+        data[4..8].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+        // A later version may lay out its archive however it likes
+        data.truncate(PACKET_PREFIX_SIZE + 3);
+        assert!(matches!(
+            Packet::deserialize(&data),
+            Err(PacketError::UnsupportedVersion(v)) if v == PROTOCOL_VERSION + 1
+        ));
+        assert!(matches!(
+            Packet::deserialize(b"garbage!"),
+            Err(PacketError::BadMagic)
+        ));
+
+        let denial = DenyReason::encode_version_denial();
+        assert_eq!(
+            DenyReason::decode_version_denial(&denial),
+            Some(DenyReason::version_mismatch())
+        );
+        assert!(DenyReason::decode_version_denial(&data).is_none());
+    }
+
+    #[test]
+    fn test_command_batch_roundtrip() {
+        let commands: Vec<ClientCommand> = (1..=5)
+            .map(|seq| {
+                let mut cmd = ClientCommand::new(100 + seq / 2, seq);
+                cmd.encode_move_direction([1.0, 0.0, 0.0]);
+                cmd.encode_view_angles(seq as f32 * 0.1, 0.0);
+                cmd.set_flag(ClientCommand::FLAG_JUMP, seq == 3);
+                cmd
+            })
+            .collect();
+
+        let batch = ClientCommandBatch::new(&commands).unwrap();
+        assert_eq!(batch.deltas.len(), 4);
+        assert!(batch.deltas.iter().all(|d| d.move_direction.is_none()));
+
+        let header = PacketHeader::new(1, 0, 0);
+        let packet = Packet::new(header, PacketType::ClientCommandBatch(batch));
+        let mut deserialized = Packet::deserialize(&packet.serialize().unwrap()).unwrap();
+
+        let PacketType::ClientCommandBatch(batch) = deserialized.messages.remove(0).payload else {
+            panic!("Expected ClientCommandBatch");
+        };
+        let decoded = batch.commands();
+        assert_eq!(decoded.len(), commands.len());
+        for (a, b) in decoded.iter().zip(&commands) {
+            assert_eq!(a.tick, b.tick);
+            assert_eq!(a.command_sequence, b.command_sequence);
+            assert_eq!(a.move_direction, b.move_direction);
+            assert_eq!(a.view_angles, b.view_angles);
+            assert_eq!(a.input_flags, b.input_flags);
+        }
+    }
+
+    #[test]
+    fn test_command_batch_restarts_on_gap() {
+        let commands = [
+            ClientCommand::new(10, 1),
+            ClientCommand::new(11, 2),
+            ClientCommand::new(900, 3),
+            ClientCommand::new(901, 4),
+        ];
+
+        let batch = ClientCommandBatch::new(&commands).unwrap();
+        let decoded = batch.commands();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].command_sequence, 3);
+        assert_eq!(decoded[1].tick, 901);
+    }
 }
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use super::endpoint::NetworkEndpoint;
+use super::protocol::{Packet, PacketHeader, PacketType, QueryKind, QueryResponse};
+use super::token::{Key, unix_timestamp};
+use super::transport::{Transport, UdpTransport};
+
+/// How long a query challenge stays valid, give or take one window.
+pub const QUERY_CHALLENGE_SECS: u64 = 30;
+
+const QUERY_RESEND_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Hands out query challenges without remembering who asked for them.
+///
+/// A challenge is a MAC of the asker's address and the current time window
+/// under a key only we hold, so only someone receiving replies at that
+/// address can know it. That keeps the server from being used to bounce
+/// responses at a spoofed victim, without any per-address state to flood.
+pub struct QueryChallenger {
+    cipher: XChaCha20Poly1305,
+}
+
+impl QueryChallenger {
+    /// `key` should be fresh from `generate_key` and never leave the process.
+    pub fn new(key: &Key) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    pub fn challenge_for(&self, addr: SocketAddr) -> u64 {
+        self.challenge_at(addr, unix_timestamp() / QUERY_CHALLENGE_SECS)
+    }
+
+    /// Also accepts the previous window's challenge, so one handed out just
+    /// before a rollover still works.
+    pub fn verify(&self, addr: SocketAddr, challenge: u64) -> bool {
+        let window = unix_timestamp() / QUERY_CHALLENGE_SECS;
+        challenge == self.challenge_at(addr, window)
+            || challenge == self.challenge_at(addr, window.saturating_sub(1))
+    }
+
+    // Each address and window gets its own nonce, so the tag over nothing
+    // is a fresh keyed value that no other challenge says anything about
+    fn challenge_at(&self, addr: SocketAddr, window: u64) -> u64 {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        let mut nonce = [0u8; 24];
+        nonce[..16].copy_from_slice(&ip.octets());
+        nonce[16..18].copy_from_slice(&addr.port().to_le_bytes());
+        nonce[18..].copy_from_slice(&window.to_le_bytes()[..6]);
+
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(XNonce::from_slice(&nonce), &[], &mut [])
+            .expect("an empty message always fits");
+        // Zero means "no challenge yet", so never hand it out
+        u64::from_le_bytes(tag[..8].try_into().unwrap()) | 1
+    }
+}
+
+/// Asks servers about themselves without connecting, remembering each
+/// server's challenge so follow-up queries skip that round trip.
+pub struct QueryClient<T: Transport = UdpTransport> {
+    endpoint: NetworkEndpoint<T>,
+    timeout: Duration,
+    challenges: HashMap<SocketAddr, u64>,
+}
+
+impl QueryClient<UdpTransport> {
+    pub fn bind_for(server: SocketAddr, timeout: Duration) -> io::Result<Self> {
+        Self::with_transport(UdpTransport::bind_for(server)?, timeout)
+    }
+}
+
+impl<T: Transport> QueryClient<T> {
+    pub fn with_transport(transport: T, timeout: Duration) -> io::Result<Self> {
+        Ok(Self {
+            endpoint: NetworkEndpoint::with_transport(transport)?,
+            timeout,
+            challenges: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.endpoint.local_addr()
+    }
+
+    /// Blocks until the server answers or the timeout passes.
+    pub fn query(&mut self, server: SocketAddr, kind: QueryKind) -> io::Result<QueryResponse> {
+        let deadline = Instant::now() + self.timeout;
+        let mut last_send: Option<Instant> = None;
+
+        while Instant::now() < deadline {
+            if last_send.is_none_or(|sent| sent.elapsed() >= QUERY_RESEND_INTERVAL) {
+                let challenge = self.challenges.get(&server).copied().unwrap_or(0);
+                let request = Packet::new(
+                    PacketHeader::new(0, 0, 0),
+                    PacketType::query_request(kind, challenge),
+                );
+                self.endpoint.send_to(&request, server)?;
+                last_send = Some(Instant::now());
+            }
+
+            for (packet, addr) in self.endpoint.receive()? {
+                if addr != server {
+                    continue;
+                }
+                for message in packet.messages {
+                    match message.payload {
+                        // Also what a stale challenge gets back, so just retry with the new one
+                        PacketType::QueryChallenge { challenge } => {
+                            self.challenges.insert(server, challenge);
+                            last_send = None;
+                        }
+                        PacketType::QueryResponse(response) if response.kind() == kind => {
+                            return Ok(response);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("No {} reply from {}", kind, server),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_is_bound_to_address() {
+        let challenger = QueryChallenger::new(&[1; 32]);
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+
+        let challenge = challenger.challenge_for(addr);
+        assert_ne!(challenge, 0);
+        assert!(challenger.verify(addr, challenge));
+        assert!(!challenger.verify(other, challenge));
+        assert!(!challenger.verify(addr, 0));
+
+        // Another server, or this one after a restart, won't take it either
+        assert!(!QueryChallenger::new(&[2; 32]).verify(addr, challenge));
+    }
+
+    #[test]
+    fn test_challenge_depends_on_every_input() {
+        let challenger = QueryChallenger::new(&[1; 32]);
+        let v4: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:5000".parse().unwrap();
+
+        let challenges = [
+            challenger.challenge_at(v4, 10),
+            challenger.challenge_at(v4, 11),
+            challenger.challenge_at(v6, 10),
+            QueryChallenger::new(&[2; 32]).challenge_at(v4, 10),
+        ];
+        for (i, a) in challenges.iter().enumerate() {
+            assert!(challenges[i + 1..].iter().all(|b| a != b));
+        }
+        assert_eq!(challenger.challenge_at(v4, 10), challenges[0]);
+    }
+}
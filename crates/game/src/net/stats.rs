@@ -1,3 +1,14 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How often [`ConnectionStats`] closes the current sample into its history.
+pub const STATS_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Samples kept, two minutes' worth.
+pub const STATS_HISTORY_LEN: usize = 120;
+
+// RFC 3550's gain, so a single late packet barely moves it
+const JITTER_GAIN: f32 = 1.0 / 16.0;
+
 #[derive(Debug, Clone, Default)]
 pub struct PacketLossSimulation {
     pub enabled: bool,
@@ -5,69 +16,289 @@ pub struct PacketLossSimulation {
     pub min_latency_ms: u32,
     pub max_latency_ms: u32,
     pub jitter_ms: u32,
+    /// Swaps `loss_percent` for bursts of heavier loss
+    pub burst: Option<BurstLoss>,
+    pub duplicate_percent: f32,
+    pub reorder_percent: f32,
+    /// 0 leaves the link uncapped
+    pub bandwidth_bytes_per_sec: u32,
+    /// Longest a datagram waits behind the cap before it's dropped; 0 for no limit
+    pub queue_limit_ms: u32,
+}
+
+/// Gilbert–Elliott loss: the link flips between a good state, where the
+/// usual loss applies, and a bad one with its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BurstLoss {
+    pub enter_percent: f32,
+    pub exit_percent: f32,
+    pub loss_percent: f32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NetworkStats {
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub fragments_sent: u64,
+    pub fragments_received: u64,
+    pub fragment_groups_dropped: u64,
+    pub auth_failures: u64,
+}
+
+/// What a connection looked like over one sample interval.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StatsSample {
+    pub rtt_ms: f32,
+    pub jitter_ms: f32,
+    pub loss_percent: f32,
+    pub send_bytes_per_sec: u32,
+    pub receive_bytes_per_sec: u32,
+}
+
+/// Fixed-size ring of the most recent samples, oldest first.
+#[derive(Debug, Clone)]
+pub struct StatsHistory {
+    samples: VecDeque<StatsSample>,
+    capacity: usize,
 }
 
-impl PacketLossSimulation {
-    pub fn should_drop(&self) -> bool {
-        if !self.enabled || self.loss_percent <= 0.0 {
-            return false;
+impl StatsHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
         }
-        rand_percent() < (self.loss_percent / 100.0)
     }
 
-    pub fn delay_ms(&self) -> u32 {
-        if !self.enabled || self.max_latency_ms == 0 {
-            return 0;
+    pub fn push(&mut self, sample: StatsSample) {
+        if self.samples.len() >= self.capacity {
+            self.samples.pop_front();
         }
-        let base = self.min_latency_ms;
-        let range = self.max_latency_ms.saturating_sub(self.min_latency_ms);
-        let jitter = if self.jitter_ms > 0 {
-            (rand_percent() * self.jitter_ms as f32) as u32
-        } else {
-            0
-        };
-        base + (rand_percent() * range as f32) as u32 + jitter
+        self.samples.push_back(sample);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &StatsSample> {
+        self.samples.iter()
+    }
+
+    pub fn latest(&self) -> Option<&StatsSample> {
+        self.samples.back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
     }
 }
 
-#[derive(Debug, Clone, Default)]
-pub struct NetworkStats {
+impl Default for StatsHistory {
+    fn default() -> Self {
+        Self::new(STATS_HISTORY_LEN)
+    }
+}
+
+/// Link quality as seen from one end of a single connection.
+///
+/// Totals are kept as they happen; loss and bandwidth are measured over the
+/// current sample interval and only change when it closes.
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
     pub packets_sent: u64,
     pub packets_received: u64,
+    pub packets_acked: u64,
     pub packets_lost: u64,
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub rtt_ms: f32,
     pub rtt_variance: f32,
+    /// Smoothed variation in the gaps between packets arriving.
+    pub jitter_ms: f32,
     pub packet_loss_percent: f32,
+    pub send_bytes_per_sec: u32,
+    pub receive_bytes_per_sec: u32,
+    history: StatsHistory,
+
+    sample_start: Instant,
+    sample_bytes_sent: u64,
+    sample_bytes_received: u64,
+    sample_acked: u64,
+    sample_lost: u64,
+    last_arrival: Option<Instant>,
+    last_gap_ms: Option<f32>,
 }
 
-pub fn rand_percent() -> f32 {
-    use std::collections::hash_map::RandomState;
-    use std::hash::{BuildHasher, Hasher};
-
-    let state = RandomState::new();
-    let mut hasher = state.build_hasher();
-    hasher.write_u64(
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos() as u64,
-    );
-    (hasher.finish() % 10000) as f32 / 10000.0
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
 }
 
-pub fn rand_u64() -> u64 {
-    use std::collections::hash_map::RandomState;
-    use std::hash::{BuildHasher, Hasher};
-
-    let state = RandomState::new();
-    let mut hasher = state.build_hasher();
-    hasher.write_u64(
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos() as u64,
-    );
-    hasher.finish()
+impl ConnectionStats {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            packets_sent: 0,
+            packets_received: 0,
+            packets_acked: 0,
+            packets_lost: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            rtt_ms: 0.0,
+            rtt_variance: 0.0,
+            jitter_ms: 0.0,
+            packet_loss_percent: 0.0,
+            send_bytes_per_sec: 0,
+            receive_bytes_per_sec: 0,
+            history: StatsHistory::default(),
+            sample_start: now,
+            sample_bytes_sent: 0,
+            sample_bytes_received: 0,
+            sample_acked: 0,
+            sample_lost: 0,
+            last_arrival: None,
+            last_gap_ms: None,
+        }
+    }
+
+    pub fn on_sent(&mut self, bytes: usize) {
+        self.packets_sent += 1;
+        self.bytes_sent += bytes as u64;
+        self.sample_bytes_sent += bytes as u64;
+    }
+
+    pub fn on_received(&mut self, bytes: usize) {
+        self.bytes_received += bytes as u64;
+        self.sample_bytes_received += bytes as u64;
+    }
+
+    /// A packet from the peer made it through, at `now`.
+    pub fn on_arrival(&mut self, now: Instant) {
+        self.packets_received += 1;
+
+        if let Some(last) = self.last_arrival {
+            let gap_ms = now.duration_since(last).as_secs_f32() * 1000.0;
+            if let Some(last_gap_ms) = self.last_gap_ms {
+                let variation = (gap_ms - last_gap_ms).abs();
+                self.jitter_ms += (variation - self.jitter_ms) * JITTER_GAIN;
+            }
+            self.last_gap_ms = Some(gap_ms);
+        }
+        self.last_arrival = Some(now);
+    }
+
+    pub fn on_acks(&mut self, acked: usize, lost: usize, srtt_ms: f32, rtt_var_ms: f32) {
+        self.packets_acked += acked as u64;
+        self.packets_lost += lost as u64;
+        self.sample_acked += acked as u64;
+        self.sample_lost += lost as u64;
+        if acked > 0 {
+            self.rtt_ms = srtt_ms;
+            self.rtt_variance = rtt_var_ms;
+        }
+    }
+
+    /// Closes the current sample once it has run a full interval.
+    pub fn update(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.sample_start);
+        if elapsed < STATS_SAMPLE_INTERVAL {
+            return;
+        }
+
+        let secs = elapsed.as_secs_f64();
+        self.send_bytes_per_sec = (self.sample_bytes_sent as f64 / secs) as u32;
+        self.receive_bytes_per_sec = (self.sample_bytes_received as f64 / secs) as u32;
+
+        // Nothing resolved either way says nothing about loss, so keep the last figure
+        let resolved = self.sample_acked + self.sample_lost;
+        if resolved > 0 {
+            self.packet_loss_percent = self.sample_lost as f32 * 100.0 / resolved as f32;
+        }
+
+        self.history.push(StatsSample {
+            rtt_ms: self.rtt_ms,
+            jitter_ms: self.jitter_ms,
+            loss_percent: self.packet_loss_percent,
+            send_bytes_per_sec: self.send_bytes_per_sec,
+            receive_bytes_per_sec: self.receive_bytes_per_sec,
+        });
+
+        self.sample_start = now;
+        self.sample_bytes_sent = 0;
+        self.sample_bytes_received = 0;
+        self.sample_acked = 0;
+        self.sample_lost = 0;
+    }
+
+    pub fn history(&self) -> &StatsHistory {
+        &self.history
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_samples_loss_and_bandwidth() {
+        let start = Instant::now();
+        let mut stats = ConnectionStats::new(start);
+
+        for _ in 0..10 {
+            stats.on_sent(100);
+            stats.on_received(50);
+        }
+        stats.on_acks(9, 1, 40.0, 5.0);
+
+        // Nothing is measured until the interval is up
+        stats.update(start + STATS_SAMPLE_INTERVAL / 2);
+        assert!(stats.history().is_empty());
+
+        stats.update(start + STATS_SAMPLE_INTERVAL);
+        let sample = *stats.history().latest().unwrap();
+        assert_eq!(sample.loss_percent, 10.0);
+        assert_eq!(sample.send_bytes_per_sec, 1000);
+        assert_eq!(sample.receive_bytes_per_sec, 500);
+        assert_eq!(sample.rtt_ms, 40.0);
+        assert_eq!(stats.packets_lost, 1);
+
+        // A quiet interval drops the rates but not the loss figure
+        stats.update(start + STATS_SAMPLE_INTERVAL * 2);
+        let sample = *stats.history().latest().unwrap();
+        assert_eq!(sample.send_bytes_per_sec, 0);
+        assert_eq!(sample.loss_percent, 10.0);
+    }
+
+    #[test]
+    fn test_jitter_follows_arrival_variation() {
+        let start = Instant::now();
+        let mut steady = ConnectionStats::new(start);
+        let mut uneven = ConnectionStats::new(start);
+
+        for i in 0..64u32 {
+            steady.on_arrival(start + Duration::from_millis(u64::from(i) * 16));
+            let wobble = if i % 2 == 0 { 0 } else { 10 };
+            uneven.on_arrival(start + Duration::from_millis(u64::from(i) * 16 + wobble));
+        }
+
+        assert!(steady.jitter_ms < 0.01);
+        assert!(uneven.jitter_ms > 10.0);
+        assert_eq!(uneven.packets_received, 64);
+    }
+
+    #[test]
+    fn test_history_keeps_newest() {
+        let mut history = StatsHistory::new(3);
+        for i in 0..5 {
+            history.push(StatsSample {
+                rtt_ms: i as f32,
+                ..Default::default()
+            });
+        }
+        let rtts: Vec<f32> = history.iter().map(|sample| sample.rtt_ms).collect();
+        assert_eq!(rtts, [2.0, 3.0, 4.0]);
+    }
 }
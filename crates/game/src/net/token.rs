@@ -0,0 +1,576 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce, XChaCha20Poly1305, XNonce};
+use rkyv::{Archive, Deserialize, Serialize, rancor};
+
+use super::protocol::PROTOCOL_MAGIC;
+
+pub const KEY_BYTES: usize = 32;
+pub const TOKEN_NONCE_BYTES: usize = 24;
+pub const MAX_SERVER_ADDRESSES: usize = 32;
+pub const DEFAULT_TOKEN_EXPIRY_SECS: u64 = 30;
+
+/// Well-known key shared by the stock server and client so local games work
+/// without running the issuer. Never use it for a public server.
+pub const DEV_PRIVATE_KEY: Key = *b"dual-development-private-key-32b";
+
+pub type Key = [u8; KEY_BYTES];
+
+#[derive(Debug, thiserror::Error)]
+pub enum TokenError {
+    #[error("connect token expired")]
+    Expired,
+    #[error("connect token failed authentication")]
+    Authentication,
+    #[error("server address not listed in connect token")]
+    AddressNotListed,
+    #[error("connect token must list 1 to {MAX_SERVER_ADDRESSES} server addresses")]
+    InvalidAddressCount,
+    #[error("connect token already used from another address")]
+    Replayed,
+    #[error("malformed connect token: {0}")]
+    Malformed(rancor::Error),
+    #[error("invalid key: {0}")]
+    InvalidKey(&'static str),
+    #[error("failed to gather randomness: {0}")]
+    Random(getrandom::Error),
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn generate_key() -> Result<Key, TokenError> {
+    let mut key = [0u8; KEY_BYTES];
+    getrandom::getrandom(&mut key).map_err(TokenError::Random)?;
+    Ok(key)
+}
+
+pub fn generate_client_id() -> Result<u64, TokenError> {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).map_err(TokenError::Random)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn key_from_hex(hex: &str) -> Result<Key, TokenError> {
+    let hex = hex.trim();
+    if hex.len() != KEY_BYTES * 2 {
+        return Err(TokenError::InvalidKey("expected 64 hex characters"));
+    }
+
+    let mut key = [0u8; KEY_BYTES];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| TokenError::InvalidKey("not hex"))?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| TokenError::InvalidKey("not hex"))?;
+    }
+    Ok(key)
+}
+
+pub fn key_to_hex(key: &Key) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn token_associated_data(expire_timestamp: u64) -> [u8; 12] {
+    let mut data = [0u8; 12];
+    data[..4].copy_from_slice(&PROTOCOL_MAGIC.to_le_bytes());
+    data[4..].copy_from_slice(&expire_timestamp.to_le_bytes());
+    data
+}
+
+/// Public half of a connect token, handed to the client out of band. The
+/// client reads the addresses and keys but can't open `private_data`.
+#[derive(Debug, Clone, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct ConnectToken {
+    pub create_timestamp: u64,
+    pub expire_timestamp: u64,
+    pub nonce: [u8; TOKEN_NONCE_BYTES],
+    pub server_addresses: Vec<SocketAddr>,
+    pub client_to_server_key: Key,
+    pub server_to_client_key: Key,
+    pub private_data: Vec<u8>,
+}
+
+impl ConnectToken {
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expire_timestamp
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TokenError> {
+        rkyv::to_bytes::<rancor::Error>(self)
+            .map(|aligned| aligned.into_vec())
+            .map_err(TokenError::Malformed)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, TokenError> {
+        rkyv::from_bytes::<Self, rancor::Error>(data).map_err(TokenError::Malformed)
+    }
+}
+
+/// Sealed with the issuer's private key; only servers sharing that key can
+/// read it.
+#[derive(Debug, Clone, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct PrivateConnectToken {
+    pub client_id: u64,
+    pub server_addresses: Vec<SocketAddr>,
+    pub client_to_server_key: Key,
+    pub server_to_client_key: Key,
+}
+
+impl PrivateConnectToken {
+    pub fn seal(
+        &self,
+        key: &Key,
+        expire_timestamp: u64,
+        nonce: &[u8; TOKEN_NONCE_BYTES],
+    ) -> Result<Vec<u8>, TokenError> {
+        let plaintext = rkyv::to_bytes::<rancor::Error>(self).map_err(TokenError::Malformed)?;
+        let aad = token_associated_data(expire_timestamp);
+        XChaCha20Poly1305::new(key.into())
+            .encrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| TokenError::Authentication)
+    }
+
+    /// Decrypts and checks a token presented in a connection request. The
+    /// expiry is bound into the ciphertext, so a client can't extend it.
+    pub fn open(
+        key: &Key,
+        expire_timestamp: u64,
+        nonce: &[u8; TOKEN_NONCE_BYTES],
+        data: &[u8],
+        now: u64,
+    ) -> Result<Self, TokenError> {
+        if now >= expire_timestamp {
+            return Err(TokenError::Expired);
+        }
+
+        let aad = token_associated_data(expire_timestamp);
+        let plaintext = XChaCha20Poly1305::new(key.into())
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: data,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| TokenError::Authentication)?;
+
+        let token =
+            rkyv::from_bytes::<Self, rancor::Error>(&plaintext).map_err(TokenError::Malformed)?;
+        if token.server_addresses.is_empty() || token.server_addresses.len() > MAX_SERVER_ADDRESSES
+        {
+            return Err(TokenError::InvalidAddressCount);
+        }
+        Ok(token)
+    }
+
+    pub fn lists_server(&self, local_addr: SocketAddr) -> bool {
+        self.server_addresses.iter().any(|addr| {
+            *addr == local_addr
+                || (local_addr.ip().is_unspecified() && addr.port() == local_addr.port())
+        })
+    }
+}
+
+/// Mints connect tokens for a set of servers sharing `key`.
+#[derive(Clone)]
+pub struct TokenIssuer {
+    key: Key,
+    expiry: Duration,
+}
+
+impl std::fmt::Debug for TokenIssuer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenIssuer")
+            .field("expiry", &self.expiry)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenIssuer {
+    pub fn new(key: Key) -> Self {
+        Self {
+            key,
+            expiry: Duration::from_secs(DEFAULT_TOKEN_EXPIRY_SECS),
+        }
+    }
+
+    pub fn with_expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    pub fn issue(
+        &self,
+        client_id: u64,
+        server_addresses: &[SocketAddr],
+    ) -> Result<ConnectToken, TokenError> {
+        if server_addresses.is_empty() || server_addresses.len() > MAX_SERVER_ADDRESSES {
+            return Err(TokenError::InvalidAddressCount);
+        }
+
+        let create_timestamp = unix_timestamp();
+        let expire_timestamp = create_timestamp + self.expiry.as_secs();
+
+        let mut nonce = [0u8; TOKEN_NONCE_BYTES];
+        getrandom::getrandom(&mut nonce).map_err(TokenError::Random)?;
+
+        let private = PrivateConnectToken {
+            client_id,
+            server_addresses: server_addresses.to_vec(),
+            client_to_server_key: generate_key()?,
+            server_to_client_key: generate_key()?,
+        };
+        let private_data = private.seal(&self.key, expire_timestamp, &nonce)?;
+
+        Ok(ConnectToken {
+            create_timestamp,
+            expire_timestamp,
+            nonce,
+            server_addresses: private.server_addresses,
+            client_to_server_key: private.client_to_server_key,
+            server_to_client_key: private.server_to_client_key,
+            private_data,
+        })
+    }
+}
+
+/// Stateless challenge the server hands back after a valid token, so no
+/// connection is allocated until the client proves it owns its address.
+#[derive(Debug, Clone, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct ChallengeToken {
+    pub client_id: u64,
+    pub client_addr: SocketAddr,
+    pub expire_timestamp: u64,
+    pub token_nonce: [u8; TOKEN_NONCE_BYTES],
+    pub client_to_server_key: Key,
+    pub server_to_client_key: Key,
+}
+
+impl ChallengeToken {
+    fn nonce(sequence: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&sequence.to_le_bytes());
+        nonce
+    }
+
+    pub fn seal(&self, key: &Key, sequence: u64) -> Result<Vec<u8>, TokenError> {
+        let plaintext = rkyv::to_bytes::<rancor::Error>(self).map_err(TokenError::Malformed)?;
+        ChaCha20Poly1305::new(key.into())
+            .encrypt(
+                Nonce::from_slice(&Self::nonce(sequence)),
+                plaintext.as_slice(),
+            )
+            .map_err(|_| TokenError::Authentication)
+    }
+
+    pub fn open(key: &Key, sequence: u64, data: &[u8], now: u64) -> Result<Self, TokenError> {
+        let plaintext = ChaCha20Poly1305::new(key.into())
+            .decrypt(Nonce::from_slice(&Self::nonce(sequence)), data)
+            .map_err(|_| TokenError::Authentication)?;
+        let token =
+            rkyv::from_bytes::<Self, rancor::Error>(&plaintext).map_err(TokenError::Malformed)?;
+        if now >= token.expire_timestamp {
+            return Err(TokenError::Expired);
+        }
+        Ok(token)
+    }
+}
+
+/// Handed out with `ConnectionAccepted` so a client whose address changes
+/// can find its way back to the same session. Only the server can open it,
+/// and it proves nothing on its own: the resume must also carry a packet
+/// sealed with the session keys.
+#[derive(Debug, Clone, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug))]
+pub struct SessionToken {
+    pub client_id: u32,
+    pub token_client_id: u64,
+}
+
+impl SessionToken {
+    // The sequence travels in front of the ciphertext, since clients hand
+    // the token back as an opaque blob
+    pub fn seal(&self, key: &Key, sequence: u64) -> Result<Vec<u8>, TokenError> {
+        let plaintext = rkyv::to_bytes::<rancor::Error>(self).map_err(TokenError::Malformed)?;
+        let ciphertext = ChaCha20Poly1305::new(key.into())
+            .encrypt(
+                Nonce::from_slice(&ChallengeToken::nonce(sequence)),
+                plaintext.as_slice(),
+            )
+            .map_err(|_| TokenError::Authentication)?;
+
+        let mut data = sequence.to_le_bytes().to_vec();
+        data.extend_from_slice(&ciphertext);
+        Ok(data)
+    }
+
+    pub fn open(key: &Key, data: &[u8]) -> Result<Self, TokenError> {
+        let (sequence, ciphertext) = data
+            .split_first_chunk::<8>()
+            .ok_or(TokenError::Authentication)?;
+        let plaintext = ChaCha20Poly1305::new(key.into())
+            .decrypt(
+                Nonce::from_slice(&ChallengeToken::nonce(u64::from_le_bytes(*sequence))),
+                ciphertext,
+            )
+            .map_err(|_| TokenError::Authentication)?;
+        rkyv::from_bytes::<Self, rancor::Error>(&plaintext).map_err(TokenError::Malformed)
+    }
+}
+
+/// Remembers which address first presented each token so a sniffed token
+/// can't be replayed from somewhere else before it expires, and which tokens
+/// already opened a connection. The session keys come out of the token, so a
+/// second connection with it would reuse packet nonces under the same keys.
+#[derive(Debug, Default)]
+pub struct TokenReplayCache {
+    entries: HashMap<[u8; TOKEN_NONCE_BYTES], TokenUse>,
+}
+
+#[derive(Debug)]
+struct TokenUse {
+    addr: SocketAddr,
+    expire_timestamp: u64,
+    accepted: bool,
+}
+
+impl TokenReplayCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks a connection request. The client resends it until it sees a
+    /// challenge, so the first address may keep presenting the token until
+    /// a connection is accepted with it.
+    pub fn check(
+        &mut self,
+        nonce: &[u8; TOKEN_NONCE_BYTES],
+        addr: SocketAddr,
+        expire_timestamp: u64,
+        now: u64,
+    ) -> Result<(), TokenError> {
+        self.entries.retain(|_, entry| entry.expire_timestamp > now);
+
+        let entry = self.entries.entry(*nonce).or_insert(TokenUse {
+            addr,
+            expire_timestamp,
+            accepted: false,
+        });
+        if entry.accepted || entry.addr != addr {
+            return Err(TokenError::Replayed);
+        }
+        Ok(())
+    }
+
+    /// Spends the token on a connection. Fails if it was already spent.
+    pub fn accept(
+        &mut self,
+        nonce: &[u8; TOKEN_NONCE_BYTES],
+        addr: SocketAddr,
+        expire_timestamp: u64,
+        now: u64,
+    ) -> Result<(), TokenError> {
+        self.check(nonce, addr, expire_timestamp, now)?;
+        if let Some(entry) = self.entries.get_mut(nonce) {
+            entry.accepted = true;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_addr() -> SocketAddr {
+        "127.0.0.1:27015".parse().unwrap()
+    }
+
+    #[test]
+    fn test_issued_token_opens_with_shared_key() {
+        let key = generate_key().unwrap();
+        let token = TokenIssuer::new(key).issue(42, &[server_addr()]).unwrap();
+        let token = ConnectToken::from_bytes(&token.to_bytes().unwrap()).unwrap();
+
+        let private = PrivateConnectToken::open(
+            &key,
+            token.expire_timestamp,
+            &token.nonce,
+            &token.private_data,
+            unix_timestamp(),
+        )
+        .unwrap();
+
+        assert_eq!(private.client_id, 42);
+        assert_eq!(private.client_to_server_key, token.client_to_server_key);
+        assert!(private.lists_server(server_addr()));
+        assert!(private.lists_server("0.0.0.0:27015".parse().unwrap()));
+        assert!(!private.lists_server("127.0.0.1:27016".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_token_rejects_wrong_key_and_tampering() {
+        let key = generate_key().unwrap();
+        let token = TokenIssuer::new(key).issue(1, &[server_addr()]).unwrap();
+        let now = unix_timestamp();
+
+        let other = generate_key().unwrap();
+        let result = PrivateConnectToken::open(
+            &other,
+            token.expire_timestamp,
+            &token.nonce,
+            &token.private_data,
+            now,
+        );
+        assert!(matches!(result, Err(TokenError::Authentication)));
+
+        // Pushing the expiry out breaks the tag
+        let result = PrivateConnectToken::open(
+            &key,
+            token.expire_timestamp + 3600,
+            &token.nonce,
+            &token.private_data,
+            now,
+        );
+        assert!(matches!(result, Err(TokenError::Authentication)));
+
+        let mut data = token.private_data.clone();
+        data[0] ^= 1;
+        let result =
+            PrivateConnectToken::open(&key, token.expire_timestamp, &token.nonce, &data, now);
+        assert!(matches!(result, Err(TokenError::Authentication)));
+    }
+
+    #[test]
+    fn test_expired_token_rejected() {
+        let key = generate_key().unwrap();
+        let token = TokenIssuer::new(key).issue(1, &[server_addr()]).unwrap();
+
+        let result = PrivateConnectToken::open(
+            &key,
+            token.expire_timestamp,
+            &token.nonce,
+            &token.private_data,
+            token.expire_timestamp,
+        );
+        assert!(matches!(result, Err(TokenError::Expired)));
+    }
+
+    #[test]
+    fn test_challenge_token_bound_to_sequence() {
+        let key = generate_key().unwrap();
+        let challenge = ChallengeToken {
+            client_id: 7,
+            client_addr: server_addr(),
+            expire_timestamp: unix_timestamp() + 10,
+            token_nonce: [3; TOKEN_NONCE_BYTES],
+            client_to_server_key: [1; KEY_BYTES],
+            server_to_client_key: [2; KEY_BYTES],
+        };
+
+        let sealed = challenge.seal(&key, 5).unwrap();
+        let now = unix_timestamp();
+        assert_eq!(
+            ChallengeToken::open(&key, 5, &sealed, now).unwrap(),
+            challenge
+        );
+        assert!(ChallengeToken::open(&key, 6, &sealed, now).is_err());
+    }
+
+    #[test]
+    fn test_session_token_round_trip() {
+        let key = generate_key().unwrap();
+        let session = SessionToken {
+            client_id: 3,
+            token_client_id: 42,
+        };
+
+        let mut data = session.seal(&key, 9).unwrap();
+        assert_eq!(SessionToken::open(&key, &data).unwrap(), session);
+        assert!(SessionToken::open(&generate_key().unwrap(), &data).is_err());
+        assert!(SessionToken::open(&key, &data[..4]).is_err());
+
+        // The sequence is bound in as the nonce, so it can't be swapped
+        data[0] ^= 1;
+        assert!(SessionToken::open(&key, &data).is_err());
+    }
+
+    #[test]
+    fn test_replay_cache_pins_token_to_first_address() {
+        let mut cache = TokenReplayCache::new();
+        let nonce = [9; TOKEN_NONCE_BYTES];
+        let first: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+
+        assert!(cache.check(&nonce, first, 100, 50).is_ok());
+        assert!(cache.check(&nonce, first, 100, 51).is_ok());
+        assert!(matches!(
+            cache.check(&nonce, second, 100, 52),
+            Err(TokenError::Replayed)
+        ));
+
+        // Expired entries are forgotten
+        assert!(
+            cache
+                .check(&[0; TOKEN_NONCE_BYTES], second, 200, 150)
+                .is_ok()
+        );
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_replay_cache_spends_token_once() {
+        let mut cache = TokenReplayCache::new();
+        let nonce = [9; TOKEN_NONCE_BYTES];
+        let first: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+
+        assert!(cache.check(&nonce, first, 100, 50).is_ok());
+        assert!(cache.accept(&nonce, first, 100, 51).is_ok());
+
+        // Neither the same address nor another may connect with it again
+        assert!(matches!(
+            cache.accept(&nonce, first, 100, 52),
+            Err(TokenError::Replayed)
+        ));
+        assert!(matches!(
+            cache.check(&nonce, first, 100, 53),
+            Err(TokenError::Replayed)
+        ));
+        assert!(matches!(
+            cache.check(&nonce, second, 100, 54),
+            Err(TokenError::Replayed)
+        ));
+    }
+
+    #[test]
+    fn test_key_hex_round_trip() {
+        let key = generate_key().unwrap();
+        assert_eq!(key_from_hex(&key_to_hex(&key)).unwrap(), key);
+        assert!(key_from_hex("abcd").is_err());
+        assert!(key_from_hex(&"zz".repeat(KEY_BYTES)).is_err());
+    }
+}
@@ -16,6 +16,7 @@ pub struct AckTracker {
     max_pending: usize,
     srtt: f32,
     rtt_var: f32,
+    newly_lost: usize,
 }
 
 impl AckTracker {
@@ -25,6 +26,7 @@ impl AckTracker {
             max_pending,
             srtt: 100.0,
             rtt_var: 50.0,
+            newly_lost: 0,
         }
     }
 
@@ -76,6 +78,13 @@ impl AckTracker {
             self.update_rtt(rtt);
         }
 
+        // Anything that slid out of the ack bitfield unacked is never coming back
+        let before = self.pending.len();
+        self.pending.retain(|p| {
+            p.acked || !sequence_greater_than(ack, p.sequence) || ack.wrapping_sub(p.sequence) <= 32
+        });
+        self.newly_lost += before - self.pending.len();
+
         while self.pending.front().is_some_and(|p| p.acked) {
             self.pending.pop_front();
         }
@@ -100,6 +109,11 @@ impl AckTracker {
         self.rtt_var
     }
 
+    /// Packets declared lost since the last call.
+    pub fn take_lost(&mut self) -> usize {
+        std::mem::take(&mut self.newly_lost)
+    }
+
     pub fn unacked_count(&self) -> usize {
         self.pending.iter().filter(|p| !p.acked).count()
     }
@@ -122,7 +136,8 @@ impl Default for ReceiveTracker {
 impl ReceiveTracker {
     pub fn new() -> Self {
         Self {
-            last_received: 0,
+            // Nothing received yet, so the ack names a sequence never sent
+            last_received: u32::MAX,
             received_bitfield: 0,
             recent_sequences: VecDeque::with_capacity(128),
             max_recent: 128,
@@ -142,7 +157,9 @@ impl ReceiveTracker {
         if sequence_greater_than(sequence, self.last_received) {
             let diff = sequence.wrapping_sub(self.last_received);
             if diff <= 32 {
-                self.received_bitfield = (self.received_bitfield << diff) | 1;
+                // The previous newest moves into the bitfield along with the rest
+                self.received_bitfield =
+                    self.received_bitfield.checked_shl(diff).unwrap_or(0) | 1 << (diff - 1);
             } else {
                 self.received_bitfield = 0;
             }
@@ -193,6 +210,20 @@ mod tests {
         assert_eq!(bitfield & 0b11, 0b11);
     }
 
+    #[test]
+    fn test_receive_tracker_leaves_gaps_unacked() {
+        let mut tracker = ReceiveTracker::new();
+        assert_eq!(tracker.ack_data(), (u32::MAX, 0));
+
+        tracker.record_received(10);
+        tracker.record_received(13);
+
+        // 11 and 12 never arrived; 10 sits three behind the newest
+        let (ack, bitfield) = tracker.ack_data();
+        assert_eq!(ack, 13);
+        assert_eq!(bitfield & 0b111, 0b100);
+    }
+
     #[test]
     fn test_duplicate_detection() {
         let mut tracker = ReceiveTracker::new();
@@ -213,4 +244,19 @@ mod tests {
 
         assert!(tracker.srtt() > 0.0);
     }
+
+    #[test]
+    fn test_ack_tracker_detects_loss() {
+        let mut tracker = AckTracker::new(64);
+
+        for seq in 0..40 {
+            tracker.track_packet(seq);
+        }
+
+        // 0..=6 fall outside the bitfield of ack 39, 7 is simply not acked yet
+        tracker.process_ack(39, u32::MAX >> 1);
+        assert_eq!(tracker.take_lost(), 7);
+        assert_eq!(tracker.take_lost(), 0);
+        assert_eq!(tracker.unacked_count(), 1);
+    }
 }
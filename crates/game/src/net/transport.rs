@@ -0,0 +1,444 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+// Where the hub starts handing out ports for binds to port 0
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Datagram transport under a `NetworkEndpoint`. Receiving never blocks:
+/// with nothing waiting it fails with `io::ErrorKind::WouldBlock`.
+pub trait Transport {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> io::Result<usize>;
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    dual_stack: bool,
+}
+
+impl UdpTransport {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        set_dont_fragment(&socket)?;
+        Ok(Self {
+            socket,
+            dual_stack: false,
+        })
+    }
+
+    /// Binds `[::]:port` with IPv4 traffic accepted on the same socket. IPv4
+    /// peers keep showing up as plain IPv4 addresses, never as v4-mapped ones.
+    pub fn bind_dual_stack(port: u16) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(false)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        socket.set_nonblocking(true)?;
+        let socket = socket.into();
+        set_dont_fragment(&socket)?;
+        Ok(Self {
+            socket,
+            dual_stack: true,
+        })
+    }
+
+    /// Binds an ephemeral port of the family `remote` needs. IPv6 binds are
+    /// dual-stack, so IPv4 fallbacks stay reachable from them.
+    pub fn bind_for(remote: SocketAddr) -> io::Result<Self> {
+        match remote {
+            SocketAddr::V4(_) => Self::bind((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => Self::bind_dual_stack(0),
+        }
+    }
+
+    /// Binds an ephemeral IPv4 port allowed to send to broadcast addresses.
+    pub fn bind_broadcast() -> io::Result<Self> {
+        let transport = Self::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        transport.socket.set_broadcast(true)?;
+        Ok(transport)
+    }
+
+    pub fn is_dual_stack(&self) -> bool {
+        self.dual_stack
+    }
+}
+
+/// Sockets never let the kernel fragment our datagrams: an MTU probe that
+/// doesn't fit the path has to be dropped, or every probe would get through
+/// split into IP fragments and discovery would always settle on `MAX_MTU`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+    // IPv4 options also cover the v4 traffic of a dual-stack socket
+    set_socket_option(
+        socket,
+        libc::IPPROTO_IP,
+        libc::IP_MTU_DISCOVER,
+        libc::IP_PMTUDISC_DO,
+    )?;
+    if socket.local_addr()?.is_ipv6() {
+        set_socket_option(
+            socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+fn set_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+    match socket.local_addr()? {
+        SocketAddr::V4(_) => set_socket_option(socket, libc::IPPROTO_IP, libc::IP_DONTFRAG, 1),
+        SocketAddr::V6(_) => set_socket_option(socket, libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG, 1),
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+)))]
+fn set_dont_fragment(_socket: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+))]
+fn set_socket_option(
+    socket: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the fd is open for as long as `socket` is borrowed, and the
+    // option value is a live c_int of the length passed
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether a send failed because the datagram is bigger than the path MTU
+/// the kernel knows of. Sockets refuse to fragment, so this is how a path
+/// that shrank shows up.
+pub fn is_too_large(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    return error.raw_os_error() == Some(libc::EMSGSIZE);
+    #[cfg(not(unix))]
+    return false;
+}
+
+impl Transport for UdpTransport {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match addr {
+            SocketAddr::V4(v4) if self.dual_stack => {
+                let mapped = SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port());
+                self.socket.send_to(data, mapped)
+            }
+            _ => self.socket.send_to(data, addr),
+        }
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (size, addr) = self.socket.recv_from(buf)?;
+        // Rebuilding only mapped addresses keeps link-local scope ids intact
+        match addr {
+            SocketAddr::V6(v6) if let Some(v4) = v6.ip().to_ipv4_mapped() => {
+                Ok((size, SocketAddr::new(IpAddr::V4(v4), v6.port())))
+            }
+            _ => Ok((size, addr)),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Hub {
+    queues: HashMap<SocketAddr, VecDeque<(Vec<u8>, SocketAddr)>>,
+    next_port: u16,
+}
+
+/// In-memory network for tests. Every transport bound on the same hub can
+/// reach the others by address; datagrams arrive in the order they were
+/// sent, and ones sent to an address nobody holds vanish like on UDP.
+#[derive(Debug, Clone, Default)]
+pub struct LoopbackHub {
+    hub: Arc<Mutex<Hub>>,
+}
+
+impl LoopbackHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Hub> {
+        self.hub.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<LoopbackTransport> {
+        let mut addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to bind"))?;
+        // Wildcard binds get an address peers can actually send to
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+
+        let mut hub = self.lock();
+        if addr.port() == 0 {
+            loop {
+                addr.set_port(EPHEMERAL_PORT_START + hub.next_port);
+                hub.next_port = (hub.next_port + 1) % (u16::MAX - EPHEMERAL_PORT_START);
+                if !hub.queues.contains_key(&addr) {
+                    break;
+                }
+            }
+        }
+
+        if hub.queues.contains_key(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already bound", addr),
+            ));
+        }
+        hub.queues.insert(addr, VecDeque::new());
+
+        Ok(LoopbackTransport {
+            hub: self.clone(),
+            addr,
+        })
+    }
+
+    /// Datagrams waiting for `addr`.
+    pub fn pending(&self, addr: SocketAddr) -> usize {
+        self.lock().queues.get(&addr).map_or(0, VecDeque::len)
+    }
+}
+
+#[derive(Debug)]
+pub struct LoopbackTransport {
+    hub: LoopbackHub,
+    addr: SocketAddr,
+}
+
+impl Transport for LoopbackTransport {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if let Some(queue) = self.hub.lock().queues.get_mut(&addr) {
+            queue.push_back((data.to_vec(), self.addr));
+        }
+        Ok(data.len())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut hub = self.hub.lock();
+        let Some((data, from)) = hub.queues.get_mut(&self.addr).and_then(VecDeque::pop_front)
+        else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+
+        // Like a UDP socket, whatever doesn't fit the buffer is lost
+        let size = data.len().min(buf.len());
+        buf[..size].copy_from_slice(&data[..size]);
+        Ok((size, from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        self.hub.lock().queues.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loopback_delivers_in_order_with_source() {
+        let hub = LoopbackHub::new();
+        let mut server = hub.bind("127.0.0.1:5000").unwrap();
+        let mut client = hub.bind("0.0.0.0:0").unwrap();
+        let client_addr = client.local_addr().unwrap();
+        assert!(client_addr.ip().is_loopback());
+
+        client
+            .send_to(b"one", server.local_addr().unwrap())
+            .unwrap();
+        client
+            .send_to(b"two", server.local_addr().unwrap())
+            .unwrap();
+
+        let mut buf = [0u8; 16];
+        assert_eq!(server.recv_from(&mut buf).unwrap(), (3, client_addr));
+        assert_eq!(&buf[..3], b"one");
+        assert_eq!(server.recv_from(&mut buf).unwrap(), (3, client_addr));
+        assert_eq!(&buf[..3], b"two");
+        assert_eq!(
+            server.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn test_loopback_addresses_are_exclusive_until_dropped() {
+        let hub = LoopbackHub::new();
+        let first = hub.bind("127.0.0.1:5000").unwrap();
+        assert_eq!(
+            hub.bind("127.0.0.1:5000").unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+
+        let mut client = hub.bind("127.0.0.1:0").unwrap();
+        client
+            .send_to(b"lost", "127.0.0.1:5001".parse().unwrap())
+            .unwrap();
+
+        drop(first);
+        assert_eq!(hub.pending("127.0.0.1:5000".parse().unwrap()), 0);
+        assert!(hub.bind("127.0.0.1:5000").is_ok());
+    }
+
+    #[test]
+    fn test_loopback_wildcard_v6_binds_to_localhost() {
+        let hub = LoopbackHub::new();
+        let mut server = hub.bind("[::1]:5000").unwrap();
+        let mut client = hub.bind("[::]:0").unwrap();
+        assert_eq!(
+            client.local_addr().unwrap().ip(),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+
+        client
+            .send_to(b"six", server.local_addr().unwrap())
+            .unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            server.recv_from(&mut buf).unwrap(),
+            (3, client.local_addr().unwrap())
+        );
+    }
+
+    fn recv_within(transport: &mut UdpTransport, buf: &mut [u8]) -> (usize, SocketAddr) {
+        let start = std::time::Instant::now();
+        loop {
+            match transport.recv_from(buf) {
+                Ok(received) => return received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(start.elapsed().as_secs() < 2, "Nothing received");
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+                Err(e) => panic!("recv failed: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_dual_stack_serves_both_families() {
+        let mut server = UdpTransport::bind_dual_stack(0).unwrap();
+        assert!(server.is_dual_stack());
+        let port = server.local_addr().unwrap().port();
+
+        let mut v4 = UdpTransport::bind_for("127.0.0.1:1".parse().unwrap()).unwrap();
+        let mut v6 = UdpTransport::bind_for("[::1]:1".parse().unwrap()).unwrap();
+        assert!(!v4.is_dual_stack());
+        assert!(v6.is_dual_stack());
+
+        v4.send_to(b"four", SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+            .unwrap();
+        v6.send_to(b"six", SocketAddr::from((Ipv6Addr::LOCALHOST, port)))
+            .unwrap();
+
+        let mut buf = [0u8; 16];
+        let mut peers = Vec::new();
+        for _ in 0..2 {
+            let (size, from) = recv_within(&mut server, &mut buf);
+            match &buf[..size] {
+                b"four" => assert_eq!(from.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                b"six" => assert_eq!(from.ip(), IpAddr::V6(Ipv6Addr::LOCALHOST)),
+                other => panic!("Unexpected datagram {:?}", other),
+            }
+            peers.push(from);
+        }
+
+        // Replies go back out through the same socket to either family
+        for peer in peers {
+            server.send_to(b"reply", peer).unwrap();
+        }
+        assert_eq!(recv_within(&mut v4, &mut buf).0, 5);
+        assert_eq!(recv_within(&mut v6, &mut buf).0, 5);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_udp_sockets_refuse_fragmentation() {
+        use std::os::fd::AsRawFd;
+
+        let option = |socket: &UdpSocket, level, name| {
+            let mut value: libc::c_int = 0;
+            let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+            // SAFETY: value and len outlive the call and match in size
+            let result = unsafe {
+                libc::getsockopt(
+                    socket.as_raw_fd(),
+                    level,
+                    name,
+                    (&mut value as *mut libc::c_int).cast(),
+                    &mut len,
+                )
+            };
+            assert_eq!(result, 0);
+            value
+        };
+
+        let v4 = UdpTransport::bind("127.0.0.1:0").unwrap();
+        assert_eq!(
+            option(&v4.socket, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER),
+            libc::IP_PMTUDISC_DO
+        );
+
+        let dual = UdpTransport::bind_dual_stack(0).unwrap();
+        assert_eq!(
+            option(&dual.socket, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER),
+            libc::IP_PMTUDISC_DO
+        );
+        assert_eq!(
+            option(&dual.socket, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER),
+            libc::IPV6_PMTUDISC_DO
+        );
+    }
+}
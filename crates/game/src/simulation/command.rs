@@ -28,8 +28,18 @@ impl CommandBuffer {
         if self.commands.len() >= self.max_size {
             self.commands.pop_front();
         }
+        // Keep tick order so out-of-order arrivals still drain correctly
+        let index = self
+            .commands
+            .partition_point(|pending| pending.command.tick <= command.tick);
         self.commands
-            .push_back(PendingCommand { entity_id, command });
+            .insert(index, PendingCommand { entity_id, command });
+    }
+
+    pub fn contains_tick(&self, tick: u32) -> bool {
+        self.commands
+            .iter()
+            .any(|pending| pending.command.tick == tick)
     }
 
     pub fn drain_for_tick(&mut self, tick: u32) -> Vec<PendingCommand> {
@@ -160,4 +170,19 @@ mod tests {
 
         assert_eq!(buffer.len(), 1);
     }
+
+    #[test]
+    fn command_buffer_sorts_out_of_order_pushes() {
+        let mut buffer = CommandBuffer::new(64);
+
+        buffer.push(1, ClientCommand::new(7, 1));
+        buffer.push(1, ClientCommand::new(4, 2));
+        buffer.push(1, ClientCommand::new(5, 3));
+
+        assert!(buffer.contains_tick(4));
+        let drained = buffer.drain_for_tick(5);
+        assert_eq!(drained.len(), 2);
+        assert_eq!(drained[0].command.tick, 4);
+        assert_eq!(drained[1].command.tick, 5);
+    }
 }
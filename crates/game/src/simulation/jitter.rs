@@ -0,0 +1,393 @@
+use crate::net::{ClientCommand, sequence_greater_than};
+
+use super::command::CommandBuffer;
+
+const JITTER_SMOOTHING: f32 = 1.0 / 16.0;
+
+#[derive(Debug, Clone)]
+pub struct JitterBufferConfig {
+    pub min_depth: u32,
+    pub max_depth: u32,
+    pub initial_depth: u32,
+    pub capacity: usize,
+    pub adapt_interval_ticks: u32,
+}
+
+impl Default for JitterBufferConfig {
+    fn default() -> Self {
+        Self {
+            min_depth: 1,
+            max_depth: 8,
+            initial_depth: 2,
+            capacity: 64,
+            adapt_interval_ticks: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct JitterBufferStats {
+    pub target_depth: u32,
+    pub depth: u32,
+    pub jitter_ticks: f32,
+    pub underruns: u32,
+    pub overruns: u32,
+    pub late_commands: u32,
+}
+
+/// Per-client playout buffer. Commands play on the tick the client stamped
+/// them for, or are scheduled by sequence number until its clock has synced,
+/// so exactly one command is executed per tick.
+pub struct CommandJitterBuffer {
+    config: JitterBufferConfig,
+    entity_id: u32,
+    buffer: CommandBuffer,
+    tick_offset: Option<u32>,
+    target_depth: u32,
+    last_played_tick: Option<u32>,
+    last_command: Option<ClientCommand>,
+    last_lead: Option<i32>,
+    jitter_ticks: f32,
+    ticks_since_adapt: u32,
+    underruns: u32,
+    overruns: u32,
+    late_commands: u32,
+}
+
+impl CommandJitterBuffer {
+    pub fn new(entity_id: u32, config: JitterBufferConfig) -> Self {
+        let target_depth = config
+            .initial_depth
+            .clamp(config.min_depth, config.max_depth);
+
+        Self {
+            buffer: CommandBuffer::new(config.capacity),
+            entity_id,
+            tick_offset: None,
+            target_depth,
+            last_played_tick: None,
+            last_command: None,
+            last_lead: None,
+            jitter_ticks: 0.0,
+            ticks_since_adapt: 0,
+            underruns: 0,
+            overruns: 0,
+            late_commands: 0,
+            config,
+        }
+    }
+
+    pub fn entity_id(&self) -> u32 {
+        self.entity_id
+    }
+
+    pub fn push(&mut self, mut command: ClientCommand, server_tick: u32) -> bool {
+        let already_played = self.last_command.as_ref().is_some_and(|last| {
+            !sequence_greater_than(command.command_sequence, last.command_sequence)
+        });
+        if already_played {
+            return false;
+        }
+
+        let playout_tick = match self.stamped_tick(&command, server_tick) {
+            Some(tick) => {
+                // Later unstamped commands carry on from where the client put this one
+                self.tick_offset = Some(tick.wrapping_sub(command.command_sequence));
+                tick
+            }
+            None => match self.sequence_tick(command.command_sequence, server_tick) {
+                Some(tick) => tick,
+                None => return false,
+            },
+        };
+
+        if self.buffer.contains_tick(playout_tick) {
+            return false;
+        }
+
+        self.record_lead(playout_tick.wrapping_sub(server_tick) as i32);
+        command.tick = playout_tick;
+        self.buffer.push(self.entity_id, command);
+        true
+    }
+
+    /// The tick the client aimed the command at, once its clock has synced.
+    /// A little late still plays as soon as it can, behind any other late
+    /// command already waiting; further off than the buffer could ever hold
+    /// means the client's clock is wrong, so it isn't trusted.
+    fn stamped_tick(&mut self, command: &ClientCommand, server_tick: u32) -> Option<u32> {
+        if command.tick == 0 {
+            return None;
+        }
+        let window = (self.config.max_depth * 2) as i32;
+        let lead = command.tick.wrapping_sub(server_tick) as i32;
+        if !(-window..=window).contains(&lead) {
+            return None;
+        }
+
+        let earliest = self
+            .last_played_tick
+            .map_or(server_tick, |last| last.wrapping_add(1));
+        if sequence_greater_than(earliest, command.tick) {
+            self.late_commands += 1;
+            let mut tick = earliest;
+            while self.buffer.contains_tick(tick) {
+                tick = tick.wrapping_add(1);
+            }
+            return Some(tick);
+        }
+        Some(command.tick)
+    }
+
+    fn sequence_tick(&mut self, command_sequence: u32, server_tick: u32) -> Option<u32> {
+        let offset = match self.tick_offset {
+            Some(offset) => offset,
+            None => self.anchor(command_sequence, server_tick),
+        };
+        let mut playout_tick = command_sequence.wrapping_add(offset);
+
+        let is_late = self
+            .last_played_tick
+            .is_some_and(|last| !sequence_greater_than(playout_tick, last));
+        if is_late {
+            self.late_commands += 1;
+            self.record_lead(playout_tick.wrapping_sub(server_tick) as i32);
+            if !self.buffer.is_empty() {
+                return None;
+            }
+            // We've fallen behind the client entirely, start over from this command
+            let offset = self.anchor(command_sequence, server_tick);
+            playout_tick = command_sequence.wrapping_add(offset);
+        }
+
+        let lead = playout_tick.wrapping_sub(server_tick) as i32;
+        if lead > (self.config.max_depth * 2) as i32 {
+            self.overruns += 1;
+            self.buffer.clear();
+            let offset = self.anchor(command_sequence, server_tick);
+            playout_tick = command_sequence.wrapping_add(offset);
+        }
+
+        Some(playout_tick)
+    }
+
+    pub fn next_command(&mut self, server_tick: u32) -> Option<ClientCommand> {
+        self.adapt();
+
+        let mut drained = self.buffer.drain_for_tick(server_tick);
+        self.last_played_tick = Some(server_tick);
+
+        let command = match drained.pop() {
+            Some(newest) => {
+                let mut command = newest.command;
+                if !drained.is_empty() {
+                    self.overruns += 1;
+                    // Don't lose edge-triggered input from the commands we skip
+                    for skipped in &drained {
+                        command.input_flags |=
+                            skipped.command.input_flags & ClientCommand::FLAG_JUMP;
+                    }
+                }
+                command
+            }
+            None => {
+                let mut command = self.last_command.clone()?;
+                self.underruns += 1;
+                command.input_flags &= !ClientCommand::FLAG_JUMP;
+                command.tick = server_tick;
+                command
+            }
+        };
+
+        self.last_command = Some(command.clone());
+        Some(command)
+    }
+
+    pub fn stats(&self) -> JitterBufferStats {
+        JitterBufferStats {
+            target_depth: self.target_depth,
+            depth: self.buffer.len() as u32,
+            jitter_ticks: self.jitter_ticks,
+            underruns: self.underruns,
+            overruns: self.overruns,
+            late_commands: self.late_commands,
+        }
+    }
+
+    pub fn target_depth(&self) -> u32 {
+        self.target_depth
+    }
+
+    fn anchor(&mut self, command_sequence: u32, server_tick: u32) -> u32 {
+        let offset = server_tick
+            .wrapping_add(self.target_depth)
+            .wrapping_sub(command_sequence);
+        self.tick_offset = Some(offset);
+        offset
+    }
+
+    fn record_lead(&mut self, lead: i32) {
+        if let Some(last) = self.last_lead {
+            let deviation = (lead - last).unsigned_abs() as f32;
+            self.jitter_ticks += (deviation - self.jitter_ticks) * JITTER_SMOOTHING;
+        }
+        self.last_lead = Some(lead);
+    }
+
+    fn adapt(&mut self) {
+        self.ticks_since_adapt += 1;
+        if self.ticks_since_adapt < self.config.adapt_interval_ticks {
+            return;
+        }
+        self.ticks_since_adapt = 0;
+
+        let Some(offset) = self.tick_offset else {
+            return;
+        };
+
+        let desired = ((self.jitter_ticks * 2.0).ceil() as u32 + self.config.min_depth)
+            .clamp(self.config.min_depth, self.config.max_depth);
+
+        // Move one tick at a time so the client only sees a single repeat or skip
+        if desired > self.target_depth {
+            self.target_depth += 1;
+            self.tick_offset = Some(offset.wrapping_add(1));
+        } else if desired < self.target_depth {
+            self.target_depth -= 1;
+            self.tick_offset = Some(offset.wrapping_sub(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(sequence: u32) -> ClientCommand {
+        ClientCommand::new(0, sequence)
+    }
+
+    #[test]
+    fn executes_one_command_per_tick() {
+        let mut buffer = CommandJitterBuffer::new(1, JitterBufferConfig::default());
+
+        for seq in 1..=4 {
+            assert!(buffer.push(command(seq), 100));
+        }
+
+        // Initial depth of 2 delays the first command by two ticks
+        assert!(buffer.next_command(100).is_none());
+        assert!(buffer.next_command(101).is_none());
+        for (tick, seq) in (102..106).zip(1..=4) {
+            let cmd = buffer.next_command(tick).unwrap();
+            assert_eq!(cmd.command_sequence, seq);
+        }
+        assert_eq!(buffer.stats().overruns, 0);
+    }
+
+    #[test]
+    fn duplicates_last_command_when_dry() {
+        let mut buffer = CommandJitterBuffer::new(1, JitterBufferConfig::default());
+
+        let mut cmd = command(1);
+        cmd.encode_move_direction([1.0, 0.0, 0.0]);
+        buffer.push(cmd, 10);
+
+        let played = buffer.next_command(12).unwrap();
+        let repeated = buffer.next_command(13).unwrap();
+
+        assert_eq!(played.command_sequence, repeated.command_sequence);
+        assert_eq!(played.move_direction, repeated.move_direction);
+        assert_eq!(buffer.stats().underruns, 1);
+    }
+
+    #[test]
+    fn ignores_duplicates_and_late_commands() {
+        let mut buffer = CommandJitterBuffer::new(1, JitterBufferConfig::default());
+
+        assert!(buffer.push(command(1), 10));
+        assert!(buffer.push(command(2), 10));
+        assert!(!buffer.push(command(2), 10));
+
+        buffer.next_command(12);
+        buffer.next_command(13);
+        buffer.next_command(14);
+
+        assert!(!buffer.push(command(2), 14));
+        assert_eq!(buffer.stats().late_commands, 0);
+
+        // Sequence 3 was due on tick 14, so it re-anchors behind the current tick
+        assert!(buffer.push(command(3), 14));
+        assert_eq!(buffer.stats().late_commands, 1);
+        assert!(
+            buffer
+                .next_command(15)
+                .is_some_and(|cmd| cmd.command_sequence == 2)
+        );
+        assert!(
+            buffer
+                .next_command(16)
+                .is_some_and(|cmd| cmd.command_sequence == 3)
+        );
+    }
+
+    #[test]
+    fn grows_target_depth_under_jitter() {
+        let config = JitterBufferConfig {
+            adapt_interval_ticks: 1,
+            ..Default::default()
+        };
+        let mut buffer = CommandJitterBuffer::new(1, config);
+        let initial = buffer.target_depth();
+
+        let mut seq = 1;
+        for tick in 0..200u32 {
+            // Deliver commands in bursts of four every fourth tick
+            if tick % 4 == 0 {
+                for _ in 0..4 {
+                    buffer.push(command(seq), tick);
+                    seq += 1;
+                }
+            }
+            buffer.next_command(tick);
+        }
+
+        assert!(buffer.target_depth() > initial);
+    }
+
+    #[test]
+    fn plays_commands_on_their_stamped_tick() {
+        let mut buffer = CommandJitterBuffer::new(1, JitterBufferConfig::default());
+
+        // The client leads the server by five ticks rather than the default depth
+        assert!(buffer.push(ClientCommand::new(105, 1), 100));
+        assert!(buffer.push(ClientCommand::new(106, 2), 100));
+        for tick in 100..105 {
+            assert!(buffer.next_command(tick).is_none());
+        }
+        assert_eq!(buffer.next_command(105).unwrap().command_sequence, 1);
+        assert_eq!(buffer.next_command(106).unwrap().command_sequence, 2);
+
+        // Slightly late plays on the next tick; a wild stamp falls back to sequence
+        assert!(buffer.push(ClientCommand::new(105, 3), 107));
+        assert_eq!(buffer.next_command(107).unwrap().command_sequence, 3);
+        assert_eq!(buffer.stats().late_commands, 1);
+        assert!(buffer.push(ClientCommand::new(5000, 4), 108));
+        assert_eq!(buffer.next_command(108).unwrap().command_sequence, 4);
+    }
+
+    #[test]
+    fn queues_consecutive_late_commands() {
+        let mut buffer = CommandJitterBuffer::new(1, JitterBufferConfig::default());
+
+        assert!(buffer.push(ClientCommand::new(102, 1), 100));
+        assert_eq!(buffer.next_command(102).unwrap().command_sequence, 1);
+
+        // Both missed their ticks; neither is thrown away
+        assert!(buffer.push(ClientCommand::new(101, 2), 103));
+        assert!(buffer.push(ClientCommand::new(102, 3), 103));
+        assert_eq!(buffer.stats().late_commands, 2);
+        assert_eq!(buffer.next_command(103).unwrap().command_sequence, 2);
+        assert_eq!(buffer.next_command(104).unwrap().command_sequence, 3);
+    }
+}
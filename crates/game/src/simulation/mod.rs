@@ -1,5 +1,7 @@
 mod command;
+mod jitter;
 mod tick;
 
 pub use command::{CommandBuffer, CommandProcessor};
+pub use jitter::{CommandJitterBuffer, JitterBufferConfig, JitterBufferStats};
 pub use tick::{FixedTimestep, SimulationLoop, SimulationState};
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use glam::Vec3;
 
-use crate::net::{EntityState, WorldSnapshot};
+use crate::net::{EntityDelta, EntityState, WorldSnapshot};
 
 use super::entity::{Entity, EntityHandle, EntityType};
 
@@ -128,6 +128,7 @@ impl World {
             baseline_tick: 0,
             is_delta: false,
             entities,
+            entity_deltas: Vec::new(),
             removed_entity_ids: self.removed_entities.clone(),
         }
     }
@@ -147,6 +148,7 @@ impl World {
             baseline_tick: 0,
             is_delta: false,
             entities,
+            entity_deltas: Vec::new(),
             removed_entity_ids: self.removed_entities.clone(),
         }
     }
@@ -159,17 +161,19 @@ impl World {
         let baseline_entities: HashMap<u32, &EntityState> =
             baseline.entities.iter().map(|e| (e.entity_id, e)).collect();
 
-        let entities = self
-            .entities
-            .values()
-            .filter_map(|entity| {
-                let current = entity.to_network_state();
-                match baseline_entities.get(&entity.id) {
-                    Some(baseline) if states_equal(&current, baseline) => None,
-                    _ => Some(current),
-                }
-            })
-            .collect();
+        let mut entities = Vec::new();
+        let mut entity_deltas = Vec::new();
+        for entity in self.entities.values() {
+            let current = entity.to_network_state();
+            match baseline_entities.get(&entity.id) {
+                Some(baseline) if states_equal(&current, baseline) => {}
+                Some(baseline) => match EntityDelta::encode(baseline, &current) {
+                    Some(delta) => entity_deltas.push(delta),
+                    None => entities.push(current),
+                },
+                None => entities.push(current),
+            }
+        }
 
         let removed_entity_ids = baseline
             .entities
@@ -185,6 +189,7 @@ impl World {
             baseline_tick: baseline.tick,
             is_delta: true,
             entities,
+            entity_deltas,
             removed_entity_ids,
         }
     }
@@ -206,11 +211,7 @@ fn current_time_ms() -> u64 {
 fn states_equal(a: &EntityState, b: &EntityState) -> bool {
     a.entity_id == b.entity_id
         && a.entity_type == b.entity_type
-        && a.position == b.position
-        && a.velocity == b.velocity
-        && a.orientation == b.orientation
-        && a.animation_state == b.animation_state
-        && a.flags == b.flags
+        && EntityDelta::changed_fields(a, b) == 0
 }
 
 #[cfg(test)]
@@ -248,8 +249,29 @@ mod tests {
 
         assert!(delta.is_delta);
         assert_eq!(delta.baseline_tick, 0);
+        assert!(delta.entities.is_empty());
+        assert_eq!(delta.entity_deltas.len(), 1);
+        assert_eq!(delta.entity_deltas[0].entity_id, player1.id());
+        assert_eq!(
+            delta.entity_deltas[0].changed_fields,
+            EntityDelta::FIELD_POSITION
+        );
+    }
+
+    #[test]
+    fn delta_sends_new_entities_in_full() {
+        let mut world = World::new();
+        world.spawn_player(Vec3::new(0.0, 1.0, 0.0));
+
+        let baseline = world.snapshot(0);
+        world.advance_tick();
+        let item = world.spawn(EntityType::Item);
+
+        let delta = world.delta_from_baseline(&baseline, 0);
+
         assert_eq!(delta.entities.len(), 1);
-        assert_eq!(delta.entities[0].entity_id, player1.id());
+        assert_eq!(delta.entities[0].entity_id, item.id());
+        assert!(delta.entity_deltas.is_empty());
     }
 
     #[test]
@@ -1,35 +1,87 @@
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU16, Ordering};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use dual::net::{
+    BlockDirection, BlockKind, CHANNEL_WINDOW, CONNECTION_REQUEST_SIZE, CaptureDirection,
+    CaptureReader, CaptureWriter, ChallengeToken, ConnectToken, DenyReason, KEY_BYTES, Key,
+    LanBrowser, LinkConditioner, LoopbackHub, LoopbackTransport, MASTER_PAGE_SIZE, MAX_BLOCK_SIZE,
+    MAX_PACKET_SIZE, MAX_SEND_QUEUE, MIN_PROTOCOL_VERSION, MasterBrowser, MasterLink,
+    MasterRegistry, PROTOCOL_MAGIC, PROTOCOL_VERSION, PrivateConnectToken, QUERY_REQUEST_SIZE,
+    QueryChallenger, QueryClient, QueryInfo, QueryKind, QueryResponse, RateLimitConfig,
+    RateLimiter, ServerFilter, SessionToken, SnapshotCodec, TokenError, TokenIssuer, Transport,
+    UdpTransport, VERSION_DENIAL_MAGIC, generate_key, read_prefix, unix_timestamp,
+};
 use dual::{
     ClientConnection, ConnectionManager, ConnectionState, NetworkEndpoint, Packet, PacketHeader,
-    PacketLossSimulation, PacketType, Reliability,
+    PacketLossSimulation, PacketType, Reliability, TestingGround,
 };
 
-static PORT_COUNTER: AtomicU16 = AtomicU16::new(40000);
+fn bind(hub: &LoopbackHub, addr: SocketAddr) -> NetworkEndpoint<LoopbackTransport> {
+    NetworkEndpoint::with_transport(hub.bind(addr).unwrap()).unwrap()
+}
+
+const TEST_KEY: Key = [7; KEY_BYTES];
 
-fn next_port() -> u16 {
-    PORT_COUNTER.fetch_add(10, Ordering::SeqCst)
+fn connection_request(token: &ConnectToken) -> PacketType {
+    PacketType::connection_request(
+        TestingGround::new().checksum(),
+        token.expire_timestamp,
+        token.nonce,
+        token.private_data.clone(),
+    )
 }
 
-fn generate_salt() -> u64 {
-    use std::collections::hash_map::RandomState;
-    use std::hash::{BuildHasher, Hasher};
-    let state = RandomState::new();
-    let mut hasher = state.build_hasher();
-    hasher.write_u64(
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos() as u64,
-    );
-    hasher.finish()
+fn open_request(payload: &PacketType) -> Result<PrivateConnectToken, TokenError> {
+    match payload {
+        PacketType::ConnectionRequest {
+            expire_timestamp,
+            token_nonce,
+            token_data,
+            ..
+        } => PrivateConnectToken::open(
+            &TEST_KEY,
+            *expire_timestamp,
+            token_nonce,
+            token_data,
+            unix_timestamp(),
+        ),
+        _ => panic!("Expected ConnectionRequest"),
+    }
+}
+
+fn challenge_packet(
+    challenge_key: &Key,
+    client_addr: SocketAddr,
+    private: &PrivateConnectToken,
+    token: &ConnectToken,
+) -> Packet {
+    let challenge = ChallengeToken {
+        client_id: private.client_id,
+        client_addr,
+        expire_timestamp: token.expire_timestamp,
+        token_nonce: token.nonce,
+        client_to_server_key: private.client_to_server_key,
+        server_to_client_key: private.server_to_client_key,
+    };
+    Packet::new(
+        PacketHeader::new(0, 0, 0),
+        PacketType::ConnectionChallenge {
+            challenge_sequence: 0,
+            challenge_data: challenge.seal(challenge_key, 0).unwrap(),
+        },
+    )
+}
+
+fn send(conn: &mut ClientConnection, payload: PacketType, reliability: Reliability) -> Packet {
+    conn.queue_message(payload, reliability).unwrap();
+    let mut packets = conn.flush();
+    assert_eq!(packets.len(), 1);
+    packets.remove(0)
 }
 
-fn wait_for_packet(
-    endpoint: &mut NetworkEndpoint,
+fn wait_for_packet<T: Transport>(
+    endpoint: &mut NetworkEndpoint<T>,
     timeout_ms: u64,
 ) -> Option<Vec<(Packet, SocketAddr)>> {
     let start = std::time::Instant::now();
@@ -45,22 +97,26 @@ fn wait_for_packet(
 
 #[test]
 fn test_connection_handshake_full_flow() {
-    let port = next_port();
-    let server_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port + 1).parse().unwrap();
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
 
-    let mut server_endpoint = NetworkEndpoint::bind(server_addr).unwrap();
-    let mut client_endpoint = NetworkEndpoint::bind(client_addr).unwrap();
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
 
     let mut connections = ConnectionManager::new(32);
-    let client_salt = generate_salt();
+    let challenge_key = generate_key().unwrap();
+    let token = TokenIssuer::new(TEST_KEY)
+        .issue(42, &[server_addr])
+        .unwrap();
 
     // Client side connection tracker
-    let mut client_conn = ClientConnection::new(server_addr, 0, client_salt);
+    let mut client_conn = ClientConnection::new(server_addr, 0);
 
     client_endpoint.set_remote(server_addr);
-    let request = client_conn.send_packet(
-        PacketType::ConnectionRequest { client_salt },
+    let request = send(
+        &mut client_conn,
+        connection_request(&token),
         Reliability::Unreliable,
     );
     client_endpoint.send(&request).unwrap();
@@ -69,46 +125,34 @@ fn test_connection_handshake_full_flow() {
     assert_eq!(received.len(), 1);
 
     let (packet, from_addr) = &received[0];
-    match &packet.payload {
-        PacketType::ConnectionRequest { client_salt: salt } => {
-            assert_eq!(*salt, client_salt);
+    let private = open_request(&packet.messages[0].payload).unwrap();
+    assert_eq!(private.client_id, 42);
+    assert!(private.lists_server(server_addr));
 
-            let client = connections
-                .get_or_create_pending(*from_addr, *salt)
-                .unwrap();
-            let server_salt = client.server_salt;
-            let challenge = client.combined_salt();
+    // Nothing is allocated until the client echoes the challenge
+    assert_eq!(connections.total_count(), 0);
 
-            let response = client.send_packet(
-                PacketType::ConnectionChallenge {
-                    server_salt,
-                    challenge,
-                },
-                Reliability::Reliable,
-            );
-            server_endpoint.send_to(&response, *from_addr).unwrap();
-        }
-        _ => panic!("Expected ConnectionRequest"),
-    }
+    let challenge = challenge_packet(&challenge_key, *from_addr, &private, &token);
+    server_endpoint.send_to(&challenge, *from_addr).unwrap();
 
     let received = wait_for_packet(&mut client_endpoint, 200).expect("No packet received");
     assert_eq!(received.len(), 1);
 
     let (packet, _) = &received[0];
-    match &packet.payload {
+    match &packet.messages[0].payload {
         PacketType::ConnectionChallenge {
-            server_salt,
-            challenge,
+            challenge_sequence,
+            challenge_data,
         } => {
             client_conn.process_packet(packet.clone()); // Update client state
-            let expected = client_salt ^ server_salt;
-            assert_eq!(*challenge, expected);
 
-            let response = client_conn.send_packet(
+            let response = send(
+                &mut client_conn,
                 PacketType::ChallengeResponse {
-                    combined_salt: expected,
+                    challenge_sequence: *challenge_sequence,
+                    challenge_data: challenge_data.clone(),
                 },
-                Reliability::Reliable,
+                Reliability::Unreliable,
             );
             client_endpoint.send(&response).unwrap();
         }
@@ -119,18 +163,35 @@ fn test_connection_handshake_full_flow() {
     assert_eq!(received.len(), 1);
 
     let (packet, from_addr) = &received[0];
-    match &packet.payload {
-        PacketType::ChallengeResponse { combined_salt } => {
-            let client = connections.get_by_addr_mut(from_addr).unwrap();
-            assert_eq!(*combined_salt, client.combined_salt());
+    match &packet.messages[0].payload {
+        PacketType::ChallengeResponse {
+            challenge_sequence,
+            challenge_data,
+        } => {
+            let challenge = ChallengeToken::open(
+                &challenge_key,
+                *challenge_sequence,
+                challenge_data,
+                unix_timestamp(),
+            )
+            .unwrap();
+            assert_eq!(challenge.client_addr, *from_addr);
+
+            let client = connections
+                .get_or_create_pending(*from_addr, challenge.client_id)
+                .unwrap();
+            assert_eq!(client.token_client_id, 42);
 
             client.state = ConnectionState::Connected;
             let client_id = client.client_id;
 
-            let accepted = client.send_packet(
+            let accepted = send(
+                client,
                 PacketType::ConnectionAccepted {
                     client_id,
                     entity_id: 1,
+                    snapshot_codec: SnapshotCodec::default(),
+                    session_token: Vec::new(),
                 },
                 Reliability::Reliable,
             );
@@ -143,7 +204,7 @@ fn test_connection_handshake_full_flow() {
     assert_eq!(received.len(), 1);
 
     let (packet, _) = &received[0];
-    match &packet.payload {
+    match &packet.messages[0].payload {
         PacketType::ConnectionAccepted { client_id, .. } => {
             assert!(*client_id > 0);
         }
@@ -155,20 +216,21 @@ fn test_connection_handshake_full_flow() {
 
 #[test]
 fn test_connection_denied_server_full() {
-    let port = next_port();
-    let server_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port + 1).parse().unwrap();
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
 
-    let mut server_endpoint = NetworkEndpoint::bind(server_addr).unwrap();
-    let mut client_endpoint = NetworkEndpoint::bind(client_addr).unwrap();
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
 
     let mut connections = ConnectionManager::new(0);
-    let client_salt = generate_salt();
-    let mut client_conn = ClientConnection::new(server_addr, 0, client_salt);
+    let token = TokenIssuer::new(TEST_KEY).issue(1, &[server_addr]).unwrap();
+    let mut client_conn = ClientConnection::new(server_addr, 0);
 
     client_endpoint.set_remote(server_addr);
-    let request = client_conn.send_packet(
-        PacketType::ConnectionRequest { client_salt },
+    let request = send(
+        &mut client_conn,
+        connection_request(&token),
         Reliability::Unreliable,
     );
     client_endpoint.send(&request).unwrap();
@@ -178,23 +240,14 @@ fn test_connection_denied_server_full() {
     assert_eq!(received.len(), 1);
 
     let (packet, from_addr) = &received[0];
-    match &packet.payload {
-        PacketType::ConnectionRequest { client_salt: salt } => {
-            match connections.get_or_create_pending(*from_addr, *salt) {
-                Ok(_) => panic!("Should have been denied"),
-                Err(reason) => {
-                    let header = PacketHeader::new(0, 0, 0, PacketHeader::CHANNEL_UNRELIABLE, 0);
-                    let denied = Packet::new(
-                        header,
-                        PacketType::ConnectionDenied {
-                            reason: reason.to_string(),
-                        },
-                    );
-                    server_endpoint.send_to(&denied, *from_addr).unwrap();
-                }
-            }
+    let private = open_request(&packet.messages[0].payload).unwrap();
+    match connections.get_or_create_pending(*from_addr, private.client_id) {
+        Ok(_) => panic!("Should have been denied"),
+        Err(reason) => {
+            let header = PacketHeader::new(0, 0, 0);
+            let denied = Packet::new(header, PacketType::ConnectionDenied { reason });
+            server_endpoint.send_to(&denied, *from_addr).unwrap();
         }
-        _ => panic!("Expected ConnectionRequest"),
     }
 
     let received =
@@ -202,97 +255,161 @@ fn test_connection_denied_server_full() {
     assert_eq!(received.len(), 1);
 
     let (packet, _) = &received[0];
-    match &packet.payload {
+    match &packet.messages[0].payload {
         PacketType::ConnectionDenied { reason } => {
-            assert!(reason.contains("full"));
+            assert_eq!(*reason, DenyReason::ServerFull);
         }
         _ => panic!("Expected ConnectionDenied"),
     }
 }
 
 #[test]
-fn test_invalid_challenge_response_rejected() {
-    let port = next_port();
-    let server_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port + 1).parse().unwrap();
+fn test_forged_connect_token_rejected() {
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
 
-    let mut server_endpoint = NetworkEndpoint::bind(server_addr).unwrap();
-    let mut client_endpoint = NetworkEndpoint::bind(client_addr).unwrap();
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
+    let mut client_conn = ClientConnection::new(server_addr, 0);
 
-    let mut connections = ConnectionManager::new(32);
-    let client_salt = generate_salt();
-    let mut client_conn = ClientConnection::new(server_addr, 0, client_salt);
+    let forged = TokenIssuer::new(generate_key().unwrap())
+        .issue(1, &[server_addr])
+        .unwrap();
 
     client_endpoint.set_remote(server_addr);
-    let request = client_conn.send_packet(
-        PacketType::ConnectionRequest { client_salt },
+    let request = send(
+        &mut client_conn,
+        connection_request(&forged),
         Reliability::Unreliable,
     );
     client_endpoint.send(&request).unwrap();
 
     let received = wait_for_packet(&mut server_endpoint, 200).expect("No packet received");
-    let (_, from_addr) = &received[0];
+    let result = open_request(&received[0].0.messages[0].payload);
+    assert!(matches!(result, Err(TokenError::Authentication)));
+}
 
-    let client = connections
-        .get_or_create_pending(*from_addr, client_salt)
-        .unwrap();
-    let server_salt = client.server_salt;
-    let challenge = client.combined_salt();
+#[test]
+fn test_duplicate_token_client_id_rejected() {
+    let mut connections = ConnectionManager::new(32);
+    let first: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+    let second: SocketAddr = "127.0.0.1:5001".parse().unwrap();
 
-    let response = client.send_packet(
-        PacketType::ConnectionChallenge {
-            server_salt,
-            challenge,
-        },
-        Reliability::Reliable,
+    assert!(connections.get_or_create_pending(first, 9).is_ok());
+    assert!(connections.get_or_create_pending(first, 9).is_ok());
+    assert_eq!(
+        connections.get_or_create_pending(second, 9).unwrap_err(),
+        DenyReason::AlreadyConnected
     );
-    server_endpoint.send_to(&response, *from_addr).unwrap();
+    assert_eq!(connections.total_count(), 1);
+}
+
+#[test]
+fn test_invalid_challenge_response_rejected() {
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
+
+    let challenge_key = generate_key().unwrap();
+    let token = TokenIssuer::new(TEST_KEY).issue(1, &[server_addr]).unwrap();
+    let mut client_conn = ClientConnection::new(server_addr, 0);
+
+    client_endpoint.set_remote(server_addr);
+    let request = send(
+        &mut client_conn,
+        connection_request(&token),
+        Reliability::Unreliable,
+    );
+    client_endpoint.send(&request).unwrap();
+
+    let received = wait_for_packet(&mut server_endpoint, 200).expect("No packet received");
+    let (packet, from_addr) = &received[0];
+    let private = open_request(&packet.messages[0].payload).unwrap();
+
+    let challenge = challenge_packet(&challenge_key, *from_addr, &private, &token);
+    server_endpoint.send_to(&challenge, *from_addr).unwrap();
 
     let received = wait_for_packet(&mut client_endpoint, 200).expect("No packet received");
     client_conn.process_packet(received[0].0.clone());
 
-    let wrong_salt = 0xDEADBEEF;
-    let response = client_conn.send_packet(
+    let PacketType::ConnectionChallenge {
+        challenge_sequence,
+        challenge_data,
+    } = &received[0].0.messages[0].payload
+    else {
+        panic!("Expected ConnectionChallenge");
+    };
+
+    let mut tampered = challenge_data.clone();
+    tampered[0] ^= 0xFF;
+    let response = send(
+        &mut client_conn,
         PacketType::ChallengeResponse {
-            combined_salt: wrong_salt,
+            challenge_sequence: *challenge_sequence,
+            challenge_data: tampered,
         },
-        Reliability::Reliable,
+        Reliability::Unreliable,
     );
     client_endpoint.send(&response).unwrap();
 
-    let _ = wait_for_packet(&mut server_endpoint, 200).expect("No packet received");
-
-    let client = connections.get_by_addr(from_addr).unwrap();
-    assert_eq!(client.state, ConnectionState::Connecting);
-    assert_eq!(connections.connected_count(), 0);
+    let received = wait_for_packet(&mut server_endpoint, 200).expect("No packet received");
+    match &received[0].0.messages[0].payload {
+        PacketType::ChallengeResponse {
+            challenge_sequence,
+            challenge_data,
+        } => {
+            let result = ChallengeToken::open(
+                &challenge_key,
+                *challenge_sequence,
+                challenge_data,
+                unix_timestamp(),
+            );
+            assert!(matches!(result, Err(TokenError::Authentication)));
+        }
+        _ => panic!("Expected ChallengeResponse"),
+    }
 }
 
 #[test]
 fn test_ping_pong() {
-    let port = next_port();
-    let server_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port + 1).parse().unwrap();
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
 
-    let mut server_endpoint = NetworkEndpoint::bind(server_addr).unwrap();
-    let mut client_endpoint = NetworkEndpoint::bind(client_addr).unwrap();
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
 
     // Minimal connection for tests
-    let mut client_conn = ClientConnection::new(server_addr, 0, 0);
+    let mut client_conn = ClientConnection::new(server_addr, 0);
 
     let timestamp = 12345u64;
 
     client_endpoint.set_remote(server_addr);
-    let ping = client_conn.send_packet(PacketType::Ping { timestamp }, Reliability::Unreliable);
+    let ping = send(
+        &mut client_conn,
+        PacketType::Ping { timestamp },
+        Reliability::Unreliable,
+    );
     client_endpoint.send(&ping).unwrap();
 
     let received = wait_for_packet(&mut server_endpoint, 200).expect("No packet received");
     assert_eq!(received.len(), 1);
 
     let (packet, from_addr) = &received[0];
-    match &packet.payload {
+    match &packet.messages[0].payload {
         PacketType::Ping { timestamp: ts } => {
-            let header = PacketHeader::new(0, 0, 0, PacketHeader::CHANNEL_UNRELIABLE, 0);
-            let pong = Packet::new(header, PacketType::Pong { timestamp: *ts });
+            let header = PacketHeader::new(0, 0, 0);
+            let pong = Packet::new(
+                header,
+                PacketType::Pong {
+                    timestamp: *ts,
+                    server_time_ms: 0,
+                },
+            );
             server_endpoint.send_to(&pong, *from_addr).unwrap();
         }
         _ => panic!("Expected Ping"),
@@ -302,8 +419,8 @@ fn test_ping_pong() {
     assert_eq!(received.len(), 1);
 
     let (packet, _) = &received[0];
-    match &packet.payload {
-        PacketType::Pong { timestamp: ts } => {
+    match &packet.messages[0].payload {
+        PacketType::Pong { timestamp: ts, .. } => {
             assert_eq!(*ts, timestamp);
         }
         _ => panic!("Expected Pong"),
@@ -314,14 +431,14 @@ fn test_ping_pong() {
 fn test_client_command_transmission() {
     use dual::ClientCommand;
 
-    let port = next_port();
-    let server_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port + 1).parse().unwrap();
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
 
-    let mut server_endpoint = NetworkEndpoint::bind(server_addr).unwrap();
-    let mut client_endpoint = NetworkEndpoint::bind(client_addr).unwrap();
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
 
-    let mut client_conn = ClientConnection::new(server_addr, 0, 0);
+    let mut client_conn = ClientConnection::new(server_addr, 0);
 
     let mut command = ClientCommand::new(100, 1);
     command.encode_move_direction([1.0, 0.0, 0.5]);
@@ -330,7 +447,8 @@ fn test_client_command_transmission() {
     command.set_flag(ClientCommand::FLAG_JUMP, true);
 
     client_endpoint.set_remote(server_addr);
-    let packet = client_conn.send_packet(
+    let packet = send(
+        &mut client_conn,
         PacketType::ClientCommand(command.clone()),
         Reliability::Unreliable,
     );
@@ -340,7 +458,7 @@ fn test_client_command_transmission() {
     assert_eq!(received.len(), 1);
 
     let (packet, _) = &received[0];
-    match &packet.payload {
+    match &packet.messages[0].payload {
         PacketType::ClientCommand(cmd) => {
             assert_eq!(cmd.tick, 100);
             assert_eq!(cmd.command_sequence, 1);
@@ -364,12 +482,12 @@ fn test_client_command_transmission() {
 fn test_world_snapshot_transmission() {
     use dual::{EntityState, WorldSnapshot};
 
-    let port = next_port();
-    let server_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port + 1).parse().unwrap();
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
 
-    let mut server_endpoint = NetworkEndpoint::bind(server_addr).unwrap();
-    let mut client_endpoint = NetworkEndpoint::bind(client_addr).unwrap();
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
 
     let mut snapshot = WorldSnapshot::new(42, 123456789);
     snapshot.last_command_ack = 10;
@@ -379,7 +497,7 @@ fn test_world_snapshot_transmission() {
     entity.encode_velocity([5.0, -2.5, 0.0]);
     snapshot.entities.push(entity);
 
-    let header = PacketHeader::new(0, 0, 0, PacketHeader::CHANNEL_UNRELIABLE, 0);
+    let header = PacketHeader::new(0, 0, 0);
     let packet = Packet::new(header, PacketType::WorldSnapshot(snapshot));
     server_endpoint.send_to(&packet, client_addr).unwrap();
 
@@ -387,7 +505,7 @@ fn test_world_snapshot_transmission() {
     assert_eq!(received.len(), 1);
 
     let (packet, _) = &received[0];
-    match &packet.payload {
+    match &packet.messages[0].payload {
         PacketType::WorldSnapshot(snap) => {
             assert_eq!(snap.tick, 42);
             assert_eq!(snap.server_time_ms, 123456789);
@@ -402,36 +520,140 @@ fn test_world_snapshot_transmission() {
     }
 }
 
+fn large_snapshot(tick: u32, entity_count: u32) -> dual::WorldSnapshot {
+    let mut snapshot = dual::WorldSnapshot::new(tick, 1000);
+    for id in 0..entity_count {
+        let mut entity = dual::EntityState::new(id, 0);
+        entity.position = [id as f32, 1.0, -(id as f32)];
+        snapshot.entities.push(entity);
+    }
+    snapshot
+}
+
+#[test]
+fn test_large_snapshot_is_fragmented() {
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
+
+    let snapshot = large_snapshot(7, 200);
+    let header = PacketHeader::new(0, 0, 0);
+    let packet = Packet::new(header, PacketType::WorldSnapshot(snapshot));
+    assert!(packet.serialize().unwrap().len() > MAX_PACKET_SIZE);
+
+    server_endpoint.send_to(&packet, client_addr).unwrap();
+    assert!(server_endpoint.stats().fragments_sent > 1);
+
+    let received = wait_for_packet(&mut client_endpoint, 200).expect("No packet received");
+    assert_eq!(received.len(), 1);
+
+    match &received[0].0.messages[0].payload {
+        PacketType::WorldSnapshot(snap) => {
+            assert_eq!(snap.tick, 7);
+            assert_eq!(snap.entities.len(), 200);
+            assert_eq!(snap.entities[199].position[0], 199.0);
+        }
+        _ => panic!("Expected WorldSnapshot"),
+    }
+}
+
+#[test]
+fn test_large_reliable_payload_survives_fragment_loss() {
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
+    let mut server_conn = ClientConnection::new(client_addr, 1);
+    let mut client_conn = ClientConnection::new(server_addr, 0);
+
+    let packet = send(
+        &mut server_conn,
+        PacketType::WorldSnapshot(large_snapshot(3, 120)),
+        Reliability::Reliable,
+    );
+
+    // Deliver every fragment but the last, so the group can never complete
+    let data = packet.serialize().unwrap();
+    let fragments =
+        dual::net::split_packet(packet.header, 500, &data, dual::net::FRAGMENT_SIZE).unwrap();
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    for fragment in &fragments[..fragments.len() - 1] {
+        socket
+            .send_to(&fragment.serialize().unwrap(), client_addr)
+            .unwrap();
+    }
+    assert!(wait_for_packet(&mut client_endpoint, 100).is_none());
+
+    // The reliable layer resends the whole packet, which reassembles cleanly
+    thread::sleep(Duration::from_millis(250));
+    assert_eq!(server_conn.queue_resends(), 1);
+    let resends = server_conn.flush();
+    assert_eq!(resends.len(), 1);
+    server_endpoint.send_to(&resends[0], client_addr).unwrap();
+
+    let received = wait_for_packet(&mut client_endpoint, 200).expect("No packet received");
+    assert_eq!(received.len(), 1);
+    let payloads = client_conn.process_packet(received[0].0.clone());
+    assert_eq!(payloads.len(), 1);
+    match &payloads[0] {
+        PacketType::WorldSnapshot(snap) => assert_eq!(snap.entities.len(), 120),
+        _ => panic!("Expected WorldSnapshot"),
+    }
+}
+
 #[test]
 fn test_disconnect_packet() {
-    let port = next_port();
-    let server_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port + 1).parse().unwrap();
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
 
-    let mut server_endpoint = NetworkEndpoint::bind(server_addr).unwrap();
-    let mut client_endpoint = NetworkEndpoint::bind(client_addr).unwrap();
-    let mut client_conn = ClientConnection::new(server_addr, 0, 0);
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
+    let mut client_conn = ClientConnection::new(server_addr, 0);
 
     client_endpoint.set_remote(server_addr);
-    let packet = client_conn.send_packet(PacketType::Disconnect, Reliability::Reliable);
+    let packet = send(
+        &mut client_conn,
+        PacketType::Disconnect,
+        Reliability::Reliable,
+    );
     client_endpoint.send(&packet).unwrap();
 
     let received = wait_for_packet(&mut server_endpoint, 200).expect("No packet received");
     assert_eq!(received.len(), 1);
 
     let (packet, _) = &received[0];
-    assert!(matches!(&packet.payload, PacketType::Disconnect));
+    assert!(matches!(
+        &packet.messages[0].payload,
+        PacketType::Disconnect
+    ));
 }
 
 #[test]
 fn test_packet_sequence_numbers() {
-    let port = next_port();
-    let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let mut conn = ClientConnection::new(addr, 0, 0);
+    let addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let mut conn = ClientConnection::new(addr, 0);
 
-    let p1 = conn.send_packet(PacketType::Ping { timestamp: 1 }, Reliability::Unreliable);
-    let p2 = conn.send_packet(PacketType::Ping { timestamp: 2 }, Reliability::Unreliable);
-    let p3 = conn.send_packet(PacketType::Ping { timestamp: 3 }, Reliability::Unreliable);
+    let p1 = send(
+        &mut conn,
+        PacketType::Ping { timestamp: 1 },
+        Reliability::Unreliable,
+    );
+    let p2 = send(
+        &mut conn,
+        PacketType::Ping { timestamp: 2 },
+        Reliability::Unreliable,
+    );
+    let p3 = send(
+        &mut conn,
+        PacketType::Ping { timestamp: 3 },
+        Reliability::Unreliable,
+    );
 
     assert_eq!(p1.header.sequence, 0);
     assert_eq!(p2.header.sequence, 1);
@@ -440,56 +662,66 @@ fn test_packet_sequence_numbers() {
 
 #[test]
 fn test_multiple_clients_connect() {
-    let port = next_port();
-    let server_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
 
-    let mut server_endpoint = NetworkEndpoint::bind(server_addr).unwrap();
+    let mut server_endpoint = bind(&hub, server_addr);
     let mut connections = ConnectionManager::new(32);
 
-    for i in 0..3u16 {
-        let client_port = port + 2 + i;
-        let client_addr: SocketAddr = format!("127.0.0.1:{}", client_port).parse().unwrap();
-        let mut client_endpoint = NetworkEndpoint::bind(client_addr).unwrap();
-
-        let client_salt = generate_salt();
-        let mut client_conn = ClientConnection::new(server_addr, 0, client_salt);
+    // Everyone sends before the server looks, and the hub hands them over in order
+    let mut client_addrs = Vec::new();
+    let mut client_endpoints = Vec::new();
+    for i in 0..16u64 {
+        let mut client_endpoint =
+            NetworkEndpoint::with_transport(hub.bind("0.0.0.0:0").unwrap()).unwrap();
         client_endpoint.set_remote(server_addr);
 
-        let request = client_conn.send_packet(
-            PacketType::ConnectionRequest { client_salt },
+        let token = TokenIssuer::new(TEST_KEY).issue(i, &[server_addr]).unwrap();
+        let mut client_conn = ClientConnection::new(server_addr, 0);
+        let request = send(
+            &mut client_conn,
+            connection_request(&token),
             Reliability::Unreliable,
         );
         client_endpoint.send(&request).unwrap();
 
-        let received = wait_for_packet(&mut server_endpoint, 200).expect("No packet received");
-        assert_eq!(received.len(), 1);
+        client_addrs.push(client_endpoint.local_addr());
+        client_endpoints.push(client_endpoint);
+    }
 
-        let (packet, from_addr) = &received[0];
-        if let PacketType::ConnectionRequest { client_salt: salt } = &packet.payload {
-            let client = connections
-                .get_or_create_pending(*from_addr, *salt)
-                .unwrap();
-            client.state = ConnectionState::Connected;
-        }
+    let received = server_endpoint.receive().unwrap();
+    assert_eq!(received.len(), 16);
+
+    for (i, (packet, from_addr)) in received.iter().enumerate() {
+        assert_eq!(*from_addr, client_addrs[i]);
+        let private = open_request(&packet.messages[0].payload).unwrap();
+        assert_eq!(private.client_id, i as u64);
+        let client = connections
+            .get_or_create_pending(*from_addr, private.client_id)
+            .unwrap();
+        client.state = ConnectionState::Connected;
     }
 
-    assert_eq!(connections.connected_count(), 3);
-    assert_eq!(connections.total_count(), 3);
+    assert_eq!(connections.connected_count(), 16);
+    assert_eq!(connections.total_count(), 16);
 }
 
 #[test]
 fn test_receive_tracker_zero_sequence() {
-    let port = next_port();
-    let server_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port + 1).parse().unwrap();
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
 
-    let mut server_endpoint = NetworkEndpoint::bind(server_addr).unwrap();
-    let mut client_endpoint = NetworkEndpoint::bind(client_addr).unwrap();
-    let mut client_conn = ClientConnection::new(server_addr, 0, 0);
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
+    let mut client_conn = ClientConnection::new(server_addr, 0);
 
     client_endpoint.set_remote(server_addr);
-    let packet =
-        client_conn.send_packet(PacketType::Ping { timestamp: 0 }, Reliability::Unreliable);
+    let packet = send(
+        &mut client_conn,
+        PacketType::Ping { timestamp: 0 },
+        Reliability::Unreliable,
+    );
     client_endpoint.send(&packet).unwrap();
 
     let received = wait_for_packet(&mut server_endpoint, 200).expect("No packet received");
@@ -499,75 +731,97 @@ fn test_receive_tracker_zero_sequence() {
 
 #[test]
 fn test_connection_survives_packet_loss() {
-    let port = next_port();
-    let server_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port + 1).parse().unwrap();
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
 
-    let mut server_endpoint = NetworkEndpoint::bind(server_addr).unwrap();
-    let mut client_endpoint = NetworkEndpoint::bind(client_addr).unwrap();
+    let conditioner = LinkConditioner::new(hub.bind(server_addr).unwrap(), 42);
+    let mut server_endpoint = NetworkEndpoint::with_transport(conditioner).unwrap();
+    let mut client_endpoint = bind(&hub, client_addr);
 
     let mut connections = ConnectionManager::new(32);
-    let client_salt = generate_salt();
-    let mut client_conn = ClientConnection::new(server_addr, 0, client_salt);
+    let challenge_key = generate_key().unwrap();
+    let token = TokenIssuer::new(TEST_KEY).issue(1, &[server_addr]).unwrap();
+    let mut client_conn = ClientConnection::new(server_addr, 0);
 
     client_endpoint.set_remote(server_addr);
-    let request = client_conn.send_packet(
-        PacketType::ConnectionRequest { client_salt },
+    let request = send(
+        &mut client_conn,
+        connection_request(&token),
         Reliability::Unreliable,
     );
     client_endpoint.send(&request).unwrap();
 
     let received = wait_for_packet(&mut server_endpoint, 200).expect("No packet received");
     let (packet, from_addr) = &received[0];
+    let private = open_request(&packet.messages[0].payload).unwrap();
 
-    if let PacketType::ConnectionRequest { client_salt: salt } = &packet.payload {
-        let client = connections
-            .get_or_create_pending(*from_addr, *salt)
-            .unwrap();
-        let server_salt = client.server_salt;
-        let challenge = client.combined_salt();
-
-        let response = client.send_packet(
-            PacketType::ConnectionChallenge {
-                server_salt,
-                challenge,
-            },
-            Reliability::Reliable,
-        );
-        server_endpoint.send_to(&response, *from_addr).unwrap();
-    }
+    let challenge = challenge_packet(&challenge_key, *from_addr, &private, &token);
+    server_endpoint.send_to(&challenge, *from_addr).unwrap();
 
     let received = wait_for_packet(&mut client_endpoint, 200).expect("No packet received");
     client_conn.process_packet(received[0].0.clone());
 
-    let expected = client_salt ^ connections.iter().next().unwrap().server_salt;
-    let response = client_conn.send_packet(
+    let PacketType::ConnectionChallenge {
+        challenge_sequence,
+        challenge_data,
+    } = received[0].0.messages[0].payload.clone()
+    else {
+        panic!("Expected ConnectionChallenge");
+    };
+    let response = send(
+        &mut client_conn,
         PacketType::ChallengeResponse {
-            combined_salt: expected,
+            challenge_sequence,
+            challenge_data,
         },
-        Reliability::Reliable,
+        Reliability::Unreliable,
     );
     client_endpoint.send(&response).unwrap();
 
     let received = wait_for_packet(&mut server_endpoint, 200).expect("No packet received");
-    let (_, from_addr) = &received[0];
+    let (packet, from_addr) = &received[0];
+    let PacketType::ChallengeResponse {
+        challenge_sequence,
+        challenge_data,
+    } = &packet.messages[0].payload
+    else {
+        panic!("Expected ChallengeResponse");
+    };
+    let challenge = ChallengeToken::open(
+        &challenge_key,
+        *challenge_sequence,
+        challenge_data,
+        unix_timestamp(),
+    )
+    .unwrap();
 
-    let client = connections.get_by_addr_mut(from_addr).unwrap();
+    let client = connections
+        .get_or_create_pending(*from_addr, challenge.client_id)
+        .unwrap();
     client.state = ConnectionState::Connected;
 
-    client.packet_loss_sim = PacketLossSimulation {
-        enabled: true,
-        loss_percent: 30.0,
-        min_latency_ms: 30,
-        max_latency_ms: 60,
-        jitter_ms: 20,
-    };
+    server_endpoint.transport_mut().set_link_conditions(
+        *from_addr,
+        PacketLossSimulation {
+            enabled: true,
+            loss_percent: 30.0,
+            min_latency_ms: 30,
+            max_latency_ms: 60,
+            jitter_ms: 20,
+            ..Default::default()
+        },
+        PacketLossSimulation::default(),
+    );
 
     let client_id = client.client_id;
-    let accepted = client.send_packet(
+    let accepted = send(
+        client,
         PacketType::ConnectionAccepted {
             client_id,
             entity_id: 1,
+            snapshot_codec: SnapshotCodec::default(),
+            session_token: Vec::new(),
         },
         Reliability::Reliable,
     );
@@ -584,18 +838,18 @@ fn test_connection_survives_packet_loss() {
         if last_send.elapsed() >= send_interval {
             let client = connections.iter_mut().next().unwrap();
 
-            if !client.packet_loss_sim.should_drop() {
-                let snapshot = dual::WorldSnapshot::new(
-                    client.send_sequence,
-                    start.elapsed().as_millis() as u64,
-                );
-                let packet = client
-                    .send_packet(PacketType::WorldSnapshot(snapshot), Reliability::Unreliable);
-                let _ = server_endpoint.send_to(&packet, client.addr);
-            }
+            let snapshot =
+                dual::WorldSnapshot::new(client.send_sequence, start.elapsed().as_millis() as u64);
+            let packet = send(
+                client,
+                PacketType::WorldSnapshot(snapshot),
+                Reliability::Unreliable,
+            );
+            let _ = server_endpoint.send_to(&packet, client.addr);
 
             // Also send resends
-            for packet in client.collect_resends() {
+            client.queue_resends();
+            for packet in client.flush() {
                 let _ = server_endpoint.send_to(&packet, client.addr);
             }
 
@@ -609,19 +863,22 @@ fn test_connection_survives_packet_loss() {
         }
 
         // Client Resends
-        for packet in client_conn.collect_resends() {
+        client_conn.queue_resends();
+        for packet in client_conn.flush() {
             let _ = client_endpoint.send(&packet);
         }
 
-        if let Ok(received) = server_endpoint.receive() {
-            for (packet, addr) in received {
+        if let Ok(received) = server_endpoint.receive_sized() {
+            for (packet, addr, size) in received {
                 if let Some(client) = connections.get_by_addr_mut(&addr) {
+                    client.record_received(size);
                     client.process_packet(packet); // Needed to process ACKs!
                 }
             }
         }
 
-        let ping = client_conn.send_packet(
+        let ping = send(
+            &mut client_conn,
             PacketType::Ping {
                 timestamp: start.elapsed().as_millis() as u64,
             },
@@ -644,4 +901,1001 @@ fn test_connection_survives_packet_loss() {
         1,
         "Connection should survive with 30% packet loss"
     );
+
+    // The server's view of the link should reflect what the conditioner did to it
+    let stats = connections.iter_mut().next().unwrap().stats().clone();
+    let loss =
+        stats.packets_lost as f32 * 100.0 / (stats.packets_acked + stats.packets_lost) as f32;
+    assert!((15.0..45.0).contains(&loss), "Measured {}% loss", loss);
+    assert!(stats.rtt_ms >= 30.0, "Measured {}ms RTT", stats.rtt_ms);
+    assert!(stats.history().len() >= 3);
+    assert!(stats.receive_bytes_per_sec > 0);
+}
+
+#[test]
+fn test_messages_aggregate_into_one_datagram() {
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
+    let mut server_conn = ClientConnection::new(client_addr, 1);
+    let mut client_conn = ClientConnection::new(server_addr, 0);
+
+    server_conn
+        .queue_message(
+            PacketType::WorldSnapshot(large_snapshot(9, 4)),
+            Reliability::Unreliable,
+        )
+        .unwrap();
+    server_conn
+        .queue_message(
+            PacketType::Pong {
+                timestamp: 77,
+                server_time_ms: 0,
+            },
+            Reliability::Unreliable,
+        )
+        .unwrap();
+    server_conn
+        .queue_message(PacketType::Disconnect, Reliability::Reliable)
+        .unwrap();
+
+    let packets = server_conn.flush();
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].messages.len(), 3);
+    assert!(!server_conn.has_queued_messages());
+
+    server_endpoint.send_to(&packets[0], client_addr).unwrap();
+    assert_eq!(server_endpoint.stats().packets_sent, 1);
+
+    let received = wait_for_packet(&mut client_endpoint, 200).expect("No packet received");
+    let payloads = client_conn.process_packet(received[0].0.clone());
+    assert_eq!(payloads.len(), 3);
+    assert!(matches!(payloads[0], PacketType::WorldSnapshot(_)));
+    assert!(matches!(
+        payloads[1],
+        PacketType::Pong { timestamp: 77, .. }
+    ));
+    assert!(matches!(payloads[2], PacketType::Disconnect));
+}
+
+#[test]
+fn test_flush_splits_messages_at_mtu() {
+    let addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+    let mut conn = ClientConnection::new(addr, 0);
+
+    for tick in 0..12 {
+        conn.queue_message(
+            PacketType::WorldSnapshot(large_snapshot(tick, 8)),
+            Reliability::Unreliable,
+        )
+        .unwrap();
+    }
+
+    let packets = conn.flush();
+    assert!(packets.len() > 1);
+    assert_eq!(packets.iter().map(|p| p.messages.len()).sum::<usize>(), 12);
+    for packet in &packets {
+        assert!(packet.serialize().unwrap().len() <= MAX_PACKET_SIZE);
+    }
+
+    let sequences: Vec<u32> = packets.iter().map(|p| p.header.sequence).collect();
+    assert_eq!(sequences, (0..packets.len() as u32).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_acks_track_messages_per_wire_sequence() {
+    let server_addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:10".parse().unwrap();
+    let mut server_conn = ClientConnection::new(client_addr, 1);
+    let mut client_conn = ClientConnection::new(server_addr, 0);
+
+    // Two reliable messages ride in the first datagram, which gets through
+    server_conn
+        .queue_message(PacketType::Ping { timestamp: 1 }, Reliability::Reliable)
+        .unwrap();
+    server_conn
+        .queue_message(PacketType::Ping { timestamp: 2 }, Reliability::Ordered)
+        .unwrap();
+    let delivered = server_conn.flush();
+
+    // A third is lost on the wire
+    server_conn
+        .queue_message(PacketType::Ping { timestamp: 3 }, Reliability::Reliable)
+        .unwrap();
+    let _lost = server_conn.flush();
+
+    assert_eq!(client_conn.process_packet(delivered[0].clone()).len(), 2);
+
+    // Any packet from the client carries the ack for the first wire sequence
+    let reply = send(
+        &mut client_conn,
+        PacketType::Pong {
+            timestamp: 1,
+            server_time_ms: 0,
+        },
+        Reliability::Unreliable,
+    );
+    server_conn.process_packet(reply);
+
+    thread::sleep(Duration::from_millis(250));
+    assert_eq!(server_conn.queue_resends(), 1);
+
+    let resend = server_conn.flush();
+    assert_eq!(resend.len(), 1);
+    let payloads = client_conn.process_packet(resend[0].clone());
+    assert!(matches!(payloads[..], [PacketType::Ping { timestamp: 3 }]));
+}
+
+#[test]
+fn test_channels_deliver_100k_messages_under_loss() {
+    let server_addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:10".parse().unwrap();
+    let mut server_conn = ClientConnection::new(client_addr, 1);
+    let mut client_conn = ClientConnection::new(server_addr, 0);
+
+    // Each channel wraps its u16 sequence at least once
+    const COUNT: u64 = 100_000;
+    let mut next = [0u64; 2];
+    let mut received = [Vec::new(), Vec::new()];
+    let mut now = Instant::now();
+    let mut rng = 0x2545_f491_4f6c_dd1du64;
+    let mut lost = |percent: u64| {
+        rng = rng
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (rng >> 33) % 100 < percent
+    };
+    let mut backpressured = false;
+
+    while received.iter().map(Vec::len).sum::<usize>() < 2 * COUNT as usize {
+        for (channel, reliability) in [Reliability::Reliable, Reliability::Ordered]
+            .into_iter()
+            .enumerate()
+        {
+            while next[channel] < COUNT {
+                // The channel rides in the high bits so deliveries can be told apart
+                let timestamp = (channel as u64) << 32 | next[channel];
+                if server_conn
+                    .queue_message(PacketType::Ping { timestamp }, reliability)
+                    .is_err()
+                {
+                    backpressured = true;
+                    break;
+                }
+                next[channel] += 1;
+            }
+            assert!(server_conn.in_flight(reliability) <= CHANNEL_WINDOW);
+            assert!(server_conn.send_queue_len(reliability) <= MAX_SEND_QUEUE);
+        }
+
+        now += Duration::from_millis(60);
+        server_conn.queue_resends_at(now);
+        for packet in server_conn.flush() {
+            if lost(20) {
+                continue;
+            }
+            for payload in client_conn.process_packet(packet) {
+                let PacketType::Ping { timestamp } = payload else {
+                    panic!("unexpected payload");
+                };
+                received[(timestamp >> 32) as usize].push(timestamp & u32::MAX as u64);
+            }
+        }
+
+        if !lost(20) {
+            let reply = send(
+                &mut client_conn,
+                PacketType::Pong {
+                    timestamp: 0,
+                    server_time_ms: 0,
+                },
+                Reliability::Unreliable,
+            );
+            server_conn.process_packet(reply);
+        }
+    }
+
+    assert!(backpressured);
+    let mut reliable = received[0].clone();
+    reliable.sort_unstable();
+    assert_eq!(reliable, (0..COUNT).collect::<Vec<_>>());
+    assert_eq!(received[1], (0..COUNT).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_session_traffic_is_authenticated() {
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
+
+    let token = TokenIssuer::new(TEST_KEY).issue(1, &[server_addr]).unwrap();
+
+    let mut client_conn = ClientConnection::new(server_addr, 0);
+    client_conn.set_session_keys(&token.client_to_server_key, &token.server_to_client_key);
+    client_conn.state = ConnectionState::Connected;
+
+    let mut server_conn = ClientConnection::new(client_addr, 1);
+    server_conn.set_session_keys(&token.server_to_client_key, &token.client_to_server_key);
+    server_conn.state = ConnectionState::Connected;
+
+    let packet = send(
+        &mut client_conn,
+        PacketType::Ping { timestamp: 5 },
+        Reliability::Reliable,
+    );
+    assert!(packet.is_encrypted());
+    client_endpoint.send_to(&packet, server_addr).unwrap();
+
+    let received = wait_for_packet(&mut server_endpoint, 200).expect("No packet received");
+    let payloads = server_conn.process_packet(received[0].0.clone());
+    assert!(matches!(payloads[..], [PacketType::Ping { timestamp: 5 }]));
+
+    // A spoofed plaintext disconnect is dropped without touching acks
+    let last_ack = server_conn.receive_tracker.ack_data();
+    let spoofed = Packet::new(PacketHeader::new(1, 0, 0), PacketType::Disconnect);
+    assert!(server_conn.process_packet(spoofed).is_empty());
+
+    // So is one sealed with the wrong key
+    let mut forger = ClientConnection::new(server_addr, 0);
+    forger.set_session_keys(&[0; KEY_BYTES], &[0; KEY_BYTES]);
+    forger.state = ConnectionState::Connected;
+    let forged = send(&mut forger, PacketType::Disconnect, Reliability::Unreliable);
+    assert!(server_conn.process_packet(forged).is_empty());
+
+    assert_eq!(server_conn.auth_failures, 2);
+    assert_eq!(server_conn.receive_tracker.ack_data(), last_ack);
+
+    server_endpoint.record_auth_failures(server_conn.auth_failures);
+    assert_eq!(server_endpoint.stats().auth_failures, 2);
+}
+
+#[test]
+fn test_session_resumes_from_new_address() {
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let old_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+    let new_addr: SocketAddr = "127.0.0.1:40002".parse().unwrap();
+
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, new_addr);
+
+    let token = TokenIssuer::new(TEST_KEY).issue(7, &[server_addr]).unwrap();
+    let session_key = generate_key().unwrap();
+
+    let mut connections = ConnectionManager::new(4);
+    let server_conn = connections.get_or_create_pending(old_addr, 7).unwrap();
+    server_conn.set_session_keys(&token.server_to_client_key, &token.client_to_server_key);
+    server_conn.state = ConnectionState::Connected;
+    let client_id = server_conn.client_id;
+
+    let session_token = SessionToken {
+        client_id,
+        token_client_id: 7,
+    }
+    .seal(&session_key, 1)
+    .unwrap();
+
+    let mut client_conn = ClientConnection::new(server_addr, 0);
+    client_conn.set_session_keys(&token.client_to_server_key, &token.server_to_client_key);
+    client_conn.state = ConnectionState::Connected;
+
+    let inner = send(
+        &mut client_conn,
+        PacketType::Ping { timestamp: 9 },
+        Reliability::Unreliable,
+    );
+    let resume = Packet::new(
+        PacketHeader::new(0, 0, 0),
+        PacketType::SessionResume {
+            session_token: session_token.clone(),
+            packet: inner.serialize().unwrap(),
+        },
+    );
+    client_endpoint.send_to(&resume, server_addr).unwrap();
+
+    let received = wait_for_packet(&mut server_endpoint, 200).expect("No packet received");
+    let (packet, from_addr) = &received[0];
+    assert_eq!(*from_addr, new_addr);
+
+    let PacketType::SessionResume {
+        session_token,
+        packet: sealed,
+    } = &packet.messages[0].payload
+    else {
+        panic!("Expected SessionResume");
+    };
+    let session = SessionToken::open(&session_key, session_token).unwrap();
+    assert_eq!(session.client_id, client_id);
+    assert!(SessionToken::open(&generate_key().unwrap(), session_token).is_err());
+
+    let inner = Packet::deserialize(sealed).unwrap();
+    let conn = connections.get_mut(session.client_id).unwrap();
+    let payloads = conn.resume(inner.clone()).expect("Resume should succeed");
+    assert!(matches!(payloads[..], [PacketType::Ping { timestamp: 9 }]));
+
+    // Replaying the same packet from yet another address gets nowhere
+    assert!(conn.resume(inner).is_none());
+
+    assert!(connections.rebind(client_id, new_addr));
+    assert!(connections.get_by_addr(&old_addr).is_none());
+    assert_eq!(
+        connections.get_by_addr(&new_addr).unwrap().client_id,
+        client_id
+    );
+}
+
+#[test]
+fn test_foreign_version_surfaces_without_parsing() {
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut future_client = hub.bind(client_addr).unwrap();
+
+    // A later version with an archive this build can't make sense of
+    let mut datagram = Vec::new();
+    datagram.extend_from_slice(&PROTOCOL_MAGIC.to_le_bytes());
+    datagram.extend_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+    datagram.extend_from_slice(&[0xAB; 600]);
+    future_client.send_to(&datagram, server_addr).unwrap();
+
+    assert!(server_endpoint.receive().unwrap().is_empty());
+    assert_eq!(
+        server_endpoint.take_version_mismatches(),
+        vec![(client_addr, datagram.len())]
+    );
+    assert!(server_endpoint.take_version_mismatches().is_empty());
+
+    server_endpoint.send_version_denial(client_addr).unwrap();
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    let (size, _) = future_client.recv_from(&mut buf).unwrap();
+    assert!(size <= datagram.len());
+    let (magic, server_version) = read_prefix(&buf[..size]).unwrap();
+    assert_eq!(magic, VERSION_DENIAL_MAGIC);
+    assert_eq!(server_version, PROTOCOL_VERSION);
+    assert_eq!(
+        DenyReason::decode_version_denial(&buf[..size]),
+        Some(DenyReason::VersionMismatch {
+            server_version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+        })
+    );
+}
+
+#[test]
+fn test_mtu_discovery_finds_path_limit() {
+    const PATH_MTU: usize = 1300;
+
+    let token = TokenIssuer::new(TEST_KEY)
+        .issue(1, &["127.0.0.1:1".parse().unwrap()])
+        .unwrap();
+    let server_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:2".parse().unwrap();
+
+    let mut client_conn = ClientConnection::new(server_addr, 0);
+    client_conn.set_session_keys(&token.client_to_server_key, &token.server_to_client_key);
+    client_conn.state = ConnectionState::Connected;
+
+    let mut server_conn = ClientConnection::new(client_addr, 1);
+    server_conn.set_session_keys(&token.server_to_client_key, &token.client_to_server_key);
+    server_conn.state = ConnectionState::Connected;
+    server_conn.start_mtu_discovery();
+
+    let mut probe_sizes = Vec::new();
+    let start = std::time::Instant::now();
+    while server_conn.is_probing_mtu() && start.elapsed() < Duration::from_secs(5) {
+        for packet in server_conn.flush() {
+            let mtu = server_conn.mtu_for(&packet);
+            let size = packet.serialize().unwrap().len();
+            // Nothing else is queued, so every packet is a probe. Archives are
+            // padded to their alignment, so probes land just under their size
+            assert!(size <= mtu && size + 16 > mtu);
+            probe_sizes.push(mtu);
+            if size <= PATH_MTU {
+                assert!(client_conn.process_packet(packet).is_empty());
+            }
+        }
+
+        client_conn
+            .queue_message(PacketType::Ping { timestamp: 0 }, Reliability::Unreliable)
+            .unwrap();
+        for packet in client_conn.flush() {
+            server_conn.process_packet(packet);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert!(!server_conn.is_probing_mtu());
+    assert_eq!(server_conn.mtu(), 1280);
+    assert_eq!(&probe_sizes[..4], &[548, 1024, 1200, 1280]);
+    assert!(probe_sizes[4..].iter().all(|&size| size == 1400));
+
+    // Aggregation now packs up to the discovered MTU
+    for _ in 0..40 {
+        server_conn
+            .queue_message(PacketType::Ping { timestamp: 1 }, Reliability::Unreliable)
+            .unwrap();
+    }
+    for packet in server_conn.flush() {
+        assert!(packet.serialize().unwrap().len() <= 1280);
+    }
+}
+
+#[test]
+fn test_refused_send_steps_mtu_down() {
+    let token = TokenIssuer::new(TEST_KEY)
+        .issue(1, &["127.0.0.1:1".parse().unwrap()])
+        .unwrap();
+    let mut conn = ClientConnection::new("127.0.0.1:2".parse().unwrap(), 1);
+    conn.set_session_keys(&token.server_to_client_key, &token.client_to_server_key);
+    conn.state = ConnectionState::Connected;
+    assert_eq!(conn.mtu(), MAX_PACKET_SIZE);
+
+    // An ordinary packet that no longer fits means the path shrank
+    conn.queue_message(PacketType::Ping { timestamp: 0 }, Reliability::Reliable)
+        .unwrap();
+    let packets = conn.flush();
+    assert_eq!(conn.mtu_for(&packets[0]), MAX_PACKET_SIZE);
+    conn.on_packet_too_large(&packets[0]);
+    assert_eq!(conn.mtu(), 1024);
+    assert!(conn.is_probing_mtu());
+
+    // Probing back up is refused as well, which settles on the lower rung
+    let probe = conn.flush().pop().unwrap();
+    assert_eq!(conn.mtu_for(&probe), MAX_PACKET_SIZE);
+    conn.on_packet_too_large(&probe);
+    assert!(!conn.is_probing_mtu());
+    assert_eq!(conn.mtu(), 1024);
+}
+#[test]
+fn test_block_transfer_over_lossy_link() {
+    let token = TokenIssuer::new(TEST_KEY)
+        .issue(1, &["127.0.0.1:1".parse().unwrap()])
+        .unwrap();
+
+    let mut client_conn = ClientConnection::new("127.0.0.1:1".parse().unwrap(), 0);
+    client_conn.set_session_keys(&token.client_to_server_key, &token.server_to_client_key);
+    client_conn.state = ConnectionState::Connected;
+
+    let mut server_conn = ClientConnection::new("127.0.0.1:2".parse().unwrap(), 1);
+    server_conn.set_session_keys(&token.server_to_client_key, &token.client_to_server_key);
+    server_conn.state = ConnectionState::Connected;
+
+    client_conn.accept_blocks(BlockKind::Map, MAX_BLOCK_SIZE);
+
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let id = server_conn
+        .send_block(BlockKind::Map, data.clone())
+        .unwrap();
+
+    let mut sent = 0;
+    let mut completed = Vec::new();
+    let start = std::time::Instant::now();
+    while completed.is_empty() && start.elapsed() < Duration::from_secs(10) {
+        for packet in server_conn.flush() {
+            assert!(packet.serialize().unwrap().len() <= server_conn.mtu());
+            sent += 1;
+            if sent % 5 != 0 {
+                client_conn.process_packet(packet);
+            }
+        }
+
+        let progress = client_conn.block_progress();
+        if let Some(progress) = progress.first() {
+            assert_eq!(progress.direction, BlockDirection::Incoming);
+            assert!(progress.bytes_done <= data.len());
+        }
+
+        for packet in client_conn.flush() {
+            server_conn.process_packet(packet);
+        }
+        completed = client_conn.take_completed_blocks();
+        thread::sleep(Duration::from_millis(2));
+    }
+
+    assert_eq!(completed.len(), 1);
+    assert_eq!(completed[0].transfer_id, id);
+    assert_eq!(completed[0].data, data);
+
+    // The last acks make it back and the sender forgets the block
+    for packet in client_conn.flush() {
+        server_conn.process_packet(packet);
+    }
+    assert!(server_conn.block_progress().is_empty());
+    assert!(client_conn.block_progress().is_empty());
+}
+
+#[test]
+fn test_block_transfer_cancelled_by_sender() {
+    let mut client_conn = ClientConnection::new("127.0.0.1:1".parse().unwrap(), 0);
+    client_conn.state = ConnectionState::Connected;
+    let mut server_conn = ClientConnection::new("127.0.0.1:2".parse().unwrap(), 1);
+    server_conn.state = ConnectionState::Connected;
+    client_conn.accept_blocks(BlockKind::WorldState, MAX_BLOCK_SIZE);
+
+    let id = server_conn
+        .send_block(BlockKind::WorldState, vec![3; 100_000])
+        .unwrap();
+    for packet in server_conn.flush() {
+        client_conn.process_packet(packet);
+    }
+    assert_eq!(client_conn.block_progress().len(), 1);
+
+    assert!(server_conn.cancel_block(BlockDirection::Outgoing, id));
+    assert!(!server_conn.cancel_block(BlockDirection::Outgoing, id));
+    for packet in server_conn.flush() {
+        client_conn.process_packet(packet);
+    }
+    assert!(client_conn.block_progress().is_empty());
+    assert!(client_conn.take_completed_blocks().is_empty());
+}
+
+#[test]
+fn test_bandwidth_is_charged_with_bytes_sent() {
+    let token = TokenIssuer::new(TEST_KEY)
+        .issue(1, &["127.0.0.1:1".parse().unwrap()])
+        .unwrap();
+    let mut server_conn = ClientConnection::new("127.0.0.1:2".parse().unwrap(), 1);
+    server_conn.set_session_keys(&token.server_to_client_key, &token.client_to_server_key);
+    server_conn.state = ConnectionState::Connected;
+
+    server_conn
+        .queue_message(
+            PacketType::WorldSnapshot(large_snapshot(1, 8)),
+            Reliability::Unreliable,
+        )
+        .unwrap();
+    server_conn
+        .queue_message(PacketType::Ping { timestamp: 1 }, Reliability::Unreliable)
+        .unwrap();
+    let packets = server_conn.flush();
+    assert_eq!(packets.len(), 1);
+    assert!(packets[0].is_encrypted());
+
+    let sent = packets[0].serialize().unwrap().len();
+    server_conn.record_sent(&packets[0], sent);
+    assert_eq!(server_conn.bandwidth.avg_snapshot_bytes(), sent as u32);
+
+    // Traffic without a snapshot draws on the allowance but isn't averaged in
+    server_conn
+        .queue_message(PacketType::Ping { timestamp: 2 }, Reliability::Unreliable)
+        .unwrap();
+    let ping = server_conn.flush().remove(0);
+    server_conn.record_sent(&ping, 1000);
+    assert_eq!(server_conn.bandwidth.avg_snapshot_bytes(), sent as u32);
+}
+
+#[test]
+fn test_unaccepted_block_is_refused_by_receiver() {
+    let mut client_conn = ClientConnection::new("127.0.0.1:1".parse().unwrap(), 0);
+    client_conn.state = ConnectionState::Connected;
+    let mut server_conn = ClientConnection::new("127.0.0.1:2".parse().unwrap(), 1);
+    server_conn.state = ConnectionState::Connected;
+
+    // Nothing was accepted on the server side, as for any new connection
+    client_conn
+        .send_block(BlockKind::Map, vec![5; MAX_BLOCK_SIZE])
+        .unwrap();
+    for packet in client_conn.flush() {
+        server_conn.process_packet(packet);
+    }
+    assert!(server_conn.block_progress().is_empty());
+
+    for packet in server_conn.flush() {
+        client_conn.process_packet(packet);
+    }
+    assert!(client_conn.block_progress().is_empty());
+    assert!(server_conn.take_completed_blocks().is_empty());
+}
+
+#[test]
+fn test_endpoint_captures_raw_datagrams() {
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let mut server = bind(&hub, server_addr);
+    let mut client = bind(&hub, "127.0.0.1:40001".parse().unwrap());
+    let client_addr = client.local_addr();
+
+    let path = std::env::temp_dir().join(format!("dual-capture-{}.cap", std::process::id()));
+    client.start_capture(CaptureWriter::create(&path).unwrap());
+
+    let ping = Packet::new(
+        PacketHeader::new(7, 3, 0b101),
+        PacketType::Ping { timestamp: 42 },
+    );
+    client.send_to(&ping, server_addr).unwrap();
+    // Big enough to need fragments, each captured as its own datagram
+    let probe = Packet::new(
+        PacketHeader::new(8, 3, 0),
+        PacketType::MtuProbe {
+            padding: vec![0; 2000],
+        },
+    );
+    client.send_to(&probe, server_addr).unwrap();
+
+    let pong = Packet::new(
+        PacketHeader::new(1, 7, 0),
+        PacketType::Pong {
+            timestamp: 42,
+            server_time_ms: 0,
+        },
+    );
+    server.send_to(&pong, client_addr).unwrap();
+    // Junk still lands in the capture even though the endpoint ignores it
+    hub.bind("127.0.0.1:40002")
+        .unwrap()
+        .send_to(b"garbage!", client_addr)
+        .unwrap();
+    assert_eq!(client.receive().unwrap().len(), 1);
+
+    let writer = client.stop_capture().unwrap().unwrap();
+    assert!(!client.is_capturing());
+
+    let records: Vec<_> = CaptureReader::open(&path)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(records.len() as u64, writer.records());
+
+    let sent: Vec<_> = records
+        .iter()
+        .filter(|r| r.direction == CaptureDirection::Sent)
+        .collect();
+    assert!(sent.len() > 2);
+    assert!(sent.iter().all(|r| r.peer == server_addr));
+
+    let mut aligned = rkyv::util::AlignedVec::<16>::new();
+    aligned.extend_from_slice(&sent[0].data);
+    let archived = Packet::access_archived(&aligned).unwrap();
+    assert_eq!(archived.header.sequence, 7);
+    assert_eq!(archived.header.ack_bitfield, 0b101);
+
+    let received: Vec<_> = records
+        .iter()
+        .filter(|r| r.direction == CaptureDirection::Received)
+        .collect();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[1].data, b"garbage!");
+}
+
+#[test]
+fn test_dual_stack_handshake_over_both_families() {
+    let mut server_endpoint =
+        NetworkEndpoint::with_transport(UdpTransport::bind_dual_stack(0).unwrap()).unwrap();
+    let port = server_endpoint.local_addr().port();
+    let challenge_key = generate_key().unwrap();
+
+    let targets: [SocketAddr; 2] = [
+        (Ipv4Addr::LOCALHOST, port).into(),
+        (Ipv6Addr::LOCALHOST, port).into(),
+    ];
+    for server_addr in targets {
+        let mut client_endpoint =
+            NetworkEndpoint::with_transport(UdpTransport::bind_for(server_addr).unwrap()).unwrap();
+        client_endpoint.set_remote(server_addr);
+        let token = TokenIssuer::new(TEST_KEY).issue(7, &[server_addr]).unwrap();
+
+        let mut client_conn = ClientConnection::new(server_addr, 0);
+        let request = send(
+            &mut client_conn,
+            connection_request(&token),
+            Reliability::Unreliable,
+        );
+        client_endpoint.send(&request).unwrap();
+
+        let received = wait_for_packet(&mut server_endpoint, 500).expect("No request received");
+        let (packet, from_addr) = &received[0];
+        // Peers come through in their own family, never v4-mapped
+        assert_eq!(from_addr.is_ipv4(), server_addr.is_ipv4());
+
+        let private = open_request(&packet.messages[0].payload).unwrap();
+        assert!(private.lists_server(server_endpoint.local_addr()));
+
+        let challenge = challenge_packet(&challenge_key, *from_addr, &private, &token);
+        server_endpoint.send_to(&challenge, *from_addr).unwrap();
+
+        let received = wait_for_packet(&mut client_endpoint, 500).expect("No challenge received");
+        match &received[0].0.messages[0].payload {
+            PacketType::ConnectionChallenge {
+                challenge_sequence,
+                challenge_data,
+            } => {
+                let challenge = ChallengeToken::open(
+                    &challenge_key,
+                    *challenge_sequence,
+                    challenge_data,
+                    unix_timestamp(),
+                )
+                .unwrap();
+                assert_eq!(challenge.client_addr, *from_addr);
+            }
+            _ => panic!("Expected ConnectionChallenge"),
+        }
+    }
+}
+
+#[test]
+fn test_query_answered_only_after_challenge() {
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client =
+        QueryClient::with_transport(hub.bind(client_addr).unwrap(), Duration::from_secs(2))
+            .unwrap();
+
+    // Stands in for the server: challenge anything unproven, answer the rest
+    let server = thread::spawn(move || {
+        let challenger = QueryChallenger::new(&TEST_KEY);
+        let mut challenges_sent = 0;
+        let mut answered = 0;
+        while answered < 2 {
+            let received = wait_for_packet(&mut server_endpoint, 2000).expect("No query received");
+            for (packet, addr) in received {
+                let PacketType::QueryRequest {
+                    kind, challenge, ..
+                } = packet.messages[0].payload
+                else {
+                    panic!("Expected QueryRequest");
+                };
+
+                let reply = if challenger.verify(addr, challenge) {
+                    answered += 1;
+                    PacketType::QueryResponse(match kind {
+                        QueryKind::Info => QueryResponse::Info(QueryInfo {
+                            name: "test".to_string(),
+                            map: TestingGround::NAME.to_string(),
+                            map_checksum: 0,
+                            game_mode: "deathmatch".to_string(),
+                            has_password: false,
+                            protocol_version: PROTOCOL_VERSION,
+                            tick_rate: 60,
+                            players: 0,
+                            max_players: 8,
+                        }),
+                        _ => QueryResponse::Players(Vec::new()),
+                    })
+                } else {
+                    challenges_sent += 1;
+                    PacketType::QueryChallenge {
+                        challenge: challenger.challenge_for(addr),
+                    }
+                };
+                let reply = Packet::new(PacketHeader::new(0, 0, 0), reply);
+
+                // An unproven request must not get back more than it sent
+                if challenges_sent > answered {
+                    assert!(reply.serialize().unwrap().len() <= packet.serialize().unwrap().len());
+                }
+                server_endpoint.send_to(&reply, addr).unwrap();
+            }
+        }
+        challenges_sent
+    });
+
+    match client.query(server_addr, QueryKind::Info).unwrap() {
+        QueryResponse::Info(info) => assert_eq!(info.max_players, 8),
+        other => panic!("Expected info, got {:?}", other),
+    }
+    assert!(matches!(
+        client.query(server_addr, QueryKind::Players).unwrap(),
+        QueryResponse::Players(_)
+    ));
+
+    // The second query reused the challenge from the first
+    assert_eq!(server.join().unwrap(), 1);
+}
+
+#[test]
+fn test_lan_browser_lists_answering_servers() {
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let browser_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut browser =
+        LanBrowser::with_transport(hub.bind(browser_addr).unwrap(), server_addr).unwrap();
+
+    browser.poll().unwrap();
+    let received = wait_for_packet(&mut server_endpoint, 200).expect("No probe received");
+    let (packet, from_addr) = &received[0];
+    let PacketType::DiscoveryRequest { timestamp, .. } = packet.messages[0].payload else {
+        panic!("Expected DiscoveryRequest");
+    };
+
+    let info = QueryInfo {
+        name: "LAN party".to_string(),
+        map: TestingGround::NAME.to_string(),
+        map_checksum: TestingGround::new().checksum(),
+        game_mode: "deathmatch".to_string(),
+        has_password: false,
+        protocol_version: PROTOCOL_VERSION,
+        tick_rate: 60,
+        players: 3,
+        max_players: 16,
+    };
+    let response = Packet::new(
+        PacketHeader::new(0, 0, 0),
+        PacketType::DiscoveryResponse {
+            timestamp,
+            info: info.clone(),
+        },
+    );
+    server_endpoint.send_to(&response, *from_addr).unwrap();
+
+    browser.poll().unwrap();
+    let servers = browser.servers();
+    assert_eq!(servers.len(), 1);
+    assert_eq!(servers[0].addr, server_addr);
+    assert_eq!(servers[0].info, info);
+    assert!(servers[0].ping_ms >= 0.0);
+
+    // Probes only go out once per interval
+    assert!(server_endpoint.receive().unwrap().is_empty());
+}
+
+fn pump_master(endpoint: &mut NetworkEndpoint<LoopbackTransport>, registry: &mut MasterRegistry) {
+    for (packet, addr) in endpoint.receive().unwrap() {
+        if let Some(reply) = registry.handle_packet(packet, addr) {
+            endpoint.send_to(&reply, addr).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_master_lists_registered_servers() {
+    let hub = LoopbackHub::new();
+    let master_addr: SocketAddr = "127.0.0.1:27010".parse().unwrap();
+    let mut master_endpoint = bind(&hub, master_addr);
+    let mut registry = MasterRegistry::new(Duration::from_secs(90), &TEST_KEY);
+
+    // Every fourth server is full, leaving more than a page of open ones
+    let server_count = 24;
+    for i in 0..server_count {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 41000 + i));
+        let mut endpoint = bind(&hub, addr);
+        let mut link = MasterLink::new(master_addr, Duration::from_secs(30));
+        let info = QueryInfo {
+            name: format!("server {}", i),
+            map: TestingGround::NAME.to_string(),
+            map_checksum: 0,
+            game_mode: "deathmatch".to_string(),
+            has_password: false,
+            protocol_version: PROTOCOL_VERSION,
+            tick_rate: 60,
+            players: if i % 4 == 0 { 8 } else { 1 },
+            max_players: 8,
+        };
+
+        endpoint
+            .send_to(&link.heartbeat(info.clone()), master_addr)
+            .unwrap();
+        pump_master(&mut master_endpoint, &mut registry);
+
+        let received = wait_for_packet(&mut endpoint, 200).expect("No challenge received");
+        let PacketType::QueryChallenge { challenge } = received[0].0.messages[0].payload else {
+            panic!("Expected QueryChallenge");
+        };
+        link.set_challenge(challenge);
+        assert!(link.is_due());
+        endpoint
+            .send_to(&link.heartbeat(info), master_addr)
+            .unwrap();
+        pump_master(&mut master_endpoint, &mut registry);
+    }
+    assert_eq!(registry.len(), server_count as usize);
+
+    let filter = ServerFilter {
+        not_full: true,
+        ..Default::default()
+    };
+    let mut browser =
+        MasterBrowser::with_transport(hub.bind("127.0.0.1:40001").unwrap(), master_addr, filter)
+            .unwrap();
+
+    let start = Instant::now();
+    while browser.is_fetching() && start.elapsed() < Duration::from_secs(2) {
+        browser.poll().unwrap();
+        pump_master(&mut master_endpoint, &mut registry);
+    }
+    assert!(!browser.timed_out());
+
+    let servers = browser.servers();
+    assert_eq!(servers.len(), 18);
+    assert!(servers.len() > MASTER_PAGE_SIZE);
+    assert!(servers.iter().all(|entry| entry.info.players < 8));
+}
+
+#[test]
+fn test_handshake_replies_never_outgrow_request() {
+    let hub = LoopbackHub::new();
+    let server_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+
+    let mut server_endpoint = bind(&hub, server_addr);
+    let mut client_endpoint = bind(&hub, client_addr);
+
+    let challenge_key = generate_key().unwrap();
+    let token = TokenIssuer::new(TEST_KEY).issue(7, &[server_addr]).unwrap();
+    let mut client_conn = ClientConnection::new(server_addr, 0);
+
+    client_endpoint.set_remote(server_addr);
+    let request = send(
+        &mut client_conn,
+        connection_request(&token),
+        Reliability::Unreliable,
+    );
+    client_endpoint.send(&request).unwrap();
+
+    let start = Instant::now();
+    let mut received = Vec::new();
+    while received.is_empty() && start.elapsed() < Duration::from_millis(200) {
+        received = server_endpoint.receive_sized().unwrap();
+    }
+    assert_eq!(received.len(), 1);
+    let (packet, from_addr, size) = &received[0];
+    assert!(*size >= CONNECTION_REQUEST_SIZE);
+
+    // Whatever the server says back, a spoofed request can't be amplified
+    let private = open_request(&packet.messages[0].payload).unwrap();
+    let challenge = challenge_packet(&challenge_key, *from_addr, &private, &token);
+    assert!(challenge.serialize().unwrap().len() <= *size);
+    for reason in [
+        DenyReason::ServerFull,
+        DenyReason::InvalidToken,
+        DenyReason::SessionExpired,
+    ] {
+        let denied = Packet::new(
+            PacketHeader::new(0, 0, 0),
+            PacketType::ConnectionDenied { reason },
+        );
+        assert!(denied.serialize().unwrap().len() <= *size);
+    }
+
+    // Queries and LAN discovery are answered without a handshake at all
+    let wire_size = |payload: PacketType| {
+        Packet::new(PacketHeader::new(0, 0, 0), payload)
+            .serialize()
+            .unwrap()
+            .len()
+    };
+    let query = wire_size(PacketType::query_request(QueryKind::Info, 0));
+    let discovery = wire_size(PacketType::discovery_request(0));
+    assert!(query >= QUERY_REQUEST_SIZE && discovery >= QUERY_REQUEST_SIZE);
+    let info = QueryInfo {
+        name: "x".repeat(64),
+        map: TestingGround::NAME.to_string(),
+        map_checksum: TestingGround::new().checksum(),
+        game_mode: "deathmatch".to_string(),
+        has_password: false,
+        protocol_version: PROTOCOL_VERSION,
+        tick_rate: 60,
+        players: 32,
+        max_players: 32,
+    };
+    assert!(wire_size(PacketType::QueryChallenge { challenge: 1 }) <= query);
+    assert!(wire_size(PacketType::QueryResponse(QueryResponse::Info(info.clone()))) <= query);
+    assert!(wire_size(PacketType::DiscoveryResponse { timestamp: 0, info }) <= discovery);
+
+    // Flooding from one address runs dry long before the server's budget does
+    let mut limiter = RateLimiter::new(RateLimitConfig::default());
+    let now = Instant::now();
+    let allowed = (0..100)
+        .filter(|_| limiter.check_at(from_addr.ip(), now).is_ok())
+        .count();
+    assert_eq!(
+        allowed,
+        RateLimitConfig::default().per_source_burst as usize
+    );
+    assert!(limiter.check_at("127.0.0.2".parse().unwrap(), now).is_ok());
 }
@@ -0,0 +1,84 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use clap::Parser;
+
+use dual::NetworkEndpoint;
+use dual::net::{
+    MASTER_HEARTBEAT_SECS, MASTER_MISSED_HEARTBEATS, MASTER_PORT, MasterRegistry, UdpTransport,
+    generate_key,
+};
+
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Parser)]
+#[command(name = "dual-master")]
+#[command(about = "Lists Dual servers for server browsers")]
+struct Args {
+    #[arg(
+        short,
+        long,
+        default_value = "::",
+        help = "Address to listen on; :: serves IPv4 and IPv6 together"
+    )]
+    bind: IpAddr,
+
+    #[arg(short, long, default_value_t = MASTER_PORT)]
+    port: u16,
+
+    #[arg(
+        long,
+        default_value_t = MASTER_HEARTBEAT_SECS * MASTER_MISSED_HEARTBEATS as u64,
+        help = "Seconds without a heartbeat before a server is dropped"
+    )]
+    expiry: u64,
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
+
+    let transport = if args.bind == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        match UdpTransport::bind_dual_stack(args.port) {
+            Ok(transport) => transport,
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => return Err(e.into()),
+            Err(e) => {
+                log::warn!("IPv6 unavailable ({}), listening on IPv4 only", e);
+                UdpTransport::bind((Ipv4Addr::UNSPECIFIED, args.port))?
+            }
+        }
+    } else {
+        UdpTransport::bind((args.bind, args.port))?
+    };
+    let mut endpoint = NetworkEndpoint::with_transport(transport)?;
+    let mut registry = MasterRegistry::new(Duration::from_secs(args.expiry), &generate_key()?);
+    log::info!("Master server listening on {}", endpoint.local_addr());
+
+    let mut last_expire = Instant::now();
+    loop {
+        // One bad datagram or unreachable peer shouldn't take the list down
+        let packets = endpoint.receive().unwrap_or_else(|e| {
+            log::warn!("Receive failed: {}", e);
+            Vec::new()
+        });
+        for (packet, addr) in packets {
+            if let Some(reply) = registry.handle_packet(packet, addr)
+                && let Err(e) = endpoint.send_to(&reply, addr)
+            {
+                log::warn!("Failed to reply to {}: {}", addr, e);
+            }
+        }
+
+        if last_expire.elapsed() >= EXPIRE_INTERVAL {
+            for addr in registry.expire() {
+                log::info!("Dropped server {} after missed heartbeats", addr);
+            }
+            last_expire = Instant::now();
+        }
+
+        thread::sleep(Duration::from_millis(1));
+    }
+}
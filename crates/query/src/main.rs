@@ -0,0 +1,168 @@
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use clap::Parser;
+
+use dual::DEFAULT_PORT;
+use dual::net::{MASTER_PORT, MasterBrowser, QueryClient, QueryKind, QueryResponse, ServerFilter};
+
+#[derive(Parser)]
+#[command(name = "dual-query")]
+#[command(about = "Ask a Dual server about itself without connecting")]
+struct Args {
+    #[arg(help = "Server to query, as host or host:port")]
+    server: String,
+
+    #[arg(long, help = "Ask a master server for the servers it lists instead")]
+    list: bool,
+
+    #[arg(long, requires = "list", help = "Only list servers running this map")]
+    map: Option<String>,
+
+    #[arg(
+        long,
+        requires = "list",
+        help = "Only list servers with this game mode"
+    )]
+    game_mode: Option<String>,
+
+    #[arg(long, requires = "list", help = "Skip full servers")]
+    not_full: bool,
+
+    #[arg(long, requires = "list", help = "Skip password protected servers")]
+    no_password: bool,
+
+    #[arg(long, help = "Show server info (default when nothing is picked)")]
+    info: bool,
+
+    #[arg(long, help = "List connected players")]
+    players: bool,
+
+    #[arg(long, help = "List server rules")]
+    rules: bool,
+
+    #[arg(
+        long,
+        default_value_t = 2000,
+        help = "Give up after this many ms per query"
+    )]
+    timeout: u64,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.list {
+        let master = resolve(&args.server, MASTER_PORT)?;
+        return list(
+            master,
+            ServerFilter {
+                map: args.map,
+                game_mode: args.game_mode,
+                not_full: args.not_full,
+                no_password: args.no_password,
+            },
+        );
+    }
+    let server = resolve(&args.server, DEFAULT_PORT)?;
+
+    let mut kinds = Vec::new();
+    if args.info || !(args.players || args.rules) {
+        kinds.push(QueryKind::Info);
+    }
+    if args.players {
+        kinds.push(QueryKind::Players);
+    }
+    if args.rules {
+        kinds.push(QueryKind::Rules);
+    }
+
+    let mut client = QueryClient::bind_for(server, Duration::from_millis(args.timeout))?;
+    for kind in kinds {
+        match client.query(server, kind)? {
+            QueryResponse::Info(info) => {
+                println!("{} ({})", info.name, server);
+                println!("  map       {} ({:016x})", info.map, info.map_checksum);
+                println!("  mode      {}", info.game_mode);
+                println!("  players   {}/{}", info.players, info.max_players);
+                println!("  tick rate {}", info.tick_rate);
+                println!("  protocol  v{}", info.protocol_version);
+            }
+            QueryResponse::Players(players) => {
+                println!("{} players:", players.len());
+                for player in players {
+                    println!(
+                        "  client {:<10} connected {}",
+                        player.client_id,
+                        format_duration(player.connected_secs)
+                    );
+                }
+            }
+            QueryResponse::Rules(rules) => {
+                println!("{} rules:", rules.len());
+                let width = rules.iter().map(|rule| rule.key.len()).max().unwrap_or(0);
+                for rule in rules {
+                    println!("  {:width$} {}", rule.key, rule.value, width = width);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn list(master: SocketAddr, filter: ServerFilter) -> Result<()> {
+    let mut browser = MasterBrowser::bind(master, filter)?;
+    while browser.is_fetching() {
+        browser.poll()?;
+        thread::sleep(Duration::from_millis(1));
+    }
+    if browser.timed_out() {
+        bail!("No reply from master {}", master);
+    }
+
+    println!("{} servers:", browser.servers().len());
+    for entry in browser.servers() {
+        let info = &entry.info;
+        println!(
+            "  {:<24} {:<16} {:<12} {:>7}  {}{}",
+            info.name,
+            info.map,
+            info.game_mode,
+            format!("{}/{}", info.players, info.max_players),
+            entry.addr,
+            if info.has_password { " (password)" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+// Lets the port be left off, as it usually is the default
+fn resolve(server: &str, default_port: u16) -> Result<SocketAddr> {
+    if let Ok(addr) = server.parse() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+
+    let with_port = if server.contains(':') {
+        server.to_string()
+    } else {
+        format!("{}:{}", server, default_port)
+    };
+    with_port
+        .to_socket_addrs()
+        .with_context(|| format!("Couldn't resolve {}", server))?
+        .next()
+        .with_context(|| format!("{} has no addresses", server))
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
@@ -1,22 +1,107 @@
-use dual::PacketLossSimulation;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use dual::net::{
+    BandwidthConfig, DEV_PRIVATE_KEY, Key, MASTER_HEARTBEAT_SECS, PROTOCOL_VERSION, QueryRule,
+    RateLimitConfig, SnapshotCodec,
+};
+use dual::{JitterBufferConfig, PacketLossSimulation};
 
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
+    // Shown to server browsers and dual-query
+    pub name: String,
+    pub game_mode: String,
     pub tick_rate: u32,
     pub max_clients: usize,
     pub snapshot_buffer_size: usize,
+    // Snapshot interval in ticks is adapted per client between these two
     pub snapshot_send_rate: u32,
+    pub max_snapshot_interval: u32,
+    pub bandwidth: BandwidthConfig,
     pub global_packet_loss: Option<PacketLossSimulation>,
+    // Seeds the link conditioner so a simulated run can be replayed; random if unset
+    pub link_seed: Option<u64>,
+    pub jitter_buffer: JitterBufferConfig,
+    pub snapshot_codec: SnapshotCodec,
+    pub private_key: Key,
+    pub banned_ips: HashSet<IpAddr>,
+    // Applies to everything from addresses without a connection
+    pub handshake_rate_limit: RateLimitConfig,
+    // How long a silent client keeps its session, and can resume it from a new address
+    pub session_grace_secs: u64,
+    // Sent to each client as a block transfer once it connects
+    pub server_info: Option<Vec<u8>>,
+    // Every datagram in and out is written here for dual-dissect
+    pub capture_path: Option<PathBuf>,
+    // Session keys are appended here so a capture can be decrypted
+    pub key_log_path: Option<PathBuf>,
+    // Master servers to list this server with
+    pub masters: Vec<SocketAddr>,
+    pub master_heartbeat_secs: u64,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
+            name: "Dual Server".to_string(),
+            game_mode: "deathmatch".to_string(),
             tick_rate: 60,
             max_clients: 32,
             snapshot_buffer_size: 256,
             snapshot_send_rate: 1,
+            max_snapshot_interval: 6,
+            bandwidth: BandwidthConfig::default(),
             global_packet_loss: None,
+            link_seed: None,
+            jitter_buffer: JitterBufferConfig::default(),
+            snapshot_codec: SnapshotCodec::default(),
+            private_key: DEV_PRIVATE_KEY,
+            banned_ips: HashSet::new(),
+            handshake_rate_limit: RateLimitConfig::default(),
+            session_grace_secs: 120,
+            server_info: None,
+            capture_path: None,
+            key_log_path: None,
+            masters: Vec::new(),
+            master_heartbeat_secs: MASTER_HEARTBEAT_SECS,
         }
     }
 }
+
+impl ServerConfig {
+    /// The settings worth showing to anyone who asks, without connecting.
+    pub fn rules(&self) -> Vec<QueryRule> {
+        let codec = match self.snapshot_codec {
+            SnapshotCodec::Rkyv => "rkyv",
+            SnapshotCodec::Bitpacked(_) => "bitpacked",
+        };
+        [
+            ("protocol_version", PROTOCOL_VERSION.to_string()),
+            ("tick_rate", self.tick_rate.to_string()),
+            ("max_clients", self.max_clients.to_string()),
+            ("snapshot_send_rate", self.snapshot_send_rate.to_string()),
+            (
+                "max_snapshot_interval",
+                self.max_snapshot_interval.to_string(),
+            ),
+            ("snapshot_codec", codec.to_string()),
+            (
+                "min_bandwidth",
+                self.bandwidth.min_bytes_per_sec.to_string(),
+            ),
+            (
+                "max_bandwidth",
+                self.bandwidth.max_bytes_per_sec.to_string(),
+            ),
+            ("session_grace_secs", self.session_grace_secs.to_string()),
+        ]
+        .into_iter()
+        .map(|(key, value)| QueryRule {
+            key: key.to_string(),
+            value,
+        })
+        .collect()
+    }
+}
@@ -1,5 +1,7 @@
 use std::net::SocketAddr;
 
+use dual::net::{BlockKind, DenyReason};
+
 #[derive(Debug, Clone)]
 pub enum ServerEvent {
     ClientConnecting {
@@ -10,13 +12,23 @@ pub enum ServerEvent {
         addr: SocketAddr,
         entity_id: u32,
     },
+    ClientResumed {
+        client_id: u32,
+        old_addr: SocketAddr,
+        addr: SocketAddr,
+    },
     ClientDisconnected {
         client_id: u32,
         reason: DisconnectReason,
     },
     ConnectionDenied {
         addr: SocketAddr,
-        reason: String,
+        reason: DenyReason,
+    },
+    BlockReceived {
+        client_id: u32,
+        kind: BlockKind,
+        size: usize,
     },
     Error {
         message: String,
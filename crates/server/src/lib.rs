@@ -0,0 +1,4 @@
+pub mod config;
+pub mod events;
+pub mod server;
+pub mod tui;
@@ -1,13 +1,10 @@
-mod config;
-mod events;
-mod server;
-mod tui;
-
 use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
 use std::sync::atomic::Ordering;
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use clap::Parser;
 use crossterm::event::{self, Event, KeyCode, KeyEventKind};
 use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
@@ -15,28 +12,71 @@ use crossterm::{cursor, execute};
 use ratatui::Terminal;
 use ratatui::backend::CrosstermBackend;
 
-use config::ServerConfig;
 use dual::PacketLossSimulation;
-use events::ServerEvent;
-use server::GameServer;
-use tui::TuiState;
+use dual::net::{
+    BandwidthConfig, BurstLoss, DEV_PRIVATE_KEY, QuantizationConfig, SnapshotCodec, key_from_hex,
+};
+use dual_server::config::ServerConfig;
+use dual_server::events::ServerEvent;
+use dual_server::server::GameServer;
+use dual_server::tui::{self, TuiState};
+
+const DEV_KEY_WARNING: &str = "Using the development private key, so only this machine can connect; pass --private-key for public servers";
 
 #[derive(Parser)]
 #[command(name = "dual-server")]
 #[command(about = "Dual game server")]
 struct Args {
-    #[arg(short, long, default_value = "0.0.0.0")]
-    bind: String,
+    #[arg(
+        short,
+        long,
+        help = "Address to listen on; :: serves IPv4 and IPv6 together [default: ::, or 127.0.0.1 without --private-key]"
+    )]
+    bind: Option<IpAddr>,
 
     #[arg(short, long, default_value_t = dual::DEFAULT_PORT)]
     port: u16,
 
+    #[arg(
+        long,
+        default_value = "Dual Server",
+        help = "Name shown to server browsers"
+    )]
+    name: String,
+
+    #[arg(
+        long,
+        default_value = "deathmatch",
+        help = "Game mode shown to server browsers"
+    )]
+    game_mode: String,
+
+    #[arg(
+        long = "master",
+        help = "Master server to list this server with; repeatable"
+    )]
+    masters: Vec<String>,
+
+    #[arg(
+        long,
+        default_value_t = dual::net::MASTER_HEARTBEAT_SECS,
+        help = "Seconds between heartbeats to each master"
+    )]
+    heartbeat: u64,
+
     #[arg(short, long, default_value_t = 60)]
     tick_rate: u32,
 
     #[arg(short, long, default_value_t = 32)]
     max_clients: usize,
 
+    #[arg(
+        long,
+        default_value_t = 120,
+        help = "Seconds a silent client keeps its session and can resume it"
+    )]
+    session_grace: u64,
+
     #[arg(long)]
     headless: bool,
 
@@ -54,11 +94,87 @@ struct Args {
 
     #[arg(long, default_value_t = 0, help = "Jitter in ms")]
     jitter: u32,
+
+    #[arg(long, default_value_t = 0.0, help = "Packet duplication percentage")]
+    duplicate_percent: f32,
+
+    #[arg(long, default_value_t = 0.0, help = "Packet reordering percentage")]
+    reorder_percent: f32,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Link bandwidth cap in KB/s (0 = none)"
+    )]
+    bandwidth_cap: u32,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Longest queueing delay behind the bandwidth cap in ms (0 = none)"
+    )]
+    queue_limit: u32,
+
+    #[arg(
+        long,
+        help = "Chance per packet of entering a loss burst, in percent (Gilbert-Elliott)"
+    )]
+    burst_enter: Option<f32>,
+
+    #[arg(
+        long,
+        default_value_t = 25.0,
+        help = "Chance per packet of leaving a loss burst"
+    )]
+    burst_exit: f32,
+
+    #[arg(
+        long,
+        default_value_t = 100.0,
+        help = "Packet loss percentage during a burst"
+    )]
+    burst_loss: f32,
+
+    #[arg(long, help = "Seed for the link simulation, to replay a run")]
+    sim_seed: Option<u64>,
+
+    #[arg(long, default_value_t = 8, help = "Per-client bandwidth floor in KB/s")]
+    min_bandwidth: u32,
+
+    #[arg(
+        long,
+        default_value_t = 256,
+        help = "Per-client bandwidth ceiling in KB/s"
+    )]
+    max_bandwidth: u32,
+
+    #[arg(long, help = "Send snapshots with the bit-packed quantized codec")]
+    bitpacked_snapshots: bool,
+
+    #[arg(
+        long,
+        help = "Hex key shared with the token issuer (defaults to the development key)"
+    )]
+    private_key: Option<String>,
+
+    #[arg(long = "ban", help = "Refuse connections from this IP; repeatable")]
+    banned_ips: Vec<IpAddr>,
+
+    #[arg(long, help = "File sent to every client as the server config block")]
+    server_info: Option<PathBuf>,
+
+    #[arg(long, help = "Record every datagram to this file for dual-dissect")]
+    capture: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Append session keys to this file so dual-dissect can decrypt a capture"
+    )]
+    key_log: Option<PathBuf>,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    let bind_addr = format!("{}:{}", args.bind, args.port);
 
     let global_packet_loss = if args.simulate_packet_loss {
         Some(PacketLossSimulation {
@@ -67,15 +183,74 @@ fn main() -> Result<()> {
             min_latency_ms: args.min_latency,
             max_latency_ms: args.max_latency,
             jitter_ms: args.jitter,
+            burst: args.burst_enter.map(|enter_percent| BurstLoss {
+                enter_percent,
+                exit_percent: args.burst_exit,
+                loss_percent: args.burst_loss,
+            }),
+            duplicate_percent: args.duplicate_percent,
+            reorder_percent: args.reorder_percent,
+            bandwidth_bytes_per_sec: args.bandwidth_cap * 1024,
+            queue_limit_ms: args.queue_limit,
         })
     } else {
         None
     };
 
+    let private_key = match args.private_key.as_deref() {
+        Some(hex) => key_from_hex(hex)?,
+        None => DEV_PRIVATE_KEY,
+    };
+    let using_dev_key = args.private_key.is_none();
+
+    // Anyone can mint tokens for the development key, so it never leaves this machine
+    let bind = match args.bind {
+        Some(ip) if using_dev_key && !ip.is_loopback() => bail!(
+            "Refusing to listen on {} with the public development key; pass --private-key",
+            ip
+        ),
+        Some(ip) => ip,
+        None if using_dev_key => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        None => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    if using_dev_key && !args.masters.is_empty() {
+        bail!("Refusing to list with masters using the public development key; pass --private-key");
+    }
+    let bind_addr = SocketAddr::new(bind, args.port).to_string();
+
+    let server_info = args.server_info.as_ref().map(std::fs::read).transpose()?;
+
+    let masters = args
+        .masters
+        .iter()
+        .map(|master| resolve_master(master))
+        .collect::<Result<Vec<_>>>()?;
+
     let config = ServerConfig {
+        name: args.name,
+        game_mode: args.game_mode,
+        masters,
+        master_heartbeat_secs: args.heartbeat,
         tick_rate: args.tick_rate,
         max_clients: args.max_clients,
+        session_grace_secs: args.session_grace,
         global_packet_loss,
+        link_seed: args.sim_seed,
+        snapshot_codec: if args.bitpacked_snapshots {
+            SnapshotCodec::Bitpacked(QuantizationConfig::default())
+        } else {
+            SnapshotCodec::Rkyv
+        },
+        bandwidth: BandwidthConfig {
+            min_bytes_per_sec: args.min_bandwidth * 1024,
+            max_bytes_per_sec: args.max_bandwidth * 1024,
+            ..Default::default()
+        },
+        private_key,
+        banned_ips: args.banned_ips.into_iter().collect(),
+        server_info,
+        capture_path: args.capture,
+        key_log_path: args.key_log,
         ..Default::default()
     };
 
@@ -84,16 +259,37 @@ fn main() -> Result<()> {
     if args.headless {
         env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
         log::info!("Server started on {}", server.local_addr());
+        if using_dev_key {
+            log::warn!("{}", DEV_KEY_WARNING);
+        }
         server.run();
         log::info!("Server shutting down");
     } else {
-        run_with_tui(&mut server)?;
+        run_with_tui(&mut server, using_dev_key)?;
     }
 
     Ok(())
 }
 
-fn run_with_tui(server: &mut GameServer) -> io::Result<()> {
+// The master port can be left off
+fn resolve_master(master: &str) -> Result<SocketAddr> {
+    if let Ok(ip) = master.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, dual::net::MASTER_PORT));
+    }
+
+    let with_port = if master.contains(':') {
+        master.to_string()
+    } else {
+        format!("{}:{}", master, dual::net::MASTER_PORT)
+    };
+    with_port
+        .to_socket_addrs()
+        .with_context(|| format!("Couldn't resolve master {}", master))?
+        .next()
+        .with_context(|| format!("Master {} has no addresses", master))
+}
+
+fn run_with_tui(server: &mut GameServer, using_dev_key: bool) -> io::Result<()> {
     terminal::enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, cursor::Hide)?;
@@ -105,6 +301,9 @@ fn run_with_tui(server: &mut GameServer) -> io::Result<()> {
     let mut tui_state = TuiState::new();
 
     tui_state.log_info(format!("Server started on {}", server.local_addr()));
+    if using_dev_key {
+        tui_state.log_warn(DEV_KEY_WARNING);
+    }
 
     while running.load(Ordering::SeqCst) {
         server.tick_once();
@@ -124,12 +323,32 @@ fn run_with_tui(server: &mut GameServer) -> io::Result<()> {
                         client_id, addr, entity_id
                     ));
                 }
+                ServerEvent::ClientResumed {
+                    client_id,
+                    old_addr,
+                    addr,
+                } => {
+                    tui_state.log_info(format!(
+                        "Client {} moved from {} to {}",
+                        client_id, old_addr, addr
+                    ));
+                }
                 ServerEvent::ClientDisconnected { client_id, reason } => {
                     tui_state.log_info(format!("Client {} {}", client_id, reason.as_str()));
                 }
                 ServerEvent::ConnectionDenied { addr, reason } => {
                     tui_state.log_warn(format!("Connection denied to {}: {}", addr, reason));
                 }
+                ServerEvent::BlockReceived {
+                    client_id,
+                    kind,
+                    size,
+                } => {
+                    tui_state.log_info(format!(
+                        "Received {} ({} bytes) from client {}",
+                        kind, size, client_id
+                    ));
+                }
                 ServerEvent::Error { message } => {
                     tui_state.log_error(message);
                 }
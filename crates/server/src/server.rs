@@ -1,65 +1,40 @@
-use std::collections::{BinaryHeap, VecDeque};
+use std::collections::{HashMap, VecDeque};
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
 use glam::Vec3;
 
+use dual::net::{
+    BandwidthEstimator, BlockKind, CONNECTION_REQUEST_SIZE, CaptureWriter, ChallengeToken,
+    CommandBufferStatus, DenyReason, Key, KeyLogWriter, LinkConditioner, MAX_PACKET_SIZE,
+    MasterLink, PROTOCOL_VERSION, PrivateConnectToken, QueryChallenger, QueryInfo, QueryKind,
+    QueryPlayer, QueryResponse, RateLimited, RateLimiter, SessionToken, SnapshotCodec,
+    TOKEN_NONCE_BYTES, TokenError, TokenReplayCache, Transport, UdpTransport, VERSION_DENIAL_SIZE,
+    generate_client_id, generate_key, is_lan_address, is_too_large, sequence_greater_than,
+    unix_timestamp,
+};
 use dual::{
-    ClientCommand, CommandProcessor, ConnectionManager, ConnectionState, EntityHandle,
-    NetworkEndpoint, NetworkStats, Packet, PacketHeader, PacketLossSimulation, PacketType,
-    PhysicsSync, PhysicsWorld, Reliability, SnapshotBuffer, TestingGround, World, WorldSnapshot,
+    ClientCommand, ClientConnection, CommandJitterBuffer, CommandProcessor, ConnectionManager,
+    ConnectionState, EntityHandle, Message, NetworkEndpoint, NetworkStats, Packet, PacketHeader,
+    PacketLossSimulation, PacketType, PhysicsSync, PhysicsWorld, Reliability, SnapshotBuffer,
+    TestingGround, World, WorldSnapshot,
 };
 
 use crate::config::ServerConfig;
 use crate::events::{DisconnectReason, ServerEvent};
 
-#[derive(Debug)]
-struct QueuedCommand {
-    client_id: u32,
-    command: ClientCommand,
-}
-
-#[derive(Debug)]
-struct DelayedPacket {
-    send_time: Instant,
-    packet: Packet,
-    addr: SocketAddr,
-}
-
-impl PartialEq for DelayedPacket {
-    fn eq(&self, other: &Self) -> bool {
-        self.send_time == other.send_time
-    }
-}
-
-impl Eq for DelayedPacket {}
-
-impl PartialOrd for DelayedPacket {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl Ord for DelayedPacket {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        other.send_time.cmp(&self.send_time)
-    }
-}
-
-pub struct GameServer {
-    endpoint: NetworkEndpoint,
+pub struct GameServer<T: Transport = UdpTransport> {
+    endpoint: NetworkEndpoint<LinkConditioner<T>>,
     connections: ConnectionManager,
     config: ServerConfig,
     world: World,
     physics: PhysicsWorld,
     command_processor: CommandProcessor,
     snapshot_history: SnapshotBuffer,
-    command_queue: VecDeque<QueuedCommand>,
-    delayed_packets: BinaryHeap<DelayedPacket>,
-    delayed_incoming_packets: BinaryHeap<DelayedPacket>,
+    command_buffers: HashMap<u32, CommandJitterBuffer>,
     tick: u32,
     tick_duration: Duration,
     last_tick_time: Instant,
@@ -68,11 +43,62 @@ pub struct GameServer {
     #[allow(dead_code)]
     start_time: Instant,
     pending_events: VecDeque<ServerEvent>,
+    map_checksum: u64,
+    challenge_key: Key,
+    challenge_sequence: u64,
+    // Seals session tokens; never shared, so only this process can open them
+    session_key: Key,
+    session_sequence: u64,
+    token_replay: TokenReplayCache,
+    query_challenger: QueryChallenger,
+    masters: Vec<MasterLink>,
+    rate_limiter: RateLimiter,
+    rejected: RejectedTraffic,
 }
 
 impl GameServer {
+    /// Binding the IPv6 wildcard serves both families from one socket, or
+    /// IPv4 alone on hosts without IPv6.
     pub fn new(bind_addr: &str, config: ServerConfig) -> io::Result<Self> {
-        let endpoint = NetworkEndpoint::bind(bind_addr)?;
+        let addr = bind_addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to bind"))?;
+        if addr.ip() != IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+            return Self::with_transport(UdpTransport::bind(addr)?, config);
+        }
+
+        let transport = match UdpTransport::bind_dual_stack(addr.port()) {
+            Ok(transport) => transport,
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => return Err(e),
+            Err(e) => {
+                log::warn!("IPv6 unavailable ({}), listening on IPv4 only", e);
+                UdpTransport::bind((Ipv4Addr::UNSPECIFIED, addr.port()))?
+            }
+        };
+        Self::with_transport(transport, config)
+    }
+}
+
+impl<T: Transport> GameServer<T> {
+    pub fn with_transport(transport: T, config: ServerConfig) -> io::Result<Self> {
+        let seed = match config.link_seed {
+            Some(seed) => seed,
+            None => generate_client_id().map_err(io::Error::other)?,
+        };
+        let mut conditioner = LinkConditioner::new(transport, seed);
+        if let Some(sim) = &config.global_packet_loss {
+            conditioner.set_conditions(sim.clone(), sim.clone());
+        }
+        let mut endpoint = NetworkEndpoint::with_transport(conditioner)?;
+        if let Some(path) = &config.capture_path {
+            endpoint.start_capture(CaptureWriter::create(path)?);
+        }
+        if let Some(path) = &config.key_log_path {
+            endpoint.start_key_log(KeyLogWriter::create(path)?);
+        }
+        let challenge_key = generate_key().map_err(io::Error::other)?;
+        let session_key = generate_key().map_err(io::Error::other)?;
         let tick_duration = Duration::from_secs_f64(1.0 / config.tick_rate as f64);
 
         let mut pending_events = VecDeque::new();
@@ -84,18 +110,20 @@ impl GameServer {
         let mut physics = PhysicsWorld::new();
 
         let mut testing_ground = TestingGround::new();
+        let map_checksum = testing_ground.checksum();
         testing_ground.spawn(&mut world, &mut physics);
 
         Ok(Self {
             endpoint,
-            connections: ConnectionManager::new(config.max_clients),
+            connections: ConnectionManager::with_timeout(
+                config.max_clients,
+                config.session_grace_secs,
+            ),
             world,
             physics,
             command_processor: CommandProcessor::new(),
             snapshot_history: SnapshotBuffer::new(config.snapshot_buffer_size),
-            command_queue: VecDeque::new(),
-            delayed_packets: BinaryHeap::new(),
-            delayed_incoming_packets: BinaryHeap::new(),
+            command_buffers: HashMap::new(),
             tick: 0,
             tick_duration,
             last_tick_time: Instant::now(),
@@ -103,6 +131,22 @@ impl GameServer {
             running: Arc::new(AtomicBool::new(true)),
             start_time: Instant::now(),
             pending_events: VecDeque::new(),
+            map_checksum,
+            challenge_key,
+            challenge_sequence: 0,
+            session_key,
+            session_sequence: 0,
+            token_replay: TokenReplayCache::new(),
+            query_challenger: QueryChallenger::new(&generate_key().map_err(io::Error::other)?),
+            rate_limiter: RateLimiter::new(config.handshake_rate_limit.clone()),
+            rejected: RejectedTraffic::default(),
+            masters: config
+                .masters
+                .iter()
+                .map(|&addr| {
+                    MasterLink::new(addr, Duration::from_secs(config.master_heartbeat_secs))
+                })
+                .collect(),
             config,
         })
     }
@@ -137,20 +181,36 @@ impl GameServer {
     pub fn kick_client(&mut self, client_id: u32) {
         if let Some(client) = self.connections.get_mut(client_id) {
             let addr = client.addr;
-            let packet = client.send_packet(PacketType::Disconnect, Reliability::Reliable);
-            let _ = self.endpoint.send_to(&packet, addr);
+            let _ = client.queue_message(PacketType::Disconnect, Reliability::Reliable);
+            for packet in client.flush() {
+                let _ = self
+                    .endpoint
+                    .send_to_mtu(&packet, addr, client.mtu_for(&packet));
+            }
         }
 
         if let Some(client) = self.connections.remove(client_id) {
-            if let Some(entity_id) = client.entity_id {
-                self.world.despawn(EntityHandle(entity_id));
+            self.remove_client(client, DisconnectReason::Kicked);
+        }
+    }
+
+    /// Frees everything a departed client owned in the world.
+    fn remove_client(&mut self, client: ClientConnection, reason: DisconnectReason) {
+        self.endpoint
+            .transport_mut()
+            .clear_link_conditions(client.addr);
+        self.command_buffers.remove(&client.client_id);
+        if let Some(entity_id) = client.entity_id {
+            if let Some(mut entity) = self.world.despawn(EntityHandle(entity_id)) {
+                PhysicsSync::destroy_physics_body(&mut entity, &mut self.physics);
             }
-            self.pending_events
-                .push_back(ServerEvent::ClientDisconnected {
-                    client_id,
-                    reason: DisconnectReason::Kicked,
-                });
+            self.command_processor.remove_player(entity_id);
         }
+        self.pending_events
+            .push_back(ServerEvent::ClientDisconnected {
+                client_id: client.client_id,
+                reason,
+            });
     }
 
     pub fn tick_once(&mut self) {
@@ -165,70 +225,62 @@ impl GameServer {
             });
         }
 
+        self.send_master_heartbeats();
         self.process_resends();
-        self.process_delayed_packets();
+        if let Err(e) = self.endpoint.transport_mut().poll() {
+            self.pending_events.push_back(ServerEvent::Error {
+                message: format!("Failed to send delayed packets: {}", e),
+            });
+        }
 
         while self.accumulator >= self.tick_duration {
             self.accumulator -= self.tick_duration;
             self.tick();
         }
-    }
 
-    fn process_resends(&mut self) {
-        let mut packets_to_send = Vec::new();
-        for client in self.connections.iter_mut() {
-            let resends = client.collect_resends();
-            for packet in resends {
-                packets_to_send.push((client.addr, packet));
-            }
-        }
-
-        for (addr, packet) in packets_to_send {
-            let _ = self.send_packet_simulated(packet, addr);
-        }
+        self.flush_connections();
     }
 
-    fn process_delayed_packets(&mut self) {
-        let now = Instant::now();
-        while let Some(packet) = self.delayed_packets.peek() {
-            if packet.send_time <= now {
-                let DelayedPacket { packet, addr, .. } = self.delayed_packets.pop().unwrap();
-                if let Err(e) = self.endpoint.send_to(&packet, addr) {
-                    self.pending_events.push_back(ServerEvent::Error {
-                        message: format!("Failed to send delayed packet to {}: {}", addr, e),
-                    });
-                }
-            } else {
-                break;
+    fn send_master_heartbeats(&mut self) {
+        let info = self.query_info();
+        for master in self.masters.iter_mut().filter(|master| master.is_due()) {
+            let heartbeat = master.heartbeat(info.clone());
+            if let Err(e) = self.endpoint.send_to(&heartbeat, master.addr) {
+                self.pending_events.push_back(ServerEvent::Error {
+                    message: format!("Failed to reach master {}: {}", master.addr, e),
+                });
             }
         }
     }
 
-    fn send_packet_simulated(&mut self, packet: Packet, addr: SocketAddr) -> io::Result<()> {
-        let mut delay = 0;
-        let mut should_drop = false;
-
-        if let Some(client) = self.connections.get_by_addr(&addr) {
-            should_drop = client.packet_loss_sim.should_drop();
-            delay = client.packet_loss_sim.delay_ms();
-        } else if let Some(ref sim) = self.config.global_packet_loss {
-            should_drop = sim.should_drop();
-            delay = sim.delay_ms();
+    fn process_resends(&mut self) {
+        for client in self.connections.iter_mut() {
+            client.queue_resends();
         }
+    }
 
-        if should_drop {
-            return Ok(());
+    fn flush_connections(&mut self) {
+        let mut packets_to_send = Vec::new();
+        for client in self.connections.iter_mut() {
+            for packet in client.flush() {
+                let mtu = client.mtu_for(&packet);
+                packets_to_send.push((client.addr, packet, mtu));
+            }
         }
 
-        if delay == 0 {
-            self.endpoint.send_to(&packet, addr).map(|_| ())
-        } else {
-            self.delayed_packets.push(DelayedPacket {
-                send_time: Instant::now() + Duration::from_millis(delay as u64),
-                packet,
-                addr,
-            });
-            Ok(())
+        for (addr, packet, mtu) in packets_to_send {
+            let result = self.endpoint.send_to_mtu(&packet, addr, mtu);
+            let Some(client) = self.connections.get_by_addr_mut(&addr) else {
+                continue;
+            };
+            match result {
+                Ok(bytes) => client.record_sent(&packet, bytes),
+                Err(e) if is_too_large(&e) => {
+                    log::debug!("Packet to {} exceeded the path MTU of {}", addr, mtu);
+                    client.on_packet_too_large(&packet);
+                }
+                Err(_) => {}
+            }
         }
     }
 
@@ -244,58 +296,95 @@ impl GameServer {
         let snapshot = self.world.snapshot(0);
         self.snapshot_history.push(snapshot);
 
-        if self.tick % self.config.snapshot_send_rate == 0 {
-            self.broadcast_snapshots();
+        self.broadcast_snapshots();
+
+        if self.tick.is_multiple_of(self.config.tick_rate) {
+            self.send_command_buffer_status();
         }
 
-        let timed_out = self.connections.cleanup_timed_out();
-        for client_id in timed_out {
-            self.pending_events
-                .push_back(ServerEvent::ClientDisconnected {
-                    client_id,
-                    reason: DisconnectReason::Timeout,
-                });
+        for client in self.connections.cleanup_timed_out() {
+            self.remove_client(client, DisconnectReason::Timeout);
         }
     }
 
     fn process_commands(&mut self) {
-        while let Some(queued) = self.command_queue.pop_front() {
-            if let Some(client) = self.connections.get_mut(queued.client_id) {
-                if queued.command.command_sequence > client.last_command_ack {
-                    client.last_command_ack = queued.command.command_sequence;
-                }
+        for (client_id, buffer) in &mut self.command_buffers {
+            let Some(command) = buffer.next_command(self.tick) else {
+                continue;
+            };
 
-                if let Some(entity_id) = client.entity_id {
-                    if let Some(entity) = self.world.get_by_id_mut(entity_id) {
-                        self.command_processor
-                            .process(&queued.command, entity, &mut self.physics);
-                    }
-                }
+            if let Some(client) = self.connections.get_mut(*client_id)
+                && sequence_greater_than(command.command_sequence, client.last_command_ack)
+            {
+                client.last_command_ack = command.command_sequence;
+            }
+
+            if let Some(entity) = self.world.get_by_id_mut(buffer.entity_id()) {
+                self.command_processor
+                    .process(&command, entity, &mut self.physics);
             }
         }
     }
 
-    fn broadcast_snapshots(&mut self) {
-        let client_data: Vec<(SocketAddr, u32, u32, u32, bool, u32)> = self
-            .connections
+    fn send_command_buffer_status(&mut self) {
+        let statuses: Vec<(u32, CommandBufferStatus)> = self
+            .command_buffers
             .iter()
-            .filter(|c| c.state == ConnectionState::Connected)
-            .map(|c| {
-                (
-                    c.addr,
-                    c.last_command_ack,
-                    c.last_acked_tick,
-                    c.send_sequence,
-                    c.packet_loss_sim.should_drop(),
-                    c.packet_loss_sim.delay_ms(),
-                )
+            .map(|(&client_id, buffer)| {
+                let stats = buffer.stats();
+                let status = CommandBufferStatus {
+                    target_depth: stats.target_depth.min(u8::MAX as u32) as u8,
+                    depth: stats.depth.min(u8::MAX as u32) as u8,
+                    underruns: stats.underruns,
+                    overruns: stats.overruns,
+                };
+                (client_id, status)
             })
             .collect();
 
+        for (client_id, status) in statuses {
+            if let Some(client) = self.connections.get_mut(client_id) {
+                let _ = client.queue_message(
+                    PacketType::CommandBufferStatus(status),
+                    Reliability::Unreliable,
+                );
+            }
+        }
+    }
+
+    /// Sends a snapshot to every client whose adaptive interval has elapsed
+    /// and whose bandwidth estimate has room for it.
+    fn broadcast_snapshots(&mut self) {
         let current_tick = self.tick;
+        let tick_rate = self.config.tick_rate;
+        let (min_interval, max_interval) = (
+            self.config.snapshot_send_rate,
+            self.config.max_snapshot_interval,
+        );
+
+        let mut client_data: Vec<(SocketAddr, u32, u32)> = Vec::new();
+        for c in self.connections.iter_mut() {
+            if c.state != ConnectionState::Connected {
+                continue;
+            }
+
+            let interval = c
+                .bandwidth
+                .snapshot_interval(tick_rate, min_interval, max_interval);
+            c.bandwidth.refill(tick_rate, interval);
+
+            let elapsed = current_tick.wrapping_sub(c.last_snapshot_tick);
+            let due =
+                elapsed >= interval && (c.bandwidth.can_send_snapshot() || elapsed >= max_interval);
+            if due {
+                c.last_snapshot_tick = current_tick;
+                client_data.push((c.addr, c.last_command_ack, c.last_acked_tick));
+            }
+        }
+
         let max_delta_age = self.config.snapshot_buffer_size as u32 / 2;
 
-        for (addr, last_cmd_ack, last_acked_tick, _, _, _) in client_data {
+        for (addr, last_cmd_ack, last_acked_tick) in client_data {
             let snapshot = self.generate_client_snapshot(
                 last_cmd_ack,
                 last_acked_tick,
@@ -303,10 +392,19 @@ impl GameServer {
                 max_delta_age,
             );
 
+            let payload = match self.config.snapshot_codec {
+                SnapshotCodec::Rkyv => PacketType::WorldSnapshot(snapshot),
+                codec => match codec.encode(&snapshot) {
+                    Ok(data) => PacketType::PackedSnapshot(data),
+                    Err(e) => {
+                        log::error!("Failed to encode snapshot: {}", e);
+                        continue;
+                    }
+                },
+            };
+
             if let Some(client) = self.connections.get_by_addr_mut(&addr) {
-                let packet = client
-                    .send_packet(PacketType::WorldSnapshot(snapshot), Reliability::Unreliable);
-                let _ = self.send_packet_simulated(packet, addr);
+                let _ = client.queue_message(payload, Reliability::Unreliable);
             }
         }
     }
@@ -330,75 +428,174 @@ impl GameServer {
     }
 
     fn process_network(&mut self) -> io::Result<()> {
-        let packets = self.endpoint.receive()?;
-
-        for (packet, addr) in packets {
-            let mut delay = 0;
-            let mut should_drop = false;
-
-            if let Some(client) = self.connections.get_by_addr(&addr) {
-                should_drop = client.incoming_packet_loss_sim.should_drop();
-                delay = client.incoming_packet_loss_sim.delay_ms();
-            } else if let Some(ref sim) = self.config.global_packet_loss {
-                should_drop = sim.should_drop();
-                delay = sim.delay_ms();
-            }
+        let (connections, rate_limiter, rejected) = (
+            &self.connections,
+            &mut self.rate_limiter,
+            &mut self.rejected,
+        );
+        let packets = self.endpoint.receive_sized_with(|addr| {
+            Self::admit_unverified(connections, rate_limiter, rejected, addr)
+        })?;
 
-            if should_drop {
-                continue;
+        for (packet, addr, size) in packets {
+            if let Some(client) = self.connections.get_by_addr_mut(&addr) {
+                client.record_received(size);
             }
+            self.handle_received_packet(packet, addr, size)?;
+        }
 
-            if delay > 0 {
-                self.delayed_incoming_packets.push(DelayedPacket {
-                    send_time: Instant::now() + Duration::from_millis(delay as u64),
-                    packet,
-                    addr,
-                });
-            } else {
-                self.handle_received_packet(packet, addr)?;
-            }
+        for (addr, size) in self.endpoint.take_version_mismatches() {
+            self.handle_version_mismatch(addr, size)?;
         }
 
-        let now = Instant::now();
-        while let Some(packet) = self.delayed_incoming_packets.peek() {
-            if packet.send_time <= now {
-                let DelayedPacket { packet, addr, .. } =
-                    self.delayed_incoming_packets.pop().unwrap();
-                self.handle_received_packet(packet, addr)?;
-            } else {
-                break;
+        Ok(())
+    }
+
+    /// Anyone can claim an address they don't own, so until a connection
+    /// proves it, what we'll read is rationed, fragments included.
+    fn admit_unverified(
+        connections: &ConnectionManager,
+        rate_limiter: &mut RateLimiter,
+        rejected: &mut RejectedTraffic,
+        addr: SocketAddr,
+    ) -> bool {
+        if connections.get_by_addr(&addr).is_some() {
+            return true;
+        }
+        match rate_limiter.check(addr.ip()) {
+            Ok(()) => true,
+            Err(RateLimited::Source) => {
+                rejected.rate_limited_source += 1;
+                false
+            }
+            Err(RateLimited::Global) => {
+                rejected.rate_limited_global += 1;
+                false
             }
         }
+    }
+
+    /// We can't read anything a peer on another version sends, so whatever
+    /// it was, it gets told which versions we speak.
+    fn handle_version_mismatch(&mut self, addr: SocketAddr, size: usize) -> io::Result<()> {
+        if self.connections.get_by_addr(&addr).is_some() {
+            return Ok(());
+        }
+        if size < VERSION_DENIAL_SIZE {
+            self.rejected.oversized_replies += 1;
+            return Ok(());
+        }
 
+        self.pending_events
+            .push_back(ServerEvent::ClientConnecting { addr });
+        self.endpoint.send_version_denial(addr)?;
+        self.pending_events
+            .push_back(ServerEvent::ConnectionDenied {
+                addr,
+                reason: DenyReason::version_mismatch(),
+            });
         Ok(())
     }
 
-    fn handle_received_packet(&mut self, packet: Packet, addr: SocketAddr) -> io::Result<()> {
+    fn handle_received_packet(
+        &mut self,
+        packet: Packet,
+        addr: SocketAddr,
+        size: usize,
+    ) -> io::Result<()> {
+        if packet.is_query() {
+            return self.handle_query_packet(packet, addr, size);
+        }
+
+        // Sent from wherever the client is now, which may not be where we last saw it
+        if let [
+            Message {
+                payload:
+                    PacketType::SessionResume {
+                        session_token,
+                        packet,
+                    },
+                ..
+            },
+        ] = packet.messages.as_slice()
+        {
+            return self.handle_session_resume(addr, session_token, packet, size);
+        }
+
         if let Some(client) = self.connections.get_by_addr_mut(&addr) {
+            let auth_failures = client.auth_failures;
             let payloads = client.process_packet(packet);
+            self.endpoint
+                .record_auth_failures(client.auth_failures - auth_failures);
+            for block in client.take_completed_blocks() {
+                self.pending_events.push_back(ServerEvent::BlockReceived {
+                    client_id: client.client_id,
+                    kind: block.kind,
+                    size: block.data.len(),
+                });
+            }
             for payload in payloads {
                 self.handle_payload(payload, addr)?;
             }
         } else {
-            // No connection, check if it's a request
-            if let PacketType::ConnectionRequest { .. } = packet.payload {
-                self.handle_payload(packet.payload, addr)?;
+            // No connection, check if it's part of the handshake
+            let request = packet.messages.into_iter().find(|message| {
+                matches!(
+                    message.payload,
+                    PacketType::ConnectionRequest { .. } | PacketType::ChallengeResponse { .. }
+                )
+            });
+            if let Some(message) = request {
+                self.handle_handshake(message.payload, addr, size)?;
             }
         }
         Ok(())
     }
 
+    fn handle_handshake(
+        &mut self,
+        payload: PacketType,
+        addr: SocketAddr,
+        request_size: usize,
+    ) -> io::Result<()> {
+        match payload {
+            PacketType::ConnectionRequest {
+                map_checksum,
+                expire_timestamp,
+                token_nonce,
+                token_data,
+                ..
+            } => self.handle_connection_request(
+                addr,
+                request_size,
+                map_checksum,
+                expire_timestamp,
+                token_nonce,
+                &token_data,
+            ),
+            PacketType::ChallengeResponse {
+                challenge_sequence,
+                challenge_data,
+            } => self.handle_challenge_response(
+                addr,
+                request_size,
+                challenge_sequence,
+                &challenge_data,
+            ),
+            _ => Ok(()),
+        }
+    }
+
     fn handle_payload(&mut self, payload: PacketType, addr: SocketAddr) -> io::Result<()> {
         match payload {
-            PacketType::ConnectionRequest { client_salt } => {
-                self.handle_connection_request(addr, client_salt)?;
-            }
-            PacketType::ChallengeResponse { combined_salt } => {
-                self.handle_challenge_response(addr, combined_salt)?;
-            }
             PacketType::ClientCommand(command) => {
                 self.handle_client_command(addr, command)?;
             }
+            PacketType::ClientCommandBatch(batch) => {
+                for command in batch.commands() {
+                    self.handle_client_command(addr, command)?;
+                }
+            }
             PacketType::Ping { timestamp } => {
                 self.handle_ping(addr, timestamp)?;
             }
@@ -413,68 +610,213 @@ impl GameServer {
         Ok(())
     }
 
-    fn handle_connection_request(&mut self, addr: SocketAddr, client_salt: u64) -> io::Result<()> {
+    fn deny_connection(
+        &mut self,
+        addr: SocketAddr,
+        reason: DenyReason,
+        request_size: usize,
+    ) -> io::Result<()> {
+        let header = PacketHeader::new(0, 0, 0);
+        let packet = Packet::new(
+            header,
+            PacketType::ConnectionDenied {
+                reason: reason.clone(),
+            },
+        );
+        self.send_unverified(&packet, addr, request_size)?;
+        self.pending_events
+            .push_back(ServerEvent::ConnectionDenied { addr, reason });
+        Ok(())
+    }
+
+    /// Answers an address that hasn't proven it sent the request, but never
+    /// with more than it sent us, so spoofed requests can't be amplified.
+    fn send_unverified(
+        &mut self,
+        packet: &Packet,
+        addr: SocketAddr,
+        request_size: usize,
+    ) -> io::Result<()> {
+        let reply_size = packet.serialize().map_err(io::Error::other)?.len();
+        if reply_size > request_size {
+            self.rejected.oversized_replies += 1;
+            return Ok(());
+        }
+        self.endpoint
+            .send_to_mtu(packet, addr, MAX_PACKET_SIZE)
+            .map(|_| ())
+    }
+
+    fn validate_connect_token(
+        &mut self,
+        addr: SocketAddr,
+        expire_timestamp: u64,
+        token_nonce: &[u8; TOKEN_NONCE_BYTES],
+        token_data: &[u8],
+    ) -> Result<PrivateConnectToken, TokenError> {
+        let now = unix_timestamp();
+        let token = PrivateConnectToken::open(
+            &self.config.private_key,
+            expire_timestamp,
+            token_nonce,
+            token_data,
+            now,
+        )?;
+
+        if !token.lists_server(self.endpoint.local_addr()) {
+            return Err(TokenError::AddressNotListed);
+        }
+
+        self.token_replay
+            .check(token_nonce, addr, expire_timestamp, now)?;
+        Ok(token)
+    }
+
+    fn handle_connection_request(
+        &mut self,
+        addr: SocketAddr,
+        request_size: usize,
+        map_checksum: u64,
+        expire_timestamp: u64,
+        token_nonce: [u8; TOKEN_NONCE_BYTES],
+        token_data: &[u8],
+    ) -> io::Result<()> {
+        if self.connections.get_by_addr(&addr).is_some() {
+            return Ok(());
+        }
+
+        if request_size < CONNECTION_REQUEST_SIZE {
+            self.rejected.undersized_requests += 1;
+            return Ok(());
+        }
+
         self.pending_events
             .push_back(ServerEvent::ClientConnecting { addr });
 
-        let global_packet_loss = self.config.global_packet_loss.clone();
+        if self.config.banned_ips.contains(&addr.ip()) {
+            return self.deny_connection(addr, DenyReason::Banned, request_size);
+        }
 
-        let client = match self.connections.get_or_create_pending(addr, client_salt) {
-            Ok(c) => c,
-            Err(reason) => {
-                let header = PacketHeader::new(0, 0, 0, PacketHeader::CHANNEL_UNRELIABLE, 0);
-                let packet = Packet::new(
-                    header,
-                    PacketType::ConnectionDenied {
-                        reason: reason.to_string(),
-                    },
-                );
-                self.send_packet_simulated(packet, addr)?;
-                self.pending_events
-                    .push_back(ServerEvent::ConnectionDenied {
-                        addr,
-                        reason: reason.to_string(),
-                    });
-                return Ok(());
-            }
-        };
+        // Nothing is allocated for the client until it echoes the challenge
+        let token =
+            match self.validate_connect_token(addr, expire_timestamp, &token_nonce, token_data) {
+                Ok(token) => token,
+                Err(e) => {
+                    log::debug!("Rejected connect token from {}: {}", addr, e);
+                    self.rejected.invalid_tokens += 1;
+                    return self.deny_connection(addr, DenyReason::InvalidToken, request_size);
+                }
+            };
 
-        if let Some(sim) = global_packet_loss {
-            client.packet_loss_sim = sim.clone();
-            client.incoming_packet_loss_sim = sim;
+        if map_checksum != self.map_checksum {
+            let server_checksum = self.map_checksum;
+            return self.deny_connection(
+                addr,
+                DenyReason::MapMismatch { server_checksum },
+                request_size,
+            );
         }
 
-        let server_salt = client.server_salt;
-        let challenge = client.combined_salt();
+        if self.connections.total_count() >= self.config.max_clients {
+            return self.deny_connection(addr, DenyReason::ServerFull, request_size);
+        }
+
+        let challenge = ChallengeToken {
+            client_id: token.client_id,
+            client_addr: addr,
+            expire_timestamp,
+            token_nonce,
+            client_to_server_key: token.client_to_server_key,
+            server_to_client_key: token.server_to_client_key,
+        };
+        let challenge_sequence = self.challenge_sequence;
+        self.challenge_sequence += 1;
 
-        // Use reliable for challenge to ensure it arrives
-        let packet = client.send_packet(
+        let challenge_data = challenge
+            .seal(&self.challenge_key, challenge_sequence)
+            .map_err(io::Error::other)?;
+
+        let header = PacketHeader::new(0, 0, 0);
+        let packet = Packet::new(
+            header,
             PacketType::ConnectionChallenge {
-                server_salt,
-                challenge,
+                challenge_sequence,
+                challenge_data,
             },
-            Reliability::Reliable,
         );
-
-        self.send_packet_simulated(packet, addr)?;
-
-        Ok(())
+        self.send_unverified(&packet, addr, request_size)
     }
 
     fn handle_challenge_response(
         &mut self,
         addr: SocketAddr,
-        combined_salt: u64,
+        request_size: usize,
+        challenge_sequence: u64,
+        challenge_data: &[u8],
     ) -> io::Result<()> {
-        let Some(client) = self.connections.get_by_addr_mut(&addr) else {
+        // The client keeps resending until it sees ConnectionAccepted
+        if self
+            .connections
+            .get_by_addr(&addr)
+            .is_some_and(|client| client.state == ConnectionState::Connected)
+        {
             return Ok(());
+        }
+
+        let challenge = match ChallengeToken::open(
+            &self.challenge_key,
+            challenge_sequence,
+            challenge_data,
+            unix_timestamp(),
+        )
+        .and_then(|challenge| {
+            if challenge.client_addr != addr {
+                return Err(TokenError::Replayed);
+            }
+            // Each connection needs its own keys, so a token is good for one
+            self.token_replay.accept(
+                &challenge.token_nonce,
+                addr,
+                challenge.expire_timestamp,
+                unix_timestamp(),
+            )?;
+            Ok(challenge)
+        }) {
+            Ok(challenge) => challenge,
+            Err(e) => {
+                self.rejected.invalid_challenges += 1;
+                self.pending_events.push_back(ServerEvent::Error {
+                    message: format!("Invalid challenge response from {}: {}", addr, e),
+                });
+                return Ok(());
+            }
+        };
+
+        let client = match self
+            .connections
+            .get_or_create_pending(addr, challenge.client_id)
+        {
+            Ok(c) => c,
+            Err(reason) => return self.deny_connection(addr, reason, request_size),
         };
 
-        if combined_salt != client.combined_salt() {
+        client.set_session_keys(
+            &challenge.server_to_client_key,
+            &challenge.client_to_server_key,
+        );
+        self.endpoint.log_session_keys(
+            addr,
+            &challenge.server_to_client_key,
+            &challenge.client_to_server_key,
+        );
+        client.bandwidth = BandwidthEstimator::new(self.config.bandwidth.clone());
+        client.start_mtu_discovery();
+        if let Some(info) = &self.config.server_info
+            && let Err(e) = client.send_block(BlockKind::ServerConfig, info.clone())
+        {
             self.pending_events.push_back(ServerEvent::Error {
-                message: format!("Invalid challenge response from {}", addr),
+                message: format!("Failed to send server config to {}: {}", addr, e),
             });
-            return Ok(());
         }
 
         client.state = ConnectionState::Connected;
@@ -497,6 +839,18 @@ impl GameServer {
         }
 
         client.entity_id = Some(entity_id);
+        let session_token = SessionToken {
+            client_id,
+            token_client_id: challenge.client_id,
+        }
+        .seal(&self.session_key, self.session_sequence)
+        .map_err(io::Error::other)?;
+        self.session_sequence += 1;
+
+        self.command_buffers.insert(
+            client_id,
+            CommandJitterBuffer::new(entity_id, self.config.jitter_buffer.clone()),
+        );
 
         self.pending_events.push_back(ServerEvent::ClientConnected {
             client_id,
@@ -505,17 +859,186 @@ impl GameServer {
         });
 
         // Use reliable for connection accepted
-        let packet = client.send_packet(
-            PacketType::ConnectionAccepted {
+        client
+            .queue_message(
+                PacketType::ConnectionAccepted {
+                    client_id,
+                    entity_id,
+                    snapshot_codec: self.config.snapshot_codec,
+                    session_token,
+                },
+                Reliability::Reliable,
+            )
+            .map_err(io::Error::other)?;
+
+        Ok(())
+    }
+
+    fn handle_session_resume(
+        &mut self,
+        addr: SocketAddr,
+        session_token: &[u8],
+        sealed: &[u8],
+        request_size: usize,
+    ) -> io::Result<()> {
+        if self.config.banned_ips.contains(&addr.ip()) {
+            return self.deny_connection(addr, DenyReason::Banned, request_size);
+        }
+
+        let client_id = SessionToken::open(&self.session_key, session_token)
+            .ok()
+            .filter(|session| {
+                self.connections.get(session.client_id).is_some_and(|c| {
+                    c.token_client_id == session.token_client_id
+                        && c.state == ConnectionState::Connected
+                })
+            })
+            .map(|session| session.client_id);
+        let Some(client_id) = client_id else {
+            // Lets a client whose session timed out, or predates a restart, give up right away
+            return self.deny_connection(addr, DenyReason::SessionExpired, request_size);
+        };
+
+        if self
+            .connections
+            .get_by_addr(&addr)
+            .is_some_and(|other| other.client_id != client_id)
+        {
+            return Ok(());
+        }
+        let Ok(packet) = Packet::deserialize(sealed) else {
+            return Ok(());
+        };
+        let Some(client) = self.connections.get_mut(client_id) else {
+            return Ok(());
+        };
+
+        let auth_failures = client.auth_failures;
+        let payloads = client.resume(packet);
+        self.endpoint
+            .record_auth_failures(client.auth_failures - auth_failures);
+        let Some(payloads) = payloads else {
+            return Ok(());
+        };
+
+        let old_addr = client.addr;
+        if old_addr != addr {
+            self.connections.rebind(client_id, addr);
+            self.endpoint
+                .transport_mut()
+                .clear_link_conditions(old_addr);
+            self.pending_events.push_back(ServerEvent::ClientResumed {
                 client_id,
-                entity_id,
+                old_addr,
+                addr,
+            });
+        }
+
+        for payload in payloads {
+            self.handle_payload(payload, addr)?;
+        }
+        Ok(())
+    }
+
+    fn handle_query_packet(
+        &mut self,
+        packet: Packet,
+        addr: SocketAddr,
+        request_size: usize,
+    ) -> io::Result<()> {
+        if self.config.banned_ips.contains(&addr.ip()) {
+            return Ok(());
+        }
+
+        let Some(message) = packet.messages.into_iter().next() else {
+            return Ok(());
+        };
+        match message.payload {
+            PacketType::QueryRequest {
+                kind, challenge, ..
+            } => self.handle_query(addr, request_size, kind, challenge),
+            PacketType::DiscoveryRequest { timestamp, .. } => {
+                self.handle_discovery(addr, request_size, timestamp)
+            }
+            // A master wants our heartbeat again with this
+            PacketType::QueryChallenge { challenge } => {
+                if let Some(master) = self.masters.iter_mut().find(|m| m.addr == addr) {
+                    master.set_challenge(challenge);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_query(
+        &mut self,
+        addr: SocketAddr,
+        request_size: usize,
+        kind: QueryKind,
+        challenge: u64,
+    ) -> io::Result<()> {
+        // Only a challenge goes back until the asker proves it can receive
+        // at that address
+        let header = PacketHeader::new(0, 0, 0);
+        if !self.query_challenger.verify(addr, challenge) {
+            let challenge = self.query_challenger.challenge_for(addr);
+            let packet = Packet::new(header, PacketType::QueryChallenge { challenge });
+            return self.send_unverified(&packet, addr, request_size);
+        }
+
+        let response = match kind {
+            QueryKind::Info => QueryResponse::Info(self.query_info()),
+            QueryKind::Players => QueryResponse::Players(
+                self.connections
+                    .iter()
+                    .filter(|c| c.state == ConnectionState::Connected)
+                    .map(|c| QueryPlayer {
+                        client_id: c.client_id,
+                        connected_secs: c.connected_at.elapsed().as_secs(),
+                    })
+                    .collect(),
+            ),
+            QueryKind::Rules => QueryResponse::Rules(self.config.rules()),
+        };
+        let packet = Packet::new(header, PacketType::QueryResponse(response));
+        self.send_unverified(&packet, addr, request_size)
+    }
+
+    fn handle_discovery(
+        &mut self,
+        addr: SocketAddr,
+        request_size: usize,
+        timestamp: u64,
+    ) -> io::Result<()> {
+        // Unchallenged, so only worth answering where nobody can be flooded
+        if !is_lan_address(addr.ip()) {
+            return Ok(());
+        }
+
+        let packet = Packet::new(
+            PacketHeader::new(0, 0, 0),
+            PacketType::DiscoveryResponse {
+                timestamp,
+                info: self.query_info(),
             },
-            Reliability::Reliable,
         );
+        self.send_unverified(&packet, addr, request_size)
+    }
 
-        self.send_packet_simulated(packet, addr)?;
-
-        Ok(())
+    fn query_info(&self) -> QueryInfo {
+        QueryInfo {
+            name: self.config.name.clone(),
+            map: TestingGround::NAME.to_string(),
+            map_checksum: self.map_checksum,
+            game_mode: self.config.game_mode.clone(),
+            // Nothing to enter one with yet
+            has_password: false,
+            protocol_version: PROTOCOL_VERSION,
+            tick_rate: self.config.tick_rate,
+            players: self.connections.connected_count() as u32,
+            max_players: self.config.max_clients as u32,
+        }
     }
 
     fn handle_client_command(
@@ -531,19 +1054,25 @@ impl GameServer {
             return Ok(());
         }
 
-        self.command_queue.push_back(QueuedCommand {
-            client_id: client.client_id,
-            command,
-        });
+        // Redundant batches resend commands we've already executed
+        if !sequence_greater_than(command.command_sequence, client.last_command_ack) {
+            return Ok(());
+        }
+
+        if let Some(buffer) = self.command_buffers.get_mut(&client.client_id) {
+            buffer.push(command, self.tick);
+        }
 
         Ok(())
     }
 
     fn handle_ping(&mut self, addr: SocketAddr, timestamp: u64) -> io::Result<()> {
         if let Some(client) = self.connections.get_by_addr_mut(&addr) {
-            let packet =
-                client.send_packet(PacketType::Pong { timestamp }, Reliability::Unreliable);
-            self.send_packet_simulated(packet, addr)?;
+            let pong = PacketType::Pong {
+                timestamp,
+                server_time_ms: self.world.server_time_ms(),
+            };
+            let _ = client.queue_message(pong, Reliability::Unreliable);
         }
         Ok(())
     }
@@ -559,14 +1088,7 @@ impl GameServer {
 
     fn handle_disconnect(&mut self, addr: SocketAddr) -> io::Result<()> {
         if let Some(client) = self.connections.remove_by_addr(&addr) {
-            if let Some(entity_id) = client.entity_id {
-                self.world.despawn(EntityHandle(entity_id));
-            }
-            self.pending_events
-                .push_back(ServerEvent::ClientDisconnected {
-                    client_id: client.client_id,
-                    reason: DisconnectReason::Graceful,
-                });
+            self.remove_client(client, DisconnectReason::Graceful);
         }
         Ok(())
     }
@@ -578,6 +1100,7 @@ impl GameServer {
             max_clients: self.config.max_clients,
             entity_count: self.world.entity_count(),
             network_stats: self.endpoint.stats().clone(),
+            rejected: self.rejected,
         }
     }
 
@@ -589,10 +1112,23 @@ impl GameServer {
                 client_id: c.client_id,
                 addr: c.addr.to_string(),
                 entity_id: c.entity_id,
-                connected_secs: c.last_receive_time.elapsed().as_secs(),
-                last_ping_ms: self.endpoint.stats().rtt_ms,
-                packet_loss_sim: c.packet_loss_sim.clone(),
-                incoming_packet_loss_sim: c.incoming_packet_loss_sim.clone(),
+                connected_secs: c.connected_at.elapsed().as_secs(),
+                stats: c.stats().clone(),
+                bandwidth_bytes_per_sec: c.bandwidth.bytes_per_sec(),
+                snapshot_interval: c.bandwidth.snapshot_interval(
+                    self.config.tick_rate,
+                    self.config.snapshot_send_rate,
+                    self.config.max_snapshot_interval,
+                ),
+                snapshot_bytes: c.bandwidth.avg_snapshot_bytes(),
+                mtu: c.mtu(),
+                packet_loss_sim: self.endpoint.transport().link_conditions(c.addr).0.clone(),
+                incoming_packet_loss_sim: self
+                    .endpoint
+                    .transport()
+                    .link_conditions(c.addr)
+                    .1
+                    .clone(),
             })
             .collect()
     }
@@ -603,9 +1139,10 @@ impl GameServer {
         sim: PacketLossSimulation,
         incoming_sim: PacketLossSimulation,
     ) {
-        if let Some(client) = self.connections.get_mut(client_id) {
-            client.packet_loss_sim = sim;
-            client.incoming_packet_loss_sim = incoming_sim;
+        if let Some(client) = self.connections.get(client_id) {
+            self.endpoint
+                .transport_mut()
+                .set_link_conditions(client.addr, sim, incoming_sim);
         }
     }
 }
@@ -617,4 +1154,27 @@ pub struct ServerStats {
     pub max_clients: usize,
     pub entity_count: usize,
     pub network_stats: NetworkStats,
+    pub rejected: RejectedTraffic,
+}
+
+/// Traffic from unverified addresses that was dropped or went unanswered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RejectedTraffic {
+    pub rate_limited_source: u64,
+    pub rate_limited_global: u64,
+    pub undersized_requests: u64,
+    pub oversized_replies: u64,
+    pub invalid_tokens: u64,
+    pub invalid_challenges: u64,
+}
+
+impl RejectedTraffic {
+    pub fn total(&self) -> u64 {
+        self.rate_limited_source
+            + self.rate_limited_global
+            + self.undersized_requests
+            + self.oversized_replies
+            + self.invalid_tokens
+            + self.invalid_challenges
+    }
 }
@@ -2,11 +2,12 @@ use std::collections::VecDeque;
 use std::time::Instant;
 
 use dual::PacketLossSimulation;
+use dual::net::{ConnectionStats, StatsSample};
 use ratatui::Frame;
 use ratatui::layout::{Constraint, Direction, Layout, Rect};
 use ratatui::style::{Color, Modifier, Style};
 use ratatui::text::{Line, Span};
-use ratatui::widgets::{Block, Borders, Cell, Clear, Paragraph, Row, Table, Tabs};
+use ratatui::widgets::{Block, Borders, Cell, Clear, Paragraph, Row, Sparkline, Table, Tabs};
 
 use crate::server::ServerStats;
 
@@ -112,7 +113,11 @@ pub struct ClientInfo {
     pub addr: String,
     pub entity_id: Option<u32>,
     pub connected_secs: u64,
-    pub last_ping_ms: f32,
+    pub stats: ConnectionStats,
+    pub bandwidth_bytes_per_sec: u32,
+    pub snapshot_interval: u32,
+    pub snapshot_bytes: u32,
+    pub mtu: usize,
     pub packet_loss_sim: PacketLossSimulation,
     pub incoming_packet_loss_sim: PacketLossSimulation,
 }
@@ -349,7 +354,7 @@ pub fn render(frame: &mut Frame, state: &TuiState, stats: &ServerStats, clients:
         ])
         .split(frame.area());
 
-    render_header(frame, chunks[0], stats, state.uptime_secs());
+    render_header(frame, chunks[0], stats, clients, state.uptime_secs());
     render_tabs(frame, chunks[1], state);
 
     match state.active_tab {
@@ -364,18 +369,30 @@ pub fn render(frame: &mut Frame, state: &TuiState, stats: &ServerStats, clients:
     }
 }
 
-fn render_header(frame: &mut Frame, area: Rect, stats: &ServerStats, uptime_secs: u64) {
+fn render_header(
+    frame: &mut Frame,
+    area: Rect,
+    stats: &ServerStats,
+    clients: &[ClientInfo],
+    uptime_secs: u64,
+) {
     let uptime = format_duration(uptime_secs);
     let net = &stats.network_stats;
+    let avg_rtt = if clients.is_empty() {
+        0.0
+    } else {
+        clients.iter().map(|c| c.stats.rtt_ms).sum::<f32>() / clients.len() as f32
+    };
 
     let text = format!(
-        "Tick: {} | Clients: {}/{} | Entities: {} | RTT: {:.0}ms | {} | Uptime: {}",
+        "Tick: {} | Clients: {}/{} | Entities: {} | Avg RTT: {:.0}ms | {} | Rejected: {} | Uptime: {}",
         stats.tick,
         stats.client_count,
         stats.max_clients,
         stats.entity_count,
-        net.rtt_ms,
+        avg_rtt,
         format_bytes(net.bytes_sent + net.bytes_received),
+        stats.rejected.total(),
         uptime
     );
 
@@ -475,6 +492,11 @@ fn render_connections(frame: &mut Frame, area: Rect, state: &TuiState, clients:
         Cell::from("Entity"),
         Cell::from("Time"),
         Cell::from("RTT"),
+        Cell::from("Jitter"),
+        Cell::from("Loss"),
+        Cell::from("Bandwidth"),
+        Cell::from("Snapshots"),
+        Cell::from("MTU"),
         Cell::from("Sim"),
     ])
     .style(
@@ -510,7 +532,18 @@ fn render_connections(frame: &mut Frame, area: Rect, state: &TuiState, clients:
                 Cell::from(client.addr.as_str()),
                 Cell::from(entity_str),
                 Cell::from(connected),
-                Cell::from(format!("{:.0}ms", client.last_ping_ms)),
+                Cell::from(format!("{:.0}ms", client.stats.rtt_ms)),
+                Cell::from(format!("{:.1}ms", client.stats.jitter_ms)),
+                Cell::from(format!("{:.1}%", client.stats.packet_loss_percent)),
+                Cell::from(format!(
+                    "{:.1} KB/s",
+                    client.bandwidth_bytes_per_sec as f32 / 1024.0
+                )),
+                Cell::from(format!(
+                    "1/{} {}B",
+                    client.snapshot_interval, client.snapshot_bytes
+                )),
+                Cell::from(format!("{}", client.mtu)),
                 Cell::from(sim_status),
             ];
 
@@ -530,14 +563,101 @@ fn render_connections(frame: &mut Frame, area: Rect, state: &TuiState, clients:
             Constraint::Length(25),
             Constraint::Length(10),
             Constraint::Length(12),
-            Constraint::Length(10),
+            Constraint::Length(8),
+            Constraint::Length(8),
+            Constraint::Length(7),
+            Constraint::Length(12),
+            Constraint::Length(12),
+            Constraint::Length(6),
             Constraint::Length(6),
         ],
     )
     .header(header)
     .block(block);
 
-    frame.render_widget(table, area);
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(5), Constraint::Length(7)])
+        .split(area);
+    frame.render_widget(table, chunks[0]);
+
+    if let Some(client) = clients.get(state.selected_connection) {
+        render_connection_graphs(frame, chunks[1], client);
+    }
+}
+
+fn render_connection_graphs(frame: &mut Frame, area: Rect, client: &ClientInfo) {
+    let block = Block::default()
+        .title(format!(" Client {} history ", client.client_id))
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(Color::Yellow));
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Ratio(1, 4); 4])
+        .split(inner);
+
+    let stats = &client.stats;
+    let graphs = [
+        history_sparkline(
+            format!("RTT {:.0}±{:.0}ms", stats.rtt_ms, stats.rtt_variance),
+            stats,
+            columns[0],
+            |s| s.rtt_ms as u64,
+        )
+        .style(Style::default().fg(Color::Cyan)),
+        history_sparkline(
+            format!("Loss {:.1}%", stats.packet_loss_percent),
+            stats,
+            columns[1],
+            |s| s.loss_percent.ceil() as u64,
+        )
+        .style(Style::default().fg(Color::Red)),
+        history_sparkline(
+            format!(
+                "Out {}/s",
+                format_bytes(u64::from(stats.send_bytes_per_sec))
+            ),
+            stats,
+            columns[2],
+            |s| u64::from(s.send_bytes_per_sec),
+        )
+        .style(Style::default().fg(Color::Green)),
+        history_sparkline(
+            format!(
+                "In {}/s",
+                format_bytes(u64::from(stats.receive_bytes_per_sec))
+            ),
+            stats,
+            columns[3],
+            |s| u64::from(s.receive_bytes_per_sec),
+        )
+        .style(Style::default().fg(Color::Magenta)),
+    ];
+
+    for (graph, area) in graphs.into_iter().zip(columns.iter()) {
+        frame.render_widget(graph, *area);
+    }
+}
+
+/// Only the newest samples that fit in `area`, so the graph scrolls left.
+fn history_sparkline<'a>(
+    title: String,
+    stats: &ConnectionStats,
+    area: Rect,
+    value: impl Fn(&StatsSample) -> u64,
+) -> Sparkline<'a> {
+    let history = stats.history();
+    let data: Vec<u64> = history
+        .iter()
+        .skip(history.len().saturating_sub(area.width as usize))
+        .map(value)
+        .collect();
+    Sparkline::default()
+        .block(Block::default().title(title))
+        .data(data)
 }
 
 fn render_help(frame: &mut Frame, area: Rect, state: &TuiState) {
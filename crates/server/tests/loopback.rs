@@ -0,0 +1,429 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dual::net::{
+    CONNECTION_REQUEST_SIZE, CaptureDirection, CaptureReader, ConnectToken, DenyReason, KEY_BYTES,
+    Key, KeyLog, LoopbackHub, LoopbackTransport, MIN_PROTOCOL_VERSION, PROTOCOL_MAGIC,
+    PROTOCOL_VERSION, QueryClient, QueryKind, QueryResponse, RateLimitConfig, TokenIssuer,
+    Transport,
+};
+use dual::{NetworkEndpoint, Packet, PacketHeader, PacketType, TestingGround};
+use dual_client::net::{ClientConfig, NetworkClient};
+use dual_server::config::ServerConfig;
+use dual_server::events::ServerEvent;
+use dual_server::server::GameServer;
+
+const TEST_KEY: Key = [9; KEY_BYTES];
+
+fn server_addr() -> SocketAddr {
+    "127.0.0.1:27015".parse().unwrap()
+}
+
+fn start_server(hub: &LoopbackHub, config: ServerConfig) -> GameServer<LoopbackTransport> {
+    let config = ServerConfig {
+        private_key: TEST_KEY,
+        ..config
+    };
+    GameServer::with_transport(hub.bind(server_addr()).unwrap(), config).unwrap()
+}
+
+fn token(client_id: u64) -> ConnectToken {
+    TokenIssuer::new(TEST_KEY)
+        .issue(client_id, &[server_addr()])
+        .unwrap()
+}
+
+// Each client gets its own host, so the handshake rate limit sees them apart
+fn client_addr(host: u8) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, host], 50000))
+}
+
+fn connect_client(
+    hub: &LoopbackHub,
+    host: u8,
+    token: ConnectToken,
+) -> NetworkClient<LoopbackTransport> {
+    let transport = hub.bind(client_addr(host)).unwrap();
+    let mut client = NetworkClient::with_transport(transport, ClientConfig::default()).unwrap();
+    client.connect(token).unwrap();
+    client
+}
+
+/// Runs the server and clients side by side until `done` holds or time runs out.
+fn pump<T: Transport>(
+    server: &mut GameServer<LoopbackTransport>,
+    clients: &mut [NetworkClient<T>],
+    done: impl Fn(&[NetworkClient<T>]) -> bool,
+) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut last = Instant::now();
+    while Instant::now() < deadline {
+        server.tick_once();
+        let delta = last.elapsed().as_secs_f32();
+        last = Instant::now();
+        for client in clients.iter_mut() {
+            client.update(delta, None).unwrap();
+        }
+        if done(clients) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    false
+}
+
+#[test]
+fn test_clients_connect_and_receive_snapshots() {
+    let hub = LoopbackHub::new();
+    let mut server = start_server(&hub, ServerConfig::default());
+
+    let mut clients: Vec<_> = (0..4)
+        .map(|i| connect_client(&hub, 2 + i, token(100 + i as u64)))
+        .collect();
+
+    let ready = pump(&mut server, &mut clients, |clients| {
+        clients
+            .iter()
+            .all(|c| c.is_connected() && c.is_interpolation_ready())
+    });
+    assert!(ready, "clients never got the world");
+
+    assert_eq!(server.stats().client_count, 4);
+    let mut entities: Vec<_> = clients.iter().map(|c| c.entity_id().unwrap()).collect();
+    entities.sort();
+    entities.dedup();
+    assert_eq!(entities.len(), 4);
+
+    let connected = server
+        .drain_events()
+        .filter(|e| matches!(e, ServerEvent::ClientConnected { .. }))
+        .count();
+    assert_eq!(connected, 4);
+}
+
+#[test]
+fn test_full_server_denies_extra_client() {
+    let hub = LoopbackHub::new();
+    let mut server = start_server(
+        &hub,
+        ServerConfig {
+            max_clients: 1,
+            ..Default::default()
+        },
+    );
+
+    let mut clients = vec![connect_client(&hub, 2, token(1))];
+    assert!(pump(&mut server, &mut clients, |c| c[0].is_connected()));
+
+    clients.push(connect_client(&hub, 3, token(2)));
+    assert!(pump(&mut server, &mut clients, |c| c[1]
+        .deny_reason()
+        .is_some()));
+    assert_eq!(clients[1].deny_reason(), Some(&DenyReason::ServerFull));
+    assert!(clients[0].is_connected());
+    assert_eq!(server.stats().client_count, 1);
+}
+
+#[test]
+fn test_token_from_another_key_is_denied() {
+    let hub = LoopbackHub::new();
+    let mut server = start_server(&hub, ServerConfig::default());
+
+    let forged = TokenIssuer::new([1; KEY_BYTES])
+        .issue(1, &[server_addr()])
+        .unwrap();
+    let mut clients = vec![connect_client(&hub, 2, forged)];
+
+    assert!(pump(&mut server, &mut clients, |c| c[0]
+        .deny_reason()
+        .is_some()));
+    assert_eq!(clients[0].deny_reason(), Some(&DenyReason::InvalidToken));
+    assert_eq!(server.stats().rejected.invalid_tokens, 1);
+    assert_eq!(server.stats().client_count, 0);
+}
+
+#[test]
+fn test_spent_token_is_denied() {
+    let hub = LoopbackHub::new();
+    let mut server = start_server(&hub, ServerConfig::default());
+
+    let spent = token(1);
+    let mut clients = vec![connect_client(&hub, 2, spent.clone())];
+    assert!(pump(&mut server, &mut clients, |c| c[0].is_connected()));
+
+    clients[0].disconnect().unwrap();
+    assert!(pump(&mut server, &mut clients, |_| true));
+    assert_eq!(server.stats().client_count, 0);
+
+    // A second session would seal under the same keys and nonces
+    clients[0].connect(spent).unwrap();
+    assert!(pump(&mut server, &mut clients, |c| c[0]
+        .deny_reason()
+        .is_some()));
+    assert_eq!(clients[0].deny_reason(), Some(&DenyReason::InvalidToken));
+    assert_eq!(server.stats().client_count, 0);
+}
+
+/// A transport whose socket can be swapped out from under the client, like
+/// a NAT handing out a new port.
+#[derive(Clone)]
+struct Roaming(Arc<Mutex<LoopbackTransport>>);
+
+impl Transport for Roaming {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        self.0.lock().unwrap().send_to(data, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        self.0.lock().unwrap().recv_from(buf)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.0.lock().unwrap().local_addr()
+    }
+}
+
+#[test]
+fn test_client_resumes_session_from_new_address() {
+    let hub = LoopbackHub::new();
+    let mut server = start_server(&hub, ServerConfig::default());
+
+    let roaming = Roaming(Arc::new(Mutex::new(hub.bind(client_addr(2)).unwrap())));
+    let config = ClientConfig {
+        resume_after_secs: 0.2,
+        ..Default::default()
+    };
+    let mut clients = vec![NetworkClient::with_transport(roaming.clone(), config).unwrap()];
+    clients[0].connect(token(7)).unwrap();
+    assert!(pump(&mut server, &mut clients, |c| c[0].is_connected()));
+    let client_id = clients[0].client_id();
+    server.drain_events().for_each(drop);
+
+    let new_addr = SocketAddr::from(([127, 0, 0, 3], 50001));
+    *roaming.0.lock().unwrap() = hub.bind(new_addr).unwrap();
+
+    let resumed = |server: &mut GameServer<LoopbackTransport>| {
+        server.drain_events().any(|e| {
+            matches!(e, ServerEvent::ClientResumed { old_addr, addr, .. }
+                if old_addr == client_addr(2) && addr == new_addr)
+        })
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut seen = false;
+    while !seen && Instant::now() < deadline {
+        pump(&mut server, &mut clients, |_| true);
+        seen = resumed(&mut server);
+    }
+    assert!(seen, "session was never resumed");
+
+    assert!(pump(&mut server, &mut clients, |c| !c[0].is_resuming()));
+    assert!(clients[0].is_connected());
+    assert_eq!(clients[0].client_id(), client_id);
+    assert_eq!(server.stats().client_count, 1);
+}
+
+#[test]
+fn test_query_answered_by_running_server() {
+    let hub = LoopbackHub::new();
+    let mut server = start_server(
+        &hub,
+        ServerConfig {
+            name: "Loopback Arena".to_string(),
+            ..Default::default()
+        },
+    );
+
+    let transport = hub.bind(client_addr(2)).unwrap();
+    let query = thread::spawn(move || {
+        let mut client = QueryClient::with_transport(transport, Duration::from_secs(5)).unwrap();
+        client.query(server_addr(), QueryKind::Info)
+    });
+
+    let mut clients: Vec<NetworkClient<LoopbackTransport>> = Vec::new();
+    while !query.is_finished() {
+        pump(&mut server, &mut clients, |_| true);
+    }
+    match query.join().unwrap().unwrap() {
+        QueryResponse::Info(info) => {
+            assert_eq!(info.name, "Loopback Arena");
+            assert_eq!(info.max_players, 32);
+        }
+        other => panic!("Expected Info, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_unverified_traffic_is_rejected() {
+    let hub = LoopbackHub::new();
+    let mut server = start_server(&hub, ServerConfig::default());
+    let mut stranger = NetworkEndpoint::with_transport(hub.bind(client_addr(2)).unwrap()).unwrap();
+
+    // Too small to be answered without amplifying
+    let token = token(1);
+    let unpadded = Packet::new(
+        PacketHeader::new(0, 0, 0),
+        PacketType::ConnectionRequest {
+            map_checksum: TestingGround::new().checksum(),
+            expire_timestamp: token.expire_timestamp,
+            token_nonce: token.nonce,
+            token_data: token.private_data.clone(),
+            padding: Vec::new(),
+        },
+    );
+    stranger.send_to(&unpadded, server_addr()).unwrap();
+
+    // Then a flood from the same host, which the per-source bucket cuts off
+    let ping = Packet::new(
+        PacketHeader::new(0, 0, 0),
+        PacketType::Ping { timestamp: 0 },
+    );
+    for _ in 0..32 {
+        stranger.send_to(&ping, server_addr()).unwrap();
+    }
+
+    let mut clients: Vec<NetworkClient<LoopbackTransport>> = Vec::new();
+    pump(&mut server, &mut clients, |_| true);
+    thread::sleep(Duration::from_millis(20));
+    pump(&mut server, &mut clients, |_| true);
+
+    assert!(stranger.receive().unwrap().is_empty());
+    let rejected = server.stats().rejected;
+    assert_eq!(rejected.undersized_requests, 1);
+    assert!(rejected.rate_limited_source > 0);
+    assert_eq!(server.stats().client_count, 0);
+}
+
+#[test]
+fn test_stranger_fragments_are_rationed_before_reassembly() {
+    let hub = LoopbackHub::new();
+    let mut server = start_server(&hub, ServerConfig::default());
+    let mut stranger = NetworkEndpoint::with_transport(hub.bind(client_addr(2)).unwrap()).unwrap();
+
+    // Halves of groups that never complete, each one a new group to hold
+    for group_id in 0..32 {
+        let fragment = Packet::new(
+            PacketHeader::new(0, 0, 0),
+            PacketType::Fragment {
+                group_id,
+                index: 0,
+                count: 2,
+                data: vec![0; 512],
+            },
+        );
+        stranger.send_to(&fragment, server_addr()).unwrap();
+    }
+
+    let mut clients: Vec<NetworkClient<LoopbackTransport>> = Vec::new();
+    pump(&mut server, &mut clients, |_| true);
+
+    let stats = server.stats();
+    let burst = RateLimitConfig::default().per_source_burst as u64;
+    assert!(stats.network_stats.fragments_received <= burst);
+    assert!(stats.rejected.rate_limited_source >= 32 - burst);
+}
+
+#[test]
+fn test_future_client_is_told_supported_versions() {
+    let hub = LoopbackHub::new();
+    let mut server = start_server(&hub, ServerConfig::default());
+    let mut future_client =
+        NetworkEndpoint::with_transport(hub.bind(client_addr(2)).unwrap()).unwrap();
+
+    // Its archive layout is unknown to us; only the prefix can be trusted
+    let mut request = Vec::new();
+    request.extend_from_slice(&PROTOCOL_MAGIC.to_le_bytes());
+    request.extend_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+    request.resize(CONNECTION_REQUEST_SIZE, 0xAB);
+    future_client
+        .transport_mut()
+        .send_to(&request, server_addr())
+        .unwrap();
+
+    let mut clients: Vec<NetworkClient<LoopbackTransport>> = Vec::new();
+    let mut received = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while received.is_empty() && Instant::now() < deadline {
+        pump(&mut server, &mut clients, |_| true);
+        received = future_client.receive_sized().unwrap();
+    }
+
+    let (packet, from, size) = &received[0];
+    assert_eq!(*from, server_addr());
+    assert!(*size <= request.len());
+    match &packet.messages[0].payload {
+        PacketType::ConnectionDenied {
+            reason:
+                DenyReason::VersionMismatch {
+                    server_version,
+                    min_version,
+                    max_version,
+                },
+        } => {
+            assert_eq!(*server_version, PROTOCOL_VERSION);
+            assert_eq!(*min_version, MIN_PROTOCOL_VERSION);
+            assert_eq!(*max_version, PROTOCOL_VERSION);
+        }
+        other => panic!("Expected ConnectionDenied, got {:?}", other),
+    }
+    assert!(server.drain_events().any(|e| matches!(
+        e,
+        ServerEvent::ConnectionDenied {
+            reason: DenyReason::VersionMismatch { .. },
+            ..
+        }
+    )));
+    assert_eq!(server.stats().client_count, 0);
+}
+
+#[test]
+fn test_capture_decrypts_with_key_log() {
+    let dir = std::env::temp_dir();
+    let capture_path = dir.join(format!("dual-loopback-{}.cap", std::process::id()));
+    let key_log_path = dir.join(format!("dual-loopback-{}.keys", std::process::id()));
+
+    let hub = LoopbackHub::new();
+    let mut server = start_server(
+        &hub,
+        ServerConfig {
+            capture_path: Some(capture_path.clone()),
+            key_log_path: Some(key_log_path.clone()),
+            ..Default::default()
+        },
+    );
+    let mut clients = vec![connect_client(&hub, 2, token(1))];
+    assert!(pump(&mut server, &mut clients, |c| c[0].is_interpolation_ready()));
+    drop(server);
+
+    let keys = KeyLog::open(&key_log_path).unwrap();
+    assert_eq!(keys.len(), 1);
+
+    let mut sealed = 0;
+    let mut snapshots = 0;
+    for record in CaptureReader::open(&capture_path).unwrap() {
+        let record = record.unwrap();
+        let Ok(packet) = Packet::deserialize(&record.data) else {
+            continue;
+        };
+        if !packet.is_encrypted() {
+            continue;
+        }
+        sealed += 1;
+        let opened = keys
+            .open_packet(record.direction, record.peer, &packet)
+            .expect("sealed packet didn't open with the logged keys");
+        if record.direction == CaptureDirection::Sent {
+            snapshots += opened
+                .messages
+                .iter()
+                .filter(|m| matches!(m.payload, PacketType::WorldSnapshot(_)))
+                .count();
+        }
+    }
+    assert!(sealed > 0);
+    assert!(snapshots > 0);
+
+    std::fs::remove_file(capture_path).unwrap();
+    std::fs::remove_file(key_log_path).unwrap();
+}
@@ -0,0 +1,84 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+
+use dual::net::{
+    DEFAULT_TOKEN_EXPIRY_SECS, TokenIssuer, generate_client_id, generate_key, key_from_hex,
+    key_to_hex,
+};
+
+#[derive(Parser)]
+#[command(name = "dual-token")]
+#[command(about = "Issue connect tokens for Dual servers")]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generate a new private key to share between the issuer and servers
+    Keygen,
+    /// Write a connect token for one client
+    Issue {
+        #[arg(long, help = "Hex private key shared with the servers")]
+        private_key: String,
+
+        #[arg(long, help = "Client id to embed (random if omitted)")]
+        client_id: Option<u64>,
+
+        #[arg(
+            short,
+            long = "server",
+            required = true,
+            help = "Server address the token is valid for; repeat for fallbacks"
+        )]
+        servers: Vec<SocketAddr>,
+
+        #[arg(long, default_value_t = DEFAULT_TOKEN_EXPIRY_SECS, help = "Seconds until the token expires")]
+        expires_in: u64,
+
+        #[arg(short, long, help = "File to write the token to")]
+        output: PathBuf,
+    },
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    match args.command {
+        Command::Keygen => {
+            println!("{}", key_to_hex(&generate_key()?));
+        }
+        Command::Issue {
+            private_key,
+            client_id,
+            servers,
+            expires_in,
+            output,
+        } => {
+            let issuer = TokenIssuer::new(key_from_hex(&private_key)?)
+                .with_expiry(Duration::from_secs(expires_in));
+            let client_id = match client_id {
+                Some(id) => id,
+                None => generate_client_id()?,
+            };
+
+            let token = issuer.issue(client_id, &servers)?;
+            std::fs::write(&output, token.to_bytes()?)?;
+
+            println!(
+                "Issued token for client {} ({} server(s), expires in {}s) to {}",
+                client_id,
+                servers.len(),
+                expires_in,
+                output.display()
+            );
+        }
+    }
+
+    Ok(())
+}